@@ -6,8 +6,9 @@ use std::fmt::Display;
 use std::time::Duration;
 
 use hsm_api::{
-    EntryMac, GroupConfigurationStatement, GroupId, HsmId, HsmRealmStatement, LogIndex, OwnedRange,
-    Partition, PreparedTransferStatement, RecordId, RoleStatus, TransferNonce, TransferStatement,
+    EntryMac, GroupConfigurationStatement, GroupId, GroupMemberRole, HsmId, HsmRealmStatement,
+    LogIndex, OwnedRange, Partition, PreparedTransferStatement, RecordFingerprint, RecordId,
+    RegistrationStatus, RoleStatus, TenantRecordGrant, TransferNonce, TransferStatement,
 };
 use juicebox_marshalling::bytes;
 use juicebox_networking::rpc::{Rpc, Service};
@@ -39,6 +40,23 @@ pub struct AgentStatus {
     pub name: String,
     pub build_hash: String,
     pub groups: Vec<AgentGroupStatus>,
+    /// The agent's software and protocol versions. Agents that predate
+    /// versioning leave this out.
+    #[serde(default)]
+    pub version: Option<AgentVersion>,
+}
+
+/// Part of [`AgentStatus`]. Describes what software an agent is running, so
+/// that mixed versions can be detected during a rolling upgrade.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AgentVersion {
+    /// The git commit hash the agent software was built from, for display.
+    pub software: String,
+    /// The agent/HSM protocol version that the agent speaks (see
+    /// [`hsm_api::HSM_PROTOCOL_VERSION`]).
+    pub protocol: u16,
+    /// The oldest HSM protocol version that the agent works with.
+    pub min_hsm_protocol: u16,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -56,6 +74,65 @@ pub struct AgentGroupLeaderStatus {
     pub append_queue_len: usize,
 }
 
+impl Rpc<AgentService> for PartitionWatchRequest {
+    const PATH: &'static str = "partitions/watch";
+    type Response = PartitionWatchResponse;
+}
+
+/// Long-polls for changes to the partitions that the agent's HSM is leading.
+///
+/// Load balancers use this to keep their routing tables up to date without
+/// repeatedly asking every agent for its full status.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PartitionWatchRequest {
+    /// The version of the partitions that the caller already has, if any. The
+    /// agent responds immediately if its version differs from this.
+    pub since: Option<u64>,
+    /// How long the agent should wait for a change before responding with its
+    /// current (unchanged) partitions. The agent may wait less than this.
+    pub timeout: Duration,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PartitionWatchResponse {
+    /// Identifies this set of partitions. An agent's versions increase with
+    /// each change, so a larger version from the same agent is newer.
+    pub version: u64,
+    pub partitions: Vec<LeaderPartition>,
+    /// Every group that the agent's HSM is a member of, whether or not it's
+    /// leading it. Load balancers use this to find a group's new leader.
+    pub groups: Vec<(RealmId, GroupId)>,
+}
+
+/// A range of record IDs owned by a group that the agent's HSM is leading.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct LeaderPartition {
+    pub realm: RealmId,
+    pub group: GroupId,
+    pub owned_range: OwnedRange,
+    /// The first log index generated during this HSM's leadership. A later
+    /// leader of the same group always has a larger term.
+    pub term: LogIndex,
+}
+
+impl LeaderPartition {
+    /// Returns the partition the HSM owns in the group, if it's leading (or
+    /// stepping down from leading) the group.
+    pub fn from_group_status(realm: RealmId, group: &hsm_api::GroupStatus) -> Option<Self> {
+        let term = match group.role.role {
+            GroupMemberRole::Leader { starting } => starting,
+            GroupMemberRole::SteppingDown { leader_starting } => leader_starting,
+            GroupMemberRole::Witness => return None,
+        };
+        Some(Self {
+            realm,
+            group: group.id,
+            owned_range: group.leader.as_ref()?.owned_range.clone()?,
+            term,
+        })
+    }
+}
+
 impl Rpc<AgentService> for NewRealmRequest {
     const PATH: &'static str = "realm/new";
     type Response = NewRealmResponse;
@@ -404,8 +481,29 @@ pub enum RateLimitStateResponse {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct TenantRateLimitState {
     pub tenant: String,
+    /// The state of the tenant's overall limiter.
+    #[serde(with = "bytes")]
+    pub state: Vec<u8>,
+    /// The number of the tenant's requests this agent allowed in the last
+    /// second under its overall limit.
+    #[serde(default)]
+    pub recent_requests: usize,
+    /// The tenant's limiters for realms that it has a separate capacity in.
+    /// These are kept out of the entries above so that older agents skip
+    /// them, rather than counting them against the tenant's overall limit.
+    #[serde(default)]
+    pub realms: Vec<RealmRateLimitState>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RealmRateLimitState {
+    pub realm: RealmId,
     #[serde(with = "bytes")]
     pub state: Vec<u8>,
+    /// The number of the tenant's requests in this realm that this agent
+    /// allowed in the last second.
+    #[serde(default)]
+    pub recent_requests: usize,
 }
 
 impl Rpc<AgentService> for ReloadTenantConfigurationRequest {
@@ -437,6 +535,9 @@ pub struct AppRequest {
     pub encrypted: NoiseRequest,
     pub tenant: String,
     pub user: HashedUserId,
+    /// Set if the user's auth token named a secret slot.
+    #[serde(default)]
+    pub slot: Option<HashedSlotId>,
 }
 
 /// A hashed version of the user id that is used for the tenant event log. The
@@ -463,6 +564,32 @@ impl Display for HashedUserId {
     }
 }
 
+/// A hashed version of a user's secret slot that is used for the tenant event
+/// log and user accounting. Like [`HashedUserId`], the tenant needs to be able
+/// to calculate the same hash, so this needs to be stable & published.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HashedSlotId(String);
+
+impl HashedSlotId {
+    pub fn new(tenant: &str, user: &str, slot: &str) -> Self {
+        assert!(!tenant.contains(':'));
+        let h = Sha256::new()
+            .chain_update(tenant.as_bytes())
+            .chain_update([b':'])
+            .chain_update(user.as_bytes())
+            .chain_update([b':'])
+            .chain_update(slot.as_bytes())
+            .finalize();
+        HashedSlotId(hex::encode(h))
+    }
+}
+
+impl Display for HashedSlotId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 #[allow(clippy::large_enum_variant)]
 pub enum AppResponse {
@@ -478,4 +605,97 @@ pub enum AppResponse {
     SessionError,
     DecodingError,
     RateLimitExceeded,
+    /// The request would register a new secret, but the tenant has reached
+    /// its quota of registered secrets in this realm.
+    QuotaExceeded,
+    /// The tenant has been suspended, so requests for its users are rejected.
+    TenantSuspended,
+}
+
+impl Rpc<AgentService> for TenantRecordRequest {
+    const PATH: &'static str = "records/tenant";
+    type Response = TenantRecordResponse;
+}
+
+/// Performs an operation on a user's record on behalf of the tenant that owns
+/// it, such as deleting the user's registration. This is not authorized by
+/// the user. Instead, the grant must be signed by a realm service that has
+/// validated the tenant's admin token. Both the agent and the HSM check the
+/// grant.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TenantRecordRequest {
+    pub realm: RealmId,
+    pub group: GroupId,
+    pub grant: TenantRecordGrant,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum TenantRecordResponse {
+    /// The operation has committed. `status` and `fingerprint` describe the
+    /// record from before the operation. The fingerprint is what a `Delete`
+    /// grant for the record must be bound to.
+    Ok {
+        status: RegistrationStatus,
+        fingerprint: Option<RecordFingerprint>,
+    },
+    NoHsm,
+    NoStore,
+    InvalidRealm,
+    InvalidGroup,
+    NotLeader,
+    NotOwner,
+    InvalidProof,
+    CommitTimeout,
+    /// The grant has expired or wasn't signed by a trusted key.
+    Unauthorized,
+    /// The record has changed since the `Delete` grant was made. The caller
+    /// should read the record's status again and make a new grant.
+    RecordChanged,
+    /// The agent's HSM predates tenant record requests.
+    Unsupported,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use juicebox_marshalling as marshalling;
+
+    /// [`TenantRateLimitState`] as sent and expected by agents from before
+    /// tenants could have separate realm capacities.
+    #[derive(Debug, Deserialize, Serialize)]
+    struct OldTenantRateLimitState {
+        tenant: String,
+        #[serde(with = "bytes")]
+        state: Vec<u8>,
+    }
+
+    #[test]
+    fn tenant_rate_limit_state_mixed_versions() {
+        let new = vec![TenantRateLimitState {
+            tenant: String::from("acme"),
+            state: vec![1, 2, 3],
+            recent_requests: 7,
+            realms: vec![RealmRateLimitState {
+                realm: RealmId([4; 16]),
+                state: vec![5, 6],
+                recent_requests: 2,
+            }],
+        }];
+
+        // Old agents see only the tenant's overall limiter.
+        let old: Vec<OldTenantRateLimitState> =
+            marshalling::from_slice(&marshalling::to_vec(&new).unwrap()).unwrap();
+        assert_eq!(old.len(), 1);
+        assert_eq!(old[0].tenant, "acme");
+        assert_eq!(old[0].state, vec![1, 2, 3]);
+
+        // New agents treat old states as having no realm limiters.
+        let states: Vec<TenantRateLimitState> =
+            marshalling::from_slice(&marshalling::to_vec(&old).unwrap()).unwrap();
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].tenant, "acme");
+        assert_eq!(states[0].state, vec![1, 2, 3]);
+        assert_eq!(states[0].recent_requests, 0);
+        assert!(states[0].realms.is_empty());
+    }
 }
@@ -20,7 +20,7 @@ use cluster_core::hsm_ids;
 use election::HsmElection;
 use hsm_api::{
     AppResultType, Captured, CommitRequest, CommitResponse, EntryMac, GroupId, GroupMemberRole,
-    HsmId, LogIndex, PersistStateRequest, PersistStateResponse,
+    HsmId, LogIndex, PersistStateRequest, PersistStateResponse, RoleLogicalClock,
 };
 use jburl::Url;
 use juicebox_networking::rpc::{self, SendOptions};
@@ -29,8 +29,10 @@ use juicebox_realm_api::types::RealmId;
 use observability::logging::TracingSource;
 use observability::metrics::{self, Tag};
 use observability::metrics_tag as tag;
+use retry_loop::RetryError;
 use service_core::http::ReqwestClientMetrics;
 use store::log::LogRow;
+use store::log_archive::{ArchiveError, LogArchiver};
 use store::ServiceKind;
 
 /// Returned by [`Agent::commit_maybe`] and its helper [`Agent::do_commit`].
@@ -497,20 +499,20 @@ impl<T: Transport + 'static> Agent<T> {
     /// Compacts the log a single time.
     #[instrument(level = "trace", skip(self), fields(rows))]
     async fn compact_once(&self, realm: RealmId, group: GroupId, compact_index: LogIndex) {
-        let (to_compact, stats): (Vec<LogRow>, UncompactedRowsStats) =
+        let (to_compact, stats, role_at): (Vec<LogRow>, UncompactedRowsStats, RoleLogicalClock) =
             match with_lock!(&self.0.state, |locked| {
-                match group_state_mut(&mut locked.groups, realm, group)
-                    .leader
-                    .as_mut()
-                {
+                let state = group_state_mut(&mut locked.groups, realm, group);
+                let role_at = state.role.at;
+                match state.leader.as_mut() {
                     None => Err(()),
                     Some(leader) => Ok((
                         split_off_compactible_prefix(&mut leader.uncompacted_rows, compact_index),
                         UncompactedRowsStats::new(leader),
+                        role_at,
                     )),
                 }
             }) {
-                Ok((rows, stats)) => (rows, stats),
+                Ok(result) => result,
                 Err(_) => return, // no longer leader;
             };
 
@@ -518,6 +520,32 @@ impl<T: Transport + 'static> Agent<T> {
         Span::current().record("rows", to_compact.len());
 
         if !to_compact.is_empty() {
+            if let Some(archiver) = &self.0.log_archiver {
+                if let Err(err) = self
+                    .archive_rows(archiver, &realm, &group, &to_compact)
+                    .await
+                {
+                    // The entries must not be tombstoned until they've been
+                    // archived. Put the rows back so that they're retried the
+                    // next time the compaction index advances. If leadership
+                    // changed while archiving, the rows belong to a previous
+                    // term and are dropped: a later leader will compact them.
+                    warn!(?realm, ?group, ?err, "failed to archive log entries");
+                    with_lock!(&self.0.state, |locked| {
+                        let state = group_state_mut(&mut locked.groups, realm, group);
+                        if state.role.at != role_at {
+                            return;
+                        }
+                        if let Some(leader) = state.leader.as_mut() {
+                            for row in to_compact.into_iter().rev() {
+                                leader.uncompacted_rows.push_front(row);
+                            }
+                        }
+                    });
+                    return;
+                }
+            }
+
             self.0
                 .store
                 .replace_oldest_rows_with_tombstones(&realm, &group, &to_compact)
@@ -526,6 +554,39 @@ impl<T: Transport + 'static> Agent<T> {
         }
     }
 
+    /// Writes the entries in the given log rows to the archive, ahead of them
+    /// being replaced with tombstones.
+    async fn archive_rows(
+        &self,
+        archiver: &LogArchiver,
+        realm: &RealmId,
+        group: &GroupId,
+        rows: &[LogRow],
+    ) -> Result<(), ArchiveRowsError> {
+        let entries = self
+            .0
+            .store
+            .read_log_rows(realm, group, rows)
+            .await
+            .map_err(ArchiveRowsError::Read)?;
+
+        // Rows that a previous leader already replaced with tombstones leave
+        // gaps, so each consecutive run of entries gets its own segment.
+        let mut start = 0;
+        for i in 1..=entries.len() {
+            if i == entries.len() || entries[i].index != entries[i - 1].index.next() {
+                archiver.archive(realm, group, &entries[start..i]).await?;
+                start = i;
+            }
+        }
+        self.0.metrics.count(
+            "agent.compaction.archived_entries",
+            i64::try_from(entries.len()).unwrap(),
+            [tag!(?realm), tag!(?group)],
+        );
+        Ok(())
+    }
+
     /// Returns true if all the other HSMs in the group (excluding the local
     /// one) are in the witness role, and false if they couldn't be reached or
     /// aren't witnesses.
@@ -577,6 +638,15 @@ impl<T: Transport + 'static> Agent<T> {
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+enum ArchiveRowsError {
+    #[error("failed to read log rows: {0}")]
+    Read(RetryError<tonic::Status>),
+
+    #[error(transparent)]
+    Archive(#[from] ArchiveError),
+}
+
 /// Removes the rows that should be replaced with tombstones from the front of
 /// `rows` and returns those.
 fn split_off_compactible_prefix(
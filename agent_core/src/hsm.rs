@@ -1,7 +1,7 @@
 use std::fmt::{self, Debug};
 use std::future::Future;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tokio::time::Instant;
 use tracing::{instrument, span::Span};
 
@@ -9,6 +9,9 @@ use hsm_api::rpc::{HsmRequestContainer, HsmResponseContainer, HsmRpc, MetricsAct
 use juicebox_marshalling::{self as marshalling, DeserializationError, SerializationError};
 use observability::{metrics, metrics_tag as tag};
 use retry_loop::AttemptError;
+use trace::{Recorder, TraceRecord};
+
+pub mod trace;
 
 pub trait Transport: fmt::Debug + Send + Sync {
     type FatalError: fmt::Debug + From<SerializationError> + From<DeserializationError> + Send;
@@ -37,6 +40,7 @@ struct HsmClientInner<T> {
     transport: T,
     name: String,
     dd_metrics: metrics::Client,
+    recorder: Option<Recorder>,
 }
 
 impl<T> Clone for HsmClient<T> {
@@ -46,11 +50,17 @@ impl<T> Clone for HsmClient<T> {
 }
 
 impl<T: Transport> HsmClient<T> {
-    pub fn new(t: T, name: String, dd_metrics: metrics::Client) -> Self {
+    pub fn new(
+        t: T,
+        name: String,
+        dd_metrics: metrics::Client,
+        recorder: Option<Recorder>,
+    ) -> Self {
         Self(Arc::new(HsmClientInner {
             transport: t,
             name,
             dd_metrics,
+            recorder,
         }))
     }
 
@@ -78,10 +88,21 @@ impl<T: Transport> HsmClient<T> {
         })?;
         Span::current().record("req_len", req_bytes.len());
 
+        let recorded_req = self.0.recorder.as_ref().map(|_| req_bytes.clone());
+        let time = SystemTime::now();
         let start = Instant::now();
-        let res_bytes = self.0.transport.send_rpc_msg(req_name, req_bytes).await?;
+        let result = self.0.transport.send_rpc_msg(req_name, req_bytes).await;
 
         let dur = start.elapsed();
+        if let (Some(recorder), Some(request)) = (&self.0.recorder, recorded_req) {
+            recorder.record(TraceRecord {
+                time,
+                duration: dur,
+                request,
+                response: result.as_ref().ok().cloned(),
+            });
+        }
+        let res_bytes = result?;
         Span::current().record("resp_len", res_bytes.len());
         Span::current().record("rpc_dur", format!("{dur:?}"));
 
@@ -0,0 +1,489 @@
+//! Recording of the agent's RPC traffic with its HSM.
+//!
+//! When a [`Recorder`] is configured, [`HsmClient::send`](super::HsmClient::send)
+//! writes every marshalled `HsmRequestContainer` it sends, along with the
+//! HSM's response, to a trace. The `hsm_replay` tool replays a trace against
+//! a software HSM to reproduce bugs that are otherwise only visible inside
+//! the agent.
+//!
+//! A trace is a directory of files named `{seq}.hsmtrace`, with `seq`
+//! zero-padded so that listing the directory in lexicographic order gives
+//! the files in the order they were written. The recorder starts a new file
+//! once the current one exceeds a size limit, and deletes the oldest files to
+//! keep a bounded number of them.
+//!
+//! A file's contents are a magic value and a format version, followed by
+//! records. Each record holds, with all integers big-endian:
+//!
+//! - when the request was sent, in nanoseconds since the Unix epoch (u64),
+//! - how long the transport took to return, in nanoseconds (u64),
+//! - the request's length (u32) and bytes,
+//! - a 1 followed by the response's length (u32) and bytes, or a 0 if the
+//!   transport returned an error instead.
+//!
+//! Records are written in the order the responses arrived. Requests that
+//! were in flight at the same time may have been handled by the HSM in a
+//! different order.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use observability::metrics;
+
+/// Identifies a trace file.
+const MAGIC: &[u8; 8] = b"jbhsmtrc";
+
+/// The current trace format version.
+const TRACE_VERSION: u16 = 1;
+
+const TRACE_SUFFIX: &str = ".hsmtrace";
+
+/// The longest request or response a record may hold. HSM messages are far
+/// smaller than this, so a longer length means the file is corrupt, and
+/// it's rejected before allocating a buffer for it.
+const MAX_MESSAGE_LEN: u32 = 64 << 20;
+
+/// The number of records that can be waiting for the writer thread. Once
+/// this many are queued, further records are dropped rather than blocking
+/// the agent or using unbounded memory.
+const QUEUE_LEN: usize = 4096;
+
+/// One RPC to the HSM.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TraceRecord {
+    /// When the request was sent.
+    pub time: SystemTime,
+    /// How long the transport took to return.
+    pub duration: Duration,
+    /// The marshalled `HsmRequestContainer`.
+    pub request: Vec<u8>,
+    /// The marshalled `HsmResponseContainer`, or `None` if the transport
+    /// returned an error. The HSM may or may not have handled the request.
+    pub response: Option<Vec<u8>>,
+}
+
+impl TraceRecord {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let time = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        out.extend(saturating_nanos(time).to_be_bytes());
+        out.extend(saturating_nanos(self.duration).to_be_bytes());
+        encode_bytes(&self.request, out);
+        match &self.response {
+            Some(response) => {
+                out.push(1);
+                encode_bytes(response, out);
+            }
+            None => out.push(0),
+        }
+    }
+
+    /// Reads the next record, returning `None` at the end of the file.
+    fn decode(r: &mut impl Read) -> Result<Option<Self>, TraceError> {
+        let mut time = [0; 8];
+        match r.read_exact(&mut time) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+        let time = UNIX_EPOCH + Duration::from_nanos(u64::from_be_bytes(time));
+        let duration = Duration::from_nanos(u64::from_be_bytes(read_array(r)?));
+        let request = decode_bytes(r)?;
+        let response = match read_array::<1>(r)? {
+            [0] => None,
+            [1] => Some(decode_bytes(r)?),
+            [b] => return Err(TraceError::Corrupt(format!("bad response marker {b}"))),
+        };
+        Ok(Some(Self {
+            time,
+            duration,
+            request,
+            response,
+        }))
+    }
+}
+
+fn saturating_nanos(d: Duration) -> u64 {
+    d.as_nanos().try_into().unwrap_or(u64::MAX)
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend(u32::try_from(bytes.len()).unwrap().to_be_bytes());
+    out.extend(bytes);
+}
+
+fn decode_bytes(r: &mut impl Read) -> Result<Vec<u8>, TraceError> {
+    let len = u32::from_be_bytes(read_array(r)?);
+    if len > MAX_MESSAGE_LEN {
+        return Err(TraceError::Corrupt(format!(
+            "message length {len} exceeds {MAX_MESSAGE_LEN}"
+        )));
+    }
+    let mut bytes = vec![0; len as usize];
+    read_exact(r, &mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> Result<[u8; N], TraceError> {
+    let mut buf = [0; N];
+    read_exact(r, &mut buf)?;
+    Ok(buf)
+}
+
+// Like `Read::read_exact`, but running out of data part way through a record
+// means the record is truncated.
+fn read_exact(r: &mut impl Read, buf: &mut [u8]) -> Result<(), TraceError> {
+    r.read_exact(buf).map_err(|err| match err.kind() {
+        ErrorKind::UnexpectedEof => TraceError::Truncated,
+        _ => TraceError::Io(err),
+    })
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TraceError {
+    #[error("trace I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("not an HSM trace file")]
+    NotATrace,
+
+    #[error("unsupported trace format version {0}")]
+    UnsupportedVersion(u16),
+
+    /// The file ends part way through a record. This is expected for the
+    /// last file if the agent crashed while writing it.
+    #[error("trace file ends part way through a record")]
+    Truncated,
+
+    #[error("trace file is corrupt: {0}")]
+    Corrupt(String),
+}
+
+/// Returns the trace files in `dir`, oldest first.
+pub fn trace_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .filter(|path| match path {
+            Ok(path) => file_seq(path).is_some(),
+            Err(_) => true,
+        })
+        .collect::<io::Result<_>>()?;
+    files.sort();
+    Ok(files)
+}
+
+fn file_seq(path: &Path) -> Option<u64> {
+    path.file_name()?
+        .to_str()?
+        .strip_suffix(TRACE_SUFFIX)?
+        .parse()
+        .ok()
+}
+
+/// Reads the records from a trace file. It stops after returning an error.
+pub struct TraceReader {
+    reader: BufReader<File>,
+    failed: bool,
+}
+
+impl TraceReader {
+    pub fn open(path: &Path) -> Result<Self, TraceError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; MAGIC.len()];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|err| match err.kind() {
+                ErrorKind::UnexpectedEof => TraceError::NotATrace,
+                _ => TraceError::Io(err),
+            })?;
+        if &magic != MAGIC {
+            return Err(TraceError::NotATrace);
+        }
+        let version = u16::from_be_bytes(read_array(&mut reader)?);
+        if version != TRACE_VERSION {
+            return Err(TraceError::UnsupportedVersion(version));
+        }
+        Ok(Self {
+            reader,
+            failed: false,
+        })
+    }
+}
+
+impl Iterator for TraceReader {
+    type Item = Result<TraceRecord, TraceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = TraceRecord::decode(&mut self.reader).transpose();
+        self.failed = matches!(result, Some(Err(_)));
+        result
+    }
+}
+
+/// Where and how much a [`Recorder`] writes.
+#[derive(Clone, Debug)]
+pub struct RecorderOptions {
+    /// The directory to write the trace files in. It's created if needed.
+    pub dir: PathBuf,
+    /// The size after which the recorder starts a new file.
+    pub max_file_bytes: u64,
+    /// The number of files to keep, including the current one. Older files
+    /// are deleted.
+    pub max_files: usize,
+}
+
+/// Writes [`TraceRecord`]s to a rotating set of trace files.
+///
+/// The files are written by a dedicated thread, so recording never blocks
+/// the caller. If the thread falls too far behind, records are dropped and
+/// counted in the `agent.hsm_trace.dropped` metric, so a trace with drops
+/// can't be replayed past the first one. Failing to write a record is logged
+/// but otherwise ignored. Dropping the recorder waits for the thread to write
+/// any queued records.
+#[derive(Debug)]
+pub struct Recorder {
+    sender: Option<mpsc::SyncSender<TraceRecord>>,
+    thread: Option<thread::JoinHandle<()>>,
+    metrics: metrics::Client,
+}
+
+impl Recorder {
+    pub fn new(options: RecorderOptions, metrics: metrics::Client) -> Result<Self, TraceError> {
+        assert!(options.max_files > 0);
+        fs::create_dir_all(&options.dir)?;
+        let next_seq = match trace_files(&options.dir)?.last() {
+            Some(last) => file_seq(last).unwrap() + 1,
+            None => 0,
+        };
+        let mut writer = TraceWriter {
+            options,
+            next_seq,
+            current: None,
+        };
+
+        let (sender, receiver) = mpsc::sync_channel::<TraceRecord>(QUEUE_LEN);
+        let thread = thread::Builder::new()
+            .name(String::from("hsm trace recorder"))
+            .spawn(move || {
+                let mut buf = Vec::new();
+                while let Ok(record) = receiver.recv() {
+                    buf.clear();
+                    record.encode(&mut buf);
+                    if let Err(err) = writer.write(&buf) {
+                        warn!(?err, "failed to record HSM RPC");
+                        // Start a new file for the next record, rather
+                        // than appending to one that may have a partial
+                        // record at the end.
+                        writer.current = None;
+                    }
+                }
+            })?;
+        Ok(Self {
+            sender: Some(sender),
+            thread: Some(thread),
+            metrics,
+        })
+    }
+
+    pub fn record(&self, record: TraceRecord) {
+        // This fails if the queue is full or, if the writer thread panicked,
+        // disconnected. Either way the agent carries on without the record.
+        if self.sender.as_ref().unwrap().try_send(record).is_err() {
+            self.metrics
+                .incr("agent.hsm_trace.dropped", metrics::NO_TAGS);
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        // Closing the channel stops the thread once it's caught up.
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            _ = thread.join();
+        }
+    }
+}
+
+struct TraceWriter {
+    options: RecorderOptions,
+    next_seq: u64,
+    // The file being written and its length.
+    current: Option<(BufWriter<File>, u64)>,
+}
+
+impl TraceWriter {
+    fn write(&mut self, record: &[u8]) -> io::Result<()> {
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|(_, len)| *len >= self.options.max_file_bytes)
+        {
+            self.current = None;
+        }
+        let (file, len) = match &mut self.current {
+            Some(current) => current,
+            None => self.current.insert(self.rotate()?),
+        };
+        file.write_all(record)?;
+        // Flush every record, so that a crash loses as little of the trace
+        // as possible.
+        file.flush()?;
+        *len += record.len() as u64;
+        Ok(())
+    }
+
+    // Starts a new file and deletes the oldest ones.
+    fn rotate(&mut self) -> io::Result<(BufWriter<File>, u64)> {
+        let path = self
+            .options
+            .dir
+            .join(format!("{:010}{TRACE_SUFFIX}", self.next_seq));
+        self.next_seq += 1;
+        let mut file = BufWriter::new(File::options().write(true).create_new(true).open(&path)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&TRACE_VERSION.to_be_bytes())?;
+        info!(path = %path.display(), "started new HSM trace file");
+
+        let files = trace_files(&self.options.dir)?;
+        let excess = files.len().saturating_sub(self.options.max_files);
+        for old in &files[..excess] {
+            fs::remove_file(old)?;
+        }
+        Ok((file, (MAGIC.len() + 2) as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(i: u8) -> TraceRecord {
+        TraceRecord {
+            time: UNIX_EPOCH + Duration::from_secs(1_700_000_000 + u64::from(i)),
+            duration: Duration::from_micros(u64::from(i) * 10),
+            request: vec![i; 20],
+            response: (i % 3 != 0).then(|| vec![i; 30]),
+        }
+    }
+
+    fn read_all(dir: &Path) -> Vec<TraceRecord> {
+        trace_files(dir)
+            .unwrap()
+            .iter()
+            .flat_map(|path| TraceReader::open(path).unwrap())
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_record_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let recorder = Recorder::new(
+            RecorderOptions {
+                dir: dir.path().to_owned(),
+                max_file_bytes: 1 << 20,
+                max_files: 2,
+            },
+            metrics::Client::NONE,
+        )
+        .unwrap();
+        let expected: Vec<TraceRecord> = (0..10).map(record).collect();
+        for r in &expected {
+            recorder.record(r.clone());
+        }
+        drop(recorder);
+        assert_eq!(expected, read_all(dir.path()));
+        assert_eq!(1, trace_files(dir.path()).unwrap().len());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let recorder = Recorder::new(
+            RecorderOptions {
+                dir: dir.path().to_owned(),
+                // Each record is 41 or 75 bytes, so this is 2 records per file.
+                max_file_bytes: 100,
+                max_files: 3,
+            },
+            metrics::Client::NONE,
+        )
+        .unwrap();
+        for i in 0..10 {
+            recorder.record(record(i));
+        }
+        drop(recorder);
+        let records = read_all(dir.path());
+        // Only the last 3 files, with the last 6 records, are kept.
+        assert_eq!((4..10).map(record).collect::<Vec<_>>(), records);
+        let names: Vec<String> = trace_files(dir.path())
+            .unwrap()
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(
+            vec![
+                "0000000002.hsmtrace",
+                "0000000003.hsmtrace",
+                "0000000004.hsmtrace"
+            ],
+            names
+        );
+
+        // A new recorder carries on after the existing files.
+        let recorder = Recorder::new(
+            RecorderOptions {
+                dir: dir.path().to_owned(),
+                max_file_bytes: 100,
+                max_files: 3,
+            },
+            metrics::Client::NONE,
+        )
+        .unwrap();
+        recorder.record(record(10));
+        drop(recorder);
+        let records = read_all(dir.path());
+        assert_eq!((6..11).map(record).collect::<Vec<_>>(), records);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(format!("{:010}{TRACE_SUFFIX}", 0));
+        let mut data = MAGIC.to_vec();
+        data.extend(TRACE_VERSION.to_be_bytes());
+        record(1).encode(&mut data);
+        record(2).encode(&mut data);
+        data.truncate(data.len() - 5);
+        fs::write(&path, &data).unwrap();
+
+        let mut reader = TraceReader::open(&path).unwrap();
+        assert_eq!(record(1), reader.next().unwrap().unwrap());
+        assert!(matches!(reader.next(), Some(Err(TraceError::Truncated))));
+        assert!(reader.next().is_none());
+
+        // A corrupt length is rejected rather than allocated.
+        let mut data = MAGIC.to_vec();
+        data.extend(TRACE_VERSION.to_be_bytes());
+        data.extend([0; 16]);
+        data.extend(u32::MAX.to_be_bytes());
+        fs::write(&path, &data).unwrap();
+        let mut reader = TraceReader::open(&path).unwrap();
+        assert!(matches!(reader.next(), Some(Err(TraceError::Corrupt(_)))));
+        assert!(reader.next().is_none());
+
+        fs::write(&path, b"not a trace").unwrap();
+        assert!(matches!(
+            TraceReader::open(&path),
+            Err(TraceError::NotATrace)
+        ));
+    }
+}
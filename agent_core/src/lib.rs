@@ -29,23 +29,26 @@ mod append;
 mod commit;
 pub mod hsm;
 pub mod merkle;
+mod partitions;
 mod peers;
+mod quotas;
 mod rate;
 pub mod service;
+mod tenant_records;
 mod tenants;
 mod transfer;
 
 use agent_api::merkle::TreeStoreError;
 use agent_api::{
-    AgentGroupLeaderStatus, AgentGroupStatus, AgentStatus, AppRequest, AppResponse,
+    AgentGroupLeaderStatus, AgentGroupStatus, AgentStatus, AgentVersion, AppRequest, AppResponse,
     BecomeLeaderRequest, BecomeLeaderResponse, CancelPreparedTransferRequest,
-    CompleteTransferRequest, GroupOwnsRangeRequest, HashedUserId, JoinGroupRequest,
+    CompleteTransferRequest, GroupOwnsRangeRequest, HashedSlotId, HashedUserId, JoinGroupRequest,
     JoinGroupResponse, JoinRealmRequest, JoinRealmResponse, NewGroupRequest, NewGroupResponse,
-    NewRealmRequest, NewRealmResponse, PrepareTransferRequest, RateLimitStateRequest,
-    RateLimitStateResponse, ReadCapturedRequest, ReadCapturedResponse,
-    ReloadTenantConfigurationRequest, ReloadTenantConfigurationResponse, StatusRequest,
-    StatusResponse, StepDownRequest, StepDownResponse, TenantRateLimitState, TransferInRequest,
-    TransferOutRequest,
+    NewRealmRequest, NewRealmResponse, PartitionWatchRequest, PrepareTransferRequest,
+    RateLimitStateRequest, RateLimitStateResponse, ReadCapturedRequest, ReadCapturedResponse,
+    RealmRateLimitState, ReloadTenantConfigurationRequest, ReloadTenantConfigurationResponse,
+    StatusRequest, StatusResponse, StepDownRequest, StepDownResponse, TenantRateLimitState,
+    TenantRecordRequest, TransferInRequest, TransferOutRequest,
 };
 use append::{Append, AppendingState};
 use build_info::BuildInfo;
@@ -54,8 +57,8 @@ use hsm::{HsmClient, Transport};
 use hsm_api::merkle::StoreDelta;
 use hsm_api::{
     AppResultType, CaptureJumpRequest, CaptureJumpResponse, CaptureNextRequest,
-    CaptureNextResponse, Captured, EntryMac, GroupId, GroupMemberRole, HsmId, LogEntry, LogIndex,
-    RoleLogicalClock, RoleStatus,
+    CaptureNextResponse, Captured, EntryMac, GroupId, GroupMemberRole, HsmId, HsmVersion, LogEntry,
+    LogIndex, RoleLogicalClock, RoleStatus, TenantRecordKey, TenantTag, HSM_PROTOCOL_VERSION,
 };
 use jburl::Url;
 use juicebox_marshalling as marshalling;
@@ -66,17 +69,24 @@ use juicebox_realm_api::types::RealmId;
 use observability::logging::TracingSource;
 use observability::tracing::TracingMiddleware;
 use observability::{metrics, metrics_tag as tag};
+use partitions::PartitionPublisher;
 use peers::DiscoveryWatcher;
 use pubsub_api::{Message, Publisher};
-use rate::{PeerId, RateLimiter, Time};
+use quotas::Quotas;
+use rate::{Limit, PeerId, RateLimiter, Time};
 use retry_loop::{retry_logging, retry_logging_debug, AttemptError, Retry, RetryError};
 use service_core::http::ReqwestClientMetrics;
 use service_core::rpc::{handle_rpc, HandlerError};
 use store::log::{LogEntriesIter, LogEntriesIterError, LogRow, ReadLastLogEntryFatal};
+use store::log_archive::LogArchiver;
 use store::tenant_config::TenantConfiguration;
 use store::{discovery, store_retries, ServiceKind};
 use tenants::UserAccountingWriter;
 
+/// The oldest HSM protocol version (see [`hsm_api::HSM_PROTOCOL_VERSION`])
+/// that the agent works with. Raise this to stop supporting older HSMs.
+pub const MIN_HSM_PROTOCOL_VERSION: u16 = 0;
+
 #[derive(Debug)]
 pub struct Agent<T>(Arc<AgentInner<T>>);
 
@@ -92,10 +102,16 @@ struct AgentInner<T> {
     discovery: DiscoveryWatcher,
     state: Mutex<State>,
     tenant_limiters: RateLimiters,
+    quotas: Quotas,
+    /// The reason each suspended tenant was suspended.
+    suspended_tenants: Mutex<HashMap<String, String>>,
     metrics: metrics::Client,
     accountant: UserAccountingWriter,
     event_publisher: Box<dyn Publisher>,
     default_rate_limiter_rate: usize,
+    tenant_record_keys: Vec<TenantRecordKey>,
+    log_archiver: Option<LogArchiver>,
+    partitions: PartitionPublisher,
 }
 
 #[derive(Debug)]
@@ -111,6 +127,8 @@ struct State {
     /// service discovery. It's always available for leaders. It will never go
     /// from `Some` to `None`.
     hsm_id: Option<HsmId>,
+    /// The HSM's versions, from its status at startup.
+    hsm_version: Option<HsmVersion>,
 }
 
 impl State {
@@ -218,7 +236,39 @@ struct RateLimiters(Arc<RateLimitersInner>);
 #[derive(Debug)]
 struct RateLimitersInner {
     last_state: Mutex<Vec<TenantRateLimitState>>,
-    tenants: Mutex<HashMap<String, RateLimiter>>,
+    tenants: Mutex<HashMap<LimiterKey, RateLimiter>>,
+}
+
+/// Identifies a rate limiter. Each tenant has an overall limiter, plus one
+/// for each realm that it has a separate capacity configured for.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct LimiterKey {
+    tenant: String,
+    realm: Option<RealmId>,
+}
+
+impl LimiterKey {
+    fn tenant(tenant: String) -> Self {
+        Self {
+            tenant,
+            realm: None,
+        }
+    }
+
+    /// Returns the key of the limiter that applies to requests for `tenant`
+    /// in `realm`: the tenant's limiter for that realm if it has one,
+    /// otherwise its overall limiter.
+    fn for_request<V>(limiters: &HashMap<LimiterKey, V>, tenant: &str, realm: RealmId) -> Self {
+        let key = Self {
+            tenant: tenant.to_owned(),
+            realm: Some(realm),
+        };
+        if limiters.contains_key(&key) {
+            key
+        } else {
+            Self::tenant(key.tenant)
+        }
+    }
 }
 
 impl RateLimiters {
@@ -238,16 +288,44 @@ impl RateLimiters {
             let new_state: Vec<_> = with_lock!(&self.0.tenants, |tenants_locked| {
                 tenants_locked
                     .iter_mut()
-                    .map(|(t, b)| (t.clone(), b.state(now)))
+                    .map(|(k, b)| (k.clone(), b.state(now)))
                     .collect()
             });
-            let serialized: Vec<_> = new_state
-                .into_iter()
-                .map(|(t, s)| TenantRateLimitState {
-                    tenant: t,
-                    state: marshalling::to_vec(&s).unwrap(),
-                })
-                .collect();
+            let mut by_tenant: HashMap<String, TenantRateLimitState> = HashMap::new();
+            let mut realm_states = Vec::new();
+            for (k, s) in new_state {
+                let state = marshalling::to_vec(&s).unwrap();
+                let recent_requests = s.reqs_since(now - Duration::from_secs(1));
+                match k.realm {
+                    None => {
+                        by_tenant.insert(
+                            k.tenant.clone(),
+                            TenantRateLimitState {
+                                tenant: k.tenant,
+                                state,
+                                recent_requests,
+                                realms: Vec::new(),
+                            },
+                        );
+                    }
+                    Some(realm) => realm_states.push((
+                        k.tenant,
+                        RealmRateLimitState {
+                            realm,
+                            state,
+                            recent_requests,
+                        },
+                    )),
+                }
+            }
+            // Realm limiters are only created alongside the tenant's overall
+            // limiter, so every realm state has a tenant entry to go in.
+            for (tenant, realm_state) in realm_states {
+                if let Some(entry) = by_tenant.get_mut(&tenant) {
+                    entry.realms.push(realm_state);
+                }
+            }
+            let serialized: Vec<_> = by_tenant.into_values().collect();
 
             let prev = with_lock!(&self.0.last_state, |cache_locked| {
                 mem::replace(cache_locked, serialized)
@@ -308,6 +386,10 @@ pub struct AgentConfiguration {
     pub event_publisher: Box<dyn Publisher>,
     pub metrics: metrics::Client,
     pub default_rate_limiter_rate: usize,
+    /// Tenant record grants must be signed by one of these keys.
+    pub tenant_record_keys: Vec<TenantRecordKey>,
+    /// If set, log entries are archived here before being compacted.
+    pub log_archiver: Option<LogArchiver>,
 }
 
 #[cfg(not(feature = "lock_instr"))]
@@ -375,13 +457,19 @@ impl<T: Transport + 'static> Agent<T> {
                 captures: Vec::new(),
                 registered: false,
                 hsm_id: None,
+                hsm_version: None,
                 groups: HashMap::new(),
             }),
             tenant_limiters: RateLimiters::new(config.metrics.clone()),
+            quotas: Quotas::default(),
+            suspended_tenants: Mutex::new(HashMap::new()),
             metrics: config.metrics.clone(),
             accountant: UserAccountingWriter::new(config.store, config.metrics),
             event_publisher: config.event_publisher,
             default_rate_limiter_rate: config.default_rate_limiter_rate,
+            tenant_record_keys: config.tenant_record_keys,
+            log_archiver: config.log_archiver,
+            partitions: PartitionPublisher::new(),
         }))
     }
 
@@ -397,6 +485,25 @@ impl<T: Transport + 'static> Agent<T> {
             .await
             .expect("failed to get HSM status");
 
+        let hsm_version = status.version();
+        if !hsm_version.works_with_agent(HSM_PROTOCOL_VERSION, MIN_HSM_PROTOCOL_VERSION) {
+            panic!(
+                "HSM speaks protocol version {} and needs agents to speak at least {}, \
+                but this agent speaks {} and needs HSMs to speak at least {}",
+                hsm_version.protocol,
+                hsm_version.min_agent_protocol,
+                HSM_PROTOCOL_VERSION,
+                MIN_HSM_PROTOCOL_VERSION,
+            );
+        }
+        info!(
+            software = hsm_version.software,
+            protocol = hsm_version.protocol,
+            log_entry_format = hsm_version.log_entry_format,
+            "HSM version"
+        );
+        self.0.state.lock().unwrap().hsm_version = Some(hsm_version);
+
         let Some(realm) = status.realm else {
             warn!("HSM does not have a realm");
             return;
@@ -689,6 +796,7 @@ impl<T: Transport + 'static> Agent<T> {
                     warn!(?err, "failed to get status from HSM");
                 }
                 Ok(hsm_status) => {
+                    self.0.partitions.update(&hsm_status);
                     if let Some(hsm_realm_status) = hsm_status.realm {
                         let realm = hsm_realm_status.id;
                         with_lock!(&self.0.state, |agent_state| {
@@ -747,6 +855,7 @@ impl<T: Transport + 'static> Agent<T> {
         self.start_service_registration(url.clone());
         self.start_ratelimit_fetcher(url.clone());
         self.start_tenant_config_fetcher().await;
+        self.start_quota_refresher();
         self.restart_watching().await;
         self.start_nvram_writer();
         let wd = self.clone();
@@ -905,7 +1014,7 @@ impl<T: Transport + 'static> Agent<T> {
         let mut disco_rx = self.0.discovery.subscribe(ServiceKind::Agent);
         tokio::spawn(async move {
             let mut urls = disco_rx.borrow_and_update().excluding(&our_url);
-            let mut tenant_states: HashMap<String, rate::PeerStates> = HashMap::new();
+            let mut tenant_states: HashMap<LimiterKey, rate::PeerStates> = HashMap::new();
             info!(peers=?urls, "starting rate limit collector");
             loop {
                 if matches!(disco_rx.has_changed(), Ok(true)) {
@@ -933,8 +1042,19 @@ impl<T: Transport + 'static> Agent<T> {
                 while let Some((peer, result)) = peer_results.next().await {
                     if let Ok(RateLimitStateResponse::Ok(updates)) = result {
                         let now = Time::now();
+                        let mut states = Vec::with_capacity(updates.len());
                         for update in updates {
-                            match marshalling::from_slice(&update.state) {
+                            for rs in update.realms {
+                                let key = LimiterKey {
+                                    tenant: update.tenant.clone(),
+                                    realm: Some(rs.realm),
+                                };
+                                states.push((key, rs.state));
+                            }
+                            states.push((LimiterKey::tenant(update.tenant), update.state));
+                        }
+                        for (key, state) in states {
+                            match marshalling::from_slice(&state) {
                                 Err(err) => {
                                     warn!(
                                         ?peer,
@@ -943,7 +1063,7 @@ impl<T: Transport + 'static> Agent<T> {
                                     );
                                 }
                                 Ok(state) => {
-                                    tenant_states.entry(update.tenant).or_default().update(
+                                    tenant_states.entry(key).or_default().update(
                                         now,
                                         peer.clone(),
                                         state,
@@ -957,15 +1077,27 @@ impl<T: Transport + 'static> Agent<T> {
                 let now = Time::now();
                 let merged_states: Vec<_> = tenant_states
                     .iter_mut()
-                    .map(|(tenant, states)| (tenant.clone(), states.merged(now)))
+                    .map(|(key, states)| (key.clone(), states.merged(now)))
                     .collect();
 
                 with_lock!(&agent.tenant_limiters.0.tenants, |locked| {
-                    for (tenant, state) in merged_states {
-                        locked
-                            .entry(tenant)
-                            .or_insert_with(|| RateLimiter::new(agent.default_rate_limiter_rate))
-                            .update_from_peers(state);
+                    for (key, state) in merged_states {
+                        if key.realm.is_some() {
+                            // Realm limiters only exist for tenants with a
+                            // configured realm capacity. If we don't know
+                            // about it yet, the next config reload will add
+                            // it.
+                            if let Some(rl) = locked.get_mut(&key) {
+                                rl.update_from_peers(now, state);
+                            }
+                        } else {
+                            locked
+                                .entry(key)
+                                .or_insert_with(|| {
+                                    RateLimiter::new(Limit::new(agent.default_rate_limiter_rate))
+                                })
+                                .update_from_peers(now, state);
+                        }
                     }
                 });
                 sleep(Duration::from_millis(10)).await;
@@ -974,7 +1106,7 @@ impl<T: Transport + 'static> Agent<T> {
     }
 
     async fn start_tenant_config_fetcher(&self) {
-        let mut last = match self.reload_tenant_config(&[]).await {
+        let mut last = match self.reload_tenant_config(None).await {
             Ok(ReloadTenantConfigResult::Updated(config)) => config,
             Ok(ReloadTenantConfigResult::NotChanged) => Vec::new(),
             Err(_) => Vec::new(),
@@ -983,7 +1115,7 @@ impl<T: Transport + 'static> Agent<T> {
         tokio::spawn(async move {
             loop {
                 sleep(Duration::from_secs(60)).await;
-                match agent.reload_tenant_config(&last).await {
+                match agent.reload_tenant_config(Some(&last)).await {
                     Ok(ReloadTenantConfigResult::NotChanged) => {}
                     Ok(ReloadTenantConfigResult::Updated(new_config)) => {
                         last = new_config;
@@ -996,9 +1128,11 @@ impl<T: Transport + 'static> Agent<T> {
         });
     }
 
+    /// Reads the tenant configuration from the store and applies it, unless
+    /// it's the same as `last`.
     async fn reload_tenant_config(
         &self,
-        last: &[(String, TenantConfiguration)],
+        last: Option<&[(String, TenantConfiguration)]>,
     ) -> Result<ReloadTenantConfigResult, RetryError<tonic::Status>> {
         match self.0.store.get_tenants().await {
             Err(err) => {
@@ -1007,17 +1141,67 @@ impl<T: Transport + 'static> Agent<T> {
             }
             Ok(tenants) => {
                 debug!(?tenants, "got tenant info from bigtable");
-                if tenants == last {
+                if last == Some(tenants.as_slice()) {
                     return Ok(ReloadTenantConfigResult::NotChanged);
                 }
+                let mut limits: HashMap<LimiterKey, Limit> = HashMap::new();
+                for (tenant, config) in &tenants {
+                    let record_per_min = config.record_reqs_per_min();
+                    limits.insert(
+                        LimiterKey::tenant(tenant.clone()),
+                        Limit {
+                            rate: config.capacity_reqs_per_sec(),
+                            burst: config.burst_reqs(),
+                            record_per_min,
+                        },
+                    );
+                    for rc in &config.realm_capacities {
+                        limits.insert(
+                            LimiterKey {
+                                tenant: tenant.clone(),
+                                realm: Some(rc.realm),
+                            },
+                            Limit {
+                                rate: rc.capacity_reqs_per_sec(),
+                                burst: rc.burst_reqs(),
+                                record_per_min,
+                            },
+                        );
+                    }
+                }
                 with_lock!(&self.0.tenant_limiters.0.tenants, |locked| {
-                    for (tenant, config) in &tenants {
+                    // Tenants whose configuration was deleted go back to the
+                    // default limit, and realm limiters that are no longer
+                    // configured are dropped.
+                    locked.retain(|key, rl| match limits.get(key) {
+                        Some(_) => true,
+                        None if key.realm.is_none() => {
+                            rl.update_limit(Limit::new(self.0.default_rate_limiter_rate));
+                            true
+                        }
+                        None => false,
+                    });
+                    for (key, limit) in limits {
                         locked
-                            .entry(tenant.clone())
-                            .and_modify(|rl| rl.update_limit(config.capacity_reqs_per_sec()))
-                            .or_insert_with(|| RateLimiter::new(config.capacity_reqs_per_sec()));
+                            .entry(key)
+                            .and_modify(|rl| rl.update_limit(limit))
+                            .or_insert_with(|| RateLimiter::new(limit));
                     }
                 });
+                self.0.quotas.update_limits(&tenants);
+                let suspended: HashMap<String, String> = tenants
+                    .iter()
+                    .filter_map(|(tenant, config)| {
+                        config
+                            .suspended
+                            .as_ref()
+                            .map(|reason| (tenant.clone(), reason.clone()))
+                    })
+                    .collect();
+                for (tenant, reason) in &suspended {
+                    info!(%tenant, %reason, "tenant is suspended");
+                }
+                *self.0.suspended_tenants.lock().unwrap() = suspended;
                 info!(num_tenants=?tenants.len(), "updated tenant rate limiting configuration");
                 Ok(ReloadTenantConfigResult::Updated(tenants))
             }
@@ -1028,7 +1212,7 @@ impl<T: Transport + 'static> Agent<T> {
         &self,
         _: ReloadTenantConfigurationRequest,
     ) -> Result<ReloadTenantConfigurationResponse, HandlerError> {
-        match self.reload_tenant_config(&[]).await {
+        match self.reload_tenant_config(None).await {
             Err(_) => Ok(ReloadTenantConfigurationResponse::NoStore),
             Ok(ReloadTenantConfigResult::NotChanged) => {
                 Ok(ReloadTenantConfigurationResponse::Ok { num_tenants: 0 })
@@ -1075,6 +1259,9 @@ impl<T: Transport + 'static> Service<Request<IncomingBody>> for Agent<T> {
                 match path {
                     AppRequest::PATH => handle_rpc(&agent, request, Self::handle_app).await,
                     StatusRequest::PATH => handle_rpc(&agent, request, Self::handle_status).await,
+                    PartitionWatchRequest::PATH => {
+                        handle_rpc(&agent, request, Self::handle_partition_watch).await
+                    }
                     RateLimitStateRequest::PATH => {
                         handle_rpc(&agent, request, Self::handle_ratelimit_state).await
                     }
@@ -1121,6 +1308,9 @@ impl<T: Transport + 'static> Service<Request<IncomingBody>> for Agent<T> {
                     GroupOwnsRangeRequest::PATH => {
                         handle_rpc(&agent, request, Self::handle_group_owns_range).await
                     }
+                    TenantRecordRequest::PATH => {
+                        handle_rpc(&agent, request, Self::handle_tenant_record).await
+                    }
                     _ => Ok(Response::builder()
                         .status(hyper::StatusCode::NOT_FOUND)
                         .body(Full::from(Bytes::new()))
@@ -1184,6 +1374,11 @@ impl<T: Transport + 'static> Agent<T> {
                 name: self.0.name.clone(),
                 build_hash: self.0.build_info.git_hash.unwrap_or("").to_owned(),
                 groups,
+                version: Some(AgentVersion {
+                    software: self.0.build_info.git_hash.unwrap_or("unknown").to_owned(),
+                    protocol: HSM_PROTOCOL_VERSION,
+                    min_hsm_protocol: MIN_HSM_PROTOCOL_VERSION,
+                }),
             },
         })
     }
@@ -1729,6 +1924,7 @@ impl<T: Transport + 'static> Agent<T> {
                         unreachable!("handle_handshake shouldn't be used for Transport requests");
                     }
                 },
+                tenant: Some(TenantTag::new(&request.tenant)),
             })
             .await
         {
@@ -1765,6 +1961,7 @@ impl<T: Transport + 'static> Agent<T> {
         let tenant_tag = tag!("tenant": request.tenant);
         let tenant = request.tenant.clone();
         let user = request.user.clone();
+        let slot = request.slot.clone();
         let record_id = request.record_id.clone();
 
         match self.start_app_request(request, &tags).await {
@@ -1789,15 +1986,17 @@ impl<T: Transport + 'static> Agent<T> {
                     ) {
                         let record_id = record_id.clone();
                         with_lock!(&self.0.tenant_limiters.0.tenants, |locked| {
-                            locked
-                            .get_mut(&tenant)
-                            .expect(
-                                "start_app_request created the bucket if it didn't already exist",
-                            )
-                            .add_reservation(record_id);
+                            // The limiter can be missing if a config reload
+                            // removed the tenant's realm capacity since
+                            // start_app_request. The reservation is only an
+                            // optimization, so it's fine to skip it.
+                            let key = LimiterKey::for_request(locked, &tenant, realm);
+                            if let Some(rl) = locked.get_mut(&key) {
+                                rl.add_reservation(record_id);
+                            }
                         });
                     }
-                    if let Some(msg) = create_tenant_event_msg(request_type, &user) {
+                    if let Some(msg) = create_tenant_event_msg(request_type, &user, slot.as_ref()) {
                         if let Err(err) = self.0.event_publisher.publish(realm, &tenant, msg).await
                         {
                             warn!(?err, "error publishing event");
@@ -1825,9 +2024,10 @@ impl<T: Transport + 'static> Agent<T> {
                     if has_delta {
                         match request_type {
                             AppResultType::Register2 => {
+                                self.0.quotas.registered(realm, &tenant);
                                 self.0
                                     .accountant
-                                    .secret_registered(realm, tenant, record_id)
+                                    .secret_registered(realm, tenant, record_id, &user, slot)
                                     .await
                             }
                             AppResultType::Delete => {
@@ -1893,13 +2093,26 @@ impl<T: Transport + 'static> Agent<T> {
         type HsmResponse = hsm_api::AppResponse;
         type Response = AppResponse;
 
+        if let Some(reason) = self
+            .0
+            .suspended_tenants
+            .lock()
+            .unwrap()
+            .get(&request.tenant)
+        {
+            warn!(tenant=?request.tenant, %reason, "rejecting request for suspended tenant");
+            return Err(Response::TenantSuspended);
+        }
+
         let rate_limit_result = {
-            let tenant = request.tenant.clone();
+            let tenant = &request.tenant;
+            let realm = request.realm;
             let rec_id = request.record_id.clone();
             with_lock!(&self.0.tenant_limiters.0.tenants, move |locked| {
-                let bucket = locked
-                    .entry(tenant)
-                    .or_insert_with(|| RateLimiter::new(self.0.default_rate_limiter_rate));
+                let key = LimiterKey::for_request(locked, tenant, realm);
+                let bucket = locked.entry(key).or_insert_with(|| {
+                    RateLimiter::new(Limit::new(self.0.default_rate_limiter_rate))
+                });
                 bucket.allow(rec_id)
             })
         };
@@ -1909,6 +2122,10 @@ impl<T: Transport + 'static> Agent<T> {
         } else {
             debug!(tenant=?request.tenant, bucket=?rate_limit_result, "rate limit allowed");
         }
+        let realm = request.realm;
+        // The HSM only rejects requests that would register new secrets.
+        let quota_exceeded = self.0.quotas.is_exceeded(realm, &request.tenant);
+        let tenant = TenantTag::new(&request.tenant);
 
         #[derive(Debug, thiserror::Error)]
         enum FatalError<T: Transport> {
@@ -1949,6 +2166,8 @@ impl<T: Transport + 'static> Agent<T> {
                         AppResponse::SessionError => "session_error",
                         AppResponse::DecodingError => "decoding_error",
                         AppResponse::RateLimitExceeded => "rate_limit_exceeded",
+                        AppResponse::QuotaExceeded => "quota_exceeded",
+                        AppResponse::TenantSuspended => "tenant_suspended",
                     },
                 };
                 AttemptError::Fatal {
@@ -2037,6 +2256,8 @@ impl<T: Transport + 'static> Agent<T> {
                     encrypted: request.encrypted.clone(),
                     proof,
                     index: entry.index,
+                    quota_exceeded,
+                    tenant: Some(tenant),
                 })
                 .await
             {
@@ -2073,6 +2294,10 @@ impl<T: Transport + 'static> Agent<T> {
                 Ok(HsmResponse::DecodingError) => {
                     Err(FatalError::Other(Response::DecodingError).into())
                 }
+                Ok(HsmResponse::QuotaExceeded) => {
+                    warn!(tenant=?request.tenant, ?realm, "registration quota exceeded");
+                    Err(FatalError::Other(Response::QuotaExceeded).into())
+                }
 
                 Ok(HsmResponse::Ok { entry, delta }) => {
                     trace!(
@@ -2108,11 +2333,11 @@ impl<T: Transport + 'static> Agent<T> {
 
             if role_now.at < group_state.role.at {
                 warn!(%role_now, %group_state.role, "skipping stale state update");
-                return None;
+                return Err(());
             }
             if role_now.at == group_state.role.at {
                 assert_eq!(role_now.role, group_state.role.role);
-                return None;
+                return Err(());
             }
 
             info!(?group, from=%group_state.role, to=%role_now, "HSM role transitioned");
@@ -2123,7 +2348,7 @@ impl<T: Transport + 'static> Agent<T> {
                     // If we've transitioned to witness from leader/stepdown we
                     // need to cleanup our leader state.
                     group_state.leader = None;
-                    None
+                    Ok(None)
                 }
                 GroupMemberRole::SteppingDown {
                     leader_starting: starting_index,
@@ -2143,17 +2368,25 @@ impl<T: Transport + 'static> Agent<T> {
                             uncompacted_rows: VecDeque::new(),
                             response_channels: HashMap::new(),
                         });
-                        Some((group_state.configuration.clone(), starting_index))
+                        Ok(Some((group_state.configuration.clone(), starting_index)))
                     } else {
                         // Leader tasks already running. (e.g. become_leader
                         // called while already leader, or transitioning from
                         // leader to stepping down)
-                        None
+                        Ok(None)
                     }
                 }
             }
         });
 
+        // The role changed, so the partitions the HSM is leading probably did
+        // too.
+        let Ok(starting_info) = starting_info else {
+            return;
+        };
+        let agent = self.clone();
+        tokio::spawn(async move { agent.refresh_partitions().await });
+
         if let Some((config, starting_index)) = starting_info {
             info!(name=?self.0.name, ?realm, ?group, "Starting group committer");
             tokio::spawn(
@@ -2164,8 +2397,12 @@ impl<T: Transport + 'static> Agent<T> {
     }
 }
 
-fn create_tenant_event_msg(r: &AppResultType, u: &HashedUserId) -> Option<Message> {
-    match r {
+fn create_tenant_event_msg(
+    r: &AppResultType,
+    u: &HashedUserId,
+    slot: Option<&HashedSlotId>,
+) -> Option<Message> {
+    let mut msg = match r {
         AppResultType::Register1 => None,
         AppResultType::Register2 => Some(json!({
             "user":u.to_string(),
@@ -2188,14 +2425,19 @@ fn create_tenant_event_msg(r: &AppResultType, u: &HashedUserId) -> Option<Messag
             "user":u.to_string(),
             "event":"deleted"
         })),
+    }?;
+    // Events for users without a slot keep the same format as before slots
+    // existed.
+    if let Some(slot) = slot {
+        msg["slot"] = json!(slot.to_string());
     }
-    .map(Message)
+    Some(Message(msg))
 }
 
 #[cfg(test)]
 mod tests {
     use super::create_tenant_event_msg;
-    use agent_api::HashedUserId;
+    use agent_api::{HashedSlotId, HashedUserId};
     use hsm_api::{AppResultType, GuessState};
     use pubsub_api::Message;
     use serde_json::json;
@@ -2204,16 +2446,20 @@ mod tests {
     fn create_tenant_event_messages() {
         let user = HashedUserId::new("test", "121314");
 
-        assert!(create_tenant_event_msg(&AppResultType::Register1, &user).is_none());
-        assert!(create_tenant_event_msg(&AppResultType::Recover1, &user).is_none());
-        assert!(
-            create_tenant_event_msg(&AppResultType::Recover2 { updated: None }, &user).is_none()
-        );
+        assert!(create_tenant_event_msg(&AppResultType::Register1, &user, None).is_none());
+        assert!(create_tenant_event_msg(&AppResultType::Recover1, &user, None).is_none());
         assert!(
-            create_tenant_event_msg(&AppResultType::Recover3 { recovered: false }, &user).is_none()
+            create_tenant_event_msg(&AppResultType::Recover2 { updated: None }, &user, None)
+                .is_none()
         );
+        assert!(create_tenant_event_msg(
+            &AppResultType::Recover3 { recovered: false },
+            &user,
+            None
+        )
+        .is_none());
 
-        let m = create_tenant_event_msg(&AppResultType::Register2, &user);
+        let m = create_tenant_event_msg(&AppResultType::Register2, &user, None);
         assert_eq!(
             Some(Message(
                 json!({"event":"registered","user":"447ddec5f08c757d40e7acb9f1bc10ed44a960683bb991f5e4ed17498f786ff8"})
@@ -2229,6 +2475,7 @@ mod tests {
                 }),
             },
             &user,
+            None,
         );
         assert_eq!(
             Some(Message(
@@ -2237,7 +2484,7 @@ mod tests {
             m
         );
 
-        let m = create_tenant_event_msg(&AppResultType::Recover3 { recovered: true }, &user);
+        let m = create_tenant_event_msg(&AppResultType::Recover3 { recovered: true }, &user, None);
         assert_eq!(
             Some(Message(
                 json!({"event":"share_recovered","user":"447ddec5f08c757d40e7acb9f1bc10ed44a960683bb991f5e4ed17498f786ff8"})
@@ -2245,12 +2492,21 @@ mod tests {
             m
         );
 
-        let m = create_tenant_event_msg(&AppResultType::Delete, &user);
+        let m = create_tenant_event_msg(&AppResultType::Delete, &user, None);
         assert_eq!(
             Some(Message(
                 json!({"event":"deleted","user":"447ddec5f08c757d40e7acb9f1bc10ed44a960683bb991f5e4ed17498f786ff8"})
             )),
             m
         );
+
+        let slot = HashedSlotId::new("test", "121314", "phone");
+        let m = create_tenant_event_msg(&AppResultType::Delete, &user, Some(&slot));
+        assert_eq!(
+            Some(Message(
+                json!({"event":"deleted","user":"447ddec5f08c757d40e7acb9f1bc10ed44a960683bb991f5e4ed17498f786ff8","slot":slot.to_string()})
+            )),
+            m
+        );
     }
 }
@@ -0,0 +1,211 @@
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tracing::{debug, warn};
+
+use super::{Agent, Transport};
+use agent_api::{LeaderPartition, PartitionWatchRequest, PartitionWatchResponse};
+use hsm_api::GroupId;
+use juicebox_realm_api::types::RealmId;
+use service_core::rpc::HandlerError;
+
+/// The longest that a [`PartitionWatchRequest`] will be held open for.
+const MAX_WATCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Tracks the partitions that the local HSM is leading and notifies watchers
+/// when they change.
+#[derive(Debug)]
+pub(super) struct PartitionPublisher {
+    sender: watch::Sender<PartitionWatchResponse>,
+}
+
+impl PartitionPublisher {
+    pub(super) fn new() -> Self {
+        // The initial version is taken from the clock so that a restarted
+        // agent's versions are newer than those from before the restart.
+        // Otherwise a watcher could miss the change or ignore it as stale.
+        let version = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or_default();
+        let (sender, _) = watch::channel(PartitionWatchResponse {
+            version,
+            partitions: Vec::new(),
+            groups: Vec::new(),
+        });
+        Self { sender }
+    }
+
+    /// Updates the published partitions from the HSM's status, bumping the
+    /// version if they've changed.
+    pub(super) fn update(&self, status: &hsm_api::StatusResponse) {
+        let (partitions, groups) = leader_partitions(status);
+        self.sender.send_if_modified(|current| {
+            if current.partitions == partitions && current.groups == groups {
+                return false;
+            }
+            debug!(?partitions, "leader partitions changed");
+            current.version = current.version.wrapping_add(1);
+            current.partitions = partitions;
+            current.groups = groups;
+            true
+        });
+    }
+
+    /// Returns the current partitions once their version differs from `since`
+    /// or the timeout elapses, whichever is first.
+    pub(super) async fn watch(
+        &self,
+        since: Option<u64>,
+        timeout: Duration,
+    ) -> PartitionWatchResponse {
+        let mut receiver = self.sender.subscribe();
+        if let Some(since) = since {
+            // The sender is owned by `self`, so the channel can't be closed
+            // while this is waiting.
+            let _ = tokio::time::timeout(
+                timeout.min(MAX_WATCH_TIMEOUT),
+                receiver.wait_for(|current| current.version != since),
+            )
+            .await;
+        }
+        let current = receiver.borrow().clone();
+        current
+    }
+}
+
+/// Returns the partitions that the HSM owns in groups it's leading, and all
+/// the groups it's a member of.
+fn leader_partitions(
+    status: &hsm_api::StatusResponse,
+) -> (Vec<LeaderPartition>, Vec<(RealmId, GroupId)>) {
+    let Some(realm) = &status.realm else {
+        return (Vec::new(), Vec::new());
+    };
+    let mut partitions: Vec<LeaderPartition> = realm
+        .groups
+        .iter()
+        .filter_map(|group| LeaderPartition::from_group_status(realm.id, group))
+        .collect();
+    partitions.sort_by_key(|p| p.group);
+    let mut groups: Vec<(RealmId, GroupId)> = realm
+        .groups
+        .iter()
+        .map(|group| (realm.id, group.id))
+        .collect();
+    groups.sort_by_key(|(_, group)| *group);
+    (partitions, groups)
+}
+
+impl<T: Transport + 'static> Agent<T> {
+    pub(super) async fn handle_partition_watch(
+        &self,
+        request: PartitionWatchRequest,
+    ) -> Result<PartitionWatchResponse, HandlerError> {
+        Ok(self
+            .0
+            .partitions
+            .watch(request.since, request.timeout)
+            .await)
+    }
+
+    /// Asks the HSM for its current status and publishes any change in the
+    /// partitions it's leading.
+    ///
+    /// This is called after events that are likely to change the partitions.
+    /// The watchdog also publishes the status it fetches periodically, which
+    /// covers any changes missed here.
+    pub(super) async fn refresh_partitions(&self) {
+        match self.0.hsm.send(hsm_api::StatusRequest {}).await {
+            Ok(status) => self.0.partitions.update(&status),
+            Err(err) => warn!(?err, "failed to get HSM status to refresh partitions"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hsm_api::{
+        GroupMemberRole, GroupStatus, HsmId, LeaderStatus, LogIndex, OwnedRange, RealmStatus,
+        RoleLogicalClock, RoleStatus,
+    };
+
+    fn status(owned: Option<OwnedRange>) -> hsm_api::StatusResponse {
+        let realm = RealmId([3; 16]);
+        hsm_api::StatusResponse {
+            id: HsmId([1; 16]),
+            public_key: hsm_api::PublicKey(vec![0; 32]),
+            version: None,
+            nvram: None,
+            realm: Some(RealmStatus {
+                id: realm,
+                statement: hsm_api::HsmRealmStatement::from([0; 32]),
+                groups: vec![GroupStatus {
+                    id: GroupId([2; 16]),
+                    configuration: vec![HsmId([1; 16])],
+                    captured: None,
+                    leader: Some(LeaderStatus {
+                        committed: None,
+                        last: LogIndex::FIRST,
+                        owned_range: owned,
+                        transferring: None,
+                        sessions: None,
+                    }),
+                    role: RoleStatus {
+                        role: GroupMemberRole::Leader {
+                            starting: LogIndex::FIRST,
+                        },
+                        at: RoleLogicalClock::start(),
+                    },
+                }],
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn watch_returns_on_change() {
+        let publisher = PartitionPublisher::new();
+        let initial = publisher.watch(None, Duration::ZERO).await;
+        assert!(initial.partitions.is_empty());
+
+        // Nothing's changed, so this waits out the timeout.
+        let unchanged = publisher
+            .watch(Some(initial.version), Duration::from_millis(10))
+            .await;
+        assert_eq!(initial.version, unchanged.version);
+
+        // Joining a group is a change, even without leading it.
+        publisher.update(&status(None));
+        let joined = publisher.watch(Some(initial.version), Duration::ZERO).await;
+        assert_ne!(initial.version, joined.version);
+        assert!(joined.partitions.is_empty());
+        assert_eq!(vec![(RealmId([3; 16]), GroupId([2; 16]))], joined.groups);
+
+        // The same status again isn't a change.
+        publisher.update(&status(None));
+        let unchanged = publisher.watch(Some(joined.version), Duration::ZERO).await;
+        assert_eq!(joined.version, unchanged.version);
+
+        let watcher = publisher.watch(Some(joined.version), Duration::from_secs(10));
+        let (changed, ()) = tokio::join!(watcher, async {
+            tokio::task::yield_now().await;
+            publisher.update(&status(Some(OwnedRange::full())));
+        });
+        assert_ne!(joined.version, changed.version);
+        assert_eq!(
+            vec![LeaderPartition {
+                realm: RealmId([3; 16]),
+                group: GroupId([2; 16]),
+                owned_range: OwnedRange::full(),
+                term: LogIndex::FIRST,
+            }],
+            changed.partitions
+        );
+
+        // A watcher with a stale version returns immediately.
+        let stale = publisher
+            .watch(Some(joined.version), MAX_WATCH_TIMEOUT)
+            .await;
+        assert_eq!(changed.version, stale.version);
+    }
+}
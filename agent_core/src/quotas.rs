@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+use super::{Agent, Transport};
+use juicebox_realm_api::types::RealmId;
+use store::tenant_config::TenantConfiguration;
+
+/// How often the number of registered secrets is recounted from the store for
+/// tenants that have a quota.
+const QUOTA_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Tracks how many secrets each tenant with a quota has registered in each
+/// realm (see [`TenantConfiguration::max_registered_secrets`]).
+///
+/// The counts come from the user accounting table and are approximate: the
+/// table is written asynchronously, and registrations on other agents aren't
+/// seen until the next recount. Registrations on this agent are added to the
+/// count in the meantime. Until a tenant has been counted in a realm, its
+/// registrations are allowed.
+#[derive(Debug, Default)]
+pub(super) struct Quotas(Mutex<QuotaState>);
+
+#[derive(Debug, Default)]
+struct QuotaState {
+    limits: HashMap<String, u64>,
+    counts: HashMap<(RealmId, String), Count>,
+}
+
+#[derive(Debug, Default)]
+struct Count {
+    /// The count from the store, once it's been read.
+    stored: Option<usize>,
+    /// Secrets registered through this agent since the count was read.
+    local: usize,
+}
+
+impl Quotas {
+    /// Replaces the tenants' quotas with those from the tenant configuration.
+    pub(super) fn update_limits(&self, tenants: &[(String, TenantConfiguration)]) {
+        let mut locked = self.0.lock().unwrap();
+        locked.limits = tenants
+            .iter()
+            .filter_map(|(tenant, config)| {
+                config
+                    .max_registered_secrets
+                    .map(|limit| (tenant.clone(), limit))
+            })
+            .collect();
+        let QuotaState { limits, counts } = &mut *locked;
+        counts.retain(|(_, tenant), _| limits.contains_key(tenant));
+    }
+
+    /// Returns true if the tenant may not register any more secrets in the
+    /// realm.
+    pub(super) fn is_exceeded(&self, realm: RealmId, tenant: &str) -> bool {
+        let mut locked = self.0.lock().unwrap();
+        let Some(limit) = locked.limits.get(tenant).copied() else {
+            return false;
+        };
+        // This creates the entry if needed so that the next recount includes
+        // it.
+        let count = locked.counts.entry((realm, tenant.to_owned())).or_default();
+        match count.stored {
+            None => false,
+            Some(stored) => (stored + count.local) as u64 >= limit,
+        }
+    }
+
+    /// Records that a secret was registered through this agent.
+    pub(super) fn registered(&self, realm: RealmId, tenant: &str) {
+        let mut locked = self.0.lock().unwrap();
+        if let Some(count) = locked.counts.get_mut(&(realm, tenant.to_owned())) {
+            count.local += 1;
+        }
+    }
+
+    fn counted(&self, realm: RealmId, tenant: &str, local_before: usize, stored: usize) {
+        let mut locked = self.0.lock().unwrap();
+        if let Some(count) = locked.counts.get_mut(&(realm, tenant.to_owned())) {
+            // Registrations from before the read started are included in the
+            // stored count (give or take the accounting writer's batching),
+            // but later ones might not be.
+            count.local = count.local.saturating_sub(local_before);
+            count.stored = Some(stored);
+        }
+    }
+
+    fn to_count(&self) -> Vec<(RealmId, String, usize)> {
+        let locked = self.0.lock().unwrap();
+        locked
+            .counts
+            .iter()
+            .map(|((realm, tenant), count)| (*realm, tenant.clone(), count.local))
+            .collect()
+    }
+}
+
+impl<T: Transport + 'static> Agent<T> {
+    pub(super) fn start_quota_refresher(&self) {
+        let agent = self.clone();
+        tokio::spawn(async move {
+            loop {
+                for (realm, tenant, local_before) in agent.0.quotas.to_count() {
+                    match agent
+                        .0
+                        .store
+                        .count_registered_records(&realm, &tenant)
+                        .await
+                    {
+                        Ok(stored) => {
+                            debug!(?realm, %tenant, stored, "counted registered secrets");
+                            agent.0.quotas.counted(realm, &tenant, local_before, stored);
+                        }
+                        Err(err) => {
+                            warn!(?realm, %tenant, ?err, "failed to count registered secrets");
+                        }
+                    }
+                }
+                sleep(QUOTA_REFRESH_INTERVAL).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quotas() {
+        let realm = RealmId([1; 16]);
+        let quotas = Quotas::default();
+        quotas.update_limits(&[
+            (
+                String::from("acme"),
+                TenantConfiguration {
+                    max_registered_secrets: Some(3),
+                    ..TenantConfiguration::new(10)
+                },
+            ),
+            (String::from("bigco"), TenantConfiguration::new(10)),
+        ]);
+
+        // Not counted yet, so allowed.
+        assert!(!quotas.is_exceeded(realm, "acme"));
+        assert!(!quotas.is_exceeded(realm, "bigco"));
+        assert_eq!(quotas.to_count(), [(realm, String::from("acme"), 0)]);
+
+        quotas.registered(realm, "acme");
+        quotas.counted(realm, "acme", 1, 1);
+        assert!(!quotas.is_exceeded(realm, "acme"));
+        quotas.registered(realm, "acme");
+        assert!(!quotas.is_exceeded(realm, "acme"));
+        quotas.registered(realm, "acme");
+        assert!(quotas.is_exceeded(realm, "acme"));
+        assert!(!quotas.is_exceeded(RealmId([2; 16]), "acme"));
+
+        // A recount that started after the first of the local registrations.
+        quotas.counted(realm, "acme", 1, 2);
+        assert!(quotas.is_exceeded(realm, "acme"));
+        quotas.counted(realm, "acme", 1, 1);
+        assert!(!quotas.is_exceeded(realm, "acme"));
+
+        quotas.update_limits(&[(String::from("acme"), TenantConfiguration::new(10))]);
+        assert!(!quotas.is_exceeded(realm, "acme"));
+        assert!(quotas.to_count().is_empty());
+    }
+}
@@ -3,6 +3,7 @@ use std::cmp::{max, Ordering};
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::hash::Hash;
+use std::mem;
 use std::ops::{Add, Deref, Sub};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
@@ -58,29 +59,72 @@ pub struct RateLimitResult {
     pub allowed: bool,
 }
 
+/// The limits enforced by a [`RateLimiter`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Limit {
+    /// The sustained rate, in requests per second.
+    pub rate: usize,
+    /// The number of requests that can be made at once after a quiet period.
+    pub burst: usize,
+    /// If set, each record may make at most this many requests per minute, on
+    /// top of the overall limit.
+    pub record_per_min: Option<usize>,
+}
+
+impl Limit {
+    /// Returns a limit of `rate` requests per second, with a burst of one
+    /// second's worth of requests.
+    pub fn new(rate: usize) -> Self {
+        Self {
+            rate,
+            burst: rate,
+            record_per_min: None,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct PeerId(pub String);
 
+/// A token bucket rate limiter that's shared with peers.
+///
+/// Each agent has its own bucket for the tenant. Requests take a token from it
+/// when they're allowed locally, and when peers report having allowed them.
+/// Requests that use a reservation are allowed even if the bucket is empty,
+/// but they still take a token.
 #[derive(Debug)]
 pub struct RateLimiter {
-    rate: usize, // reqs / second
+    limit: Limit,
+    bucket: Bucket,
+    // Requests allowed in about the last second, for peers running older
+    // versions and for reporting.
     my_reqs: OrderedVecDeque<Time>,
     others_reqs: OrderedVecDeque<Time>,
+    // The number of requests allowed locally since this was created.
+    allowed_total: u64,
+    records: RecordBuckets,
     reservations: Reservations,
 }
 
 impl RateLimiter {
-    pub fn new(http_reqs_per_second: usize) -> Self {
+    pub fn new(limit: Limit) -> Self {
         RateLimiter {
+            limit,
+            bucket: Bucket::full(limit.burst),
             my_reqs: OrderedVecDeque::default(),
             others_reqs: OrderedVecDeque::default(),
-            rate: http_reqs_per_second,
+            allowed_total: 0,
+            records: RecordBuckets::default(),
             reservations: Reservations::default(),
         }
     }
 
-    pub fn update_limit(&mut self, new_reqs_per_second: usize) {
-        self.rate = new_reqs_per_second;
+    pub fn update_limit(&mut self, new_limit: Limit) {
+        self.limit = new_limit;
+        self.bucket.tokens = self.bucket.tokens.min(new_limit.burst as f64);
+        if new_limit.record_per_min.is_none() {
+            self.records = RecordBuckets::default();
+        }
     }
 
     pub fn allow(&mut self, id: RecordId) -> RateLimitResult {
@@ -92,15 +136,21 @@ impl RateLimiter {
         self.my_reqs.remove_smaller(&window_start);
         self.others_reqs.remove_smaller(&window_start);
         let used = self.my_reqs.len() + self.others_reqs.len();
-        let ok = used < self.rate;
 
-        let reservation = self.reservations.use_reservation(now, rec);
+        self.bucket
+            .refill(now, self.limit.rate as f64, self.limit.burst);
+        let ok = self.bucket.tokens >= 1.0 && self.records.has_token(now, &rec, self.limit);
+
+        let reservation = self.reservations.use_reservation(now, rec.clone());
         if ok || reservation {
+            self.bucket.tokens -= 1.0;
+            self.records.take(now, rec, self.limit);
             self.my_reqs.add(now);
+            self.allowed_total += 1;
         }
         RateLimitResult {
             used,
-            limit: self.rate,
+            limit: self.limit.rate,
             reservation,
             allowed: ok || reservation,
         }
@@ -120,69 +170,214 @@ impl RateLimiter {
         self.reservations
             .used
             .retain(|(tm, _id)| *tm >= expire_before);
+        self.my_reqs.remove_smaller(&(now - Duration::from_secs(1)));
 
         State {
+            version: STATE_VERSION,
             reqs: self.my_reqs.clone(),
             reservations: self.reservations.mine.clone(),
             reservations_used: self.reservations.used.clone(),
+            allowed_total: self.allowed_total,
+        }
+    }
+
+    pub fn update_from_peers(&mut self, now: Time, state: MergedStates) {
+        self.others_reqs = state.state.reqs;
+        self.reservations.others = state.state.reservations;
+        self.reservations.used_others = state.state.reservations_used;
+
+        // The bucket can go into debt when peers allow requests at the same
+        // time, but only by up to one burst.
+        self.bucket
+            .refill(now, self.limit.rate as f64, self.limit.burst);
+        self.bucket.tokens =
+            (self.bucket.tokens - state.new_reqs as f64).max(-(self.limit.burst as f64));
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Time,
+}
+
+impl Bucket {
+    fn full(capacity: usize) -> Self {
+        Bucket {
+            tokens: capacity as f64,
+            updated: Time(0),
         }
     }
 
-    pub fn update_from_peers(&mut self, state: MergedStates) {
-        self.others_reqs = state.0.reqs;
-        self.reservations.others = state.0.reservations;
-        self.reservations.used_others = state.0.reservations_used;
+    /// Adds the tokens that accrued since the last refill.
+    fn refill(&mut self, now: Time, per_sec: f64, capacity: usize) {
+        if now > self.updated {
+            let elapsed = Duration::from_millis(now.0 - self.updated.0);
+            self.tokens = (self.tokens + per_sec * elapsed.as_secs_f64()).min(capacity as f64);
+            self.updated = now;
+        }
     }
 }
 
+/// Token buckets for individual records (see [`Limit::record_per_min`]).
+/// Buckets are dropped once they've refilled.
 #[derive(Debug, Default)]
-pub struct PeerStates(HashMap<PeerId, (Time, State)>);
+struct RecordBuckets {
+    // Each record's bucket and when it'll be full again.
+    buckets: HashMap<RecordId, (Bucket, Time)>,
+    full_at: OrderedVecDeque<Expiry>,
+}
+
+impl RecordBuckets {
+    fn has_token(&mut self, now: Time, id: &RecordId, limit: Limit) -> bool {
+        let Some(per_min) = limit.record_per_min else {
+            return true;
+        };
+        self.expire(&now);
+        match self.buckets.get_mut(id) {
+            None => per_min >= 1,
+            Some((bucket, _)) => {
+                bucket.refill(now, per_min as f64 / 60.0, per_min);
+                bucket.tokens >= 1.0
+            }
+        }
+    }
+
+    fn take(&mut self, now: Time, id: RecordId, limit: Limit) {
+        let Some(per_min) = limit.record_per_min.filter(|per_min| *per_min > 0) else {
+            return;
+        };
+        let per_sec = per_min as f64 / 60.0;
+        let (bucket, full_at) = self
+            .buckets
+            .entry(id.clone())
+            .or_insert_with(|| (Bucket::full(per_min), now));
+        bucket.refill(now, per_sec, per_min);
+        bucket.tokens -= 1.0;
+        *full_at = now + Duration::from_secs_f64((per_min as f64 - bucket.tokens) / per_sec);
+        self.full_at.add(Expiry { when: *full_at, id });
+    }
+
+    fn expire(&mut self, now: &Time) {
+        while self.full_at.front().is_some_and(|e| e.when <= *now) {
+            let expiry = self.full_at.pop_front().unwrap();
+            if let Occupied(e) = self.buckets.entry(expiry.id) {
+                if e.get().1 == expiry.when {
+                    e.remove();
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PeerStates {
+    peers: HashMap<PeerId, (Time, State)>,
+    // Requests that peers have allowed since the last merge.
+    new_reqs: u64,
+}
 
 impl PeerStates {
     pub fn update(&mut self, now: Time, from: PeerId, state: State) {
-        self.0.insert(from, (now, state));
+        self.new_reqs += match self.peers.get(&from) {
+            Some((_, prev)) => state.allowed_since(prev),
+            None => state.reqs.len() as u64,
+        };
+        self.peers.insert(from, (now, state));
     }
 
     pub fn merged(&mut self, now: Time) -> MergedStates {
         let exp = now - RESERVATION_LIFETIME;
-        self.0.retain(|_peer, (when, _state)| *when > exp);
-        let states: Vec<_> = self.0.values().map(|(_, s)| s.clone()).collect();
-        MergedStates::merge(states)
+        self.peers.retain(|_peer, (when, _state)| *when > exp);
+        let states: Vec<_> = self.peers.values().map(|(_, s)| s.clone()).collect();
+        let mut merged = MergedStates::merge(states);
+        merged.new_reqs = mem::take(&mut self.new_reqs);
+        merged
     }
 }
 
+/// The current version of the [`State`] format.
+///
+/// Agents ignore fields they don't know about, so newer versions only add
+/// fields, and agents keep filling in the older ones. Version 1 didn't have a
+/// version field.
+///
+/// - Version 2 added `allowed_total`.
+const STATE_VERSION: u32 = 2;
+
+fn state_version_1() -> u32 {
+    1
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct State {
+    #[serde(default = "state_version_1")]
+    version: u32,
     reqs: OrderedVecDeque<Time>,
     reservations: ReservationSet,
     reservations_used: HashSet<(Time, RecordId)>,
+    // The number of requests the agent has allowed.
+    #[serde(default)]
+    allowed_total: u64,
 }
 
-pub struct MergedStates(State);
+impl State {
+    /// Returns the number of requests in this state that were allowed at or
+    /// after `since`.
+    pub fn reqs_since(&self, since: Time) -> usize {
+        self.reqs.iter().filter(|t| **t >= since).count()
+    }
+
+    /// Returns the number of requests the agent allowed between `prev` and
+    /// this state.
+    fn allowed_since(&self, prev: &State) -> u64 {
+        if self.version >= 2 && prev.version >= 2 {
+            if self.allowed_total >= prev.allowed_total {
+                self.allowed_total - prev.allowed_total
+            } else {
+                // The agent restarted.
+                self.allowed_total
+            }
+        } else {
+            // Older agents only report their recent requests.
+            match prev.reqs.back() {
+                Some(last) => self.reqs.iter().filter(|t| *t > last).count() as u64,
+                None => self.reqs.len() as u64,
+            }
+        }
+    }
+}
+
+pub struct MergedStates {
+    state: State,
+    new_reqs: u64,
+}
 
 impl MergedStates {
     pub fn merge(mut peers: Vec<State>) -> Self {
-        if peers.is_empty() {
-            return MergedStates(State::default());
-        }
-        if peers.len() == 1 {
-            return MergedStates(peers.pop().unwrap());
-        }
-        let mut reqs = Vec::with_capacity(peers.len());
-        let mut res = Vec::with_capacity(peers.len());
-        let mut used =
-            HashSet::with_capacity(peers.iter().map(|p| p.reservations_used.len()).sum());
-        for peer in peers {
-            reqs.push(peer.reqs);
-            res.push(peer.reservations);
-            used.extend(peer.reservations_used);
-        }
-        let r = State {
-            reqs: OrderedVecDeque::merge(reqs),
-            reservations: ReservationSet::merge(res),
-            reservations_used: used,
+        let state = if peers.is_empty() {
+            State::default()
+        } else if peers.len() == 1 {
+            peers.pop().unwrap()
+        } else {
+            let mut reqs = Vec::with_capacity(peers.len());
+            let mut res = Vec::with_capacity(peers.len());
+            let mut used =
+                HashSet::with_capacity(peers.iter().map(|p| p.reservations_used.len()).sum());
+            for peer in peers {
+                reqs.push(peer.reqs);
+                res.push(peer.reservations);
+                used.extend(peer.reservations_used);
+            }
+            State {
+                version: STATE_VERSION,
+                reqs: OrderedVecDeque::merge(reqs),
+                reservations: ReservationSet::merge(res),
+                reservations_used: used,
+                allowed_total: 0,
+            }
         };
-        MergedStates(r)
+        MergedStates { state, new_reqs: 0 }
     }
 }
 
@@ -353,8 +548,11 @@ mod tests {
     use std::iter::zip;
     use std::time::Duration;
 
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashSet;
+
     use super::{
-        MergedStates, OrderedVecDeque, PeerId, RateLimiter, ReservationSet, State, Time,
+        Limit, OrderedVecDeque, PeerId, PeerStates, RateLimiter, ReservationSet, State, Time,
         RESERVATION_LIFETIME,
     };
     use hsm_api::RecordId;
@@ -436,9 +634,10 @@ mod tests {
 
     #[test]
     fn local_allow() {
-        let mut rl = RateLimiter::new(5);
+        let mut rl = RateLimiter::new(Limit::new(5));
         let now = Time::now();
 
+        // The bucket starts full.
         assert!(rl.allow_inner(now, rid(1)).allowed);
         assert!(rl.allow_inner(millis(now, 10), rid(2)).allowed);
         assert!(rl.allow_inner(millis(now, 20), rid(3)).allowed);
@@ -446,23 +645,97 @@ mod tests {
         assert!(rl.allow_inner(millis(now, 40), rid(5)).allowed);
 
         assert!(!rl.allow_inner(millis(now, 50), rid(6)).allowed);
-        assert!(!rl.allow_inner(millis(now, 999), rid(7)).allowed);
+        assert!(!rl.allow_inner(millis(now, 150), rid(7)).allowed);
 
-        // first request should now be outside the 1 second window.
-        assert!(rl.allow_inner(millis(now, 1005), rid(7)).allowed);
-        assert!(!rl.allow_inner(millis(now, 1006), rid(8)).allowed);
+        // A token is added every 200ms.
+        assert!(rl.allow_inner(millis(now, 250), rid(7)).allowed);
+        assert!(!rl.allow_inner(millis(now, 260), rid(8)).allowed);
 
         // if the record id has a reservation, that should be allowed.
-        rl.add_reservation_inner(millis(now, 1007), rid(9));
-        assert!(!rl.allow_inner(millis(now, 1008), rid(10)).allowed);
-        assert!(rl.allow_inner(millis(now, 1008), rid(9)).allowed);
+        rl.add_reservation_inner(millis(now, 270), rid(9));
+        assert!(!rl.allow_inner(millis(now, 280), rid(10)).allowed);
+        assert!(rl.allow_inner(millis(now, 280), rid(9)).allowed);
         // but its only good for one call
-        assert!(!rl.allow_inner(millis(now, 1009), rid(9)).allowed);
+        assert!(!rl.allow_inner(millis(now, 290), rid(9)).allowed);
+
+        // the reserved call still takes a token, so the next one takes longer
+        // to arrive.
+        assert!(!rl.allow_inner(millis(now, 550), rid(11)).allowed);
+        assert!(rl.allow_inner(millis(now, 650), rid(11)).allowed);
+
+        let state = rl.state(millis(now, 660));
+        assert_eq!(6, state.reqs_since(millis(now, 20)));
+        assert_eq!(2, state.reqs_since(millis(now, 251)));
+
+        // The bucket is full again after a quiet period, but no fuller.
+        for i in 0..5 {
+            assert!(rl.allow_inner(millis(now, 5000), rid(i)).allowed);
+        }
+        assert!(!rl.allow_inner(millis(now, 5000), rid(6)).allowed);
+    }
 
-        // the reserved call still counts in the window, so we can't make other
-        // requests until 2 more items have expires
-        assert!(!rl.allow_inner(millis(now, 1015), rid(11)).allowed);
-        assert!(rl.allow_inner(millis(now, 1025), rid(11)).allowed);
+    #[test]
+    fn burst() {
+        let mut rl = RateLimiter::new(Limit {
+            rate: 2,
+            burst: 10,
+            record_per_min: None,
+        });
+        let now = Time::now();
+        for i in 0..10 {
+            assert!(rl.allow_inner(now, rid(i)).allowed);
+        }
+        assert!(!rl.allow_inner(millis(now, 1), rid(10)).allowed);
+        assert!(!rl.allow_inner(millis(now, 400), rid(10)).allowed);
+        assert!(rl.allow_inner(millis(now, 600), rid(10)).allowed);
+        assert!(!rl.allow_inner(millis(now, 700), rid(11)).allowed);
+
+        // Lowering the burst empties the bucket down to it.
+        rl.update_limit(Limit {
+            rate: 2,
+            burst: 2,
+            record_per_min: None,
+        });
+        assert!(rl.allow_inner(millis(now, 10_000), rid(12)).allowed);
+        assert!(rl.allow_inner(millis(now, 10_000), rid(13)).allowed);
+        assert!(!rl.allow_inner(millis(now, 10_000), rid(14)).allowed);
+    }
+
+    #[test]
+    fn record_limit() {
+        let mut rl = RateLimiter::new(Limit {
+            rate: 100,
+            burst: 100,
+            record_per_min: Some(2),
+        });
+        let now = Time::now();
+        assert!(rl.allow_inner(now, rid(1)).allowed);
+        assert!(rl.allow_inner(millis(now, 1), rid(1)).allowed);
+        assert!(!rl.allow_inner(millis(now, 2), rid(1)).allowed);
+        // Other records aren't affected.
+        assert!(rl.allow_inner(millis(now, 3), rid(2)).allowed);
+
+        // A record gets a token back every 30 seconds.
+        assert!(!rl.allow_inner(millis(now, 29_000), rid(1)).allowed);
+        assert!(rl.allow_inner(millis(now, 30_500), rid(1)).allowed);
+        assert!(!rl.allow_inner(millis(now, 30_600), rid(1)).allowed);
+
+        // Reservations are allowed past the record's limit.
+        rl.add_reservation_inner(millis(now, 30_700), rid(1));
+        assert!(rl.allow_inner(millis(now, 30_800), rid(1)).allowed);
+
+        // Buckets are dropped once they're full again.
+        assert!(rl.records.buckets.contains_key(&rid(1)));
+        assert!(!rl.records.buckets.contains_key(&rid(2)));
+        assert!(rl.allow_inner(millis(now, 200_000), rid(3)).allowed);
+        assert_eq!(1, rl.records.buckets.len());
+        assert!(rl.records.buckets.contains_key(&rid(3)));
+
+        rl.update_limit(Limit::new(100));
+        assert!(rl.records.buckets.is_empty());
+        for _ in 0..5 {
+            assert!(rl.allow_inner(millis(now, 200_001), rid(3)).allowed);
+        }
     }
 
     fn millis(t: Time, millis: u64) -> Time {
@@ -471,7 +744,8 @@ mod tests {
 
     #[test]
     fn distributed_limiters() {
-        let mut limiters: Vec<_> = (0..3).map(|_| RateLimiter::new(5)).collect();
+        let mut limiters: Vec<_> = (0..3).map(|_| RateLimiter::new(Limit::new(5))).collect();
+        let mut peers: Vec<_> = (0..3).map(|_| PeerStates::default()).collect();
         let node_ids: Vec<PeerId> = ["a", "b", "c"]
             .into_iter()
             .map(|s| PeerId(String::from(s)))
@@ -481,7 +755,7 @@ mod tests {
         for rl in &mut limiters {
             assert!(rl.allow_inner(now, rid(32)).allowed);
         }
-        trade_limiter_state(now, &node_ids, &mut limiters);
+        trade_limiter_state(now, &node_ids, &mut limiters, &mut peers);
         // everyone should think there's been 3 requests out of the allowed 5
         assert!(limiters[0].allow_inner(millis(now, 10), rid(33)).allowed);
         assert!(limiters[0].allow_inner(millis(now, 20), rid(34)).allowed);
@@ -494,24 +768,26 @@ mod tests {
         assert!(!limiters[1].allow_inner(millis(now, 60), rid(35)).allowed);
 
         // after a sync, they should all agree now that the limit is reached.
-        trade_limiter_state(millis(now, 60), &node_ids, &mut limiters);
+        trade_limiter_state(millis(now, 60), &node_ids, &mut limiters, &mut peers);
         for rl in &mut limiters {
             assert!(!rl.allow_inner(millis(now, 70), rid(36)).allowed);
         }
-        // items from other buckets should get expired
-        // currently there's a total of 7 requests in the 0...1000 window
-        assert!(!limiters[2].allow_inner(millis(now, 999), rid(46)).allowed);
-        // 3 of those should get expired at 1000. 4 left allows one more.
-        assert!(limiters[2].allow_inner(millis(now, 1005), rid(47)).allowed);
-        assert!(!limiters[2].allow_inner(millis(now, 1006), rid(48)).allowed);
+        // Each bucket went into debt by the requests that were allowed
+        // elsewhere, so it takes a while to get a token back.
+        assert!(!limiters[2].allow_inner(millis(now, 550), rid(46)).allowed);
+        assert!(limiters[2].allow_inner(millis(now, 650), rid(47)).allowed);
+        assert!(!limiters[2].allow_inner(millis(now, 660), rid(48)).allowed);
 
-        // 1 more expired at 1010
-        assert!(limiters[2].allow_inner(millis(now, 1011), rid(49)).allowed);
+        // Syncing again doesn't count the same requests twice.
+        trade_limiter_state(millis(now, 700), &node_ids, &mut limiters, &mut peers);
+        assert!(limiters[2].allow_inner(millis(now, 900), rid(49)).allowed);
+        assert!(limiters[0].allow_inner(millis(now, 900), rid(49)).allowed);
     }
 
     #[test]
     fn distributed_limiters_with_reservations() {
-        let mut limiters: Vec<_> = (0..3).map(|_| RateLimiter::new(5)).collect();
+        let mut limiters: Vec<_> = (0..3).map(|_| RateLimiter::new(Limit::new(5))).collect();
+        let mut peers: Vec<_> = (0..3).map(|_| PeerStates::default()).collect();
         let node_ids: Vec<PeerId> = ["a", "b", "c"]
             .into_iter()
             .map(|s| PeerId(String::from(s)))
@@ -523,7 +799,7 @@ mod tests {
             assert!(rl.allow_inner(millis(now, 1), rid(2)).allowed);
             assert!(rl.allow_inner(millis(now, 2), rid(3)).allowed);
         }
-        trade_limiter_state(millis(now, 3), &node_ids, &mut limiters);
+        trade_limiter_state(millis(now, 3), &node_ids, &mut limiters, &mut peers);
         // over the limit now everywhere
         for rl in &mut limiters {
             assert!(!rl.allow_inner(millis(now, 10), rid(4)).allowed);
@@ -533,7 +809,7 @@ mod tests {
         // use the reservation
         assert!(limiters[0].allow_inner(millis(now, 20), rid(45)).allowed);
 
-        trade_limiter_state(millis(now, 20), &node_ids, &mut limiters);
+        trade_limiter_state(millis(now, 20), &node_ids, &mut limiters, &mut peers);
         // still over the limit everywhere
         for rl in &mut limiters {
             assert!(!rl.allow_inner(millis(now, 30), rid(4)).allowed);
@@ -545,18 +821,75 @@ mod tests {
 
         // get a reservation on one node, then use it from another
         limiters[0].add_reservation_inner(millis(now, 50), rid(46));
-        trade_limiter_state(millis(now, 51), &node_ids, &mut limiters);
+        trade_limiter_state(millis(now, 51), &node_ids, &mut limiters, &mut peers);
         assert!(limiters[2].allow_inner(millis(now, 60), rid(46)).allowed);
         assert!(!limiters[2].allow_inner(millis(now, 62), rid(46)).allowed);
 
         // after a sync, everyone should know the reservation was used
-        trade_limiter_state(millis(now, 64), &node_ids, &mut limiters);
+        trade_limiter_state(millis(now, 64), &node_ids, &mut limiters, &mut peers);
         for rl in &mut limiters {
             assert!(!rl.allow_inner(millis(now, 65), rid(46)).allowed);
         }
     }
 
-    fn trade_limiter_state(now: Time, ids: &[PeerId], limiters: &mut [RateLimiter]) {
+    // The state format from before it was versioned.
+    #[derive(Deserialize, Serialize)]
+    struct StateV1 {
+        reqs: OrderedVecDeque<Time>,
+        reservations: ReservationSet,
+        reservations_used: HashSet<(Time, RecordId)>,
+    }
+
+    #[test]
+    fn mixed_version_peers() {
+        let now = Time::now();
+        let mut old_reqs = OrderedVecDeque::default();
+        old_reqs.add(now);
+        old_reqs.add(millis(now, 1));
+        let v1_state = |reqs: &OrderedVecDeque<Time>| -> State {
+            let old = StateV1 {
+                reqs: reqs.clone(),
+                reservations: ReservationSet::default(),
+                reservations_used: HashSet::new(),
+            };
+            juicebox_marshalling::from_slice(&juicebox_marshalling::to_vec(&old).unwrap()).unwrap()
+        };
+
+        let mut peers = PeerStates::default();
+        let old_peer = PeerId(String::from("old"));
+        let state = v1_state(&old_reqs);
+        assert_eq!(1, state.version);
+        peers.update(millis(now, 2), old_peer.clone(), state);
+        old_reqs.add(millis(now, 3));
+        peers.update(millis(now, 4), old_peer.clone(), v1_state(&old_reqs));
+        assert_eq!(3, peers.merged(millis(now, 4)).new_reqs);
+
+        // Older agents can read the current format.
+        let mut rl = RateLimiter::new(Limit::new(5));
+        assert!(rl.allow_inner(now, rid(1)).allowed);
+        let new_state = juicebox_marshalling::to_vec(&rl.state(millis(now, 1))).unwrap();
+        let old: StateV1 = juicebox_marshalling::from_slice(&new_state).unwrap();
+        assert_eq!(1, old.reqs.len());
+
+        // Newer agents count requests exactly.
+        let new_peer = PeerId(String::from("new"));
+        peers.update(
+            millis(now, 5),
+            new_peer.clone(),
+            juicebox_marshalling::from_slice(&new_state).unwrap(),
+        );
+        assert!(rl.allow_inner(millis(now, 5), rid(2)).allowed);
+        assert!(rl.allow_inner(millis(now, 5), rid(3)).allowed);
+        peers.update(millis(now, 6), new_peer, rl.state(millis(now, 6)));
+        assert_eq!(3, peers.merged(millis(now, 6)).new_reqs);
+    }
+
+    fn trade_limiter_state(
+        now: Time,
+        ids: &[PeerId],
+        limiters: &mut [RateLimiter],
+        peers: &mut [PeerStates],
+    ) {
         assert_eq!(ids.len(), limiters.len());
         let states: Vec<(&PeerId, Vec<u8>)> = zip(
             ids,
@@ -568,13 +901,15 @@ mod tests {
         .collect();
 
         for i in 0..ids.len() {
-            let other_stats: Vec<State> = states
-                .iter()
-                .filter(|(id, _)| **id != ids[i])
-                .map(|(_, s)| juicebox_marshalling::from_slice(s).unwrap())
-                .collect();
-            let merged = MergedStates::merge(other_stats);
-            limiters[i].update_from_peers(merged);
+            for (id, state) in states.iter().filter(|(id, _)| **id != ids[i]) {
+                peers[i].update(
+                    now,
+                    (*id).clone(),
+                    juicebox_marshalling::from_slice(state).unwrap(),
+                );
+            }
+            let merged = peers[i].merged(now);
+            limiters[i].update_from_peers(now, merged);
         }
     }
 }
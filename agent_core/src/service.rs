@@ -2,20 +2,25 @@ use clap::{Args, CommandFactory, FromArgMatches, Parser};
 use http::Uri;
 use std::fmt::Debug;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::task::JoinHandle;
 use tracing::info;
 
+use crate::hsm::trace::{Recorder, RecorderOptions};
 use crate::hsm::{HsmClient, Transport};
 use crate::{Agent, AgentConfiguration};
 use build_info::BuildInfo;
 use google::{auth, GrpcConnectionOptions};
+use hsm_api::TenantRecordKey;
 use observability::{logging, metrics};
 use service_core::clap_parsers::{parse_duration, parse_listen};
 use service_core::future_task::FutureTask;
 use service_core::metrics::start_uptime_reporter;
 use service_core::panic;
 use service_core::term::install_termination_handler;
+use store::log_archive::{LocalDirectory, LogArchiver};
 
 #[derive(Debug, Parser)]
 #[command(version)]
@@ -103,6 +108,36 @@ pub struct AgentArgs<SA: Args + Debug> {
     #[arg(long, default_value_t = 10)]
     pub default_rate_limit: usize,
 
+    /// An Ed25519 public key, in hex, that tenant record grants may be signed
+    /// with. This should also be given to the HSM. May be repeated, for key
+    /// rotation. Without any, tenant record requests are rejected.
+    #[arg(long = "tenant-record-key", value_name = "KEY")]
+    pub tenant_record_keys: Vec<TenantRecordKey>,
+
+    /// Directory to archive log entries to before they are compacted
+    /// [default: disabled].
+    #[arg(long, value_name = "DIR")]
+    pub log_archive_dir: Option<PathBuf>,
+
+    /// Directory to record the agent's RPCs with its HSM in, for replaying
+    /// with hsm_replay. Only traces that start when the HSM booted can be
+    /// replayed [default: disabled].
+    #[arg(long, value_name = "DIR")]
+    pub hsm_trace_dir: Option<PathBuf>,
+
+    /// The size after which to start a new HSM trace file, in bytes.
+    #[arg(long, value_name = "BYTES", default_value_t = 64 << 20)]
+    pub hsm_trace_file_size: u64,
+
+    /// The number of HSM trace files to keep. The oldest are deleted.
+    #[arg(
+        long,
+        value_name = "FILES",
+        default_value_t = 16,
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    pub hsm_trace_files: u64,
+
     // Args for a specific type of agent service.
     #[command(flatten)]
     pub service: SA,
@@ -195,7 +230,18 @@ where
     }
 
     let name = args.name();
-    let hsm_client = HsmClient::new(transport, name.clone(), metrics.clone());
+    let recorder = args.hsm_trace_dir.map(|dir| {
+        Recorder::new(
+            RecorderOptions {
+                dir,
+                max_file_bytes: args.hsm_trace_file_size,
+                max_files: args.hsm_trace_files.try_into().unwrap(),
+            },
+            metrics.clone(),
+        )
+        .expect("failed to start recording HSM RPCs")
+    });
+    let hsm_client = HsmClient::new(transport, name.clone(), metrics.clone(), recorder);
 
     let pubsub_project = args.pubsub_project.unwrap_or(args.bigtable.project);
     let pubsub_options = GrpcConnectionOptions {
@@ -226,6 +272,10 @@ where
             event_publisher: pubsub,
             metrics,
             default_rate_limiter_rate: args.default_rate_limit,
+            tenant_record_keys: args.tenant_record_keys,
+            log_archiver: args
+                .log_archive_dir
+                .map(|dir| LogArchiver::new(Arc::new(LocalDirectory::new(dir)))),
         },
         hsm_client,
     );
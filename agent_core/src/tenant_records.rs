@@ -0,0 +1,201 @@
+use std::time::{Duration, SystemTime};
+use tokio::time::sleep;
+use tracing::{info, trace, warn};
+
+use super::append::Append;
+use super::transfer::WaitForCommitResult;
+use super::{merkle, Agent, GroupState, Transport};
+use agent_api::merkle::TreeStoreError;
+use agent_api::{TenantRecordRequest, TenantRecordResponse};
+use hsm_api::{LogEntry, RegistrationStatus, TenantRecordOperation};
+use observability::metrics_tag as tag;
+use retry_loop::RetryError;
+use service_core::rpc::HandlerError;
+use store::log::ReadLastLogEntryFatal;
+
+/// The number of times an operation is attempted when the HSM rejects the
+/// Merkle proof as stale. This is expected to succeed on the first or second try.
+const MAX_STALE_PROOF_ATTEMPTS: usize = 10;
+
+impl<T: Transport + 'static> Agent<T> {
+    pub(super) async fn handle_tenant_record(
+        &self,
+        request: TenantRecordRequest,
+    ) -> Result<TenantRecordResponse, HandlerError> {
+        type Response = TenantRecordResponse;
+        type HsmResponse = hsm_api::TenantRecordResponse;
+        let realm = request.realm;
+        let group = request.group;
+        let grant = request.grant;
+        let tags = [tag!(?realm), tag!(?group)];
+
+        // The HSM checks the signature too, but it can't check the expiry.
+        // This also avoids loading a proof for a request that will fail.
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if grant.expires < now || !grant.verify(&realm, &self.0.tenant_record_keys) {
+            warn!(
+                ?realm,
+                tenant = %grant.tenant,
+                requested_by = %grant.requested_by,
+                record_id = ?grant.record_id,
+                operation = ?grant.operation,
+                expires = grant.expires,
+                "rejected unauthorized tenant record request"
+            );
+            return Ok(Response::Unauthorized);
+        }
+
+        // HSMs that predate tenant record requests can't decode them.
+        let supported = self
+            .0
+            .state
+            .lock()
+            .unwrap()
+            .hsm_version
+            .as_ref()
+            .is_some_and(|version| version.supports("TenantRecord"));
+        if !supported {
+            return Ok(Response::Unsupported);
+        }
+
+        for attempt in 1..=MAX_STALE_PROOF_ATTEMPTS {
+            let cached_entry: Option<LogEntry> = {
+                let locked = self.0.state.lock().unwrap();
+                match locked.groups.get(&(realm, group)) {
+                    None => return Ok(Response::InvalidGroup),
+                    Some(GroupState { leader: None, .. }) => return Ok(Response::NotLeader),
+                    Some(GroupState {
+                        leader: Some(leader),
+                        ..
+                    }) => leader.last_appended.clone(),
+                }
+            };
+            let entry = match cached_entry {
+                Some(entry) => entry,
+                None => match self.0.store.read_last_log_entry(&realm, &group).await {
+                    Ok(entry) => entry,
+                    Err(RetryError::Fatal {
+                        error: ReadLastLogEntryFatal::EmptyLog,
+                    }) => return Ok(Response::InvalidGroup),
+                    Err(_) => return Ok(Response::NoStore),
+                },
+            };
+            let Some(partition) = entry.partition else {
+                return Ok(Response::NotOwner);
+            };
+
+            let proof = match merkle::read(
+                &realm,
+                &self.0.store,
+                &partition.range,
+                &partition.root_hash,
+                &grant.record_id,
+                &self.0.metrics,
+                &tags,
+            )
+            .await
+            {
+                Ok(proof) => proof,
+                Err(TreeStoreError::MissingNode | TreeStoreError::Busy) => {
+                    trace!(attempt, "couldn't read proof, will retry");
+                    sleep(Duration::from_millis(10)).await;
+                    continue;
+                }
+                Err(TreeStoreError::Network(err)) => {
+                    warn!(?err, "handle_tenant_record: error reading proof");
+                    return Ok(Response::NoStore);
+                }
+            };
+
+            match self
+                .0
+                .hsm
+                .send(hsm_api::TenantRecordRequest {
+                    realm,
+                    group,
+                    proof,
+                    index: entry.index,
+                    grant: grant.clone(),
+                })
+                .await
+            {
+                Err(_) => return Ok(Response::NoHsm),
+                Ok(HsmResponse::InvalidRealm) => return Ok(Response::InvalidRealm),
+                Ok(HsmResponse::InvalidGroup) => return Ok(Response::InvalidGroup),
+                Ok(HsmResponse::NotLeader(role)) => {
+                    self.maybe_role_changed(realm, group, role);
+                    return Ok(Response::NotLeader);
+                }
+                Ok(HsmResponse::NotOwner) => return Ok(Response::NotOwner),
+                Ok(HsmResponse::Unauthorized) => return Ok(Response::Unauthorized),
+                Ok(HsmResponse::InvalidProof | HsmResponse::InvalidRecordData) => {
+                    return Ok(Response::InvalidProof)
+                }
+                Ok(HsmResponse::RecordChanged) => return Ok(Response::RecordChanged),
+                Ok(HsmResponse::StaleProof) => {
+                    trace!(attempt, "hsm said stale proof, will retry");
+                    sleep(Duration::from_millis(1)).await;
+                }
+                Ok(HsmResponse::Ok {
+                    entry,
+                    delta,
+                    clock,
+                    status,
+                    fingerprint,
+                }) => {
+                    let index = entry.index;
+                    self.append(realm, group, Append { entry, delta });
+                    match self
+                        .wait_for_commit(realm, group, index, clock, Duration::from_secs(60))
+                        .await
+                    {
+                        WaitForCommitResult::Committed => {}
+                        WaitForCommitResult::NotLeader => return Ok(Response::NotLeader),
+                        WaitForCommitResult::Timeout => return Ok(Response::CommitTimeout),
+                    }
+                    // These operations aren't authorized by the user, so
+                    // they're always logged for auditing.
+                    info!(
+                        ?realm,
+                        tenant = %grant.tenant,
+                        requested_by = %grant.requested_by,
+                        record_id = ?grant.record_id,
+                        operation = ?grant.operation,
+                        ?status,
+                        "performed record operation on behalf of tenant"
+                    );
+                    if matches!(grant.operation, TenantRecordOperation::Delete { .. })
+                        && status != RegistrationStatus::NotRegistered
+                    {
+                        self.0
+                            .accountant
+                            .secret_deleted(realm, grant.tenant, grant.record_id)
+                            .await;
+                    }
+                    self.0.metrics.incr(
+                        "agent.tenant_record.count",
+                        [
+                            tag!(?realm),
+                            tag!(?group),
+                            tag!("operation": grant.operation.name()),
+                            tag!(?status),
+                        ],
+                    );
+                    return Ok(Response::Ok {
+                        status,
+                        fingerprint,
+                    });
+                }
+            }
+        }
+        warn!(
+            ?realm,
+            ?group,
+            "gave up on tenant record operation after repeated stale proofs"
+        );
+        Ok(Response::NoStore)
+    }
+}
@@ -3,6 +3,7 @@ use std::time::SystemTime;
 use tokio::sync::mpsc;
 use tracing::trace;
 
+use agent_api::{HashedSlotId, HashedUserId};
 use hsm_api::RecordId;
 use juicebox_realm_api::types::RealmId;
 use observability::metrics;
@@ -45,16 +46,24 @@ impl UserAccountingWriter {
         UserAccountingWriter { tx }
     }
 
-    pub async fn secret_registered(&self, realm: RealmId, tenant: String, id: RecordId) {
+    pub async fn secret_registered(
+        &self,
+        realm: RealmId,
+        tenant: String,
+        id: RecordId,
+        user: &HashedUserId,
+        slot: Option<HashedSlotId>,
+    ) {
         self.tx
             .send((
                 realm,
-                UserAccounting {
+                UserAccounting::new(
                     tenant,
                     id,
-                    when: SystemTime::now(),
-                    event: UserAccountingEvent::SecretRegistered,
-                },
+                    SystemTime::now(),
+                    UserAccountingEvent::SecretRegistered,
+                )
+                .with_user(user.to_string(), slot.map(|slot| slot.to_string())),
             ))
             .await
             .unwrap();
@@ -64,12 +73,12 @@ impl UserAccountingWriter {
         self.tx
             .send((
                 realm,
-                UserAccounting {
+                UserAccounting::new(
                     tenant,
                     id,
-                    when: SystemTime::now(),
-                    event: UserAccountingEvent::SecretDeleted,
-                },
+                    SystemTime::now(),
+                    UserAccountingEvent::SecretDeleted,
+                ),
             ))
             .await
             .unwrap();
@@ -243,6 +243,7 @@ impl<T: Transport + 'static> Agent<T> {
                         Some(entry) => self.append(realm, source, Append { entry, delta }),
                         None => assert!(delta.is_empty()),
                     }
+                    self.refresh_partitions().await;
                     match self
                         .wait_for_commit(
                             request.realm,
@@ -419,6 +420,7 @@ impl<T: Transport + 'static> Agent<T> {
                 }) => {
                     let index = entry.index;
                     self.append(request.realm, request.destination, Append { entry, delta });
+                    self.refresh_partitions().await;
                     match self
                         .wait_for_commit(
                             request.realm,
@@ -542,7 +544,7 @@ impl<T: Transport + 'static> Agent<T> {
         }
     }
 
-    async fn wait_for_commit(
+    pub(super) async fn wait_for_commit(
         &self,
         realm: RealmId,
         group: GroupId,
@@ -587,7 +589,7 @@ impl<T: Transport + 'static> Agent<T> {
     }
 }
 
-enum WaitForCommitResult {
+pub(super) enum WaitForCommitResult {
     /// The requested index is committed.
     Committed,
     /// Not leader for the group, or lost leadership while waiting.
@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
 use thiserror::Error;
 
-use hsm_api::{GroupId, HsmId, OwnedRange};
+use hsm_api::{GroupId, HsmId, OwnedRange, RecordId};
 use juicebox_networking::rpc::{Rpc, RpcError, Service};
 use juicebox_sdk::RealmId;
 
@@ -144,3 +145,54 @@ pub enum TransferError {
     #[error("an RPC error occurred to one of the leaders: {0}")]
     RpcError(RpcError),
 }
+
+impl Rpc<ClusterService> for OffboardTenantRequest {
+    const PATH: &'static str = "tenant/offboard";
+    type Response = Result<OffboardTenantStatus, OffboardTenantError>;
+}
+
+/// Request that the cluster manager delete every record that belongs to a
+/// tenant in a realm.
+///
+/// The first request starts the offboarding, which then runs in the
+/// background. The offboarding is resumed from where it left off if it's
+/// interrupted. Repeating the request returns its progress, which includes a
+/// final report once it's finished.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OffboardTenantRequest {
+    pub realm: RealmId,
+    pub tenant: String,
+    /// A token signed with the tenant's admin key, authorizing the
+    /// offboarding. The token's subject is recorded as the
+    /// requester.
+    pub token: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct OffboardTenantStatus {
+    pub requested_by: String,
+    pub started: SystemTime,
+    /// Set once every record has been processed.
+    pub finished: Option<SystemTime>,
+    /// Every record up to and including this one has been processed.
+    pub checkpoint: Option<RecordId>,
+    /// The number of records that had a secret that was deleted.
+    pub deleted: u64,
+    /// The number of records that had no secret to delete.
+    pub not_registered: u64,
+    /// The most recent error that interrupted the offboarding, if it's not
+    /// finished.
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, Error, PartialEq, Serialize)]
+pub enum OffboardTenantError {
+    #[error("the tenant name is invalid")]
+    InvalidTenant,
+    #[error("the token isn't a valid admin token for the tenant")]
+    InvalidAuth,
+    #[error("failed to read or write to the store")]
+    NoStore,
+    #[error("failed to access the secret manager")]
+    NoSecretManager,
+}
@@ -4,10 +4,13 @@ pub mod auth_token;
 pub mod configuration;
 pub mod groups;
 pub mod join_realm;
+pub mod log_archive;
+pub mod merkle_gc;
 pub mod new_group;
 pub mod new_realm;
 pub mod partitions;
 pub mod rebalance;
+pub mod snapshot;
 pub mod stepdown;
 pub mod table_stats;
 pub mod tenants;
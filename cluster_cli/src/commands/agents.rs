@@ -3,9 +3,9 @@ use futures::StreamExt;
 use std::fmt;
 use std::time::Duration;
 
-use agent_api::{StatusRequest, StatusResponse};
+use agent_api::{AgentVersion, StatusRequest, StatusResponse};
 use cluster_core::workload::{GroupWorkload, HsmWorkload};
-use hsm_api::{GroupStatus, HsmId, OwnedRange, Transferring};
+use hsm_api::{GroupStatus, HsmId, HsmVersion, OwnedRange, SessionPartition, Transferring};
 use jburl::Url;
 use juicebox_networking::reqwest::Client;
 use juicebox_networking::rpc::{self, RpcError};
@@ -27,13 +27,92 @@ pub async fn list_agents(c: &Client, cluster: &ClusterInfo) -> anyhow::Result<()
         .collect::<FuturesUnordered<_>>();
 
     println!();
+    let mut versions = Vec::new();
     while let Some((url, result)) = futures.next().await {
+        versions.push(Versions::new(url, &result));
         print_agent_status(url, result)
     }
 
+    print_version_matrix(versions);
     Ok(())
 }
 
+/// The versions reported by one agent and its HSM.
+struct Versions {
+    url: Url,
+    // None if the agent couldn't be reached.
+    agent: Option<AgentVersion>,
+    hsm: Option<HsmVersion>,
+}
+
+impl Versions {
+    fn new(url: &Url, status: &Result<StatusResponse, RpcError>) -> Self {
+        match status {
+            Ok(status) => Self {
+                url: url.clone(),
+                // Agents that predate versioning speak protocol 0.
+                agent: Some(status.agent.version.clone().unwrap_or(AgentVersion {
+                    software: String::from("unknown"),
+                    protocol: 0,
+                    min_hsm_protocol: 0,
+                })),
+                hsm: status.hsm.as_ref().map(|hsm| hsm.version()),
+            },
+            Err(_) => Self {
+                url: url.clone(),
+                agent: None,
+                hsm: None,
+            },
+        }
+    }
+}
+
+/// Prints a table of the versions each agent and HSM are running, flagging
+/// the combinations that won't work together.
+fn print_version_matrix(mut versions: Vec<Versions>) {
+    versions.sort_by(|a, b| a.url.cmp(&b.url));
+    let hsms: Vec<&HsmVersion> = versions.iter().filter_map(|v| v.hsm.as_ref()).collect();
+
+    println!("versions:");
+    println!(
+        "{TAB}{:<40} {:<12} {:>6} {:<12} {:>6} {:>10} {:<10}",
+        "agent", "agent sw", "proto", "HSM sw", "proto", "log format", "status"
+    );
+    for row in &versions {
+        let (agent_sw, agent_proto) = match &row.agent {
+            Some(agent) => (agent.software.as_str(), agent.protocol.to_string()),
+            None => ("-", String::from("-")),
+        };
+        let (hsm_sw, hsm_proto, log_format) = match &row.hsm {
+            Some(hsm) => (
+                hsm.software.as_str(),
+                hsm.protocol.to_string(),
+                hsm.log_entry_format.to_string(),
+            ),
+            None => ("-", String::from("-"), String::from("-")),
+        };
+        let status = match (&row.agent, &row.hsm) {
+            (None, _) => "unknown",
+            (Some(_), None) => "no HSM",
+            (Some(agent), Some(hsm))
+                if !hsm.works_with_agent(agent.protocol, agent.min_hsm_protocol) =>
+            {
+                "AGENT/HSM MISMATCH"
+            }
+            // Some other HSM can't verify this one's log entries, so it
+            // shouldn't lead a group with it.
+            (Some(_), Some(hsm)) if !hsms.iter().all(|other| other.can_witness(hsm)) => {
+                "CAN'T LEAD"
+            }
+            (Some(_), Some(_)) => "ok",
+        };
+        println!(
+            "{TAB}{:<40} {agent_sw:<12.12} {agent_proto:>6} {hsm_sw:<12.12} {hsm_proto:>6} {log_format:>10} {status:<10}",
+            row.url.as_str()
+        );
+    }
+}
+
 const TAB: &str = "    ";
 
 fn print_agent_status(url: &Url, status: Result<StatusResponse, RpcError>) {
@@ -55,6 +134,17 @@ fn print_agent_status(url: &Url, status: Result<StatusResponse, RpcError>) {
                     }
                     println!("{TAB}HSM ID: {}", status.id);
                     println!("{TAB}public key: {:?}", status.public_key);
+                    let version = status.version();
+                    println!(
+                        "{TAB}HSM version: {} (protocol {}, log entry format {})",
+                        version.software, version.protocol, version.log_entry_format
+                    );
+                    if let Some(nvram) = &status.nvram {
+                        println!(
+                            "{TAB}NVRAM: {} of {} bytes used, room for {} more groups",
+                            nvram.used, nvram.limit, nvram.group_capacity
+                        );
+                    }
 
                     match status.realm {
                         Some(mut realm) => {
@@ -80,6 +170,13 @@ fn print_agent_status(url: &Url, status: Result<StatusResponse, RpcError>) {
             println!("{TAB}Agent status:");
             println!("{TAB}{TAB}name: {}", agent.name);
             println!("{TAB}{TAB}build: {}", agent.build_hash);
+            match &agent.version {
+                Some(version) => println!(
+                    "{TAB}{TAB}version: {} (protocol {})",
+                    version.software, version.protocol
+                ),
+                None => println!("{TAB}{TAB}version: unknown"),
+            }
             agent.groups.sort_unstable_by_key(|s| s.group);
             for group in agent.groups {
                 println!("{TAB}{TAB}group: {}", group.group);
@@ -155,6 +252,24 @@ fn print_group_status(hsm_id: &HsmId, group: &GroupStatus, work: &GroupWorkload)
                 println!("{TAB}{TAB}{TAB}{TAB}root hash:   {:?}", p.root_hash);
             }
         }
+        if let Some(sessions) = &leader.sessions {
+            println!(
+                "{TAB}{TAB}{TAB}sessions: {} of {} in {} partitions (limit {} each when full)",
+                sessions.entries,
+                sessions.limit,
+                sessions.partition_count,
+                sessions.partition_limit
+            );
+            for p in &sessions.partitions {
+                let partition = match p.partition {
+                    SessionPartition::Tenant(tag) => format!("tenant {tag:?}"),
+                    SessionPartition::RecordPrefix(prefix) => {
+                        format!("record IDs starting {prefix:02x}")
+                    }
+                };
+                println!("{TAB}{TAB}{TAB}{TAB}{partition}: {}", p.entries);
+            }
+        }
     }
 
     println!("{TAB}{TAB}configuration:");
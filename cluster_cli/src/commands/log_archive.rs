@@ -0,0 +1,88 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use hsm_api::{GroupId, LogEntry};
+use juicebox_realm_api::types::RealmId;
+use store::log_archive::{ArchiveError, ArchiveReader, LocalDirectory};
+
+pub async fn check(dir: &Path, realm: RealmId, group: Option<GroupId>) -> anyhow::Result<()> {
+    let reader = ArchiveReader::new(Arc::new(LocalDirectory::new(dir)));
+    let groups = match group {
+        Some(group) => vec![group],
+        None => reader.list_groups(&realm).await?,
+    };
+    if groups.is_empty() {
+        println!("no archived log entries for realm {realm:?} in {dir:?}");
+        return Ok(());
+    }
+
+    let mut problems = 0;
+    for group in groups {
+        problems += check_group(&reader, realm, group).await?;
+    }
+    if problems > 0 {
+        return Err(anyhow!("found {problems} problems in the log archive"));
+    }
+    println!("no problems found");
+    Ok(())
+}
+
+/// Reads back all of a group's archived entries, printing a summary and any
+/// problems found. Returns the number of problems.
+async fn check_group(
+    reader: &ArchiveReader,
+    realm: RealmId,
+    group: GroupId,
+) -> anyhow::Result<usize> {
+    let segments = reader.list_segments(&realm, &group).await?;
+    println!("group {group:?}: {} segments", segments.len());
+    let Some((start, _)) = segments.first() else {
+        return Ok(0);
+    };
+
+    let mut problems = 0;
+    let mut entries_read: u64 = 0;
+    let mut prev: Option<LogEntry> = None;
+    let mut iter = reader.read_entries_iter(realm, group, *start);
+    loop {
+        match iter.next().await {
+            Ok(entries) if entries.is_empty() => break,
+            Ok(entries) => {
+                for entry in entries {
+                    if let Some(prev) = &prev {
+                        if prev.index.next() == entry.index && prev.entry_mac != entry.prev_mac {
+                            println!(
+                                "  entry {} doesn't chain from entry {}: its prev_mac doesn't match",
+                                entry.index, prev.index
+                            );
+                            problems += 1;
+                        }
+                    }
+                    entries_read += 1;
+                    prev = Some(entry);
+                }
+            }
+            Err(ArchiveError::Gap { missing, resumes }) => {
+                println!("  entries from {missing} up to {resumes} are not archived");
+                problems += 1;
+                iter = reader.read_entries_iter(realm, group, resumes);
+            }
+            Err(err @ ArchiveError::Corrupt { .. }) => {
+                println!("  {err}; skipping the rest of this group");
+                problems += 1;
+                break;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    match &prev {
+        Some(last) => println!(
+            "  read {entries_read} entries from {start} to {}",
+            last.index
+        ),
+        None => println!("  read no entries"),
+    }
+    Ok(problems)
+}
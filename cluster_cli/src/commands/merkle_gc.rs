@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use juicebox_sdk::RealmId;
+use store::merkle_gc::{MerkleGcOptions, MerkleGcReport};
+use store::StoreClient;
+
+pub async fn collect_garbage(
+    store: &StoreClient,
+    realm: RealmId,
+    min_age: Duration,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let options = MerkleGcOptions {
+        min_age,
+        dry_run,
+        ..MerkleGcOptions::default()
+    };
+    let report = store.collect_merkle_garbage(&realm, &options).await?;
+    print_report(&report, dry_run);
+    Ok(())
+}
+
+fn print_report(report: &MerkleGcReport, dry_run: bool) {
+    println!(
+        "walked {} roots across {} groups",
+        report.roots, report.groups
+    );
+    println!("scanned {} nodes:", report.nodes_scanned);
+    println!("  {} reachable", report.nodes_reachable);
+    println!("  {} unreachable", report.nodes_unreachable);
+    println!(
+        "  {} unreachable but too recent to delete",
+        report.nodes_too_young
+    );
+    if report.missing_from_older_roots > 0 {
+        println!(
+            "{} nodes were missing from older roots (these were already deleted by the agents)",
+            report.missing_from_older_roots
+        );
+    }
+    if report.nodes_corrupt > 0 {
+        println!(
+            "{} reachable nodes couldn't be decoded, so nothing was deleted",
+            report.nodes_corrupt
+        );
+    }
+    if dry_run {
+        println!("dry run: would have deleted {} cells", report.cells_deleted);
+    } else {
+        println!("deleted {} cells", report.cells_deleted);
+    }
+}
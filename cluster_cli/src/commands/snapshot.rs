@@ -0,0 +1,62 @@
+use anyhow::Context;
+use std::fs;
+use std::path::Path;
+
+use juicebox_sdk::RealmId;
+use store::snapshot::RealmSnapshot;
+use store::{StoreAdminClient, StoreClient};
+
+pub async fn export(store: &StoreClient, realm: RealmId, path: &Path) -> anyhow::Result<()> {
+    let snapshot = store.export_snapshot(&realm).await?;
+    fs::write(path, snapshot.encode()?)
+        .with_context(|| format!("failed to write snapshot to {path:?}"))?;
+
+    println!(
+        "exported {} groups, {} tenants, and {} user accounting rows to {path:?}",
+        snapshot.groups.len(),
+        snapshot.tenants.len(),
+        snapshot.user_accounting.len()
+    );
+    for group in &snapshot.groups {
+        println!(
+            "  group {:?}: log index {}, {} Merkle nodes",
+            group.group,
+            group.last_entry.index,
+            group.merkle_nodes.len()
+        );
+    }
+    Ok(())
+}
+
+pub async fn import(
+    store_admin: &StoreAdminClient,
+    store: &StoreClient,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let bytes = fs::read(path).with_context(|| format!("failed to read snapshot from {path:?}"))?;
+    let snapshot = RealmSnapshot::decode(&bytes)?;
+    let realm = snapshot.realm;
+
+    store_admin
+        .initialize_shared_tables()
+        .await
+        .context("failed to initialize shared tables")?;
+    match store_admin.initialize_realm(&realm).await {
+        Ok(()) => println!("created tables for realm {realm:?}"),
+        Err(err) if err.code() == tonic::Code::AlreadyExists => {
+            println!("tables for realm {realm:?} already exist")
+        }
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to create tables for realm {realm:?}"))
+        }
+    }
+
+    store.import_snapshot(&snapshot).await?;
+    println!(
+        "imported {} groups, {} tenants, and {} user accounting rows into realm {realm:?}",
+        snapshot.groups.len(),
+        snapshot.tenants.len(),
+        snapshot.user_accounting.len()
+    );
+    Ok(())
+}
@@ -1,22 +1,519 @@
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
 use futures::future::join_all;
 use futures::FutureExt;
+use serde_json::json;
+use std::time::Duration;
+use tokio::time::sleep;
 
-use agent_api::{ReloadTenantConfigurationRequest, ReloadTenantConfigurationResponse};
+use super::super::cluster::ClusterInfo;
+use super::users::date_range;
+use crate::{OutputFormat, UserSummaryWhen};
+use agent_api::{
+    RateLimitStateRequest, RateLimitStateResponse, ReloadTenantConfigurationRequest,
+    ReloadTenantConfigurationResponse,
+};
+use cluster_api::OffboardTenantRequest;
+use hsm_api::TenantRecordGrant;
+use jburl::Url;
 use juicebox_networking::rpc;
 use juicebox_sdk::reqwest::Client;
-use store::tenant_config::TenantConfiguration;
+use juicebox_sdk::RealmId;
+use secret_manager::{
+    get_tenant_record_signing_key, tenant_record_signing_key_name, SecretManager,
+};
+use store::tenant_config::{RealmCapacity, TenantConfiguration};
 use store::StoreClient;
+use table::{Column, FmtWriteStdOut, Justify, Table, TableStyle};
 
+/// Sets the tenant's capacity (operations per second and burst), either
+/// overall or for a single realm. If `capacity` is `None`, the tenant's
+/// separate capacity for `realm` is removed instead.
 pub(crate) async fn set_capacity(
     store: &StoreClient,
     agents_client: &Client,
     tenant: String,
+    realm: Option<RealmId>,
+    capacity: Option<(usize, Option<usize>)>,
+) -> anyhow::Result<()> {
+    let existing = get_tenant(store, &tenant).await?;
+    let config = match (realm, capacity, existing) {
+        (None, Some((ops_per_sec, burst_ops)), Some(config)) => TenantConfiguration {
+            capacity_ops_per_sec: ops_per_sec,
+            burst_ops,
+            ..config
+        },
+        (None, Some((ops_per_sec, burst_ops)), None) => TenantConfiguration {
+            burst_ops,
+            ..TenantConfiguration::new(ops_per_sec)
+        },
+        (None, None, _) => unreachable!("clap requires ops_per_sec unless removing a realm"),
+        (Some(_), _, None) => {
+            return Err(anyhow!(
+                "tenant {tenant:?} has no configuration: set its capacity first"
+            ))
+        }
+        (Some(realm), capacity, Some(mut config)) => {
+            config.realm_capacities.retain(|rc| rc.realm != realm);
+            if let Some((ops_per_sec, burst_ops)) = capacity {
+                config.realm_capacities.push(RealmCapacity {
+                    realm,
+                    capacity_ops_per_sec: ops_per_sec,
+                    burst_ops,
+                });
+                config.realm_capacities.sort_by_key(|rc| rc.realm);
+            }
+            config
+        }
+    };
+    store.update_tenant(&tenant, &config).await?;
+    reload_agents(store, agents_client).await?;
+
+    println!("Updated tenant configuration");
+    Ok(())
+}
+
+pub(crate) async fn set_origins(
+    store: &StoreClient,
+    tenant: String,
+    origins: Option<Vec<String>>,
+) -> anyhow::Result<()> {
+    let mut config = get_tenant(store, &tenant)
+        .await?
+        .ok_or_else(|| anyhow!("tenant {tenant:?} has no configuration: set its capacity first"))?;
+    config.allowed_origins = origins;
+    store.update_tenant(&tenant, &config).await?;
+
+    match &config.allowed_origins {
+        None => println!("Tenant {tenant:?} now allows requests from any origin"),
+        Some(origins) if origins.is_empty() => {
+            println!("Tenant {tenant:?} now allows no browser origins")
+        }
+        Some(origins) => {
+            println!("Tenant {tenant:?} now allows requests from these origins:");
+            for origin in origins {
+                println!("  {origin}");
+            }
+        }
+    }
+    println!("Load balancers will pick up the change within 10 seconds");
+    Ok(())
+}
+
+pub(crate) async fn set_user_limit(
+    store: &StoreClient,
+    agents_client: &Client,
+    tenant: String,
+    ops_per_min: Option<usize>,
+) -> anyhow::Result<()> {
+    let mut config = get_tenant(store, &tenant)
+        .await?
+        .ok_or_else(|| anyhow!("tenant {tenant:?} has no configuration: set its capacity first"))?;
+    config.record_ops_per_min = ops_per_min;
+    store.update_tenant(&tenant, &config).await?;
+    reload_agents(store, agents_client).await?;
+
+    match ops_per_min {
+        None => println!("Tenant {tenant:?} no longer has a per-user limit"),
+        Some(ops) => {
+            println!(
+                "Each of tenant {tenant:?}'s users may now make up to {ops} operations per minute"
+            )
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn set_quota(
+    store: &StoreClient,
+    agents_client: &Client,
+    tenant: String,
+    max_registered_secrets: Option<u64>,
+) -> anyhow::Result<()> {
+    let mut config = get_tenant(store, &tenant)
+        .await?
+        .ok_or_else(|| anyhow!("tenant {tenant:?} has no configuration: set its capacity first"))?;
+    config.max_registered_secrets = max_registered_secrets;
+    store.update_tenant(&tenant, &config).await?;
+    reload_agents(store, agents_client).await?;
+
+    match max_registered_secrets {
+        None => println!("Tenant {tenant:?} no longer has a quota"),
+        Some(max) => {
+            println!("Tenant {tenant:?} may now register up to {max} secrets in each realm")
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn show(
+    store: &StoreClient,
+    agents_client: &Client,
+    cluster: &ClusterInfo,
+    tenant: String,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
+    let config = get_tenant(store, &tenant)
+        .await?
+        .ok_or_else(|| anyhow!("tenant {tenant:?} has no configuration"))?;
+
+    // Each agent reports the tenant's requests it allowed in the last second.
+    let mut recent_requests = 0;
+    for (url, result) in join_all(cluster.agents.iter().map(|url| {
+        rpc::send(agents_client, url, RateLimitStateRequest {}).map(|res| (url.clone(), res))
+    }))
+    .await
+    {
+        match result {
+            Ok(RateLimitStateResponse::Ok(states)) => {
+                recent_requests += states
+                    .iter()
+                    .filter(|state| state.tenant == tenant)
+                    .map(|state| {
+                        state.recent_requests
+                            + state
+                                .realms
+                                .iter()
+                                .map(|realm| realm.recent_requests)
+                                .sum::<usize>()
+                    })
+                    .sum::<usize>();
+            }
+            Err(e) => eprintln!("rpc error to agent {url}: {e:?}"),
+        }
+    }
+
+    let (start, end) = date_range(UserSummaryWhen::ThisMonth, None, None)?;
+    let mut realms: Vec<RealmId> = cluster.realms.iter().copied().collect();
+    realms.sort_unstable();
+    let mut realm_counts = Vec::with_capacity(realms.len());
+    for realm in realms {
+        let registered = store
+            .count_registered_records(&realm, &tenant)
+            .await
+            .with_context(|| format!("failed to count registered secrets in realm {realm:?}"))?;
+        let summary = store
+            .count_realm_users(&realm, start, end)
+            .await
+            .with_context(|| format!("failed to count active users in realm {realm:?}"))?;
+        let count_for = |counts: &[(String, usize)]| {
+            counts
+                .iter()
+                .find_map(|(t, count)| (*t == tenant).then_some(*count))
+                .unwrap_or(0)
+        };
+        realm_counts.push(RealmCounts {
+            realm,
+            registered,
+            active_users: count_for(&summary.tenant_user_counts),
+            active_slots: count_for(&summary.tenant_slot_counts),
+        });
+    }
+
+    match format {
+        OutputFormat::Json => {
+            let json = json!({
+                "tenant": tenant,
+                "config": config,
+                "recent_requests_per_sec": recent_requests,
+                "request_limit_per_sec": config.capacity_reqs_per_sec(),
+                "realms": realm_counts
+                    .iter()
+                    .map(|counts| json!({
+                        "realm": hex::encode(counts.realm.0),
+                        "registered_secrets": counts.registered,
+                        "active_users_this_month": counts.active_users,
+                        "active_slots_this_month": counts.active_slots,
+                    }))
+                    .collect::<Vec<_>>(),
+            });
+            println!("{}", serde_json::to_string_pretty(&json)?);
+        }
+        OutputFormat::Table => {
+            println!("tenant: {tenant}");
+            println!("capacity: {}", format_capacity(&config));
+            for rc in &config.realm_capacities {
+                println!(
+                    "capacity in realm {:?}: {} ops/s, burst {} ops",
+                    rc.realm,
+                    rc.capacity_ops_per_sec,
+                    rc.burst_ops.unwrap_or(rc.capacity_ops_per_sec)
+                );
+            }
+            match config.record_ops_per_min {
+                None => println!("per-user limit: none"),
+                Some(ops) => println!("per-user limit: {ops} ops/min"),
+            }
+            println!(
+                "current usage: {recent_requests} of {} requests/s",
+                config.capacity_reqs_per_sec()
+            );
+            println!("allowed origins: {}", format_origins(&config));
+            match config.max_registered_secrets {
+                None => println!("quota: none"),
+                Some(max) => println!("quota: {max} registered secrets per realm"),
+            }
+            println!("state: {}", format_state(&config));
+            println!();
+
+            let rows: Vec<_> = realm_counts
+                .into_iter()
+                .map(|counts| {
+                    [
+                        format!("{:?}", counts.realm),
+                        counts.registered.to_string(),
+                        counts.active_users.to_string(),
+                        counts.active_slots.to_string(),
+                    ]
+                })
+                .collect();
+            let table = Table::new(
+                [
+                    Column::new("Realm"),
+                    Column::new("Registered").justify(Justify::Right),
+                    Column::new("Active Users").justify(Justify::Right),
+                    Column::new("Active Slots").justify(Justify::Right),
+                ],
+                rows,
+                TableStyle::default(),
+            );
+            table.render(&mut FmtWriteStdOut::stdout()).unwrap();
+        }
+    }
+    Ok(())
+}
+
+struct RealmCounts {
+    realm: RealmId,
+    registered: usize,
+    /// Users with a secret stored at some point this month.
+    active_users: usize,
+    active_slots: usize,
+}
+
+pub(crate) async fn suspend(
+    store: &StoreClient,
+    agents_client: &Client,
+    tenant: String,
+    reason: String,
     ops_per_sec: usize,
 ) -> anyhow::Result<()> {
-    let config = TenantConfiguration {
-        capacity_ops_per_sec: ops_per_sec,
+    // A tenant without a configuration can still be suspended: it gets a new
+    // configuration with the given capacity, which applies once it's resumed.
+    let (config, created) = match get_tenant(store, &tenant).await? {
+        Some(config) => (
+            TenantConfiguration {
+                suspended: Some(reason),
+                ..config
+            },
+            false,
+        ),
+        None => (
+            TenantConfiguration {
+                suspended: Some(reason),
+                ..TenantConfiguration::new(ops_per_sec)
+            },
+            true,
+        ),
     };
     store.update_tenant(&tenant, &config).await?;
+    reload_agents(store, agents_client).await?;
+
+    println!("Suspended tenant {tenant:?}");
+    if created {
+        println!(
+            "Created a configuration for it with a capacity of {ops_per_sec} operations per second"
+        );
+    }
+    println!("Load balancers will pick up the change within 10 seconds");
+    Ok(())
+}
+
+pub(crate) async fn resume(
+    store: &StoreClient,
+    agents_client: &Client,
+    tenant: String,
+) -> anyhow::Result<()> {
+    // A tenant without a configuration was never suspended.
+    let mut config = match get_tenant(store, &tenant).await? {
+        Some(config) if config.suspended.is_some() => config,
+        _ => {
+            println!("Tenant {tenant:?} is not suspended");
+            return Ok(());
+        }
+    };
+    config.suspended = None;
+    store.update_tenant(&tenant, &config).await?;
+    reload_agents(store, agents_client).await?;
+
+    println!("Resumed tenant {tenant:?}");
+    println!("Load balancers will pick up the change within 10 seconds");
+    Ok(())
+}
+
+pub(crate) async fn list(store: &StoreClient, format: OutputFormat) -> anyhow::Result<()> {
+    let mut tenants = store.get_tenants().await?;
+    tenants.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+    match format {
+        OutputFormat::Json => {
+            let json: Vec<_> = tenants
+                .iter()
+                .map(|(tenant, config)| json!({ "tenant": tenant, "config": config }))
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&json)?);
+        }
+        OutputFormat::Table => {
+            let rows: Vec<_> = tenants
+                .iter()
+                .map(|(tenant, config)| {
+                    [
+                        tenant.clone(),
+                        config.capacity_ops_per_sec.to_string(),
+                        format_quota(config),
+                        format_origins(config),
+                        format_state(config),
+                    ]
+                })
+                .collect();
+            let table = Table::new(
+                [
+                    Column::new("Tenant"),
+                    Column::new("Ops/s").justify(Justify::Right),
+                    Column::new("Quota").justify(Justify::Right),
+                    Column::new("Origins"),
+                    Column::new("State"),
+                ],
+                rows,
+                TableStyle::default(),
+            );
+            table.render(&mut FmtWriteStdOut::stdout()).unwrap();
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn history(
+    store: &StoreClient,
+    tenant: String,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
+    let history = store.get_tenant_history(&tenant).await?;
+    if history.is_empty() {
+        return Err(anyhow!("tenant {tenant:?} has never been configured"));
+    }
+
+    match format {
+        OutputFormat::Json => {
+            let json: Vec<_> = history
+                .iter()
+                .map(|version| {
+                    json!({
+                        "written": DateTime::<Utc>::from(version.written).to_rfc3339(),
+                        "config": version.config,
+                    })
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&json)?);
+        }
+        OutputFormat::Table => {
+            let rows: Vec<_> = history
+                .iter()
+                .map(|version| {
+                    let written = DateTime::<Utc>::from(version.written).to_rfc3339();
+                    match &version.config {
+                        None => [
+                            written,
+                            String::from(""),
+                            String::from(""),
+                            String::from(""),
+                            String::from("[deleted]"),
+                        ],
+                        Some(config) => [
+                            written,
+                            config.capacity_ops_per_sec.to_string(),
+                            format_quota(config),
+                            format_origins(config),
+                            format_state(config),
+                        ],
+                    }
+                })
+                .collect();
+            let table = Table::new(
+                [
+                    Column::new("Written"),
+                    Column::new("Ops/s").justify(Justify::Right),
+                    Column::new("Quota").justify(Justify::Right),
+                    Column::new("Origins"),
+                    Column::new("State"),
+                ],
+                rows,
+                TableStyle::default(),
+            );
+            table.render(&mut FmtWriteStdOut::stdout()).unwrap();
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn delete_config(
+    store: &StoreClient,
+    agents_client: &Client,
+    tenant: String,
+) -> anyhow::Result<()> {
+    if get_tenant(store, &tenant).await?.is_none() {
+        return Err(anyhow!("tenant {tenant:?} has no configuration"));
+    }
+    store.delete_tenant(&tenant).await?;
+    reload_agents(store, agents_client).await?;
+
+    println!("Deleted the configuration for tenant {tenant:?}");
+    println!("Its requests are now limited by the agents' default rate limit");
+    Ok(())
+}
+
+fn format_capacity(config: &TenantConfiguration) -> String {
+    format!(
+        "{} ops/s, burst {} ops",
+        config.capacity_ops_per_sec,
+        config.burst_ops.unwrap_or(config.capacity_ops_per_sec)
+    )
+}
+
+fn format_quota(config: &TenantConfiguration) -> String {
+    match config.max_registered_secrets {
+        None => String::from(""),
+        Some(max) => max.to_string(),
+    }
+}
+
+fn format_origins(config: &TenantConfiguration) -> String {
+    match &config.allowed_origins {
+        None => String::from("any"),
+        Some(origins) if origins.is_empty() => String::from("none"),
+        Some(origins) => origins.join(", "),
+    }
+}
+
+fn format_state(config: &TenantConfiguration) -> String {
+    match &config.suspended {
+        None => String::from("enabled"),
+        Some(reason) => format!("suspended: {reason}"),
+    }
+}
+
+async fn get_tenant(
+    store: &StoreClient,
+    tenant: &str,
+) -> anyhow::Result<Option<TenantConfiguration>> {
+    Ok(store
+        .get_tenants()
+        .await?
+        .into_iter()
+        .find_map(|(name, config)| (name == tenant).then_some(config)))
+}
+
+async fn reload_agents(store: &StoreClient, agents_client: &Client) -> anyhow::Result<()> {
     let agents = store.get_addresses(Some(store::ServiceKind::Agent)).await?;
     for (url, result) in join_all(agents.iter().map(|(url, _)| {
         rpc::send(agents_client, url, ReloadTenantConfigurationRequest {})
@@ -34,7 +531,69 @@ pub(crate) async fn set_capacity(
             Err(e) => eprintln!("rpc error to agent {url}: {e:?}"),
         }
     }
+    Ok(())
+}
 
-    println!("Updated tenant configuration");
+pub(crate) async fn offboard(
+    cluster: &ClusterInfo,
+    client: &Client,
+    cluster_url: &Option<Url>,
+    realm: RealmId,
+    tenant: String,
+    token: String,
+) -> anyhow::Result<()> {
+    let url = match cluster_url {
+        Some(url) => url,
+        None => {
+            if cluster.managers.is_empty() {
+                return Err(anyhow!("No cluster managers in service discovery, and no explicit cluster manager URL set."));
+            }
+            &cluster.managers[0]
+        }
+    };
+
+    println!("Offboarding tenant {tenant:?} from realm {realm:?}");
+    // The cluster manager checks the token.
+    let req = OffboardTenantRequest {
+        realm,
+        tenant,
+        token,
+    };
+    loop {
+        let status = rpc::send(client, url, req.clone())
+            .await
+            .context("error while asking cluster manager to offboard tenant")??;
+        if let Some(finished) = status.finished {
+            println!(
+                "Offboarding requested by {:?} finished after {:?}",
+                status.requested_by,
+                finished
+                    .duration_since(status.started)
+                    .unwrap_or(Duration::ZERO)
+            );
+            println!("deleted: {} records", status.deleted);
+            println!("not registered: {} records", status.not_registered);
+            return Ok(());
+        }
+        println!(
+            "deleted: {}, not registered: {}{}",
+            status.deleted,
+            status.not_registered,
+            match &status.last_error {
+                Some(err) => format!(" (retrying after error: {err})"),
+                None => String::new(),
+            }
+        );
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// Prints the public key that agents and HSMs need to be given (with
+/// `--tenant-record-key`) to accept tenant record grants.
+pub(crate) async fn record_key(secret_manager: &impl SecretManager) -> anyhow::Result<()> {
+    let signing_key = get_tenant_record_signing_key(secret_manager)
+        .await?
+        .ok_or_else(|| anyhow!("missing secret: {}", tenant_record_signing_key_name().0))?;
+    println!("{:?}", TenantRecordGrant::public_key(&signing_key));
     Ok(())
 }
@@ -2,6 +2,7 @@ use anyhow::{anyhow, Context};
 use chrono::{DateTime, Datelike, Months, Utc};
 use futures::future::join_all;
 use std::collections::HashSet;
+use std::iter::zip;
 use std::time::SystemTime;
 
 use agent_api::StatusRequest;
@@ -37,19 +38,19 @@ pub(crate) async fn user_summary(
             .await
             .context("counting rows in realm-users table")?;
         if !printed_headers {
-            println!("start,end,realm,tenant,users");
+            println!("start,end,realm,tenant,users,slots");
             printed_headers = true;
         }
         let start = DateTime::<Utc>::from(r.start);
         let end = DateTime::<Utc>::from(r.end);
-        for row in r.tenant_user_counts {
-            println!("{},{},{:?},\"{}\",{}", start, end, realm, row.0, row.1);
+        for ((tenant, users), (_, slots)) in zip(r.tenant_user_counts, r.tenant_slot_counts) {
+            println!("{start},{end},{realm:?},\"{tenant}\",{users},{slots}");
         }
     }
     Ok(())
 }
 
-fn date_range(
+pub(crate) fn date_range(
     w: UserSummaryWhen,
     start: Option<SystemTime>,
     end: Option<SystemTime>,
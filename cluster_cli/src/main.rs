@@ -1,6 +1,7 @@
 use anyhow::Context;
 use chrono::{LocalResult, TimeZone, Utc};
 use clap::{command, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
 use std::process::ExitCode;
 use std::time::{Duration, SystemTime};
 use tracing::{info, Level};
@@ -13,6 +14,7 @@ use juicebox_realm_api::types::RealmId;
 use juicebox_realm_auth::Scope;
 use observability::{logging, metrics};
 use secret_manager::new_google_secret_manager;
+use service_core::clap_parsers::parse_duration;
 
 mod cluster;
 mod commands;
@@ -37,6 +39,10 @@ struct Args {
 enum Command {
     /// Print detailed information about every discoverable agent.
     ///
+    /// This ends with a table of the software and protocol versions that each
+    /// agent and HSM are running, flagging combinations that won't work
+    /// together.
+    ///
     /// See 'groups' for a higher-level view of the realms and groups in the
     /// cluster.
     Agents,
@@ -102,6 +108,42 @@ enum Command {
         agents: Vec<Url>,
     },
 
+    /// Check the log entries that agents archived before compacting them.
+    ///
+    /// This reads back every archived segment for the realm's groups and
+    /// checks that the entries chain together, reporting any entries that are
+    /// missing from the archive.
+    LogArchive {
+        /// The directory that the agents archive log entries to (their
+        /// `--log-archive-dir`).
+        #[arg(long)]
+        dir: PathBuf,
+
+        /// Realm ID.
+        #[arg(value_parser = parse_resolvable_realm_id)]
+        realm: ResolvableRealmId,
+
+        /// Only check this group [default: all archived groups].
+        #[arg(long, value_parser = parse_resolvable_group_id)]
+        group: Option<ResolvableGroupId>,
+    },
+
+    /// Delete Merkle tree nodes that are no longer reachable from any recent
+    /// root.
+    MerkleGc {
+        /// Realm ID.
+        #[arg(value_parser = parse_resolvable_realm_id)]
+        realm: ResolvableRealmId,
+
+        /// Only delete unreachable nodes that are at least this old.
+        #[arg(long, default_value = "1h", value_parser = parse_duration)]
+        min_age: Duration,
+
+        /// Report what would be deleted without deleting anything.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+
     /// Create a new group on a set of agents' HSMs.
     ///
     /// The new group will not have ownership of any user records. Use
@@ -164,6 +206,12 @@ enum Command {
         realm: Option<ResolvableRealmId>,
     },
 
+    /// Export or import a snapshot of a realm's store state.
+    Snapshot {
+        #[command(subcommand)]
+        command: SnapshotCommand,
+    },
+
     /// Print information about a Bigtable table.
     TableStats {
         /// Only the "log" and "merkle" tables are currently supported.
@@ -223,9 +271,9 @@ enum Command {
         end: RecordId,
     },
 
-    /// Report counts of active users by tenant for a month. These are users
-    /// that have a secret stored at some point during the month (in the UTC
-    /// timezone)
+    /// Report counts of active users and secret slots by tenant for a month.
+    /// These are users that have a secret stored at some point during the
+    /// month (in the UTC timezone)
     UserSummary {
         /// Restrict the report to just these realms(s). If not set will report
         /// on realms that are found via service discovery.
@@ -252,6 +300,12 @@ enum UserSummaryWhen {
     LastMonth,
 }
 
+#[derive(Clone, Copy, Eq, PartialEq, ValueEnum)]
+enum OutputFormat {
+    Table,
+    Json,
+}
+
 #[derive(Clone, Eq, PartialEq, ValueEnum)]
 enum Table {
     Log,
@@ -265,16 +319,212 @@ enum StepdownType {
     Group,
 }
 
+#[derive(Subcommand)]
+enum SnapshotCommand {
+    /// Write a snapshot of a realm to a local file.
+    ///
+    /// The snapshot contains the last log entry and reachable Merkle nodes of
+    /// each group, the tenant configurations, and the realm's user accounting
+    /// rows.
+    Export {
+        /// Realm ID.
+        #[arg(value_parser = parse_resolvable_realm_id)]
+        realm: ResolvableRealmId,
+
+        /// The file to write the snapshot to.
+        file: PathBuf,
+    },
+
+    /// Load a snapshot from a local file into the store.
+    ///
+    /// The realm's tables are created if needed. The logs of the groups in the
+    /// snapshot must be empty. HSMs with the realm's keys can then resume
+    /// from the restored log entries.
+    Import {
+        /// The snapshot file to load.
+        file: PathBuf,
+    },
+}
+
 #[derive(Subcommand)]
 enum TenantCommand {
     /// Configure the capacity/rate limit for a tenant.
+    ///
+    /// With `--realm`, this sets a separate capacity for the tenant's
+    /// requests to that realm, which then don't count against its overall
+    /// capacity.
     SetCapacity {
         /// The tenant name/identifier.
         tenant: String,
 
         /// The number of allowed (client) operations per second.
+        #[arg(required_unless_present = "remove")]
+        ops_per_sec: Option<usize>,
+
+        /// The number of operations that may be made at once after a quiet
+        /// period. Defaults to one second's worth.
+        #[arg(long)]
+        burst: Option<usize>,
+
+        /// Set the capacity for just this realm.
+        #[arg(long, value_parser = parse_resolvable_realm_id)]
+        realm: Option<ResolvableRealmId>,
+
+        /// Remove the tenant's separate capacity for the `--realm`.
+        #[arg(long, requires = "realm", conflicts_with_all = ["ops_per_sec", "burst"])]
+        remove: bool,
+    },
+
+    /// Configure the browser origins allowed to make requests for a tenant.
+    ///
+    /// Requests from other origins are rejected by the load balancers, which
+    /// pick up changes within 10 seconds. The tenant's capacity must already
+    /// be set.
+    SetOrigins {
+        /// The tenant name/identifier.
+        tenant: String,
+
+        /// The allowed origins, like `https://www.example.com`. If none are
+        /// given, browsers can't make requests for the tenant at all.
+        origins: Vec<String>,
+
+        /// Allow requests from any origin. This is the default for tenants.
+        #[arg(long, conflicts_with = "origins")]
+        any: bool,
+    },
+
+    /// Configure the maximum number of secrets a tenant's users may have
+    /// registered in each realm.
+    ///
+    /// Registrations over the quota are rejected. The counts are refreshed
+    /// periodically, so the quota may be exceeded slightly. The tenant's
+    /// capacity must already be set.
+    SetQuota {
+        /// The tenant name/identifier.
+        tenant: String,
+
+        /// The maximum number of registered secrets per realm.
+        #[arg(required_unless_present = "none")]
+        max_registered_secrets: Option<u64>,
+
+        /// Remove the tenant's quota.
+        #[arg(long, conflicts_with = "max_registered_secrets")]
+        none: bool,
+    },
+
+    /// Configure how many operations each of a tenant's users may make per
+    /// minute.
+    ///
+    /// This protects against a single user using up the tenant's capacity,
+    /// and slows down guessing attacks. The tenant's capacity must already
+    /// be set.
+    SetUserLimit {
+        /// The tenant name/identifier.
+        tenant: String,
+
+        /// The number of allowed (client) operations per minute for each
+        /// user.
+        #[arg(required_unless_present = "none")]
+        ops_per_min: Option<usize>,
+
+        /// Remove the tenant's per-user limit.
+        #[arg(long, conflicts_with = "ops_per_min")]
+        none: bool,
+    },
+
+    /// Print a tenant's configuration, current usage, and number of users in
+    /// each realm.
+    Show {
+        /// The tenant name/identifier.
+        tenant: String,
+
+        /// How to print the output.
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+
+    /// Print every version of a tenant's configuration, newest first.
+    History {
+        /// The tenant name/identifier.
+        tenant: String,
+
+        /// How to print the output.
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+
+    /// Delete a tenant's configuration.
+    ///
+    /// The tenant's requests are then limited by the agents' default rate
+    /// limit. Its configuration history and user records are kept.
+    DeleteConfig {
+        /// The tenant name/identifier.
+        tenant: String,
+    },
+
+    /// Stop serving requests for a tenant's users.
+    ///
+    /// The agents are told to reload their configuration right away, and the
+    /// load balancers pick up the change within 10 seconds. The tenant's
+    /// records are kept.
+    Suspend {
+        /// The tenant name/identifier.
+        tenant: String,
+
+        /// Why the tenant is being suspended, such as an expired contract.
+        #[arg(long)]
+        reason: String,
+
+        /// The capacity, in operations per second, to give the tenant if it
+        /// has no configuration yet. This applies once it's resumed.
+        #[arg(long, default_value_t = 10)]
         ops_per_sec: usize,
     },
+
+    /// Start serving requests for a suspended tenant's users again.
+    Resume {
+        /// The tenant name/identifier.
+        tenant: String,
+    },
+
+    /// List the configured tenants.
+    List {
+        /// How to print the output.
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+
+    /// Delete all of a tenant's user records from a realm.
+    ///
+    /// This asks a cluster manager to start (or resume) the offboarding, then
+    /// waits for it to finish. The offboarding continues in the background if
+    /// this command is interrupted.
+    Offboard {
+        /// URL to a cluster manager, which will execute the request. By
+        /// default it will find a cluster manager using service discovery.
+        #[arg(short, long)]
+        cluster: Option<Url>,
+
+        /// The realm ID.
+        #[arg(long, value_parser = parse_resolvable_realm_id)]
+        realm: ResolvableRealmId,
+
+        /// The tenant name/identifier.
+        tenant: String,
+
+        /// An auth token signed with the tenant's admin key, authorizing the
+        /// offboarding. The cluster manager checks this, and records the
+        /// token's subject as the requester.
+        #[arg(long)]
+        token: String,
+    },
+
+    /// Print the public key for the realm's tenant record signing key.
+    ///
+    /// Agents and HSMs must be given this with `--tenant-record-key` to accept
+    /// the grants that load balancers and cluster managers sign for tenant
+    /// record operations, like offboarding.
+    RecordKey,
 }
 
 #[derive(Subcommand)]
@@ -348,7 +598,13 @@ enum ExperimentalCommand {
 
 impl Command {
     fn needs_secret_manager(&self) -> bool {
-        matches!(self, Command::AuthToken { .. })
+        matches!(
+            self,
+            Command::AuthToken { .. }
+                | Command::Tenant {
+                    command: TenantCommand::RecordKey
+                }
+        )
     }
 }
 
@@ -405,15 +661,29 @@ async fn run(args: Args) -> anyhow::Result<()> {
     let store = args
         .bigtable
         .connect_data(
-            auth_manager,
+            auth_manager.clone(),
             store::Options {
-                metrics,
+                metrics: metrics.clone(),
                 ..store::Options::default()
             },
         )
         .await
         .context("unable to connect to Bigtable")?;
 
+    // Importing a snapshot can target an empty store, so it's handled before
+    // anything tries to discover the cluster.
+    if let Command::Snapshot {
+        command: SnapshotCommand::Import { file },
+    } = &args.command
+    {
+        let store_admin = args
+            .bigtable
+            .connect_admin(auth_manager, metrics)
+            .await
+            .context("unable to connect to Bigtable admin")?;
+        return commands::snapshot::import(&store_admin, &store, file).await;
+    }
+
     let agents_client = Client::new(ClientOptions::default());
     let cluster_info = ClusterInfo::new(&store, &agents_client).await?;
 
@@ -488,6 +758,28 @@ async fn run(args: Args) -> anyhow::Result<()> {
             .await
         }
 
+        Command::LogArchive { dir, realm, group } => {
+            let group = match group {
+                Some(group) => Some(group.resolve(&cluster_info)?.group),
+                None => None,
+            };
+            commands::log_archive::check(&dir, realm.resolve(&cluster_info)?, group).await
+        }
+
+        Command::MerkleGc {
+            realm,
+            min_age,
+            dry_run,
+        } => {
+            commands::merkle_gc::collect_garbage(
+                &store,
+                realm.resolve(&cluster_info)?,
+                min_age,
+                dry_run,
+            )
+            .await
+        }
+
         Command::NewGroup { realm, agents } => {
             commands::new_group::new_group(realm.resolve(&cluster_info)?, &agents, &agents_client)
                 .await
@@ -500,6 +792,13 @@ async fn run(args: Args) -> anyhow::Result<()> {
             commands::partitions::print(&cluster_info, realm).await
         }
 
+        Command::Snapshot { command } => match command {
+            SnapshotCommand::Export { realm, file } => {
+                commands::snapshot::export(&store, realm.resolve(&cluster_info)?, &file).await
+            }
+            SnapshotCommand::Import { .. } => unreachable!("handled before cluster discovery"),
+        },
+
         Command::TableStats {
             table: Table::Log,
             realm,
@@ -514,7 +813,80 @@ async fn run(args: Args) -> anyhow::Result<()> {
             TenantCommand::SetCapacity {
                 tenant,
                 ops_per_sec,
-            } => commands::tenants::set_capacity(&store, &agents_client, tenant, ops_per_sec).await,
+                burst,
+                realm,
+                remove: _,
+            } => {
+                let realm = realm.map(|r| r.resolve(&cluster_info)).transpose()?;
+                commands::tenants::set_capacity(
+                    &store,
+                    &agents_client,
+                    tenant,
+                    realm,
+                    ops_per_sec.map(|ops_per_sec| (ops_per_sec, burst)),
+                )
+                .await
+            }
+            TenantCommand::SetOrigins {
+                tenant,
+                origins,
+                any,
+            } => commands::tenants::set_origins(&store, tenant, (!any).then_some(origins)).await,
+            TenantCommand::SetQuota {
+                tenant,
+                max_registered_secrets,
+                none: _,
+            } => {
+                commands::tenants::set_quota(&store, &agents_client, tenant, max_registered_secrets)
+                    .await
+            }
+            TenantCommand::SetUserLimit {
+                tenant,
+                ops_per_min,
+                none: _,
+            } => {
+                commands::tenants::set_user_limit(&store, &agents_client, tenant, ops_per_min).await
+            }
+            TenantCommand::Show { tenant, format } => {
+                commands::tenants::show(&store, &agents_client, &cluster_info, tenant, format).await
+            }
+            TenantCommand::History { tenant, format } => {
+                commands::tenants::history(&store, tenant, format).await
+            }
+            TenantCommand::DeleteConfig { tenant } => {
+                commands::tenants::delete_config(&store, &agents_client, tenant).await
+            }
+            TenantCommand::Suspend {
+                tenant,
+                reason,
+                ops_per_sec,
+            } => {
+                commands::tenants::suspend(&store, &agents_client, tenant, reason, ops_per_sec)
+                    .await
+            }
+            TenantCommand::Resume { tenant } => {
+                commands::tenants::resume(&store, &agents_client, tenant).await
+            }
+            TenantCommand::List { format } => commands::tenants::list(&store, format).await,
+            TenantCommand::Offboard {
+                cluster,
+                realm,
+                tenant,
+                token,
+            } => {
+                commands::tenants::offboard(
+                    &cluster_info,
+                    &agents_client,
+                    &cluster,
+                    realm.resolve(&cluster_info)?,
+                    tenant,
+                    token,
+                )
+                .await
+            }
+            TenantCommand::RecordKey => {
+                commands::tenants::record_key(&secret_manager.unwrap()).await
+            }
         },
 
         Command::Transfer {
@@ -696,13 +1068,29 @@ mod tests {
             vec!["cluster", "experimental", "transfer", "--help"],
             vec!["cluster", "groups", "--help"],
             vec!["cluster", "join-realm", "--help"],
+            vec!["cluster", "log-archive", "--help"],
+            vec!["cluster", "merkle-gc", "--help"],
             vec!["cluster", "new-group", "--help"],
             vec!["cluster", "new-realm", "--help"],
             vec!["cluster", "rebalance", "--help"],
+            vec!["cluster", "snapshot", "--help"],
+            vec!["cluster", "snapshot", "export", "--help"],
+            vec!["cluster", "snapshot", "import", "--help"],
             vec!["cluster", "stepdown", "--help"],
             vec!["cluster", "table-stats", "--help"],
             vec!["cluster", "tenant", "--help"],
+            vec!["cluster", "tenant", "delete-config", "--help"],
+            vec!["cluster", "tenant", "history", "--help"],
+            vec!["cluster", "tenant", "list", "--help"],
+            vec!["cluster", "tenant", "offboard", "--help"],
+            vec!["cluster", "tenant", "record-key", "--help"],
+            vec!["cluster", "tenant", "resume", "--help"],
             vec!["cluster", "tenant", "set-capacity", "--help"],
+            vec!["cluster", "tenant", "set-origins", "--help"],
+            vec!["cluster", "tenant", "set-quota", "--help"],
+            vec!["cluster", "tenant", "set-user-limit", "--help"],
+            vec!["cluster", "tenant", "show", "--help"],
+            vec!["cluster", "tenant", "suspend", "--help"],
             vec!["cluster", "transfer", "--help"],
             vec!["cluster", "user-summary", "--help"],
         ] {
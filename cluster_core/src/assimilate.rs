@@ -32,6 +32,8 @@ pub enum AssimilateError {
     JoinRealmError(#[from] JoinRealmError),
     #[error("Unable to create new group: {0}")]
     NewGroupError(#[from] NewGroupError),
+    #[error("HSM {hsm:?} doesn't have room in its NVRAM to join {groups} new groups")]
+    NotEnoughGroupRoom { hsm: HsmId, groups: usize },
     #[error("Could not find any available HSMs in realm {0:?} (need at least one)")]
     NoHsmInRealm(RealmId),
     #[error("Could not find owner for record id: {0:?}")]
@@ -214,6 +216,9 @@ fn get_unique_realm(
 /// Finds or creates groups such that each HSM is a member of `group_size`
 /// groups in a particular cyclic sorted order.
 ///
+/// This checks that every HSM has room for the groups it needs to join before
+/// creating any of them.
+///
 /// Note: This is unstable in that adding/removing an HSM to the cluster can
 /// cause a whole new set of groups to be created.
 async fn nominal_groups(
@@ -221,7 +226,7 @@ async fn nominal_groups(
     realm: RealmId,
     hsm_statuses: &[(Url, StatusResponse)],
     agents_client: &Client,
-) -> Result<Vec<GroupId>, NewGroupError> {
+) -> Result<Vec<GroupId>, AssimilateError> {
     // Groups are not "reused" so that if the number of HSMs is exactly the
     // group size, then the result is that many groups, not just one.
     let mut used: HashSet<GroupId> = HashSet::new();
@@ -229,12 +234,19 @@ async fn nominal_groups(
     // The outcome for a particular search is summarized and cloned into this
     // enum to avoid shared state with async code.
     enum Group {
-        Existing { id: GroupId },
-        New { agent_urls: Vec<Url> },
+        Existing {
+            id: GroupId,
+        },
+        New {
+            agent_urls: Vec<Url>,
+            hsms: Vec<HsmId>,
+        },
     }
 
-    let mut groups: Vec<GroupId> =
-        try_join_all(hsm_statuses.iter().enumerate().map(|(i, (_, status0))| {
+    let plan: Vec<Group> = hsm_statuses
+        .iter()
+        .enumerate()
+        .map(|(i, (_, status0))| {
             let target = hsm_statuses.iter().cycle().skip(i).take(group_size);
             let target_hsms: HashSet<&HsmId> =
                 target.clone().map(|(_, status)| &status.id).collect();
@@ -242,7 +254,7 @@ async fn nominal_groups(
             // Look for a not-yet-used group consisting of `target_hsms`. For
             // stability, pick the group with the smallest ID if there are
             // multiple candidates.
-            let group: Group = match status0.realm.as_ref().and_then(|realm_status| {
+            match status0.realm.as_ref().and_then(|realm_status| {
                 realm_status
                     .groups
                     .iter()
@@ -256,24 +268,56 @@ async fn nominal_groups(
                     Group::Existing { id }
                 }
                 None => Group::New {
-                    agent_urls: target.map(|(url, _)| url.clone()).collect(),
+                    agent_urls: target.clone().map(|(url, _)| url.clone()).collect(),
+                    hsms: target.map(|(_, status)| status.id).collect(),
                 },
-            };
-
-            // Actually create the group, if needed.
-            async move {
-                match group {
-                    Group::Existing { id } => Ok(id),
-                    Group::New { agent_urls } => new_group(agents_client, realm, &agent_urls).await,
-                }
             }
-        }))
-        .await?;
+        })
+        .collect();
+
+    let new_groups: Vec<&[HsmId]> = plan
+        .iter()
+        .filter_map(|group| match group {
+            Group::Existing { .. } => None,
+            Group::New { hsms, .. } => Some(hsms.as_slice()),
+        })
+        .collect();
+    check_group_room(hsm_statuses, &new_groups)?;
+
+    // Actually create the groups, where needed.
+    let mut groups: Vec<GroupId> = try_join_all(plan.into_iter().map(|group| async move {
+        match group {
+            Group::Existing { id } => Ok(id),
+            Group::New { agent_urls, .. } => new_group(agents_client, realm, &agent_urls).await,
+        }
+    }))
+    .await?;
 
     groups.sort_unstable();
     Ok(groups)
 }
 
+/// Checks that every HSM has room to join the new groups it's a member of.
+fn check_group_room(
+    hsm_statuses: &[(Url, StatusResponse)],
+    new_groups: &[&[HsmId]],
+) -> Result<(), AssimilateError> {
+    for (_, status) in hsm_statuses {
+        let joining: Vec<Vec<HsmId>> = new_groups
+            .iter()
+            .filter(|members| members.contains(&status.id))
+            .map(|members| members.to_vec())
+            .collect();
+        if !status.has_room_for_groups(&joining) {
+            return Err(AssimilateError::NotEnoughGroupRoom {
+                hsm: status.id,
+                groups: joining.len(),
+            });
+        }
+    }
+    Ok(())
+}
+
 /// Returns the status of every available HSM, sorted by HSM ID.
 async fn get_hsm_statuses(
     agents_client: &Client,
@@ -300,3 +344,65 @@ async fn get_hsm_statuses(
     hsms.sort_unstable_by_key(|(_, status)| status.id);
     Ok(hsms)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hsm_api::{NVRamStatus, PublicKey};
+
+    fn hsm(i: u8) -> HsmId {
+        HsmId([i; 16])
+    }
+
+    fn status(i: u8, nvram: Option<NVRamStatus>) -> (Url, StatusResponse) {
+        (
+            Url::parse(&format!("http://agent{i}")).unwrap(),
+            StatusResponse {
+                id: hsm(i),
+                realm: None,
+                public_key: PublicKey(vec![0; 32]),
+                version: None,
+                nvram,
+            },
+        )
+    }
+
+    #[test]
+    fn check_group_room_counts_shared_members_once() {
+        let nvram = NVRamStatus {
+            used: 100,
+            limit: 4000,
+            // Room for only one group whose 9 members are all new.
+            group_capacity: 1,
+            free: 300,
+            group_cost: 76,
+            hsm_id_cost: 17,
+        };
+        let statuses = [status(1, Some(nvram)), status(2, None)];
+
+        // Three groups with the same members cost 3 * 76 + 3 * 17 = 279.
+        let shared: &[HsmId] = &[hsm(1), hsm(3), hsm(4)];
+        assert!(check_group_room(&statuses, &[shared, shared, shared]).is_ok());
+
+        // Three groups with different members cost 3 * 76 + 7 * 17 = 347.
+        let distinct: [&[HsmId]; 3] = [
+            &[hsm(1), hsm(3), hsm(4)],
+            &[hsm(1), hsm(5), hsm(6)],
+            &[hsm(1), hsm(7), hsm(8)],
+        ];
+        let err = check_group_room(&statuses, &distinct).unwrap_err();
+        assert!(
+            matches!(err, AssimilateError::NotEnoughGroupRoom { hsm: id, groups: 3 } if id == hsm(1)),
+            "{err:?}"
+        );
+
+        // HSMs that don't report their NVRAM usage can join up to 16 groups.
+        let pair: &[HsmId] = &[hsm(2), hsm(3)];
+        assert!(check_group_room(&statuses, &[pair; 16]).is_ok());
+        let err = check_group_room(&statuses, &[pair; 17]).unwrap_err();
+        assert!(
+            matches!(err, AssimilateError::NotEnoughGroupRoom { hsm: id, groups: 17 } if id == hsm(2)),
+            "{err:?}"
+        );
+    }
+}
@@ -158,6 +158,8 @@ pub async fn wait_for_management_grant(
 pub enum ManagementLeaseKey {
     RealmGroup(RealmId, GroupId),
     Ownership(RealmId),
+    MerkleGc(RealmId),
+    Offboarding(RealmId, String),
 }
 
 impl From<ManagementLeaseKey> for LeaseKey {
@@ -165,6 +167,8 @@ impl From<ManagementLeaseKey> for LeaseKey {
         let k = match value {
             ManagementLeaseKey::RealmGroup(r, g) => format!("{r:?}-{g:?}"),
             ManagementLeaseKey::Ownership(r) => format!("{r:?}-ownership"),
+            ManagementLeaseKey::MerkleGc(r) => format!("{r:?}-merkle-gc"),
+            ManagementLeaseKey::Offboarding(r, tenant) => format!("{r:?}-offboarding-{tenant}"),
         };
         LeaseKey(LeaseType::ClusterManagement, k)
     }
@@ -271,6 +275,24 @@ pub fn find_leader(status: &HsmStatuses, realm: RealmId, group: GroupId) -> Opti
     None
 }
 
+/// Returns the group that owns the record and the URL of the agent for its
+/// leader.
+pub fn find_record_owner(
+    status: &HsmStatuses,
+    realm: RealmId,
+    record_id: &RecordId,
+) -> Option<(GroupId, Url)> {
+    status.values().find_map(|(status, url)| {
+        let rs = status.realm.as_ref().filter(|rs| rs.id == realm)?;
+        rs.groups.iter().find_map(|gs| {
+            let owned_range = gs.leader.as_ref()?.owned_range.as_ref()?;
+            owned_range
+                .contains(record_id)
+                .then(|| (gs.id, url.clone()))
+        })
+    })
+}
+
 // Extracts the range owners from the provided StatusRequest results and returns
 // the set of owners that cover `range_to_check`. If `range_to_check` is not
 // fully covered None is returned. This is used to help verify ownership of all
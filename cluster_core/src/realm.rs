@@ -6,7 +6,7 @@ use agent_api::{
     JoinGroupRequest, JoinGroupResponse, JoinRealmRequest, JoinRealmResponse, NewGroupRequest,
     NewGroupResponse, NewRealmRequest, NewRealmResponse, StatusRequest,
 };
-use hsm_api::{GroupId, HsmId, HsmRealmStatement, GROUPS_LIMIT};
+use hsm_api::{GroupId, HsmId, HsmRealmStatement, StatusResponse};
 use jburl::Url;
 use juicebox_networking::reqwest::Client;
 use juicebox_networking::rpc::{self, RpcError};
@@ -174,9 +174,9 @@ pub async fn new_group(
     type Error = NewGroupError;
     info!(?realm, "setting up new group");
 
-    // Ensure all HSMs are up, have joined the realm, and have capacity for
-    // another group. Get their ID and statements to form the configuration.
-    let mut hsms: Vec<(HsmId, HsmRealmStatement)> =
+    // Ensure all HSMs are up and have joined the realm. Get their ID and
+    // statements to form the configuration.
+    let statuses: Vec<(StatusResponse, HsmRealmStatement)> =
         try_join_all(agents.iter().map(|agent| async {
             let status = rpc::send(agents_client, agent, StatusRequest {})
                 .await
@@ -186,7 +186,7 @@ pub async fn new_group(
                     agent: agent.clone(),
                 });
             };
-            let Some(realm_status) = hsm_status.realm else {
+            let Some(realm_status) = &hsm_status.realm else {
                 return Err(Error::InvalidRealm {
                     agent: agent.clone(),
                 });
@@ -196,18 +196,30 @@ pub async fn new_group(
                     agent: agent.clone(),
                 });
             }
-            if realm_status.groups.len() >= usize::from(GROUPS_LIMIT) {
-                return Err(Error::TooManyGroups {
-                    agent: agent.clone(),
-                });
-            }
-            Ok((hsm_status.id, realm_status.statement))
+            let statement = realm_status.statement.clone();
+            Ok((hsm_status, statement))
         }))
         .await?;
 
-    let first: HsmId = hsms[0].0; // located at agent group[0]
+    let first: HsmId = statuses[0].0.id; // located at agent group[0]
+    let mut configuration: Vec<HsmId> = statuses.iter().map(|(status, _)| status.id).collect();
+    configuration.sort_unstable();
+
+    // Ensure all HSMs have room in their NVRAM for the group. That depends on
+    // how many of its members each HSM already shares a group with.
+    for (agent, (status, _)) in agents.iter().zip(&statuses) {
+        if !status.has_room_for_groups(&[configuration.clone()]) {
+            return Err(Error::TooManyGroups {
+                agent: agent.clone(),
+            });
+        }
+    }
+
+    let mut hsms: Vec<(HsmId, HsmRealmStatement)> = statuses
+        .into_iter()
+        .map(|(status, statement)| (status.id, statement))
+        .collect();
     hsms.sort_unstable_by(|(id1, _), (id2, _)| id1.cmp(id2));
-    let configuration: Vec<HsmId> = hsms.iter().map(|(id, _)| *id).collect();
 
     debug!(
         ?hsms,
@@ -1,14 +1,17 @@
 use clap::Parser;
+use std::path::PathBuf;
 use std::{net::SocketAddr, time::Duration};
 use tracing::info;
 
-use google::auth;
+use google::{auth, GrpcConnectionOptions};
 use manager::Manager;
 use observability::{logging, metrics};
+use secret_manager::{new_google_secret_manager, Periodic, SecretManager, SecretsFile};
 use service_core::clap_parsers::{parse_duration, parse_listen};
 use service_core::metrics::start_uptime_reporter;
 use service_core::panic;
 use service_core::term::install_termination_handler;
+use store::merkle_gc::MerkleGcOptions;
 
 mod manager;
 
@@ -35,6 +38,29 @@ struct Args {
     /// Interval for rebalancing the cluster.
     #[arg(long, default_value="60s", value_parser=parse_duration)]
     rebalance_interval: Duration,
+
+    /// Interval for deleting orphaned Merkle tree nodes [default: disabled].
+    #[arg(long, value_parser=parse_duration)]
+    merkle_gc_interval: Option<Duration>,
+
+    /// Orphaned Merkle tree nodes are only deleted once they're at least this
+    /// old.
+    #[arg(long, default_value="1h", value_parser=parse_duration)]
+    merkle_gc_min_age: Duration,
+
+    /// Report orphaned Merkle tree nodes without deleting them.
+    #[arg(long, default_value_t = false)]
+    merkle_gc_dry_run: bool,
+
+    /// Interval for resuming tenant offboardings that were interrupted.
+    #[arg(long, default_value="60s", value_parser=parse_duration)]
+    offboarding_interval: Duration,
+
+    /// Name of JSON file containing the tenant admin keys and the tenant
+    /// record signing key, which are needed to offboard tenants. The default
+    /// is to fetch these from Google Secret Manager.
+    #[arg(long)]
+    secrets_file: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -53,7 +79,7 @@ async fn main() {
     let metrics = metrics::Client::new("cluster_manager", Some(&build));
     start_uptime_reporter(metrics.clone()).await;
 
-    let auth_manager = if args.bigtable.needs_auth() {
+    let auth_manager = if args.bigtable.needs_auth() || args.secrets_file.is_none() {
         Some(
             auth::from_adc()
                 .await
@@ -77,7 +103,7 @@ async fn main() {
     let store = args
         .bigtable
         .connect_data(
-            auth_manager,
+            auth_manager.clone(),
             store::Options {
                 metrics: metrics.clone(),
                 ..store::Options::default()
@@ -86,13 +112,51 @@ async fn main() {
         .await
         .expect("Unable to connect to Bigtable data");
 
+    let secret_manager: Box<dyn SecretManager> = match args.secrets_file {
+        Some(secrets_file) => {
+            info!(path = ?secrets_file, "loading secrets from JSON file");
+            Box::new(
+                Periodic::new(SecretsFile::new(secrets_file), Duration::from_secs(5))
+                    .await
+                    .expect("failed to load secrets from JSON file"),
+            )
+        }
+
+        None => {
+            info!("connecting to Google Cloud Secret Manager");
+            Box::new(
+                new_google_secret_manager(
+                    &args.bigtable.project,
+                    auth_manager.unwrap(),
+                    Duration::from_secs(60),
+                    GrpcConnectionOptions::default(),
+                    metrics.clone(),
+                )
+                .await
+                .expect("failed to load Google SecretManager secrets"),
+            )
+        }
+    };
+
     let manager = Manager::new(
         args.listen.to_string(),
         store,
+        secret_manager,
         args.interval,
         args.rebalance_interval,
         metrics,
     );
+    if let Some(interval) = args.merkle_gc_interval {
+        manager.start_merkle_gc(
+            interval,
+            MerkleGcOptions {
+                min_age: args.merkle_gc_min_age,
+                dry_run: args.merkle_gc_dry_run,
+                ..MerkleGcOptions::default()
+            },
+        );
+    }
+    manager.start_tenant_offboarding(args.offboarding_interval);
     let (url, handle) = manager
         .listen(args.listen)
         .await
@@ -28,12 +28,15 @@ use observability::logging::TracingSource;
 use observability::metrics;
 use observability::tracing::TracingMiddleware;
 use retry_loop::RetryError;
+use secret_manager::SecretManager;
 use service_core::http::ReqwestClientMetrics;
 use service_core::rpc::handle_rpc;
 use store::discovery::{REGISTER_FAILURE_DELAY, REGISTER_INTERVAL};
 use store::{ServiceKind, StoreClient};
 
 mod leader;
+mod merkle_gc;
+mod offboarding;
 mod rebalance;
 mod stepdown;
 mod transfer;
@@ -46,6 +49,8 @@ struct ManagerInner {
     name: String,
     store: StoreClient,
     agents: ReqwestClientMetrics,
+    // Used to check tenants' admin tokens and to sign tenant record grants.
+    secret_manager: Box<dyn SecretManager>,
     // Set when the initial registration in service discovery completes
     // successfully.
     registered: AtomicBool,
@@ -55,6 +60,7 @@ impl Manager {
     pub fn new(
         name: String,
         store: StoreClient,
+        secret_manager: Box<dyn SecretManager>,
         update_interval: Duration,
         rebalance_interval: Duration,
         metrics: metrics::Client,
@@ -65,6 +71,7 @@ impl Manager {
             name,
             store,
             agents,
+            secret_manager,
             registered: AtomicBool::new(false),
         }));
         let manager = m.clone();
@@ -202,6 +209,9 @@ impl Service<Request<IncomingBody>> for Manager {
                     cluster_api::TransferRequest::PATH => {
                         handle_rpc(&manager, request, Self::handle_transfer).await
                     }
+                    cluster_api::OffboardTenantRequest::PATH => {
+                        handle_rpc(&manager, request, Self::handle_offboard_tenant).await
+                    }
                     _ => Ok(Response::builder()
                         .status(http::StatusCode::NOT_FOUND)
                         .body(Full::from(Bytes::new()))
@@ -257,6 +267,8 @@ mod tests {
     use super::*;
     use juicebox_process_group::ProcessGroup;
     use once_cell::sync::Lazy;
+    use secret_manager::{Secret, SecretName, SecretVersion};
+    use std::collections::HashMap;
     use testing::exec::bigtable::{emulator, BigtableRunner};
     use testing::exec::PortIssuer;
 
@@ -286,6 +298,7 @@ mod tests {
         let m1 = Manager::new(
             String::from("one"),
             store.clone(),
+            Box::<HashMap<SecretName, HashMap<SecretVersion, Secret>>>::default(),
             Duration::from_secs(1000),
             Duration::from_secs(1000),
             metrics::Client::NONE,
@@ -293,6 +306,7 @@ mod tests {
         let m2 = Manager::new(
             String::from("two"),
             store,
+            Box::<HashMap<SecretName, HashMap<SecretVersion, Secret>>>::default(),
             Duration::from_secs(1000),
             Duration::from_secs(1000),
             metrics::Client::NONE,
@@ -6,7 +6,7 @@ use super::{ManagementGrant, Manager};
 use agent_api::{BecomeLeaderRequest, BecomeLeaderResponse};
 use cluster_core::workload::{HsmWorkload, WorkAmount};
 use cluster_core::{discover_hsm_statuses, Error, HsmStatuses};
-use hsm_api::{GroupId, HsmId, LogIndex};
+use hsm_api::{GroupId, HsmId, HsmVersion, LogIndex};
 use juicebox_networking::rpc::{self, RpcError};
 use juicebox_realm_api::types::RealmId;
 use service_core::http::ReqwestClientMetrics;
@@ -93,6 +93,25 @@ pub(super) async fn assign_group_a_leader(
     let mut scored: Vec<Score> = hsm_status
         .values()
         .filter(|(status, _url)| skipping != Some(status.id) && group_members.contains(&status.id))
+        .filter(|(status, _url)| {
+            // Members whose status we don't have can't be checked here. They'll
+            // refuse to witness entries they can't verify.
+            let witnesses = group_members
+                .iter()
+                .filter(|id| **id != status.id)
+                .filter_map(|id| hsm_status.get(id))
+                .map(|(witness, _url)| witness.version());
+            let ok = witnesses_can_verify(&status.version(), witnesses);
+            if !ok {
+                warn!(
+                    hsm=?status.id,
+                    ?realm,
+                    ?group,
+                    "not asking HSM to become leader: other group members can't verify its log entries"
+                );
+            }
+            ok
+        })
         .flat_map(|(status, _url)| {
             HsmWorkload::new(status).map(|w| Score {
                 id: w.id,
@@ -138,6 +157,16 @@ pub(super) async fn assign_group_a_leader(
     last_result
 }
 
+/// Returns true if every witness can verify the log entries that a leader
+/// running `candidate` would write. Electing a leader that fails this would
+/// stall the group during a rolling upgrade.
+fn witnesses_can_verify(
+    candidate: &HsmVersion,
+    mut witnesses: impl Iterator<Item = HsmVersion>,
+) -> bool {
+    witnesses.all(|witness| witness.can_witness(candidate))
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 struct Score {
     // total workload on the HSM
@@ -164,8 +193,8 @@ impl PartialOrd for Score {
 
 #[cfg(test)]
 mod tests {
-    use super::{Score, WorkAmount};
-    use hsm_api::{HsmId, LogIndex};
+    use super::{witnesses_can_verify, Score, WorkAmount};
+    use hsm_api::{HsmId, HsmVersion, LogIndex};
 
     #[test]
     fn score_order() {
@@ -198,4 +227,36 @@ mod tests {
         scores.sort();
         assert_eq!(vec![b, c, d, a, e], scores);
     }
+
+    #[test]
+    fn witness_compatibility() {
+        let old = HsmVersion::legacy();
+        let new = HsmVersion {
+            log_entry_format: old.log_entry_format + 1,
+            min_log_entry_format: old.log_entry_format,
+            ..HsmVersion::current("new")
+        };
+
+        // Mid-upgrade, only an old HSM can lead, because the new ones can
+        // verify its entries but not the other way around.
+        let members = [old.clone(), new.clone(), new.clone()];
+        let can_lead: Vec<bool> = (0..members.len())
+            .map(|i| {
+                let witnesses = members
+                    .iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j != i)
+                    .map(|(_, v)| v.clone());
+                witnesses_can_verify(&members[i], witnesses)
+            })
+            .collect();
+        assert_eq!(vec![true, false, false], can_lead);
+
+        // Once all the HSMs are upgraded, any of them can lead.
+        assert!(witnesses_can_verify(
+            &new,
+            [new.clone(), new.clone()].into_iter()
+        ));
+        assert!(witnesses_can_verify(&old, std::iter::empty()));
+    }
 }
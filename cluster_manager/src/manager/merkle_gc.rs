@@ -0,0 +1,70 @@
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{info, instrument, span, warn, Level};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use super::Manager;
+use cluster_core::{discover_hsm_statuses, ManagementGrant, ManagementLeaseKey};
+use juicebox_realm_api::types::RealmId;
+use observability::logging::TracingSource;
+use retry_loop::RetryError;
+use store::merkle_gc::MerkleGcOptions;
+
+impl Manager {
+    /// Starts a background task that periodically deletes orphaned Merkle
+    /// nodes from every discoverable realm.
+    pub fn start_merkle_gc(&self, interval: Duration, options: MerkleGcOptions) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let cx = opentelemetry::Context::new().with_value(TracingSource::BackgroundJob);
+            loop {
+                sleep(interval).await;
+
+                let span = span!(Level::TRACE, "merkle_gc_loop");
+                span.set_parent(cx.clone());
+
+                if let Err(err) = manager.collect_merkle_garbage(&options).await {
+                    warn!(?err, "Error while collecting orphaned Merkle nodes");
+                }
+            }
+        });
+    }
+
+    /// Runs the Merkle garbage collector once for each realm that has a
+    /// discoverable HSM. Realms that another cluster manager is already
+    /// collecting are skipped.
+    #[instrument(level = "trace", skip(self))]
+    async fn collect_merkle_garbage(
+        &self,
+        options: &MerkleGcOptions,
+    ) -> Result<(), RetryError<tonic::Status>> {
+        let hsm_status = discover_hsm_statuses(&self.0.store, &self.0.agents).await?;
+        let realms: HashSet<RealmId> = hsm_status
+            .values()
+            .filter_map(|(status, _url)| status.realm.as_ref().map(|realm| realm.id))
+            .collect();
+
+        for realm in realms {
+            let Some(_grant) = ManagementGrant::obtain(
+                self.0.store.clone(),
+                self.0.name.clone(),
+                ManagementLeaseKey::MerkleGc(realm),
+            )
+            .await?
+            else {
+                info!(
+                    ?realm,
+                    "Skipping Merkle GC for realm being collected elsewhere"
+                );
+                continue;
+            };
+
+            match self.0.store.collect_merkle_garbage(&realm, options).await {
+                Ok(report) => info!(?realm, ?report, "collected orphaned Merkle nodes"),
+                Err(err) => warn!(?realm, ?err, "Merkle garbage collection failed"),
+            }
+        }
+        Ok(())
+    }
+}
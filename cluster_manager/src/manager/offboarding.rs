@@ -0,0 +1,514 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::time::sleep;
+use tracing::{info, instrument, span, warn, Instrument, Level};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use super::Manager;
+use agent_api::{TenantRecordRequest, TenantRecordResponse};
+use cluster_api::{OffboardTenantError, OffboardTenantRequest, OffboardTenantStatus};
+use cluster_core::{
+    discover_hsm_statuses, find_record_owner, HsmStatuses, ManagementGrant, ManagementLeaseKey,
+};
+use hsm_api::{RecordId, RegistrationStatus, TenantRecordGrant, TenantRecordOperation};
+use juicebox_networking::rpc::{self, RpcError};
+use juicebox_realm_api::types::{AuthToken, RealmId};
+use observability::logging::TracingSource;
+use retry_loop::RetryError;
+use secret_manager::{
+    get_tenant_record_signing_key, tenant_record_signing_key_name, validate_tenant_admin_token,
+    TenantAdminTokenError,
+};
+use service_core::rpc::HandlerError;
+use store::offboarding::TenantOffboarding;
+
+/// The number of record IDs to read from the user accounting table at a time.
+/// Progress is saved after each batch.
+const BATCH_SIZE: usize = 100;
+
+/// The number of times to try deleting a record before giving up on the
+/// offboarding for now. Retries are expected if leadership or ownership of the
+/// record changes during the offboarding.
+const MAX_ATTEMPTS_PER_RECORD: u32 = 5;
+
+/// How long the grant signed for each deletion is valid for. Agents reject
+/// expired grants.
+const GRANT_LIFETIME: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error)]
+enum OffboardingError {
+    #[error("store error: {0}")]
+    Store(#[from] RetryError<tonic::Status>),
+    #[error("failed to get the tenant record signing key: {0}")]
+    SigningKey(anyhow::Error),
+    #[error("no group leader owns record {0:?}")]
+    NoOwner(RecordId),
+    #[error("RPC error to agent: {0}")]
+    Rpc(#[from] RpcError),
+    #[error("agent failed to delete record {record_id:?}: {response:?}")]
+    Agent {
+        record_id: RecordId,
+        response: TenantRecordResponse,
+    },
+}
+
+impl Manager {
+    pub(super) async fn handle_offboard_tenant(
+        &self,
+        req: OffboardTenantRequest,
+    ) -> Result<Result<OffboardTenantStatus, OffboardTenantError>, HandlerError> {
+        // Tenant names are part of the user accounting table's row keys,
+        // which use ':' as a separator.
+        if req.tenant.is_empty() || req.tenant.contains(':') {
+            return Ok(Err(OffboardTenantError::InvalidTenant));
+        }
+
+        let token = AuthToken::from(req.token);
+        let requested_by =
+            match validate_tenant_admin_token(&*self.0.secret_manager, req.realm, &token).await {
+                Ok(claims) if claims.issuer == req.tenant => claims.subject,
+                Ok(_) | Err(TenantAdminTokenError::Invalid) => {
+                    warn!(realm = ?req.realm, tenant = %req.tenant, "invalid offboarding token");
+                    return Ok(Err(OffboardTenantError::InvalidAuth));
+                }
+                Err(err @ TenantAdminTokenError::SecretManager(_)) => {
+                    warn!(?err, "failed to validate offboarding token");
+                    return Ok(Err(OffboardTenantError::NoSecretManager));
+                }
+            };
+
+        let offboarding = match self
+            .0
+            .store
+            .get_tenant_offboarding(&req.realm, &req.tenant)
+            .await
+        {
+            Ok(Some(offboarding)) => offboarding,
+            Ok(None) => {
+                info!(
+                    realm = ?req.realm,
+                    tenant = %req.tenant,
+                    %requested_by,
+                    "starting tenant offboarding"
+                );
+                let offboarding = TenantOffboarding::new(req.realm, req.tenant, requested_by);
+                if let Err(err) = self.0.store.put_tenant_offboarding(&offboarding).await {
+                    warn!(?err, "failed to save new tenant offboarding");
+                    return Ok(Err(OffboardTenantError::NoStore));
+                }
+                offboarding
+            }
+            Err(err) => {
+                warn!(?err, "failed to read tenant offboarding");
+                return Ok(Err(OffboardTenantError::NoStore));
+            }
+        };
+
+        if offboarding.finished.is_none() {
+            // This is a no-op if the offboarding is already running.
+            let manager = self.clone();
+            let realm = offboarding.realm;
+            let tenant = offboarding.tenant.clone();
+            tokio::spawn(
+                async move {
+                    if let Err(err) = manager.offboard_tenant(realm, tenant).await {
+                        warn!(?err, "Error while offboarding tenant");
+                    }
+                }
+                .instrument(span!(Level::TRACE, "offboard_tenant")),
+            );
+        }
+        Ok(Ok(offboarding_status(offboarding)))
+    }
+
+    /// Starts a background task that periodically resumes any tenant
+    /// offboardings that haven't finished. This picks up offboardings that
+    /// were interrupted by errors or by a cluster manager restarting.
+    pub fn start_tenant_offboarding(&self, interval: Duration) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let cx = opentelemetry::Context::new().with_value(TracingSource::BackgroundJob);
+            loop {
+                sleep(interval).await;
+
+                let span = span!(Level::TRACE, "tenant_offboarding_loop");
+                span.set_parent(cx.clone());
+
+                if let Err(err) = manager.resume_tenant_offboardings().await {
+                    warn!(?err, "Error while resuming tenant offboardings");
+                }
+            }
+        });
+    }
+
+    async fn resume_tenant_offboardings(&self) -> Result<(), RetryError<tonic::Status>> {
+        for offboarding in self.0.store.list_tenant_offboardings().await? {
+            if offboarding.finished.is_none() {
+                self.offboard_tenant(offboarding.realm, offboarding.tenant)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the tenant's records from the realm, continuing from the last
+    /// checkpoint. Does nothing if another task is already offboarding the
+    /// tenant.
+    #[instrument(level = "trace", skip(self))]
+    async fn offboard_tenant(
+        &self,
+        realm: RealmId,
+        tenant: String,
+    ) -> Result<(), RetryError<tonic::Status>> {
+        let Some(_grant) = ManagementGrant::obtain(
+            self.0.store.clone(),
+            self.0.name.clone(),
+            ManagementLeaseKey::Offboarding(realm, tenant.clone()),
+        )
+        .await?
+        else {
+            info!(?realm, %tenant, "Skipping tenant being offboarded elsewhere");
+            return Ok(());
+        };
+
+        // This is read again after obtaining the lease because another task
+        // may have made progress since the caller read it.
+        let Some(mut offboarding) = self.0.store.get_tenant_offboarding(&realm, &tenant).await?
+        else {
+            return Ok(());
+        };
+        if offboarding.finished.is_some() {
+            return Ok(());
+        }
+
+        info!(?realm, %tenant, checkpoint = ?offboarding.checkpoint, "offboarding tenant");
+        match self.offboard_tenant_records(&mut offboarding).await {
+            Ok(()) => {
+                offboarding.finished = Some(SystemTime::now());
+                offboarding.last_error = None;
+                info!(
+                    ?realm,
+                    %tenant,
+                    deleted = offboarding.deleted,
+                    not_registered = offboarding.not_registered,
+                    "finished offboarding tenant"
+                );
+            }
+            Err(err) => {
+                warn!(
+                    ?realm,
+                    %tenant,
+                    ?err,
+                    checkpoint = ?offboarding.checkpoint,
+                    "tenant offboarding interrupted"
+                );
+                offboarding.last_error = Some(err.to_string());
+            }
+        }
+        self.0.store.put_tenant_offboarding(&offboarding).await
+    }
+
+    async fn offboard_tenant_records(
+        &self,
+        offboarding: &mut TenantOffboarding,
+    ) -> Result<(), OffboardingError> {
+        let realm = offboarding.realm;
+        // This is read for each run so that a new version of the key is
+        // picked up.
+        let signing_key = get_tenant_record_signing_key(&*self.0.secret_manager)
+            .await
+            .and_then(|key| {
+                key.ok_or_else(|| {
+                    anyhow::anyhow!("missing secret: {}", tenant_record_signing_key_name().0)
+                })
+            })
+            .map_err(OffboardingError::SigningKey)?;
+        let mut statuses = discover_hsm_statuses(&self.0.store, &self.0.agents).await?;
+        loop {
+            let record_ids = self
+                .0
+                .store
+                .read_tenant_record_ids(
+                    &realm,
+                    &offboarding.tenant,
+                    offboarding.checkpoint.as_ref(),
+                    BATCH_SIZE,
+                )
+                .await?;
+            if record_ids.is_empty() {
+                return Ok(());
+            }
+            for record_id in record_ids {
+                if self
+                    .delete_tenant_record(&mut statuses, offboarding, &signing_key, &record_id)
+                    .await?
+                {
+                    offboarding.deleted += 1;
+                } else {
+                    offboarding.not_registered += 1;
+                }
+                offboarding.checkpoint = Some(record_id);
+            }
+            self.0.store.put_tenant_offboarding(offboarding).await?;
+        }
+    }
+
+    /// Deletes the record through the leader of the group that owns it.
+    /// Returns true if the record had a secret that was deleted.
+    async fn delete_tenant_record(
+        &self,
+        statuses: &mut HsmStatuses,
+        offboarding: &TenantOffboarding,
+        signing_key: &[u8; 32],
+        record_id: &RecordId,
+    ) -> Result<bool, OffboardingError> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let error = match find_record_owner(statuses, offboarding.realm, record_id) {
+                None => OffboardingError::NoOwner(record_id.clone()),
+                Some((group, agent)) => {
+                    let send = |operation| {
+                        rpc::send(
+                            &self.0.agents,
+                            &agent,
+                            TenantRecordRequest {
+                                realm: offboarding.realm,
+                                group,
+                                grant: TenantRecordGrant::sign(
+                                    &offboarding.realm,
+                                    offboarding.tenant.clone(),
+                                    offboarding.requested_by.clone(),
+                                    record_id.clone(),
+                                    operation,
+                                    grant_expiry(),
+                                    signing_key,
+                                ),
+                            },
+                        )
+                    };
+                    // Delete grants are bound to the record's current
+                    // fingerprint. If the record changes in between, the
+                    // agent returns `RecordChanged` and this is retried.
+                    let result = match send(TenantRecordOperation::GetStatus).await {
+                        Ok(TenantRecordResponse::Ok { fingerprint, .. }) => {
+                            send(TenantRecordOperation::Delete { fingerprint }).await
+                        }
+                        result => result,
+                    };
+                    match result {
+                        Ok(TenantRecordResponse::Ok { status, .. }) => {
+                            return Ok(status != RegistrationStatus::NotRegistered)
+                        }
+                        Ok(
+                            response @ (TenantRecordResponse::InvalidRealm
+                            | TenantRecordResponse::InvalidProof
+                            | TenantRecordResponse::Unauthorized),
+                        ) => {
+                            return Err(OffboardingError::Agent {
+                                record_id: record_id.clone(),
+                                response,
+                            })
+                        }
+                        Ok(response) => OffboardingError::Agent {
+                            record_id: record_id.clone(),
+                            response,
+                        },
+                        Err(err) => OffboardingError::Rpc(err),
+                    }
+                }
+            };
+            if attempt >= MAX_ATTEMPTS_PER_RECORD {
+                return Err(error);
+            }
+            // The leader or owner of the record may have changed.
+            warn!(?error, attempt, "failed to delete record, will retry");
+            sleep(Duration::from_millis(100) * attempt).await;
+            *statuses = discover_hsm_statuses(&self.0.store, &self.0.agents).await?;
+        }
+    }
+}
+
+/// Returns the expiry time for a grant signed now, in seconds since the Unix
+/// epoch.
+fn grant_expiry() -> u64 {
+    (SystemTime::now() + GRANT_LIFETIME)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn offboarding_status(offboarding: TenantOffboarding) -> OffboardTenantStatus {
+    OffboardTenantStatus {
+        requested_by: offboarding.requested_by,
+        started: offboarding.started,
+        finished: offboarding.finished,
+        checkpoint: offboarding.checkpoint,
+        deleted: offboarding.deleted,
+        not_registered: offboarding.not_registered,
+        last_error: offboarding.last_error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use juicebox_process_group::ProcessGroup;
+    use juicebox_realm_auth::creation::create_token;
+    use juicebox_realm_auth::{AuthKeyVersion, Claims, Scope};
+    use observability::metrics;
+    use once_cell::sync::Lazy;
+    use secret_manager::{
+        tenant_admin_secret_name, Secret, SecretAlgorithm, SecretName, SecretVersion,
+    };
+    use std::collections::HashMap;
+    use testing::exec::bigtable::{emulator, BigtableRunner};
+    use testing::exec::PortIssuer;
+
+    static PORT: Lazy<PortIssuer> = Lazy::new(|| PortIssuer::new(8232));
+
+    const REALM: RealmId = RealmId([7; 16]);
+
+    fn secrets() -> HashMap<SecretName, HashMap<SecretVersion, Secret>> {
+        // An Ed25519 private key in PKCS #8 DER.
+        let mut signing_key = vec![
+            0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22,
+            0x04, 0x20,
+        ];
+        signing_key.extend([1; 32]);
+        HashMap::from([
+            (
+                tenant_admin_secret_name("acme"),
+                HashMap::from([(
+                    SecretVersion(1),
+                    Secret {
+                        data: b"acme admin key".to_vec().into(),
+                        algorithm: SecretAlgorithm::HmacSha256,
+                    },
+                )]),
+            ),
+            (
+                tenant_record_signing_key_name(),
+                HashMap::from([(
+                    SecretVersion(1),
+                    Secret {
+                        data: signing_key.into(),
+                        algorithm: SecretAlgorithm::Edwards25519,
+                    },
+                )]),
+            ),
+        ])
+    }
+
+    fn token(tenant: &str, key: &[u8]) -> String {
+        create_token(
+            &Claims {
+                issuer: tenant.to_owned(),
+                subject: String::from("ops@acme"),
+                audience: REALM,
+                scope: Some(Scope::User),
+            },
+            &Secret {
+                data: key.to_vec().into(),
+                algorithm: SecretAlgorithm::HmacSha256,
+            }
+            .try_into()
+            .unwrap(),
+            AuthKeyVersion(1),
+        )
+        .expose_secret()
+        .to_owned()
+    }
+
+    async fn offboard(
+        manager: &Manager,
+        tenant: &str,
+        token: String,
+    ) -> Result<OffboardTenantStatus, OffboardTenantError> {
+        manager
+            .handle_offboard_tenant(OffboardTenantRequest {
+                realm: REALM,
+                tenant: tenant.to_owned(),
+                token,
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn offboard_tenant() {
+        let mut pg = ProcessGroup::new();
+        let bt_args = emulator(PORT.next());
+        BigtableRunner::run(&mut pg, &bt_args).await;
+        bt_args
+            .connect_admin(None, metrics::Client::NONE)
+            .await
+            .expect("failed to connect to bigtable admin service")
+            .initialize_shared_tables()
+            .await
+            .expect("failed to initialize tables");
+        let store = bt_args
+            .connect_data(None, store::Options::default())
+            .await
+            .expect("failed to connect to bigtable data service");
+
+        let manager = Manager::new(
+            String::from("one"),
+            store.clone(),
+            Box::new(secrets()),
+            Duration::from_secs(1000),
+            Duration::from_secs(1000),
+            metrics::Client::NONE,
+        );
+
+        assert!(matches!(
+            offboard(&manager, "ac:me", token("ac:me", b"acme admin key")).await,
+            Err(OffboardTenantError::InvalidTenant)
+        ));
+        for (tenant, bad) in [
+            // Signed with the wrong key.
+            ("acme", token("acme", b"acme user key")),
+            // Valid, but for a different tenant than the one being offboarded.
+            ("bigcorp", token("acme", b"acme admin key")),
+            ("acme", String::from("not a token")),
+        ] {
+            assert!(matches!(
+                offboard(&manager, tenant, bad).await,
+                Err(OffboardTenantError::InvalidAuth)
+            ));
+        }
+        assert_eq!(
+            store.get_tenant_offboarding(&REALM, "acme").await.unwrap(),
+            None
+        );
+        assert_eq!(
+            store
+                .get_tenant_offboarding(&REALM, "bigcorp")
+                .await
+                .unwrap(),
+            None
+        );
+
+        // The requester comes from the token, not the request.
+        let status = offboard(&manager, "acme", token("acme", b"acme admin key"))
+            .await
+            .unwrap();
+        assert_eq!(status.requested_by, "ops@acme");
+
+        // There are no records, so the background task finishes right away.
+        let mut offboarding = None;
+        for _ in 0..100 {
+            offboarding = store.get_tenant_offboarding(&REALM, "acme").await.unwrap();
+            if offboarding.as_ref().is_some_and(|o| o.finished.is_some()) {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        let offboarding = offboarding.unwrap();
+        assert!(offboarding.finished.is_some(), "{offboarding:?}");
+        assert_eq!(offboarding.requested_by, "ops@acme");
+        assert_eq!(offboarding.deleted, 0);
+        assert_eq!(offboarding.not_registered, 0);
+        assert_eq!(offboarding.last_error, None);
+    }
+}
@@ -28,6 +28,7 @@ use entrust_nfast::{
     StatNodeTag_PerModule, Status_ObjectInUse, Status_SEEWorldFailed,
     TicketDestination_AnySEEWorld,
 };
+use hsm_api::TenantRecordKey;
 use juicebox_marshalling::{self as marshalling, DeserializationError, SerializationError};
 use observability::{metrics, metrics_tag as tag};
 use retry_loop::AttemptError;
@@ -96,7 +97,11 @@ impl HsmTransportConstructor<EntrustArgs, EntrustSeeTransport> for EntrustConstr
         metrics: &metrics::Client,
     ) -> (EntrustSeeTransport, Option<FutureTask<()>>) {
         (
-            EntrustSeeTransport::new(args.service.clone(), metrics.clone()),
+            EntrustSeeTransport::new(
+                args.service.clone(),
+                args.tenant_record_keys.clone(),
+                metrics.clone(),
+            ),
             None,
         )
     }
@@ -106,7 +111,11 @@ impl HsmTransportConstructor<EntrustArgs, EntrustSeeTransport> for EntrustConstr
 struct EntrustSeeTransport(async_channel::Sender<WorkerRequest>);
 
 impl EntrustSeeTransport {
-    fn new(args: EntrustArgs, metrics: metrics::Client) -> Self {
+    fn new(
+        args: EntrustArgs,
+        tenant_record_keys: Vec<TenantRecordKey>,
+        metrics: metrics::Client,
+    ) -> Self {
         let (sender, receiver) = async_channel::bounded(128);
 
         let metrics_clone = metrics.clone();
@@ -116,6 +125,7 @@ impl EntrustSeeTransport {
         let num_threads = args.transport_threads;
         let start = SEEWorldStarter {
             args,
+            tenant_record_keys,
             conn,
             metrics,
         };
@@ -214,6 +224,7 @@ impl WorkerRequest {
 #[derive(Debug)]
 struct SEEWorldStarter {
     args: EntrustArgs,
+    tenant_record_keys: Vec<TenantRecordKey>,
     conn: NFastConn,
     metrics: metrics::Client,
 }
@@ -366,7 +377,7 @@ impl SEEWorldConn {
         }
     }
 
-    fn start_hsmcore(&self, nvram: NvRamState) {
+    fn start_hsmcore(&self, nvram: NvRamState, tenant_record_keys: Vec<TenantRecordKey>) {
         // Collect up all the key tickets we need.
         let comm_private_key = self.ticket_for_key("simple", "jbox-noise", KeyHalf::Private);
         let comm_public_key = self.ticket_for_key("simple", "jbox-noise", KeyHalf::Public);
@@ -379,14 +390,20 @@ impl SEEWorldConn {
             // This is large enough that a malicious client can't churn the
             // entire cache faster than a different client can get their
             // register/recover completed. It takes ~2ms for the HSM to complete
-            // a Noise handshake, no session can be evicted within 8192 * 2 /
-            // 1000 = 16.384 seconds of its creation.
+            // a Noise handshake, so filling the cache with new sessions takes
+            // at least 8192 * 2ms = ~16 seconds.
             max_sessions: 8192,
+            // Sessions are partitioned by tenant. Once the cache is full, a
+            // tenant with a quarter of it or more only evicts its own
+            // sessions, and other tenants evict from the largest partitions
+            // first. So one busy tenant can't churn everyone else's sessions.
+            max_sessions_per_partition: 2048,
             comm_private_key,
             comm_public_key,
             mac_key,
             record_key,
             nvram,
+            tenant_record_keys,
         };
         let start_msg = marshalling::to_vec(&start).expect("Failed to serialize StartRequest");
         let resp_bytes = self
@@ -498,7 +515,7 @@ impl SEEWorldStarter {
             metrics: self.metrics,
             module: self.args.module,
         };
-        conn.start_hsmcore(self.args.nvram_state());
+        conn.start_hsmcore(self.args.nvram_state(), self.tenant_record_keys);
         Ok(conn)
     }
 
@@ -8,6 +8,7 @@ extern crate alloc;
 use alloc::format;
 use alloc::string::String;
 use alloc::vec::Vec;
+use hsm_api::TenantRecordKey;
 use juicebox_marshalling::bytes;
 use serde::{Deserialize, Serialize};
 
@@ -25,11 +26,14 @@ pub enum NvRamState {
 pub struct StartRequest {
     pub tree_overlay_size: u16,
     pub max_sessions: u16,
+    pub max_sessions_per_partition: u16,
     pub comm_private_key: Ticket,
     pub comm_public_key: Ticket,
     pub mac_key: Ticket,
     pub record_key: Ticket,
     pub nvram: NvRamState,
+    /// The HSM accepts tenant record grants signed by any of these keys.
+    pub tenant_record_keys: Vec<TenantRecordKey>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
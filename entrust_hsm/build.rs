@@ -3,6 +3,7 @@ use std::env;
 use std::fs::File;
 use std::io::{copy, Write};
 use std::path::PathBuf;
+use std::process::Command;
 
 fn main() {
     // Tell cargo to invalidate the built crate whenever the wrapper changes
@@ -52,6 +53,23 @@ fn main() {
         "07fe0cfa4e37f3f77cfcc7c579918c51d4e220723ad84f90e4921556b566fb8b",
         "SHA-256 of {out_file:?} (left) doesn't match expected (right)"
     );
+
+    // The HSM reports the commit it was built from as its software version.
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        println!("cargo:rerun-if-changed={git_dir}/HEAD");
+        println!("cargo:rerun-if-changed={git_dir}/refs");
+        if let Some(git_hash) = git(&["rev-parse", "HEAD"]) {
+            println!("cargo:rustc-env=BUILD_GIT_HASH={git_hash}");
+        }
+    }
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_owned())
 }
 
 struct WritableHash(Sha256);
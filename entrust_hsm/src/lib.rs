@@ -154,7 +154,10 @@ fn start_hsm(req: StartRequest) -> Result<Hsm<NCipher>, StartResponse> {
             name: String::from("entrust"),
             tree_overlay_size: req.tree_overlay_size,
             max_sessions: req.max_sessions,
+            max_sessions_per_partition: req.max_sessions_per_partition,
             metrics,
+            tenant_record_keys: req.tenant_record_keys,
+            software_version: String::from(option_env!("BUILD_GIT_HASH").unwrap_or("unknown")),
         },
         platform,
         keys,
@@ -5,13 +5,16 @@ pub mod rpc;
 
 extern crate alloc;
 
+use alloc::collections::BTreeSet;
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
-use blake2::Blake2sMac256;
+use blake2::{Blake2s256, Blake2sMac256};
 use core::cmp::{max, min};
 use core::fmt::{self, Display};
 use core::ops::Deref;
 use core::time::Duration;
-use digest::CtOutput;
+use digest::{CtOutput, Digest};
+use ed25519_dalek::Signer;
 use serde::{Deserialize, Serialize};
 use subtle::{Choice, ConstantTimeEq};
 
@@ -26,6 +29,7 @@ use merkle::{HashOutput, ReadProof, StoreDelta};
 ///
 /// Group IDs are generated randomly by the first HSM to create the group.
 #[derive(Copy, Clone, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct GroupId(#[serde(with = "bytes")] pub [u8; 16]);
 
 impl fmt::Debug for GroupId {
@@ -48,6 +52,7 @@ impl fmt::Display for GroupId {
 /// initialized. The HSM persists its ID along with its other non-volatile
 /// state.
 #[derive(Copy, Clone, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct HsmId(#[serde(with = "bytes")] pub [u8; 16]);
 
 impl fmt::Debug for HsmId {
@@ -84,6 +89,7 @@ impl fmt::Debug for PublicKey {
 /// manage; see [`OwnedRange`]. The record IDs are also used as the lookup keys
 /// into the Merkle trees.
 #[derive(Clone, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct RecordId(#[serde(with = "bytes")] pub [u8; Self::NUM_BYTES]);
 
 impl RecordId {
@@ -186,6 +192,7 @@ impl fmt::Display for RecordId {
 
 /// A sequential number for an entry in a log (see [`LogEntry`]).
 #[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct LogIndex(pub u64);
 
 impl LogIndex {
@@ -222,6 +229,7 @@ impl fmt::Display for LogIndex {
 ///
 /// It also identifies a snapshot of a Merkle tree.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct Partition {
     pub range: OwnedRange,
     pub root_hash: DataHash,
@@ -235,6 +243,7 @@ pub struct Partition {
 /// log entries, but they do not directly persist log entries; the agents do
 /// this externally on commodity hardware.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct LogEntry {
     /// Each log contains entries numbered sequentially.
     ///
@@ -264,6 +273,7 @@ pub struct LogEntry {
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum Transferring {
     /// If this group is currently expecting a future transfer of ownership of
     /// records into this group, this field includes some metadata about that.
@@ -277,6 +287,7 @@ pub enum Transferring {
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct TransferringOut {
     pub destination: GroupId,
     pub partition: Partition,
@@ -287,6 +298,7 @@ pub struct TransferringOut {
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct TransferringIn {
     pub source: GroupId,
     pub range: OwnedRange,
@@ -299,6 +311,7 @@ pub struct TransferringIn {
 
 /// A Fixed size array of bytes that are compared in constant time.
 #[derive(Clone, Deserialize, Eq, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct CtBytes<const N: usize>(#[serde(with = "bytes")] [u8; N]);
 
 impl<const N: usize> CtBytes<N> {
@@ -357,6 +370,7 @@ impl<const N: usize> fmt::Debug for CtBytes<N> {
 ///
 /// See [super::mac::EntryMacMessage].
 #[derive(Clone, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct EntryMac(CtBytes<32>);
 
 impl EntryMac {
@@ -397,6 +411,7 @@ impl From<[u8; 32]> for EntryMac {
 ///
 /// See also [`Partition`].
 #[derive(Clone, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct OwnedRange {
     /// Inclusive.
     pub start: RecordId,
@@ -504,6 +519,7 @@ impl OwnedRange {
 ///
 /// The hash of the root node serves as the hash of the tree.
 #[derive(Clone, Copy, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct DataHash(#[serde(with = "bytes")] pub [u8; 32]);
 
 impl fmt::Debug for DataHash {
@@ -541,13 +557,16 @@ pub const CONFIGURATION_LIMIT: u8 = 9;
 
 /// The maximum number of replication groups permitted per HSM.
 ///
-/// This is restricted to avoid filling up HSM NVRAM.
-pub const GROUPS_LIMIT: u8 = 16;
+/// This is restricted to avoid filling up HSM NVRAM. Depending on how many
+/// distinct HSMs the groups have between them, NVRAM may fill up before an HSM
+/// reaches this limit. See [`NVRamStatus::group_capacity`].
+pub const GROUPS_LIMIT: u8 = 32;
 
 /// A MAC over a realm ID, group ID, and group configuration.
 ///
 /// See [super::mac::GroupConfigurationStatementMessage].
 #[derive(Clone, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct GroupConfigurationStatement(CtBytes<32>);
 
 impl Deref for GroupConfigurationStatement {
@@ -574,6 +593,7 @@ impl From<CtBytes<32>> for GroupConfigurationStatement {
 ///
 /// See [super::mac::HsmRealmStatementMessage].
 #[derive(Clone, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct HsmRealmStatement(CtBytes<32>);
 
 impl Deref for HsmRealmStatement {
@@ -614,6 +634,7 @@ impl From<[u8; 32]> for HsmRealmStatement {
 ///
 /// See [super::mac::CapturedStatementMessage].
 #[derive(Clone, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct CapturedStatement(CtBytes<32>);
 
 impl Deref for CapturedStatement {
@@ -642,6 +663,7 @@ impl From<CtBytes<32>> for CapturedStatement {
 /// [`TransferStatement`], which is generated by the source group, is not
 /// stale.
 #[derive(Copy, Clone, Deserialize, Eq, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct TransferNonce(#[serde(with = "bytes")] pub [u8; 16]);
 
 impl fmt::Debug for TransferNonce {
@@ -660,6 +682,7 @@ impl PartialEq for TransferNonce {
 }
 
 #[derive(Clone, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct PreparedTransferStatement(CtBytes<32>);
 
 impl Deref for PreparedTransferStatement {
@@ -693,6 +716,7 @@ impl From<CtBytes<32>> for PreparedTransferStatement {
 ///
 /// See [super::mac::TransferStatementMessage].
 #[derive(Clone, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct TransferStatement(CtBytes<32>);
 
 impl Deref for TransferStatement {
@@ -720,6 +744,7 @@ impl From<CtBytes<32>> for TransferStatement {
 /// return the resulting role and clock. The Agent can use the role + clock
 /// values to ensure its own state relating to the group role is in sync.
 #[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct RoleLogicalClock(pub u64);
 
 impl RoleLogicalClock {
@@ -757,6 +782,184 @@ pub struct StatusResponse {
     /// The public key used by clients for encrypted communication (over Noise)
     /// to the HSM.
     pub public_key: PublicKey,
+    /// The HSM's software and protocol versions. HSMs that predate versioning
+    /// leave this out; see [`HsmVersion::legacy`].
+    #[serde(default)]
+    pub version: Option<HsmVersion>,
+    /// How much of its NVRAM the HSM is using. HSMs that predate the compact
+    /// NVRAM encoding leave this out.
+    #[serde(default)]
+    pub nvram: Option<NVRamStatus>,
+}
+
+impl StatusResponse {
+    /// Returns the HSM's versions, treating an HSM that didn't report them as
+    /// [`HsmVersion::legacy`].
+    pub fn version(&self) -> HsmVersion {
+        self.version.clone().unwrap_or_else(HsmVersion::legacy)
+    }
+
+    /// Returns how many more groups the HSM can join, assuming that their
+    /// members are all new to it. See [`StatusResponse::has_room_for_groups`]
+    /// for an exact answer.
+    pub fn group_capacity(&self) -> usize {
+        match &self.nvram {
+            Some(nvram) => usize::from(nvram.group_capacity),
+            None => LEGACY_GROUPS_LIMIT.saturating_sub(self.group_count()),
+        }
+    }
+
+    /// Returns true if the HSM has room to join new groups with the given
+    /// members, on top of the groups it's already in.
+    ///
+    /// The HSM stores each distinct member ID once, so a group whose members
+    /// the HSM already shares a group with takes less room than one with new
+    /// members.
+    pub fn has_room_for_groups(&self, groups: &[Vec<HsmId>]) -> bool {
+        let count = self.group_count() + groups.len();
+        let Some(nvram) = &self.nvram else {
+            return count <= LEGACY_GROUPS_LIMIT;
+        };
+        if count > usize::from(GROUPS_LIMIT) {
+            return false;
+        }
+        let mut known: BTreeSet<HsmId> = self
+            .realm
+            .iter()
+            .flat_map(|realm| &realm.groups)
+            .flat_map(|group| &group.configuration)
+            .copied()
+            .collect();
+        let mut need = 0u64;
+        for members in groups {
+            need += u64::from(nvram.group_cost);
+            for hsm in members {
+                if known.insert(*hsm) {
+                    need += u64::from(nvram.hsm_id_cost);
+                }
+            }
+        }
+        need <= u64::from(nvram.free)
+    }
+
+    fn group_count(&self) -> usize {
+        self.realm.as_ref().map_or(0, |realm| realm.groups.len())
+    }
+}
+
+/// HSMs that don't report their NVRAM usage were limited to this many
+/// groups, which always fit.
+const LEGACY_GROUPS_LIMIT: usize = 16;
+
+/// Part of [`StatusResponse`]. Describes how much of its NVRAM an HSM is
+/// using, which limits how many groups it can join.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NVRamStatus {
+    /// The number of bytes the HSM's persistent state takes up now.
+    pub used: u32,
+    /// The number of bytes of NVRAM available for the HSM's persistent state.
+    pub limit: u32,
+    /// How many more groups the HSM can join. This assumes that the new
+    /// groups' members are all new to the HSM, so more may fit if they're not.
+    /// It's never more than [`GROUPS_LIMIT`] minus the groups the HSM is
+    /// already in.
+    pub group_capacity: u8,
+    /// The number of bytes left for new groups, once every existing group has
+    /// captured an entry.
+    pub free: u32,
+    /// The most bytes one more group takes, not counting its members' IDs.
+    pub group_cost: u32,
+    /// The bytes each member ID that's new to the HSM takes. The HSM stores
+    /// each distinct ID once, however many of its groups have that member.
+    pub hsm_id_cost: u32,
+}
+
+/// The version of the RPC protocol between agents and HSMs that this crate
+/// defines.
+///
+/// Bump this when a change to the requests or responses matters to the other
+/// side, like adding a request or a field that it must not ignore. HSMs that
+/// predate versioning speak version 0.
+pub const HSM_PROTOCOL_VERSION: u16 = 1;
+
+/// The oldest agent protocol version that HSMs built from this crate work
+/// with. Raise this to stop supporting older agents.
+pub const MIN_AGENT_PROTOCOL_VERSION: u16 = 0;
+
+/// The format of the log entries (see [`LogEntry`]) that leaders built from
+/// this crate write. Bump this when witnesses built from older code couldn't
+/// verify the entries.
+pub const LOG_ENTRY_FORMAT: u16 = 1;
+
+/// The oldest log entry format that HSMs built from this crate can verify.
+pub const MIN_LOG_ENTRY_FORMAT: u16 = 1;
+
+/// Part of [`StatusResponse`]. Describes what software an HSM is running and
+/// what it can do, so that mixed versions can be detected during a rolling
+/// upgrade.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct HsmVersion {
+    /// The git commit hash the HSM software was built from, for display.
+    pub software: String,
+    /// The agent/HSM protocol version that the HSM speaks.
+    pub protocol: u16,
+    /// The oldest agent protocol version that the HSM works with.
+    pub min_agent_protocol: u16,
+    /// The log entry format the HSM writes as a leader.
+    pub log_entry_format: u16,
+    /// The oldest log entry format the HSM can verify as a witness.
+    pub min_log_entry_format: u16,
+    /// The names of the requests the HSM handles (see
+    /// [`rpc::HsmRequest::name`]). Requests added after versioning should
+    /// only be sent to HSMs that list them.
+    pub capabilities: Vec<String>,
+}
+
+impl HsmVersion {
+    /// Returns the versions of an HSM built from this crate.
+    pub fn current(software: &str) -> Self {
+        Self {
+            software: software.to_string(),
+            protocol: HSM_PROTOCOL_VERSION,
+            min_agent_protocol: MIN_AGENT_PROTOCOL_VERSION,
+            log_entry_format: LOG_ENTRY_FORMAT,
+            min_log_entry_format: MIN_LOG_ENTRY_FORMAT,
+            capabilities: rpc::HsmRequest::NAMES
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+        }
+    }
+
+    /// Returns the versions of an HSM that predates versioning.
+    pub fn legacy() -> Self {
+        Self {
+            software: String::from("unknown"),
+            protocol: 0,
+            min_agent_protocol: 0,
+            log_entry_format: 1,
+            min_log_entry_format: 1,
+            capabilities: Vec::new(),
+        }
+    }
+
+    /// Returns true if the HSM and an agent that speaks `agent_protocol` and
+    /// works with HSMs back to `min_hsm_protocol` can work together.
+    pub fn works_with_agent(&self, agent_protocol: u16, min_hsm_protocol: u16) -> bool {
+        agent_protocol >= self.min_agent_protocol && self.protocol >= min_hsm_protocol
+    }
+
+    /// Returns true if the HSM can verify the log entries that a leader
+    /// running `leader` writes.
+    pub fn can_witness(&self, leader: &HsmVersion) -> bool {
+        (self.min_log_entry_format..=self.log_entry_format).contains(&leader.log_entry_format)
+    }
+
+    /// Returns true if the HSM handles the named request (see
+    /// [`rpc::HsmRequest::name`]).
+    pub fn supports(&self, request: &str) -> bool {
+        self.capabilities.iter().any(|c| c == request)
+    }
 }
 
 /// Part of [`StatusResponse`]. Contains information about the HSM's
@@ -903,6 +1106,111 @@ pub struct LeaderStatus {
     /// be committed yet, and it may never become committed. It may also be out
     /// of date.
     pub transferring: Option<Transferring>,
+
+    /// The Noise sessions that the leader has cached for this group. This is
+    /// `None` for HSMs that predate session cache partitioning.
+    #[serde(default)]
+    pub sessions: Option<SessionCacheStatus>,
+}
+
+/// Part of [`LeaderStatus`]. Describes a group leader's cache of Noise
+/// sessions.
+///
+/// The cache is divided into partitions (see [`SessionPartition`]) so that
+/// one tenant can't evict every other tenant's sessions. Partitions may grow
+/// freely until the whole cache is full. After that, a new session in a
+/// partition with `partition_limit` or more sessions evicts that partition's
+/// least recently used session, and any other new session evicts the least
+/// recently used session of the largest partition.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SessionCacheStatus {
+    /// The number of sessions cached across all partitions.
+    pub entries: u32,
+    /// The maximum number of sessions the cache holds.
+    pub limit: u32,
+    /// The number of sessions at which a partition evicts its own sessions
+    /// rather than other partitions' when the cache is full.
+    pub partition_limit: u32,
+    /// The number of non-empty partitions.
+    pub partition_count: u32,
+    /// The largest partitions, largest first. This includes at most
+    /// [`SessionCacheStatus::MAX_PARTITIONS_REPORTED`] partitions, so it may
+    /// not include all of them.
+    pub partitions: Vec<SessionPartitionStatus>,
+}
+
+impl SessionCacheStatus {
+    /// The most partitions an HSM will include in
+    /// [`SessionCacheStatus::partitions`]. This keeps status responses small
+    /// when many tenants have sessions.
+    pub const MAX_PARTITIONS_REPORTED: usize = 16;
+}
+
+/// Part of [`SessionCacheStatus`]. Describes one partition of the cache.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SessionPartitionStatus {
+    pub partition: SessionPartition,
+    /// The number of sessions cached in this partition.
+    pub entries: u32,
+}
+
+/// Identifies which partition of a group leader's Noise session cache a
+/// session belongs to. See [`SessionCacheStatus`].
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub enum SessionPartition {
+    /// The sessions of one tenant, as identified by the agent.
+    Tenant(TenantTag),
+    /// The sessions for record IDs starting with this byte. HSMs use this
+    /// when the agent doesn't identify the tenant.
+    ///
+    /// Record IDs are uniformly distributed, so this spreads any one tenant's
+    /// sessions across all the partitions. It limits how much of the cache
+    /// the users of one prefix can take but not how much one tenant can.
+    RecordPrefix(u8),
+}
+
+impl SessionPartition {
+    /// Returns the partition for a session with the given record, using the
+    /// tenant if known.
+    pub fn new(tenant: Option<TenantTag>, record_id: &RecordId) -> Self {
+        match tenant {
+            Some(tag) => Self::Tenant(tag),
+            None => Self::RecordPrefix(record_id.0[0]),
+        }
+    }
+}
+
+/// A short, opaque identifier for a tenant.
+///
+/// Agents pass this to HSMs in [`HandshakeRequest`] and [`AppRequest`] so
+/// that each tenant's Noise sessions can be cached in a separate partition.
+/// It's derived from the tenant's name with [`TenantTag::new`]. Distinct
+/// tenants could collide, which would only make them share a partition.
+#[derive(Clone, Copy, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct TenantTag(#[serde(with = "bytes")] pub [u8; Self::NUM_BYTES]);
+
+impl TenantTag {
+    pub const NUM_BYTES: usize = 8;
+
+    pub fn new(tenant: &str) -> Self {
+        let digest = Blake2s256::new()
+            .chain_update(b"Juicebox tenant tag")
+            .chain_update(tenant.as_bytes())
+            .finalize();
+        let mut tag = [0u8; Self::NUM_BYTES];
+        tag.copy_from_slice(&digest[..Self::NUM_BYTES]);
+        Self(tag)
+    }
+}
+
+impl fmt::Debug for TenantTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x")?;
+        let mut buf = [0u8; Self::NUM_BYTES * 2];
+        hex::encode_to_slice(self.0, &mut buf).unwrap();
+        f.write_str(core::str::from_utf8(&buf).unwrap())
+    }
 }
 
 /// Request type for the HSM NewRealm RPC (see [`NewRealmResponse`]). Creates a
@@ -1008,7 +1316,7 @@ pub enum JoinRealmResponse {
 /// rollback attacks, an HSM cannot forget this information (unless it's
 /// reinitialized with a new identity). Therefore, you should not accumulate
 /// too many unnecessary groups. Each HSM can join up to [`GROUPS_LIMIT`]
-/// groups.
+/// groups, as NVRAM allows (see [`NVRamStatus::group_capacity`]).
 #[derive(Debug, Deserialize, Serialize)]
 pub struct NewGroupRequest {
     /// The ID of the realm that every new group member should have already
@@ -1056,7 +1364,7 @@ pub enum NewGroupResponse {
     /// secret keys.
     InvalidStatement,
     /// This HSM cannot join any more groups, since it is already a member of
-    /// [`GROUPS_LIMIT`].
+    /// [`GROUPS_LIMIT`], or its NVRAM doesn't have room for another group.
     TooManyGroups,
 }
 
@@ -1074,7 +1382,8 @@ pub enum NewGroupResponse {
 /// Each HSM has to persist information about each group that it's a member of.
 /// To prevent rollback attacks, an HSM cannot forget this information (unless
 /// it's reinitialized with a new identity). Each HSM can join up to
-/// [`GROUPS_LIMIT`] groups.
+/// [`GROUPS_LIMIT`] groups, as NVRAM allows (see
+/// [`NVRamStatus::group_capacity`]).
 #[derive(Debug, Deserialize, Serialize)]
 pub struct JoinGroupRequest {
     /// The ID of the realm that this HSM should have already joined.
@@ -1107,7 +1416,7 @@ pub enum JoinGroupResponse {
     /// The `statement` MAC was invalid.
     InvalidStatement,
     /// This HSM cannot join any more groups, since it is already a member of
-    /// [`GROUPS_LIMIT`].
+    /// [`GROUPS_LIMIT`], or its NVRAM doesn't have room for another group.
     TooManyGroups,
 }
 
@@ -1858,6 +2167,12 @@ pub struct HandshakeRequest {
     pub session_id: SessionId,
     /// A handshake request from a client, which must have an empty payload.
     pub handshake: noise::HandshakeRequest,
+    /// The tenant that the record belongs to, which selects the partition of
+    /// the HSM's session cache that holds the new session. If this is
+    /// `None`, the HSM partitions by record ID instead (see
+    /// [`SessionPartition`]).
+    #[serde(default)]
+    pub tenant: Option<TenantTag>,
 }
 
 /// Response type for the HSM Handshake RPC (see [`HandshakeRequest`]).
@@ -1949,6 +2264,16 @@ pub struct AppRequest {
     pub proof: ReadProof<DataHash>,
     /// The log index that `proof` was generated from.
     pub index: LogIndex,
+    /// Set if the record's tenant has reached its quota of registered secrets
+    /// in this realm. If so, the HSM rejects requests that would register a
+    /// secret for a record that doesn't already have one.
+    #[serde(default)]
+    pub quota_exceeded: bool,
+    /// The tenant that the record belongs to, which selects the partition of
+    /// the HSM's session cache that holds the session. If this is `None`, the
+    /// HSM partitions by record ID instead (see [`SessionPartition`]).
+    #[serde(default)]
+    pub tenant: Option<TenantTag>,
 }
 
 /// Response type for the HSM App RPC (see [`AppRequest`]).
@@ -2015,6 +2340,12 @@ pub enum AppResponse {
     SessionError,
     /// The Noise payload's plaintext could not be deserialized.
     DecodingError,
+    /// The request would register a new secret, but the tenant has reached
+    /// its quota (see [`AppRequest::quota_exceeded`]).
+    ///
+    /// The Noise session is not preserved, so the client must start a new
+    /// one.
+    QuotaExceeded,
 }
 
 /// The different types of results of AppRequests that the client may make.
@@ -2035,19 +2366,294 @@ pub struct GuessState {
     pub guess_count: u16,
 }
 
+/// Request type for the HSM TenantRecord RPC (see [`TenantRecordResponse`]).
+/// The HSM performs an operation on a user's record on behalf of the tenant
+/// that owns it.
+///
+/// Unlike the App RPC, this isn't sent by the user over a Noise session. It's
+/// used by tenants to manage their users' data, for example to delete a
+/// user's registration or when the tenant is offboarded. The operation must
+/// be authorized by a [`TenantRecordGrant`], which the HSM verifies.
+///
+/// Like the App RPC, the HSM produces changes to the Merkle tree (if any) and
+/// a log entry to be persisted to an external storage system. The operation
+/// is only complete once that log entry commits.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TenantRecordRequest {
+    /// The ID of the realm containing the group.
+    pub realm: RealmId,
+    /// The ID of the group, which should be responsible for the partition
+    /// containing the grant's record ID.
+    pub group: GroupId,
+    /// A recent Merkle proof leading to the record, if any. See
+    /// [`AppRequest::proof`].
+    pub proof: ReadProof<DataHash>,
+    /// The log index that `proof` was generated from.
+    pub index: LogIndex,
+    /// Identifies the record and the operation to perform on it.
+    pub grant: TenantRecordGrant,
+}
+
+/// An operation that a tenant may perform on a user's record.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum TenantRecordOperation {
+    /// Reports the record's registration status and fingerprint without
+    /// changing it.
+    GetStatus,
+    /// Clears any registered secret from the record, as long as the record
+    /// still has the given fingerprint (`None` if the record doesn't exist).
+    /// The fingerprint comes from a `GetStatus` operation.
+    ///
+    /// HSMs can't check when a grant expires, so this binding is what stops
+    /// an agent from replaying an old `Delete` grant once the record has
+    /// changed, such as after the user registers again.
+    Delete {
+        fingerprint: Option<RecordFingerprint>,
+    },
+}
+
+impl TenantRecordOperation {
+    /// Returns the operation's name, without its parameters. This is
+    /// suitable for metric tags.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::GetStatus => "GetStatus",
+            Self::Delete { .. } => "Delete",
+        }
+    }
+}
+
+/// Identifies one stored version of a user's record, so that a `Delete`
+/// [`TenantRecordOperation`] can be bound to it.
+///
+/// It's a hash of the record's encrypted Merkle leaf. Records are encrypted
+/// with a fresh nonce every time they're written, so the fingerprint changes
+/// with every write, and it reveals nothing about the record's contents.
+#[derive(Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RecordFingerprint(#[serde(with = "bytes")] pub [u8; 32]);
+
+impl RecordFingerprint {
+    /// Returns the fingerprint of a record, given its encrypted leaf value.
+    pub fn of_leaf(value: &[u8]) -> Self {
+        let digest = Blake2s256::new()
+            .chain_update(b"Juicebox record fingerprint")
+            .chain_update(value)
+            .finalize();
+        Self(digest.into())
+    }
+}
+
+impl fmt::Debug for RecordFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut buf = [0u8; 64];
+        hex::encode_to_slice(self.0, &mut buf).unwrap();
+        f.write_str(core::str::from_utf8(&buf).unwrap())
+    }
+}
+
+/// Authorizes one operation on one user's record on behalf of the tenant that
+/// owns it.
+///
+/// Grants are issued by realm services (the load balancer and the cluster
+/// manager) after they've validated a token signed with the tenant's admin
+/// key. They're signed with the realm's tenant record signing key. HSMs and
+/// agents only accept grants signed by one of the [`TenantRecordKey`]s they
+/// were started with.
+///
+/// Only agents check when a grant expires, and they aren't trusted, so a
+/// grant may be replayed to the HSM after it expires. `Delete` grants are
+/// bound to the record's [`RecordFingerprint`], so they stop working once the
+/// record changes. `GetStatus` grants aren't bound to anything, so a replay
+/// can tell the agent whether the user is still registered.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TenantRecordGrant {
+    /// The tenant that owns the record.
+    pub tenant: String,
+    /// Who authorized the operation on behalf of the tenant (the subject of
+    /// the tenant's admin token). This is recorded for auditing.
+    pub requested_by: String,
+    /// The record ID that identifies the user within the realm.
+    pub record_id: RecordId,
+    pub operation: TenantRecordOperation,
+    /// The grant isn't valid after this time, in seconds since the Unix
+    /// epoch. HSMs don't have a trustworthy clock, so this is only checked by
+    /// the agent.
+    pub expires: u64,
+    /// An Ed25519 signature over the realm ID and the rest of the grant.
+    #[serde(with = "bytes")]
+    pub signature: Vec<u8>,
+}
+
+/// An Ed25519 public key for verifying [`TenantRecordGrant`]s.
+#[derive(Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TenantRecordKey(#[serde(with = "bytes")] pub [u8; 32]);
+
+impl fmt::Debug for TenantRecordKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut buf = [0u8; 64];
+        hex::encode_to_slice(self.0, &mut buf).unwrap();
+        f.write_str(core::str::from_utf8(&buf).unwrap())
+    }
+}
+
+/// Parses a key from 64 hex digits, as given to HSMs and agents on the
+/// command line.
+impl core::str::FromStr for TenantRecordKey {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut key = [0u8; 32];
+        hex::decode_to_slice(s, &mut key)
+            .map_err(|_| "expected a 32-byte Ed25519 public key in hex")?;
+        Ok(Self(key))
+    }
+}
+
+impl TenantRecordGrant {
+    /// Creates a grant signed with the given Ed25519 private key (seed).
+    pub fn sign(
+        realm: &RealmId,
+        tenant: String,
+        requested_by: String,
+        record_id: RecordId,
+        operation: TenantRecordOperation,
+        expires: u64,
+        signing_key: &[u8; 32],
+    ) -> Self {
+        let mut grant = Self {
+            tenant,
+            requested_by,
+            record_id,
+            operation,
+            expires,
+            signature: Vec::new(),
+        };
+        let key = ed25519_dalek::SigningKey::from_bytes(signing_key);
+        grant.signature = key.sign(&grant.message(realm)).to_bytes().to_vec();
+        grant
+    }
+
+    /// Returns the public key corresponding to an Ed25519 private key (seed).
+    pub fn public_key(signing_key: &[u8; 32]) -> TenantRecordKey {
+        TenantRecordKey(
+            ed25519_dalek::SigningKey::from_bytes(signing_key)
+                .verifying_key()
+                .to_bytes(),
+        )
+    }
+
+    /// Returns true if the grant was signed for this realm by the private key
+    /// for any of the given public keys.
+    pub fn verify(&self, realm: &RealmId, keys: &[TenantRecordKey]) -> bool {
+        let Ok(signature) = ed25519_dalek::Signature::from_slice(&self.signature) else {
+            return false;
+        };
+        let message = self.message(realm);
+        keys.iter().any(|key| {
+            ed25519_dalek::VerifyingKey::from_bytes(&key.0)
+                .is_ok_and(|key| key.verify_strict(&message, &signature).is_ok())
+        })
+    }
+
+    fn message(&self, realm: &RealmId) -> Vec<u8> {
+        #[derive(Serialize)]
+        struct Message<'a> {
+            realm: &'a RealmId,
+            tenant: &'a str,
+            requested_by: &'a str,
+            record_id: &'a RecordId,
+            operation: TenantRecordOperation,
+            expires: u64,
+        }
+
+        let mut message = b"tenant_record_grant".to_vec();
+        message.extend(
+            juicebox_marshalling::to_vec(&Message {
+                realm,
+                tenant: &self.tenant,
+                requested_by: &self.requested_by,
+                record_id: &self.record_id,
+                operation: self.operation,
+                expires: self.expires,
+            })
+            .expect("failed to serialize tenant record grant"),
+        );
+        message
+    }
+}
+
+/// The registration status of a user's record, as seen by the tenant.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum RegistrationStatus {
+    NotRegistered,
+    Registered,
+    /// The record has a registration, but the user ran out of guesses.
+    NoGuesses,
+}
+
+/// Response type for the HSM TenantRecord RPC (see [`TenantRecordRequest`]).
+#[derive(Debug, Deserialize, Serialize)]
+#[allow(clippy::large_enum_variant)]
+pub enum TenantRecordResponse {
+    /// The HSM successfully processed the request.
+    ///
+    /// The caller should append the returned entry to the group's log (along
+    /// with the Merkle tree changes) and wait for the entry to commit.
+    Ok {
+        entry: LogEntry,
+        delta: StoreDelta<DataHash>,
+        clock: RoleLogicalClock,
+        /// The record's registration status before the operation.
+        status: RegistrationStatus,
+        /// The record's fingerprint before the operation, or `None` if the
+        /// record didn't exist.
+        fingerprint: Option<RecordFingerprint>,
+    },
+    /// This HSM is not a member of this realm.
+    InvalidRealm,
+    /// This HSM is not a member of this group.
+    InvalidGroup,
+    /// The HSM did not have previous knowledge about this proof's root hash.
+    ///
+    /// The caller should retry with a proof from a more recent snapshot of the
+    /// Merkle tree.
+    StaleProof,
+    /// Either the request's record ID did not match the one in the Merkle
+    /// proof, the proof was not internally consistent, or the proof was not
+    /// conclusive with respect to this record ID.
+    InvalidProof,
+    /// This HSM does not believe that this group manages the partition to
+    /// which this record ID is assigned.
+    NotOwner,
+    /// This HSM is not a leader of this group.
+    NotLeader(RoleStatus),
+    /// The Merkle leaf node could not be decrypted into a user record.
+    InvalidRecordData,
+    /// The grant wasn't signed by a key that this HSM accepts.
+    Unauthorized,
+    /// The grant is for a `Delete` operation, but the record's fingerprint
+    /// has changed since the grant was made. The caller needs a new grant.
+    RecordChanged,
+}
+
 #[cfg(test)]
 mod tests {
+    use alloc::string::String;
+    use alloc::vec::Vec;
     use alloc::{format, vec};
     use core::mem::size_of;
     use subtle::ConstantTimeEq;
 
     use super::{
-        CtBytes, DataHash, EntryMac, GroupId, HsmId, LogEntry, LogIndex, OwnedRange, Partition,
-        RecordId, Transferring, TransferringOut,
+        CtBytes, DataHash, EntryMac, GroupId, HsmId, HsmVersion, LogEntry, LogIndex, OwnedRange,
+        Partition, PublicKey, RealmStatus, RecordFingerprint, RecordId, StatusResponse,
+        TenantRecordGrant, TenantRecordKey, TenantRecordOperation, Transferring, TransferringOut,
+        HSM_PROTOCOL_VERSION, LOG_ENTRY_FORMAT,
     };
     use crate::merkle::HashOutput;
     use bitvec::Bits;
     use juicebox_marshalling as marshalling;
+    use juicebox_realm_api::types::RealmId;
 
     #[test]
     fn log_index() {
@@ -2344,6 +2950,128 @@ mod tests {
         );
     }
 
+    #[test]
+    fn legacy_status_response() {
+        // The StatusResponse fields from before versioning.
+        #[derive(serde::Serialize)]
+        struct LegacyStatusResponse {
+            id: HsmId,
+            realm: Option<RealmStatus>,
+            public_key: PublicKey,
+        }
+
+        let legacy = marshalling::to_vec(&LegacyStatusResponse {
+            id: HsmId([1; 16]),
+            realm: None,
+            public_key: PublicKey(vec![2; 32]),
+        })
+        .unwrap();
+        let status: StatusResponse = marshalling::from_slice(&legacy).unwrap();
+        assert_eq!(None, status.version);
+        assert_eq!(HsmVersion::legacy(), status.version());
+        assert_eq!(None, status.nvram);
+        assert_eq!(16, status.group_capacity());
+    }
+
+    #[test]
+    fn hsm_version_compatibility() {
+        let current = HsmVersion::current("test");
+        let legacy = HsmVersion::legacy();
+        assert_eq!(HSM_PROTOCOL_VERSION, current.protocol);
+        assert!(current.supports("Status"));
+        assert!(!legacy.supports("Status"));
+
+        assert!(current.works_with_agent(HSM_PROTOCOL_VERSION, HSM_PROTOCOL_VERSION));
+        assert!(current.works_with_agent(0, 0));
+        assert!(!legacy.works_with_agent(HSM_PROTOCOL_VERSION, 1));
+        let picky = HsmVersion {
+            min_agent_protocol: HSM_PROTOCOL_VERSION + 1,
+            ..current.clone()
+        };
+        assert!(!picky.works_with_agent(HSM_PROTOCOL_VERSION, 0));
+
+        assert!(current.can_witness(&legacy));
+        assert!(legacy.can_witness(&current));
+        let newer = HsmVersion {
+            log_entry_format: LOG_ENTRY_FORMAT + 1,
+            min_log_entry_format: LOG_ENTRY_FORMAT,
+            ..current.clone()
+        };
+        assert!(newer.can_witness(&current));
+        assert!(!current.can_witness(&newer));
+    }
+
+    #[test]
+    fn tenant_record_grant() {
+        let realm = RealmId([1; 16]);
+        let signing_key = [7; 32];
+        let key = TenantRecordGrant::public_key(&signing_key);
+        let other_key = TenantRecordGrant::public_key(&[8; 32]);
+        let grant = TenantRecordGrant::sign(
+            &realm,
+            String::from("acme"),
+            String::from("alice"),
+            rec_id("0102"),
+            TenantRecordOperation::Delete {
+                fingerprint: Some(RecordFingerprint::of_leaf(b"leaf")),
+            },
+            1_700_000_000,
+            &signing_key,
+        );
+        assert!(grant.verify(&realm, &[key]));
+        assert!(grant.verify(&realm, &[other_key, key]));
+        assert!(!grant.verify(&realm, &[other_key]));
+        assert!(!grant.verify(&realm, &[]));
+        assert!(!grant.verify(&RealmId([2; 16]), &[key]));
+
+        let tampered = [
+            TenantRecordGrant {
+                tenant: String::from("bigcorp"),
+                ..grant.clone()
+            },
+            TenantRecordGrant {
+                requested_by: String::from("mallory"),
+                ..grant.clone()
+            },
+            TenantRecordGrant {
+                record_id: rec_id("0103"),
+                ..grant.clone()
+            },
+            TenantRecordGrant {
+                operation: TenantRecordOperation::GetStatus,
+                ..grant.clone()
+            },
+            TenantRecordGrant {
+                operation: TenantRecordOperation::Delete {
+                    fingerprint: Some(RecordFingerprint::of_leaf(b"other leaf")),
+                },
+                ..grant.clone()
+            },
+            TenantRecordGrant {
+                operation: TenantRecordOperation::Delete { fingerprint: None },
+                ..grant.clone()
+            },
+            TenantRecordGrant {
+                expires: grant.expires + 1,
+                ..grant.clone()
+            },
+            TenantRecordGrant {
+                signature: vec![0; 64],
+                ..grant.clone()
+            },
+            TenantRecordGrant {
+                signature: Vec::new(),
+                ..grant.clone()
+            },
+        ];
+        for grant in tampered {
+            assert!(!grant.verify(&realm, &[key]), "{grant:?}");
+        }
+
+        assert_eq!(format!("{key:?}").parse::<TenantRecordKey>(), Ok(key));
+        assert!("0102".parse::<TenantRecordKey>().is_err());
+    }
+
     fn mkrange(s: u8, e: u8) -> OwnedRange {
         let start = RecordId::min_id().with(&[s]);
         let end = RecordId::max_id().with(&[e]);
@@ -13,8 +13,9 @@ use super::{
     JoinGroupRequest, JoinGroupResponse, JoinRealmRequest, JoinRealmResponse, NewGroupRequest,
     NewGroupResponse, NewRealmRequest, NewRealmResponse, PersistStateRequest, PersistStateResponse,
     PrepareTransferRequest, PrepareTransferResponse, StatusRequest, StatusResponse,
-    StepDownRequest, StepDownResponse, TransferInRequest, TransferInResponse, TransferOutRequest,
-    TransferOutResponse, TransferStatementRequest, TransferStatementResponse,
+    StepDownRequest, StepDownResponse, TenantRecordRequest, TenantRecordResponse,
+    TransferInRequest, TransferInResponse, TransferOutRequest, TransferOutResponse,
+    TransferStatementRequest, TransferStatementResponse,
 };
 
 // Nanoseconds upto ~4.29 seconds.
@@ -75,9 +76,34 @@ pub enum HsmRequest {
     CompleteTransfer(CompleteTransferRequest),
     HandshakeRequest(HandshakeRequest),
     AppRequest(AppRequest),
+    TenantRecord(TenantRecordRequest),
 }
 
 impl HsmRequest {
+    /// The names of all the requests, as returned by [`HsmRequest::name`].
+    pub const NAMES: &'static [&'static str] = &[
+        "Status",
+        "NewRealm",
+        "JoinRealm",
+        "NewGroup",
+        "JoinGroup",
+        "BecomeLeader",
+        "StepDown",
+        "CaptureJump",
+        "CaptureNext",
+        "PersistState",
+        "Commit",
+        "PrepareTransfer",
+        "CancelPreparedTransfer",
+        "TransferOut",
+        "TransferStatement",
+        "TransferIn",
+        "CompleteTransfer",
+        "HandshakeRequest",
+        "AppRequest",
+        "TenantRecord",
+    ];
+
     pub fn name(&self) -> &'static str {
         match self {
             HsmRequest::Status(_) => "Status",
@@ -99,6 +125,7 @@ impl HsmRequest {
             HsmRequest::CompleteTransfer(_) => "CompleteTransfer",
             HsmRequest::HandshakeRequest(_) => "HandshakeRequest",
             HsmRequest::AppRequest(_) => "AppRequest",
+            HsmRequest::TenantRecord(_) => "TenantRecord",
         }
     }
 }
@@ -235,3 +262,191 @@ impl HsmRpc for AppRequest {
         HsmRequest::AppRequest(self)
     }
 }
+
+impl HsmRpc for TenantRecordRequest {
+    type Response = TenantRecordResponse;
+    fn to_req(self) -> HsmRequest {
+        HsmRequest::TenantRecord(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use juicebox_noise::server as noise;
+    use juicebox_realm_api::requests::NoiseRequest;
+    use juicebox_realm_api::types::{RealmId, SessionId};
+
+    use super::*;
+    use crate::merkle::ReadProof;
+    use crate::{
+        Captured, CtBytes, DataHash, EntryMac, GroupId, HsmId, LogEntry, LogIndex, OwnedRange,
+        Partition, RecordId, TenantRecordGrant, TenantRecordOperation, TransferNonce,
+    };
+
+    #[test]
+    fn request_names() {
+        let realm = RealmId([1; 16]);
+        let group = GroupId([2; 16]);
+        let other_group = GroupId([3; 16]);
+        let hsm = HsmId([4; 16]);
+        let statement = CtBytes::from([5; 32]);
+        let nonce = || TransferNonce([6; 16]);
+        let entry = || LogEntry {
+            index: LogIndex::FIRST,
+            partition: None,
+            transferring: None,
+            prev_mac: EntryMac::from([7; 32]),
+            entry_mac: EntryMac::from([8; 32]),
+            hsm,
+        };
+        let captured = || Captured {
+            hsm,
+            realm,
+            group,
+            index: LogIndex::FIRST,
+            mac: EntryMac::from([8; 32]),
+            statement: statement.clone().into(),
+        };
+        let proof = || ReadProof {
+            key: RecordId([9; 32]),
+            range: OwnedRange::full(),
+            leaf: None,
+            path: Vec::new(),
+            root_hash: DataHash([10; 32]),
+        };
+        let handshake = || noise::HandshakeRequest {
+            client_ephemeral_public: vec![11; 32],
+            payload_ciphertext: Vec::new(),
+        };
+
+        // One of every request. A new request needs adding here as well as
+        // to `NAMES`, or the lengths won't match.
+        let requests = [
+            HsmRequest::Status(StatusRequest {}),
+            HsmRequest::NewRealm(NewRealmRequest {}),
+            HsmRequest::JoinRealm(JoinRealmRequest {
+                realm,
+                peer: hsm,
+                statement: statement.clone().into(),
+            }),
+            HsmRequest::NewGroup(NewGroupRequest {
+                realm,
+                members: vec![(hsm, statement.clone().into())],
+            }),
+            HsmRequest::JoinGroup(JoinGroupRequest {
+                realm,
+                group,
+                configuration: vec![hsm],
+                statement: statement.clone().into(),
+            }),
+            HsmRequest::BecomeLeader(BecomeLeaderRequest {
+                realm,
+                group,
+                last_entry: entry(),
+            }),
+            HsmRequest::StepDown(StepDownRequest {
+                realm,
+                group,
+                force: false,
+            }),
+            HsmRequest::CaptureJump(CaptureJumpRequest { jump: captured() }),
+            HsmRequest::CaptureNext(CaptureNextRequest {
+                realm,
+                group,
+                entries: vec![entry()],
+            }),
+            HsmRequest::PersistState(PersistStateRequest {}),
+            HsmRequest::Commit(CommitRequest {
+                realm,
+                group,
+                captures: vec![captured()],
+            }),
+            HsmRequest::PrepareTransfer(PrepareTransferRequest {
+                realm,
+                source: group,
+                destination: other_group,
+                range: OwnedRange::full(),
+            }),
+            HsmRequest::CancelPreparedTransfer(CancelPreparedTransferRequest {
+                realm,
+                source: group,
+                destination: other_group,
+                range: OwnedRange::full(),
+            }),
+            HsmRequest::TransferOut(TransferOutRequest {
+                realm,
+                source: group,
+                destination: other_group,
+                range: OwnedRange::full(),
+                nonce: nonce(),
+                statement: statement.clone().into(),
+                proof: None,
+            }),
+            HsmRequest::TransferStatement(TransferStatementRequest {
+                realm,
+                source: group,
+                destination: other_group,
+                nonce: nonce(),
+            }),
+            HsmRequest::TransferIn(TransferInRequest {
+                realm,
+                source: group,
+                destination: other_group,
+                transferring: Partition {
+                    range: OwnedRange::full(),
+                    root_hash: DataHash([10; 32]),
+                },
+                proofs: None,
+                nonce: nonce(),
+                statement: statement.clone().into(),
+            }),
+            HsmRequest::CompleteTransfer(CompleteTransferRequest {
+                realm,
+                source: group,
+                destination: other_group,
+                range: OwnedRange::full(),
+            }),
+            HsmRequest::HandshakeRequest(HandshakeRequest {
+                realm,
+                group,
+                record_id: RecordId([9; 32]),
+                session_id: SessionId(12),
+                handshake: handshake(),
+                tenant: None,
+            }),
+            HsmRequest::AppRequest(AppRequest {
+                realm,
+                group,
+                record_id: RecordId([9; 32]),
+                session_id: SessionId(12),
+                encrypted: NoiseRequest::Handshake {
+                    handshake: handshake(),
+                },
+                proof: proof(),
+                index: LogIndex::FIRST,
+                quota_exceeded: false,
+                tenant: None,
+            }),
+            HsmRequest::TenantRecord(TenantRecordRequest {
+                realm,
+                group,
+                proof: proof(),
+                index: LogIndex::FIRST,
+                grant: TenantRecordGrant::sign(
+                    &realm,
+                    String::from("acme"),
+                    String::from("admin"),
+                    RecordId([9; 32]),
+                    TenantRecordOperation::Delete { fingerprint: None },
+                    u64::MAX,
+                    &[13; 32],
+                ),
+            }),
+        ];
+        let names: Vec<&str> = requests.iter().map(HsmRequest::name).collect();
+        assert_eq!(HsmRequest::NAMES, names);
+    }
+}
@@ -0,0 +1,15 @@
+#![no_main]
+
+use hsm_api::rpc::HsmRequestContainer;
+use juicebox_marshalling as marshalling;
+use libfuzzer_sys::fuzz_target;
+
+// Decoding arbitrary bytes must fail cleanly, and anything that does decode
+// must encode back to something that decodes the same way.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(request) = marshalling::from_slice::<HsmRequestContainer>(data) {
+        let encoded = marshalling::to_vec(&request).unwrap();
+        let decoded: HsmRequestContainer = marshalling::from_slice(&encoded).unwrap();
+        assert_eq!(encoded, marshalling::to_vec(&decoded).unwrap());
+    }
+});
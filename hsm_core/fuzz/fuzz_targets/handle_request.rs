@@ -0,0 +1,9 @@
+#![no_main]
+
+use hsm_core_fuzz::Harness;
+use libfuzzer_sys::fuzz_target;
+
+// Hands arbitrary bytes to an HSM with a realm, which must not panic.
+fuzz_target!(|data: &[u8]| {
+    Harness::new().handle_bytes(data);
+});
@@ -0,0 +1,14 @@
+#![no_main]
+
+use hsm_core_fuzz::{Action, Harness};
+use libfuzzer_sys::fuzz_target;
+
+// Runs a sequence of well-typed requests, many of them built from genuine
+// log entries and captures with a field tampered with. The harness panics if
+// the HSM accepts anything it didn't MAC.
+fuzz_target!(|actions: Vec<Action>| {
+    let mut harness = Harness::new();
+    for action in actions {
+        harness.run(action);
+    }
+});
@@ -0,0 +1,722 @@
+//! Shared setup and checks for the fuzz targets of `Hsm::handle_request`.
+//!
+//! The agent is untrusted, so the HSM has to survive anything it's sent, and
+//! must only act on log entries, captures, and statements that carry valid
+//! MACs from the realm. The targets run the HSM on the simulation platform
+//! from `hsm_sim`, which is deterministic, so every failure the fuzzer finds
+//! reproduces from its input alone.
+
+use arbitrary::Arbitrary;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+
+use hsm_api::merkle::StoreDelta;
+use hsm_api::rpc::{HsmRequestContainer, HsmResponseContainer, HsmRpc, MetricsAction};
+use hsm_api::{
+    AppRequest, AppResponse, BecomeLeaderRequest, BecomeLeaderResponse,
+    CancelPreparedTransferRequest, CancelPreparedTransferResponse, CaptureJumpRequest,
+    CaptureJumpResponse, CaptureNextRequest, CaptureNextResponse, Captured, CapturedStatement,
+    CommitRequest, CommitResponse, CompleteTransferRequest, CompleteTransferResponse, DataHash,
+    EntryMac, GroupConfigurationStatement, GroupId, HsmId, JoinGroupRequest, JoinGroupResponse,
+    LogEntry, LogIndex, NewRealmRequest, NewRealmResponse, OwnedRange, Partition,
+    PersistStateRequest, PersistStateResponse, PrepareTransferRequest, PrepareTransferResponse,
+    PreparedTransferStatement, RecordFingerprint, RecordId, StatusRequest, StepDownRequest,
+    TenantRecordGrant, TenantRecordOperation, TenantRecordRequest, TenantRecordResponse,
+    TransferInRequest, TransferInResponse, TransferNonce, TransferOutRequest, TransferOutResponse,
+    TransferStatement, TransferStatementRequest, TransferStatementResponse, Transferring,
+};
+use hsm_core::hsm::mac::MacKey;
+use hsm_core::hsm::{Hsm, HsmOptions, MetricsReporting, RealmKeys, RecordEncryptionKey};
+use hsm_sim::{SimClock, SimPlatform, Store};
+use juicebox_marshalling as marshalling;
+use juicebox_noise::client::Handshake;
+use juicebox_realm_api::requests::{NoiseRequest, SecretsRequest};
+use juicebox_realm_api::types::{RealmId, SessionId};
+
+/// The key that the harness signs tenant record grants with. The HSM accepts
+/// its public key.
+const TENANT_RECORD_SIGNING_KEY: [u8; 32] = [4; 32];
+
+/// A step in a fuzzed sequence of requests. Most are built from the genuine
+/// log entries and captures that the HSM produced, optionally tampered with.
+#[derive(Arbitrary, Debug)]
+pub enum Action {
+    Status,
+    PersistState,
+    CaptureNext {
+        start: u8,
+        count: u8,
+        tamper: Option<EntryTamper>,
+    },
+    CaptureJump {
+        capture: u8,
+        tamper: Option<CaptureTamper>,
+    },
+    BecomeLeader {
+        entry: u8,
+        tamper: Option<EntryTamper>,
+    },
+    StepDown {
+        force: bool,
+    },
+    Commit {
+        captures: Vec<(u8, Option<CaptureTamper>)>,
+    },
+    App {
+        record: RecordId,
+        seed: u64,
+    },
+    TenantRecord {
+        record: RecordId,
+        delete: bool,
+        tamper: Option<GrantTamper>,
+    },
+    Forged(Forged),
+}
+
+/// Changes a tenant record grant after it's signed, or signs it with a key
+/// the HSM doesn't accept.
+#[derive(Arbitrary, Debug)]
+pub enum GrantTamper {
+    Tenant(String),
+    RequestedBy(String),
+    Record(RecordId),
+    Operation,
+    Expires(u64),
+    Signature(Vec<u8>),
+    SigningKey([u8; 32]),
+}
+
+/// Changes one field of a log entry. Every field is covered by the entry's
+/// MAC.
+#[derive(Arbitrary, Debug)]
+pub enum EntryTamper {
+    Index(LogIndex),
+    Partition(Option<Partition>),
+    Transferring(Option<Transferring>),
+    PrevMac(EntryMac),
+    EntryMac(EntryMac),
+    Hsm(HsmId),
+}
+
+impl EntryTamper {
+    fn apply(self, entry: &mut LogEntry) {
+        match self {
+            EntryTamper::Index(index) => entry.index = index,
+            EntryTamper::Partition(partition) => entry.partition = partition,
+            EntryTamper::Transferring(transferring) => entry.transferring = transferring,
+            EntryTamper::PrevMac(mac) => entry.prev_mac = mac,
+            EntryTamper::EntryMac(mac) => entry.entry_mac = mac,
+            EntryTamper::Hsm(hsm) => entry.hsm = hsm,
+        }
+    }
+}
+
+/// Changes one field of a capture. Every field is covered by the capture's
+/// statement.
+#[derive(Arbitrary, Debug)]
+pub enum CaptureTamper {
+    Hsm(HsmId),
+    Group(GroupId),
+    Index(LogIndex),
+    Mac(EntryMac),
+    Statement(CapturedStatement),
+}
+
+impl CaptureTamper {
+    fn apply(self, captured: &mut Captured) {
+        match self {
+            CaptureTamper::Hsm(hsm) => captured.hsm = hsm,
+            CaptureTamper::Group(group) => captured.group = group,
+            CaptureTamper::Index(index) => captured.index = index,
+            CaptureTamper::Mac(mac) => captured.mac = mac,
+            CaptureTamper::Statement(statement) => captured.statement = statement,
+        }
+    }
+}
+
+/// A well-typed request made up of arbitrary values. It's addressed to the
+/// harness's realm, and to its group where it needs one, so that it gets past
+/// the membership checks.
+#[derive(Arbitrary, Debug)]
+pub enum Forged {
+    CaptureNext(Vec<LogEntry>),
+    CaptureJump {
+        hsm: HsmId,
+        index: LogIndex,
+        mac: EntryMac,
+        statement: CapturedStatement,
+    },
+    BecomeLeader(LogEntry),
+    Commit(Vec<(HsmId, LogIndex, EntryMac, CapturedStatement)>),
+    JoinGroup {
+        group: GroupId,
+        configuration: Vec<HsmId>,
+        statement: GroupConfigurationStatement,
+    },
+    PrepareTransfer {
+        source: GroupId,
+        range: OwnedRange,
+    },
+    CancelPreparedTransfer {
+        source: GroupId,
+        range: OwnedRange,
+    },
+    TransferOut {
+        destination: GroupId,
+        range: OwnedRange,
+        nonce: TransferNonce,
+        statement: PreparedTransferStatement,
+    },
+    TransferStatement {
+        destination: GroupId,
+        nonce: TransferNonce,
+    },
+    TransferIn {
+        source: GroupId,
+        transferring: Partition,
+        nonce: TransferNonce,
+        statement: TransferStatement,
+    },
+    CompleteTransfer {
+        destination: GroupId,
+        range: OwnedRange,
+    },
+}
+
+/// An HSM that has created a realm and leads its first group, which has
+/// committed a couple of log entries.
+///
+/// The harness remembers everything the HSM has MACed, and panics if the HSM
+/// accepts anything else as carrying a valid MAC.
+pub struct Harness {
+    hsm: Hsm<SimPlatform>,
+    public_key: x25519_dalek::PublicKey,
+    realm: RealmId,
+    group: GroupId,
+    store: Store,
+    entries: Vec<LogEntry>,
+    captures: Vec<Captured>,
+    prepared_statements: Vec<PreparedTransferStatement>,
+    transfer_statements: Vec<TransferStatement>,
+    committed: Option<LogIndex>,
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        // Reseeding for every input keeps the hash tables' iteration order the
+        // same when a crashing input is replayed on its own.
+        hsm_core::hash::set_global_rng(Box::new(StdRng::seed_from_u64(0)));
+
+        let communication = x25519_dalek::StaticSecret::from([1; 32]);
+        let public_key = x25519_dalek::PublicKey::from(&communication);
+        let keys = RealmKeys {
+            communication: (communication, public_key),
+            record: RecordEncryptionKey::from([2; 32]),
+            mac: MacKey::from([3; 32]),
+        };
+        let mut hsm = Hsm::new(
+            HsmOptions {
+                name: String::from("fuzz"),
+                tree_overlay_size: 16,
+                max_sessions: 16,
+                max_sessions_per_partition: 8,
+                metrics: MetricsReporting::Disabled,
+                tenant_record_keys: vec![TenantRecordGrant::public_key(&TENANT_RECORD_SIGNING_KEY)],
+                software_version: String::from("fuzz"),
+            },
+            SimPlatform::new(SimClock::default(), 0),
+            keys,
+        )
+        .expect("failed to start HSM");
+
+        let NewRealmResponse::Ok {
+            realm,
+            group,
+            entry,
+            delta,
+            ..
+        } = call(&mut hsm, NewRealmRequest {})
+        else {
+            panic!("failed to create realm");
+        };
+        let mut harness = Self {
+            hsm,
+            public_key,
+            realm,
+            group,
+            store: Store::default(),
+            entries: Vec::new(),
+            captures: Vec::new(),
+            prepared_statements: Vec::new(),
+            transfer_statements: Vec::new(),
+            committed: None,
+        };
+        harness.append(entry, delta);
+        harness.commit_log();
+        // A client request gives the log an entry that chains from another.
+        match harness.app(RecordId::min_id().with(&[0x80]), 0) {
+            Some(AppResponse::Ok { .. }) => {}
+            other => panic!("client request failed: {other:?}"),
+        }
+        harness.commit_log();
+        harness
+    }
+
+    /// Hands raw bytes to the HSM, then checks that it still works.
+    pub fn handle_bytes(&mut self, data: &[u8]) {
+        let _ = self.hsm.handle_request(data);
+        let status = self.call(StatusRequest {});
+        assert!(status.realm.is_some_and(|realm| realm.id == self.realm));
+    }
+
+    pub fn run(&mut self, action: Action) {
+        match action {
+            Action::Status => {
+                self.call(StatusRequest {});
+            }
+            Action::PersistState => self.persist_state(),
+            Action::CaptureNext {
+                start,
+                count,
+                tamper,
+            } => {
+                let log = self.store.log(&self.group);
+                let start = usize::from(start) % log.len();
+                let mut entries: Vec<LogEntry> = log[start..]
+                    .iter()
+                    .take(usize::from(count))
+                    .cloned()
+                    .collect();
+                if let (Some(tamper), Some(entry)) = (tamper, entries.last_mut()) {
+                    tamper.apply(entry);
+                }
+                self.capture_next(entries);
+            }
+            Action::CaptureJump { capture, tamper } => {
+                let mut jump = self.captures[usize::from(capture) % self.captures.len()].clone();
+                if let Some(tamper) = tamper {
+                    tamper.apply(&mut jump);
+                }
+                self.capture_jump(jump);
+            }
+            Action::BecomeLeader { entry, tamper } => {
+                let log = self.store.log(&self.group);
+                let mut last_entry = log[usize::from(entry) % log.len()].clone();
+                if let Some(tamper) = tamper {
+                    tamper.apply(&mut last_entry);
+                }
+                self.become_leader(last_entry);
+            }
+            Action::StepDown { force } => {
+                self.call(StepDownRequest {
+                    realm: self.realm,
+                    group: self.group,
+                    force,
+                });
+            }
+            Action::Commit { captures } => {
+                let captures = captures
+                    .into_iter()
+                    .map(|(i, tamper)| {
+                        let mut captured =
+                            self.captures[usize::from(i) % self.captures.len()].clone();
+                        if let Some(tamper) = tamper {
+                            tamper.apply(&mut captured);
+                        }
+                        captured
+                    })
+                    .collect();
+                self.commit(captures);
+            }
+            Action::App { record, seed } => {
+                self.app(record, seed);
+            }
+            Action::TenantRecord {
+                record,
+                delete,
+                tamper,
+            } => self.tenant_record(record, delete, tamper),
+            Action::Forged(forged) => self.forged(forged),
+        }
+    }
+
+    fn forged(&mut self, forged: Forged) {
+        let (realm, group) = (self.realm, self.group);
+        match forged {
+            Forged::CaptureNext(entries) => self.capture_next(entries),
+            Forged::CaptureJump {
+                hsm,
+                index,
+                mac,
+                statement,
+            } => self.capture_jump(Captured {
+                hsm,
+                realm,
+                group,
+                index,
+                mac,
+                statement,
+            }),
+            Forged::BecomeLeader(last_entry) => self.become_leader(last_entry),
+            Forged::Commit(captures) => self.commit(
+                captures
+                    .into_iter()
+                    .map(|(hsm, index, mac, statement)| Captured {
+                        hsm,
+                        realm,
+                        group,
+                        index,
+                        mac,
+                        statement,
+                    })
+                    .collect(),
+            ),
+            Forged::JoinGroup {
+                group: other,
+                configuration,
+                statement,
+            } => {
+                let response = self.call(JoinGroupRequest {
+                    realm,
+                    group: other,
+                    configuration,
+                    statement,
+                });
+                if matches!(response, JoinGroupResponse::Ok(_)) {
+                    assert!(other == group, "HSM joined a group with a forged statement");
+                }
+            }
+            Forged::PrepareTransfer { source, range } => {
+                let response = self.call(PrepareTransferRequest {
+                    realm,
+                    source,
+                    destination: group,
+                    range,
+                });
+                if let PrepareTransferResponse::Ok(prepared) = response {
+                    if let Some(entry) = prepared.entry {
+                        self.append(entry, StoreDelta::default());
+                    }
+                    self.prepared_statements.push(prepared.statement);
+                }
+            }
+            Forged::CancelPreparedTransfer { source, range } => {
+                let response = self.call(CancelPreparedTransferRequest {
+                    realm,
+                    source,
+                    destination: group,
+                    range,
+                });
+                if let CancelPreparedTransferResponse::Ok { entry, .. } = response {
+                    self.append(entry, StoreDelta::default());
+                }
+            }
+            Forged::TransferOut {
+                destination,
+                range,
+                nonce,
+                statement,
+            } => {
+                let genuine = contains(&self.prepared_statements, &statement);
+                let response = self.call(TransferOutRequest {
+                    realm,
+                    source: group,
+                    destination,
+                    range,
+                    nonce,
+                    statement,
+                    proof: None,
+                });
+                if let TransferOutResponse::Ok { entry, delta, .. } = response {
+                    assert!(genuine, "HSM transferred out with a forged statement");
+                    if let Some(entry) = entry {
+                        self.append(entry, delta);
+                    }
+                }
+            }
+            Forged::TransferStatement { destination, nonce } => {
+                let response = self.call(TransferStatementRequest {
+                    realm,
+                    source: group,
+                    destination,
+                    nonce,
+                });
+                if let TransferStatementResponse::Ok(statement) = response {
+                    self.transfer_statements.push(statement);
+                }
+            }
+            Forged::TransferIn {
+                source,
+                transferring,
+                nonce,
+                statement,
+            } => {
+                let genuine = contains(&self.transfer_statements, &statement);
+                let response = self.call(TransferInRequest {
+                    realm,
+                    source,
+                    destination: group,
+                    transferring,
+                    proofs: None,
+                    nonce,
+                    statement,
+                });
+                if let TransferInResponse::Ok { entry, delta, .. } = response {
+                    assert!(genuine, "HSM transferred in with a forged statement");
+                    self.append(entry, delta);
+                }
+            }
+            Forged::CompleteTransfer { destination, range } => {
+                let response = self.call(CompleteTransferRequest {
+                    realm,
+                    source: group,
+                    destination,
+                    range,
+                });
+                if let CompleteTransferResponse::Ok { entry, .. } = response {
+                    self.append(entry, StoreDelta::default());
+                }
+            }
+        }
+    }
+
+    fn capture_next(&mut self, entries: Vec<LogEntry>) {
+        let genuine = entries.iter().all(|entry| self.entries.contains(entry));
+        let response = self.call(CaptureNextRequest {
+            realm: self.realm,
+            group: self.group,
+            entries,
+        });
+        if matches!(response, CaptureNextResponse::Ok(_)) {
+            assert!(genuine, "HSM captured a log entry it never MACed");
+        }
+    }
+
+    fn capture_jump(&mut self, jump: Captured) {
+        let genuine = contains(&self.captures, &jump);
+        let response = self.call(CaptureJumpRequest { jump });
+        if response == CaptureJumpResponse::Ok {
+            assert!(genuine, "HSM jumped to a capture it never MACed");
+        }
+    }
+
+    fn become_leader(&mut self, last_entry: LogEntry) {
+        let genuine = self.entries.contains(&last_entry);
+        let response = self.call(BecomeLeaderRequest {
+            realm: self.realm,
+            group: self.group,
+            last_entry,
+        });
+        if matches!(response, BecomeLeaderResponse::Ok { .. }) {
+            assert!(genuine, "HSM became leader from a log entry it never MACed");
+        }
+    }
+
+    fn commit(&mut self, captures: Vec<Captured>) {
+        let genuine = captures
+            .iter()
+            .filter(|captured| contains(&self.captures, captured))
+            .map(|captured| captured.index)
+            .max();
+        let response = self.call(CommitRequest {
+            realm: self.realm,
+            group: self.group,
+            captures,
+        });
+        if let CommitResponse::Ok(state) = response {
+            if Some(state.committed) > self.committed {
+                assert!(
+                    genuine >= Some(state.committed),
+                    "HSM committed {} without a genuine capture of it",
+                    state.committed
+                );
+                self.committed = Some(state.committed);
+            }
+        }
+    }
+
+    fn persist_state(&mut self) {
+        let PersistStateResponse::Ok { captured } = self.call(PersistStateRequest {});
+        self.captures.extend(captured);
+    }
+
+    // Sends a client request to delete a record, appending the resulting log
+    // entry. Returns None if the group doesn't own a partition.
+    fn app(&mut self, record_id: RecordId, seed: u64) -> Option<AppResponse> {
+        let last = self.store.last(&self.group)?;
+        let partition = last.partition.as_ref()?;
+        let index = last.index;
+        let proof = self
+            .store
+            .tree
+            .read(&partition.range, &partition.root_hash, &record_id)
+            .ok()?;
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let request = marshalling::to_vec(&SecretsRequest::Delete).unwrap();
+        let (_, handshake) = Handshake::start(&self.public_key, &request, &mut rng).unwrap();
+        let response = self.call(AppRequest {
+            realm: self.realm,
+            group: self.group,
+            record_id,
+            session_id: SessionId(rng.gen()),
+            encrypted: NoiseRequest::Handshake { handshake },
+            proof,
+            index,
+            quota_exceeded: false,
+            tenant: None,
+        });
+        if let AppResponse::Ok { entry, delta } = &response {
+            self.append(entry.clone(), delta.clone());
+        }
+        Some(response)
+    }
+
+    // Sends a tenant record request with a grant signed by the harness,
+    // appending the resulting log entry. The HSM must reject the grant if
+    // it's been tampered with.
+    fn tenant_record(&mut self, record_id: RecordId, delete: bool, tamper: Option<GrantTamper>) {
+        let Some(last) = self.store.last(&self.group) else {
+            return;
+        };
+        let Some(partition) = last.partition.as_ref() else {
+            return;
+        };
+        let index = last.index;
+        let Ok(proof) = self
+            .store
+            .tree
+            .read(&partition.range, &partition.root_hash, &record_id)
+        else {
+            return;
+        };
+
+        let operation = if delete {
+            TenantRecordOperation::Delete {
+                fingerprint: proof
+                    .leaf
+                    .as_ref()
+                    .map(|leaf| RecordFingerprint::of_leaf(&leaf.value)),
+            }
+        } else {
+            TenantRecordOperation::GetStatus
+        };
+        let sign = |signing_key: &[u8; 32]| {
+            TenantRecordGrant::sign(
+                &self.realm,
+                String::from("acme"),
+                String::from("admin@acme"),
+                record_id.clone(),
+                operation,
+                u64::MAX,
+                signing_key,
+            )
+        };
+        let genuine = sign(&TENANT_RECORD_SIGNING_KEY);
+        let grant = match tamper {
+            None => genuine.clone(),
+            Some(GrantTamper::SigningKey(key)) => sign(&key),
+            Some(tamper) => {
+                let mut grant = genuine.clone();
+                match tamper {
+                    GrantTamper::Tenant(tenant) => grant.tenant = tenant,
+                    GrantTamper::RequestedBy(by) => grant.requested_by = by,
+                    GrantTamper::Record(record_id) => grant.record_id = record_id,
+                    GrantTamper::Operation => {
+                        grant.operation = match operation {
+                            TenantRecordOperation::Delete { .. } => {
+                                TenantRecordOperation::GetStatus
+                            }
+                            TenantRecordOperation::GetStatus => {
+                                TenantRecordOperation::Delete { fingerprint: None }
+                            }
+                        }
+                    }
+                    GrantTamper::Expires(expires) => grant.expires = expires,
+                    GrantTamper::Signature(signature) => grant.signature = signature,
+                    GrantTamper::SigningKey(_) => unreachable!(),
+                }
+                grant
+            }
+        };
+        let is_genuine = grant == genuine;
+
+        let response = self.call(TenantRecordRequest {
+            realm: self.realm,
+            group: self.group,
+            proof,
+            index,
+            grant,
+        });
+        if let TenantRecordResponse::Ok { entry, delta, .. } = response {
+            assert!(is_genuine, "HSM accepted a tampered tenant record grant");
+            self.append(entry, delta);
+        }
+    }
+
+    // Captures, persists, and commits everything in the log.
+    fn commit_log(&mut self) {
+        let captured = self.captures.iter().map(|captured| captured.index).max();
+        let entries: Vec<LogEntry> = self
+            .store
+            .log(&self.group)
+            .iter()
+            .filter(|entry| Some(entry.index) > captured)
+            .cloned()
+            .collect();
+        let response = self.call(CaptureNextRequest {
+            realm: self.realm,
+            group: self.group,
+            entries,
+        });
+        assert!(
+            matches!(response, CaptureNextResponse::Ok(_)),
+            "{response:?}"
+        );
+        self.persist_state();
+        let captures = self.captures.clone();
+        self.commit(captures);
+    }
+
+    // Records a log entry the HSM generated, and appends it to the log if it
+    // follows on from the last entry.
+    fn append(&mut self, entry: LogEntry, delta: StoreDelta<DataHash>) {
+        self.entries.push(entry.clone());
+        self.store.append(self.group, entry, delta);
+    }
+
+    fn call<R: HsmRpc>(&mut self, request: R) -> R::Response {
+        call(&mut self.hsm, request)
+    }
+}
+
+impl Default for Harness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn call<R: HsmRpc>(hsm: &mut Hsm<SimPlatform>, request: R) -> R::Response {
+    let request = marshalling::to_vec(&HsmRequestContainer {
+        req: request.to_req(),
+        metrics: MetricsAction::Skip,
+    })
+    .unwrap();
+    let response = hsm
+        .handle_request(&request)
+        .expect("HSM failed to handle a valid request");
+    marshalling::from_slice::<HsmResponseContainer<R::Response>>(&response)
+        .unwrap()
+        .res
+}
+
+// MACs and statements only compare in constant time, if at all, so this
+// compares their encodings instead.
+fn contains<T: Serialize>(haystack: &[T], needle: &T) -> bool {
+    let needle = marshalling::to_vec(needle).unwrap();
+    haystack
+        .iter()
+        .any(|item| marshalling::to_vec(item).unwrap() == needle)
+}
@@ -12,7 +12,7 @@ use core::time::Duration;
 use digest::Digest;
 use hsm_api::merkle::StoreDelta;
 use serde::ser::SerializeTuple;
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use tracing::{info, instrument, warn};
 use x25519_dalek as x25519;
 
@@ -20,6 +20,7 @@ mod app;
 pub mod commit;
 mod configuration;
 pub mod mac;
+pub mod persistence;
 #[cfg(test)]
 mod tests;
 mod transfer;
@@ -44,11 +45,13 @@ use hsm_api::rpc::{
 use hsm_api::{
     AppRequest, AppResponse, AppResultType, BecomeLeaderRequest, BecomeLeaderResponse, Captured,
     DataHash, EntryMac, GroupId, GroupMemberRole, GroupStatus, HandshakeRequest, HandshakeResponse,
-    HsmId, HsmRealmStatement, JoinGroupRequest, JoinGroupResponse, JoinRealmRequest,
-    JoinRealmResponse, LeaderStatus, LogEntry, LogIndex, NewGroupRequest, NewGroupResponse,
+    HsmId, HsmVersion, JoinGroupRequest, JoinGroupResponse, JoinRealmRequest, JoinRealmResponse,
+    LeaderStatus, LogEntry, LogIndex, NVRamStatus, NewGroupRequest, NewGroupResponse,
     NewRealmRequest, NewRealmResponse, OwnedRange, Partition, PersistStateRequest,
-    PersistStateResponse, PublicKey, RealmStatus, RecordId, RoleLogicalClock, RoleStatus,
-    StatusRequest, StatusResponse, StepDownRequest, StepDownResponse, TransferNonce, Transferring,
+    PersistStateResponse, PublicKey, RealmStatus, RecordFingerprint, RecordId, RegistrationStatus,
+    RoleLogicalClock, RoleStatus, SessionCacheStatus, SessionPartition, SessionPartitionStatus,
+    StatusRequest, StatusResponse, StepDownRequest, StepDownResponse, TenantRecordKey,
+    TenantRecordOperation, TenantRecordRequest, TenantRecordResponse, TransferNonce, Transferring,
     CONFIGURATION_LIMIT, GROUPS_LIMIT,
 };
 use juicebox_marshalling::{self as marshalling, bytes, DeserializationError};
@@ -60,11 +63,14 @@ use juicebox_realm_api::{
     },
     types::{RealmId, SessionId},
 };
-
-// Unless sized correctly this is susceptible to DoS attacks. One user could
-// create many sessions to evict all other users' Noise connections, or one
-// attacker could collect many user accounts to evict all other connections.
-type SessionCache = lru_cache::Cache<
+use persistence::{PersistentGroupState, PersistentRealmState, PersistentState};
+
+// Sessions are partitioned, usually by tenant, so that one user or tenant
+// creating many sessions can only evict its own partition's sessions and those
+// of other partitions that are at least as large. Unless sized correctly this
+// is still susceptible to DoS attacks within a partition.
+type SessionCache = lru_cache::PartitionedCache<
+    SessionPartition,
     (RecordId, SessionId),
     noise::Transport,
     lru_cache::LogicalClock,
@@ -96,6 +102,24 @@ fn create_random_realm_id(rng: &mut impl CryptoRng) -> RealmId {
     RealmId(id)
 }
 
+fn session_cache_status(sessions: &SessionCache) -> SessionCacheStatus {
+    let stats = sessions.stats();
+    SessionCacheStatus {
+        entries: u32::try_from(stats.entries).unwrap(),
+        limit: u32::try_from(stats.limit).unwrap(),
+        partition_limit: u32::try_from(sessions.partition_limit()).unwrap(),
+        partition_count: u32::try_from(sessions.partition_count()).unwrap(),
+        partitions: sessions
+            .partitions()
+            .take(SessionCacheStatus::MAX_PARTITIONS_REPORTED)
+            .map(|(partition, stats)| SessionPartitionStatus {
+                partition: *partition,
+                entries: u32::try_from(stats.entries).unwrap(),
+            })
+            .collect(),
+    }
+}
+
 struct LogEntryBuilder {
     hsm: HsmId,
     realm: RealmId,
@@ -176,8 +200,18 @@ pub struct HsmOptions {
     pub name: String,
     pub tree_overlay_size: u16,
     pub max_sessions: u16,
+    // Once a group's Noise session cache is full, a tenant (or record ID
+    // prefix) with this many sessions cached evicts its own sessions rather
+    // than other tenants'. Must be between 1 and `max_sessions`.
+    pub max_sessions_per_partition: u16,
     // Metrics should be set to Disabled for production deployments.
     pub metrics: MetricsReporting,
+    // The keys that tenant record grants must be signed with. If this is
+    // empty, every TenantRecord request is rejected.
+    pub tenant_record_keys: Vec<TenantRecordKey>,
+    // Reported as the HSM's software version. This should identify the build,
+    // such as by its git commit hash.
+    pub software_version: String,
 }
 
 #[derive(Clone)]
@@ -204,25 +238,6 @@ impl Serialize for RealmKeys {
     }
 }
 
-#[derive(Deserialize, Serialize)]
-struct PersistentState {
-    id: HsmId,
-    realm: Option<PersistentRealmState>,
-}
-
-#[derive(Deserialize, Serialize)]
-struct PersistentRealmState {
-    id: RealmId,
-    statement: HsmRealmStatement,
-    groups: HashMap<GroupId, PersistentGroupState>,
-}
-
-#[derive(Clone, Deserialize, Serialize)]
-struct PersistentGroupState {
-    configuration: GroupConfiguration,
-    captured: Option<(LogIndex, EntryMac)>,
-}
-
 struct VolatileState {
     captured: HashMap<GroupId, (LogIndex, EntryMac)>,
     groups: HashMap<GroupId, RoleState>,
@@ -324,7 +339,10 @@ impl LeaderVolatileGroupState {
             committed: None,
             incoming: None,
             tree,
-            sessions: SessionCache::new(usize::from(options.max_sessions)),
+            sessions: SessionCache::new(
+                usize::from(options.max_sessions),
+                usize::from(options.max_sessions_per_partition),
+            ),
         }
     }
 }
@@ -462,6 +480,11 @@ pub enum PersistenceError {
     Deserialization(DeserializationError),
     InvalidChecksum,
     InvalidRealmStatement,
+    /// The state was written by a newer version of the HSM software, in a
+    /// schema this version doesn't know.
+    UnsupportedVersion(u16),
+    /// The state decoded but doesn't make sense.
+    InvalidState(&'static str),
 }
 
 impl From<IOError> for PersistenceError {
@@ -531,6 +554,10 @@ impl<'a, C: Clock> Metrics<'a, C> {
 struct NVRamWriter<N: NVRam> {
     nvram: N,
     hash_of_last_write: Option<[u8; 32]>,
+    // The NVRAM usage of the state last written or read. Working this out
+    // encodes the state a few times, so it's only done when the state
+    // changes, not for every status request.
+    status: Option<NVRamStatus>,
 }
 
 impl<N: NVRam> NVRamWriter<N> {
@@ -538,21 +565,22 @@ impl<N: NVRam> NVRamWriter<N> {
         Self {
             nvram,
             hash_of_last_write: None,
+            status: None,
         }
     }
 }
 
 impl<N: NVRam> OnMutationFinished<PersistentState> for NVRamWriter<N> {
     fn finished(&mut self, state: &PersistentState) {
-        let mut data = marshalling::to_vec(&state).expect("failed to serialize state");
+        let data = persistence::encode(state);
         let d: [u8; 32] = Blake2s256::digest(&data).into();
         if Some(d) == self.hash_of_last_write {
             // Data hasn't changed since last write, no need to write it again.
             return;
         }
-        data.extend(d);
         self.nvram.write(data).expect("Write to NVRam failed");
         self.hash_of_last_write = Some(d);
+        self.status = Some(persistence::nvram_status(state));
     }
 }
 
@@ -562,9 +590,16 @@ impl<P: Platform> Hsm<P> {
         mut platform: P,
         realm_keys: RealmKeys,
     ) -> Result<Self, PersistenceError> {
+        assert!(
+            (1..=options.max_sessions).contains(&options.max_sessions_per_partition),
+            "max_sessions_per_partition must be between 1 and max_sessions"
+        );
         let mut writer = NVRamWriter::new(platform.clone());
         let persistent = match Self::read_persisted_state(&platform)? {
-            Some(state) => state,
+            Some(state) => {
+                writer.status = Some(persistence::nvram_status(&state));
+                state
+            }
             None => {
                 let hsm_id = create_random_hsm_id(&mut platform);
                 let state = PersistentState {
@@ -687,6 +722,9 @@ impl<P: Platform> Hsm<P> {
             HsmRequest::CompleteTransfer(r) => {
                 self.dispatch_request(metrics, r, Self::handle_complete_transfer)
             }
+            HsmRequest::TenantRecord(r) => {
+                self.dispatch_request(metrics, r, Self::handle_tenant_record)
+            }
         }
     }
 
@@ -708,23 +746,17 @@ impl<P: Platform> Hsm<P> {
     fn read_persisted_state(
         nvram: &impl NVRam,
     ) -> Result<Option<PersistentState>, PersistenceError> {
-        let d = nvram.read()?;
-        if d.is_empty() {
+        let Some(decoded) = persistence::decode(&nvram.read()?)? else {
             return Ok(None);
+        };
+        if decoded.version != persistence::CURRENT_VERSION {
+            info!(
+                from = decoded.version,
+                to = persistence::CURRENT_VERSION,
+                "migrated persistent state"
+            );
         }
-        if d.len() < Blake2s256::output_size() {
-            return Err(PersistenceError::InvalidChecksum);
-        }
-        let (data, stored_digest) = d.split_at(d.len() - Blake2s256::output_size());
-        let calced_digest = Blake2s256::digest(data);
-        if stored_digest == calced_digest.as_slice() {
-            match marshalling::from_slice(data) {
-                Ok(state) => Ok(Some(state)),
-                Err(e) => Err(PersistenceError::Deserialization(e)),
-            }
-        } else {
-            Err(PersistenceError::InvalidChecksum)
-        }
+        Ok(Some(decoded.state))
     }
 
     #[instrument(level = "trace", skip(self, _metrics, _request), fields(hsm=self.options.name), ret)]
@@ -763,6 +795,7 @@ impl<P: Platform> Hsm<P> {
                                             .as_ref()
                                             .map(|p| p.range.clone()),
                                         transferring: last.transferring.clone(),
+                                        sessions: Some(session_cache_status(&leader.sessions)),
                                     })
                                 }
                                 _ => None,
@@ -772,6 +805,8 @@ impl<P: Platform> Hsm<P> {
                     })
                     .collect(),
             }),
+            version: Some(HsmVersion::current(&self.options.software_version)),
+            nvram: self.persistent.on_finished().status.clone(),
         }
     }
 
@@ -940,6 +975,9 @@ impl<P: Platform> Hsm<P> {
         ) else {
             return Response::InvalidConfiguration;
         };
+        if !persistence::has_room_for_group(&self.persistent, &configuration) {
+            return Response::TooManyGroups;
+        }
 
         let group = create_random_group_id(&mut self.platform);
         let statement =
@@ -1019,6 +1057,11 @@ impl<P: Platform> Hsm<P> {
         if realm.groups.len() >= usize::from(GROUPS_LIMIT) {
             return Response::TooManyGroups;
         }
+        if !realm.groups.contains_key(&request.group)
+            && !persistence::has_room_for_group(&self.persistent, &configuration)
+        {
+            return Response::TooManyGroups;
+        }
 
         if self
             .realm_keys
@@ -1302,9 +1345,11 @@ impl<P: Platform> Hsm<P> {
         ) {
             Ok((handshake, payload)) if payload.is_empty() => match handshake.finish(&[]) {
                 Ok((transport, response)) => {
-                    leader
-                        .sessions
-                        .insert((request.record_id, request.session_id), transport);
+                    leader.sessions.insert(
+                        SessionPartition::new(request.tenant, &request.record_id),
+                        (request.record_id, request.session_id),
+                        transport,
+                    );
                     Response::Ok {
                         noise: response,
                         session_lifetime: SESSION_LIFETIME,
@@ -1366,6 +1411,119 @@ impl<P: Platform> Hsm<P> {
         metrics.record(app_req_name.unwrap_or("app.unknown"), start);
         response
     }
+
+    #[instrument(level = "trace", skip(self, _metrics, request), fields(hsm=self.options.name), ret)]
+    fn handle_tenant_record(
+        &mut self,
+        _metrics: &mut Metrics<P>,
+        request: TenantRecordRequest,
+    ) -> TenantRecordResponse {
+        type Response = TenantRecordResponse;
+
+        // The caller isn't trusted to have checked the tenant's authorization,
+        // so the grant's signature is checked before anything else.
+        if !request
+            .grant
+            .verify(&request.realm, &self.options.tenant_record_keys)
+        {
+            return Response::Unauthorized;
+        }
+        let record_id = &request.grant.record_id;
+
+        let leader = match is_leader_record_owner(
+            &self.persistent,
+            &mut self.volatile.groups,
+            request.realm,
+            request.group,
+            record_id,
+        ) {
+            Ok(leader) => leader,
+            Err(RecordLeaderError::InvalidRealm) => return Response::InvalidRealm,
+            Err(RecordLeaderError::InvalidGroup) => return Response::InvalidGroup,
+            Err(RecordLeaderError::NotLeader(s)) => return Response::NotLeader(s),
+            Err(RecordLeaderError::NotOwner) => return Response::NotOwner,
+        };
+
+        // See the equivalent check in `handle_app`.
+        if request.index > leader.log.last_index() {
+            self.stepdown_at(request.group, StepDownPoint::LastLogIndex);
+            return Response::NotLeader(
+                self.volatile
+                    .groups
+                    .get(&request.group)
+                    .expect("We already validated that this HSM is a member of the group")
+                    .status(),
+            );
+        }
+
+        let tree = leader
+            .tree
+            .as_mut()
+            .expect("is_leader_record_owner checked that this leader owns a partition");
+        let (merkle, record) =
+            match MerkleHelper::get_record(record_id, request.proof, &self.realm_keys.record, tree)
+            {
+                Ok(record) => record,
+                Err(AppError::StaleProof) => return Response::StaleProof,
+                Err(AppError::InvalidProof) => return Response::InvalidProof,
+                Err(AppError::InvalidRecordData) => return Response::InvalidRecordData,
+                Err(
+                    AppError::MissingSession | AppError::SessionError | AppError::DecodingError,
+                ) => {
+                    unreachable!("MerkleHelper doesn't deal with Noise sessions")
+                }
+            };
+
+        // A `Delete` grant only applies to the version of the record it was
+        // made for, so that it can't be replayed once the record changes.
+        let fingerprint = merkle.fingerprint();
+        if let TenantRecordOperation::Delete {
+            fingerprint: expected,
+        } = request.grant.operation
+        {
+            if expected != fingerprint {
+                return Response::RecordChanged;
+            }
+        }
+
+        // Like App requests, this appends a log entry even if the record
+        // doesn't change. The result isn't released until the entry commits,
+        // which ensures this is still the group's leader.
+        let (status, change) =
+            app::tenant_record_operation(request.grant.operation, record.as_deref());
+        let (root_hash, delta) = merkle.update_overlay(&mut self.platform, change);
+
+        let last_entry = leader.log.last();
+        let entry = LogEntryBuilder {
+            hsm: self.persistent.id,
+            realm: request.realm,
+            group: request.group,
+            index: last_entry.entry.index.next(),
+            partition: Some(Partition {
+                range: last_entry.entry.partition.as_ref().unwrap().range.clone(),
+                root_hash,
+            }),
+            transferring: last_entry.entry.transferring.clone(),
+            prev_mac: last_entry.entry.entry_mac.clone(),
+        }
+        .build(&self.realm_keys.mac);
+
+        leader.log.append(entry.clone(), None);
+
+        let clock = self
+            .volatile
+            .groups
+            .get(&request.group)
+            .expect("already verified group membership")
+            .at;
+        Response::Ok {
+            entry,
+            delta,
+            clock,
+            status,
+            fingerprint,
+        }
+    }
 }
 
 fn is_realm_member(
@@ -1510,18 +1668,35 @@ fn handle_app_request(
             Err(response) => return response.into(),
         };
 
-    // This should be enforced by the load balancer, but double check.
+    // The load balancer should have rejected these, but the agent is
+    // untrusted, so double check.
     match &request.encrypted {
         NoiseRequest::Transport { ciphertext } => {
-            assert!(ciphertext.len() <= BODY_SIZE_LIMIT);
+            if ciphertext.len() > BODY_SIZE_LIMIT {
+                warn!(len = ciphertext.len(), "oversized transport ciphertext");
+                return AppResponse::DecodingError;
+            }
         }
         NoiseRequest::Handshake { handshake } => {
-            assert_eq!(handshake.client_ephemeral_public.len(), 32);
-            assert!(handshake.payload_ciphertext.len() <= BODY_SIZE_LIMIT);
+            if handshake.client_ephemeral_public.len() != 32 {
+                warn!(
+                    len = handshake.client_ephemeral_public.len(),
+                    "invalid client ephemeral public key"
+                );
+                return AppResponse::SessionError;
+            }
+            if handshake.payload_ciphertext.len() > BODY_SIZE_LIMIT {
+                warn!(
+                    len = handshake.payload_ciphertext.len(),
+                    "oversized handshake ciphertext"
+                );
+                return AppResponse::DecodingError;
+            }
         }
     }
 
     let (noise, secrets_request) = match NoiseHelper::decode(
+        SessionPartition::new(request.tenant, &request.record_id),
         request.record_id.clone(),
         request.session_id,
         &request.encrypted,
@@ -1535,6 +1710,13 @@ fn handle_app_request(
 
     req_name_out.replace(secrets_req_name(&secrets_request));
 
+    if request.quota_exceeded && matches!(secrets_request, SecretsRequest::Register2(_)) {
+        // Re-registering an existing secret doesn't count against the quota.
+        if app::registration_status(record.as_deref()) == RegistrationStatus::NotRegistered {
+            return AppResponse::QuotaExceeded;
+        }
+    }
+
     let (secrets_response, event, change) = app::process(secrets_request, record.as_deref(), rng);
 
     let secrets_response = noise.encode(secrets_response, &mut leader.sessions);
@@ -1632,6 +1814,15 @@ impl<'a> MerkleHelper<'a> {
         ))
     }
 
+    /// Returns the fingerprint of the record as it's currently stored, or
+    /// `None` if it doesn't exist.
+    fn fingerprint(&self) -> Option<RecordFingerprint> {
+        self.latest_proof
+            .leaf
+            .as_ref()
+            .map(|leaf| RecordFingerprint::of_leaf(&leaf.value))
+    }
+
     fn update_overlay(
         self,
         rng: &mut impl CryptoRng,
@@ -1671,6 +1862,7 @@ impl<'a> MerkleHelper<'a> {
 
 /// Used in [`handle_app_request`].
 struct NoiseHelper {
+    partition: SessionPartition,
     record_id: RecordId,
     session_id: SessionId,
     state: NoiseHelperState,
@@ -1683,6 +1875,7 @@ enum NoiseHelperState {
 
 impl NoiseHelper {
     fn decode(
+        partition: SessionPartition,
         record_id: RecordId,
         session_id: SessionId,
         encrypted: &NoiseRequest,
@@ -1721,6 +1914,7 @@ impl NoiseHelper {
         };
         Ok((
             Self {
+                partition,
                 record_id,
                 session_id,
                 state: message,
@@ -1758,7 +1952,7 @@ impl NoiseHelper {
             }
         };
 
-        sessions.insert((self.record_id, self.session_id), transport);
+        sessions.insert(self.partition, (self.record_id, self.session_id), transport);
         response
     }
 }
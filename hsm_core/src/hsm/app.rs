@@ -3,7 +3,7 @@ extern crate alloc;
 use alloc::boxed::Box;
 use alloc::format;
 use alloc::vec::Vec;
-use hsm_api::{AppResultType, GuessState};
+use hsm_api::{AppResultType, GuessState, RegistrationStatus, TenantRecordOperation};
 use marshalling::{DeserializationError, SerializationError};
 use serde::{Deserialize, Serialize};
 use subtle::ConstantTimeEq;
@@ -282,6 +282,42 @@ pub fn process(
     (result, event, rc)
 }
 
+/// Returns the record's registration status.
+pub fn registration_status(record_val: Option<&[u8]>) -> RegistrationStatus {
+    let user_record = match record_val {
+        None => UserRecord::new(),
+        Some(data) => unmarshal_user_record(data).expect("TODO"),
+    };
+    match user_record.registration_state {
+        RegistrationState::NotRegistered => RegistrationStatus::NotRegistered,
+        RegistrationState::Registered(_) => RegistrationStatus::Registered,
+        RegistrationState::NoGuesses => RegistrationStatus::NoGuesses,
+    }
+}
+
+/// Performs an operation on the record on behalf of the tenant, rather than
+/// in response to a client request. The caller must have checked the
+/// tenant's grant. Returns the record's registration status before the
+/// operation and the change to make to the record, if any.
+pub fn tenant_record_operation(
+    operation: TenantRecordOperation,
+    record_val: Option<&[u8]>,
+) -> (RegistrationStatus, Option<RecordChange>) {
+    let status = registration_status(record_val);
+    let change = match operation {
+        TenantRecordOperation::GetStatus => None,
+        TenantRecordOperation::Delete { .. } => {
+            let user_record_in = match record_val {
+                None => UserRecord::new(),
+                Some(data) => unmarshal_user_record(data).expect("TODO"),
+            };
+            let (_, user_record_out) = delete(user_record_in);
+            user_record_out.map(|u| RecordChange::Update(marshal_user_record(&u).expect("TODO")))
+        }
+    };
+    (status, change)
+}
+
 // A serialized NoGuesses is very small compared to a Registered. When the leaf
 // changes from Registered to NoGuesses, the size of the leaf can leak this
 // state before the change is committed. We pad the leaf to this size to hide
@@ -337,7 +373,7 @@ fn unmarshal_user_record(padded: &[u8]) -> Result<UserRecord, DeserializationErr
 }
 
 #[cfg(test)]
-mod tests {
+pub(super) mod tests {
     use hsm_api::GuessState;
     use juicebox_marshalling::to_be4;
     use juicebox_realm_api::{
@@ -428,17 +464,7 @@ mod tests {
 
     #[test]
     fn test_register2() {
-        let request = Register2Request {
-            version: version(),
-            oprf_private_key: oprf_private_key(),
-            oprf_signed_public_key: oprf_signed_public_key(),
-            unlock_key_commitment: unlock_key_commitment(),
-            unlock_key_tag: unlock_key_tag(),
-            encryption_key_scalar_share: user_secret_encryption_key_scalar_share(),
-            encrypted_secret: encrypted_user_secret(),
-            encrypted_secret_commitment: encrypted_user_secret_commitment(),
-            policy: policy(),
-        };
+        let request = register2_request();
         let user_record_in = UserRecord::new();
         let expected_user_record_out = registered_record(0);
         let (response, user_record_out) = register2(request, user_record_in);
@@ -682,6 +708,61 @@ mod tests {
         assert_eq!(user_record_out, Some(expected_user_record_out));
     }
 
+    #[test]
+    fn test_tenant_record_operation() {
+        use TenantRecordOperation::GetStatus;
+        // The fingerprint is checked by the caller.
+        let delete = TenantRecordOperation::Delete { fingerprint: None };
+
+        assert!(matches!(
+            tenant_record_operation(delete, None),
+            (RegistrationStatus::NotRegistered, None)
+        ));
+
+        let not_registered = marshal_user_record(&UserRecord::new()).unwrap();
+        assert!(matches!(
+            tenant_record_operation(delete, Some(&not_registered)),
+            (RegistrationStatus::NotRegistered, None)
+        ));
+
+        let registered = marshal_user_record(&registered_record(2)).unwrap();
+        assert!(matches!(
+            tenant_record_operation(GetStatus, Some(&registered)),
+            (RegistrationStatus::Registered, None)
+        ));
+        let (RegistrationStatus::Registered, Some(RecordChange::Update(changed))) =
+            tenant_record_operation(delete, Some(&registered))
+        else {
+            panic!("expected registered record to be deleted");
+        };
+        assert_eq!(not_registered, changed);
+
+        let no_guesses = marshal_user_record(&UserRecord {
+            registration_state: RegistrationState::NoGuesses,
+        })
+        .unwrap();
+        assert!(matches!(
+            tenant_record_operation(GetStatus, Some(&no_guesses)),
+            (RegistrationStatus::NoGuesses, None)
+        ));
+    }
+
+    /// Returns a valid registration request. This is also used by the HSM
+    /// tests.
+    pub(in crate::hsm) fn register2_request() -> Register2Request {
+        Register2Request {
+            version: version(),
+            oprf_private_key: oprf_private_key(),
+            oprf_signed_public_key: oprf_signed_public_key(),
+            unlock_key_commitment: unlock_key_commitment(),
+            unlock_key_tag: unlock_key_tag(),
+            encryption_key_scalar_share: user_secret_encryption_key_scalar_share(),
+            encrypted_secret: encrypted_user_secret(),
+            encrypted_secret_commitment: encrypted_user_secret_commitment(),
+            policy: policy(),
+        }
+    }
+
     fn registered_record(guess_count: u16) -> UserRecord {
         UserRecord {
             registration_state: RegistrationState::Registered(Box::new(RegisteredState {
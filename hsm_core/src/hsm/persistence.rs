@@ -0,0 +1,678 @@
+//! The HSM's persistent state and how it's stored in NVRAM.
+//!
+//! The state is stored in a versioned envelope: [`MAGIC`], the schema version
+//! as a big-endian `u16`, the CBOR-encoded state, and finally a Blake2s-256
+//! digest of everything before it. HSMs that predate the envelope stored just
+//! the CBOR-encoded state and the digest. That's schema version 1, which is
+//! recognized by the missing magic: its CBOR map can't start with `J`.
+//!
+//! The HSM works with [`PersistentState`] in memory, and converts it to and
+//! from the current schema's `Stored*` types to read and write NVRAM. State in
+//! an older schema is migrated when it's read, and it's written back in the
+//! current schema on the next change. Each old schema is frozen in its own
+//! module here, so that changing the current types can't change how old state
+//! decodes. To change the schema:
+//!
+//! 1. Copy the current `Stored*` types into a new `vN` module, where N is the
+//!    current [`CURRENT_VERSION`].
+//! 2. Change the `Stored*` types and bump [`CURRENT_VERSION`].
+//! 3. Add a migration from the `vN` types to [`PersistentState`], and re-point
+//!    the older migrations at `vN`.
+//! 4. Check that the largest state in every older schema still fits in
+//!    [`MAX_NVRAM_SIZE`] once migrated.
+//!
+//! An HSM can't read state written in a newer schema, so downgrading the HSM
+//! software across a schema change fails with
+//! [`PersistenceError::UnsupportedVersion`].
+
+extern crate alloc;
+
+use alloc::collections::BTreeSet;
+use alloc::vec;
+use alloc::vec::Vec;
+use blake2::Blake2s256;
+use core::cmp::min;
+use digest::Digest;
+use serde::{Deserialize, Serialize};
+
+use super::configuration::GroupConfiguration;
+use super::PersistenceError;
+use crate::hal::MAX_NVRAM_SIZE;
+use crate::hash::{HashExt, HashMap};
+use hsm_api::{
+    EntryMac, GroupId, HsmId, HsmRealmStatement, LogIndex, NVRamStatus, CONFIGURATION_LIMIT,
+    GROUPS_LIMIT,
+};
+use juicebox_marshalling::{self as marshalling, bytes};
+use juicebox_realm_api::types::RealmId;
+
+/// Starts every NVRAM image written in schema version 2 or later.
+pub const MAGIC: [u8; 4] = *b"JBNV";
+
+/// The schema version that this code writes.
+pub const CURRENT_VERSION: u16 = 3;
+
+const HEADER_LEN: usize = MAGIC.len() + 2;
+const DIGEST_LEN: usize = 32;
+
+#[derive(Debug)]
+pub struct PersistentState {
+    pub(super) id: HsmId,
+    pub(super) realm: Option<PersistentRealmState>,
+}
+
+#[derive(Debug)]
+pub struct PersistentRealmState {
+    pub(super) id: RealmId,
+    pub(super) statement: HsmRealmStatement,
+    pub(super) groups: HashMap<GroupId, PersistentGroupState>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PersistentGroupState {
+    pub(super) configuration: GroupConfiguration,
+    pub(super) captured: Option<(LogIndex, EntryMac)>,
+}
+
+// The current schema. These are tuple structs, which CBOR encodes as arrays,
+// so the field names don't take up NVRAM.
+
+#[derive(Deserialize, Serialize)]
+struct StoredState(HsmId, Option<StoredRealm>);
+
+#[derive(Deserialize, Serialize)]
+struct StoredRealm(
+    RealmId,
+    HsmRealmStatement,
+    // The HSM IDs in any group's configuration, sorted and without
+    // duplicates. Groups usually share most of their members, so each ID is
+    // stored once here, and groups refer to them by index.
+    Vec<HsmId>,
+    // Sorted by group ID.
+    Vec<StoredGroup>,
+);
+
+#[derive(Deserialize, Serialize)]
+struct StoredGroup(
+    GroupId,
+    // Indexes into the realm's HSM IDs, in sorted order. These are stored as a
+    // byte string, so each takes one byte. An HSM can't know about more than
+    // 256 others anyway, as their IDs wouldn't fit in NVRAM.
+    #[serde(with = "bytes")] Vec<u8>,
+    Option<(LogIndex, EntryMac)>,
+);
+
+/// The state from an NVRAM image, along with the schema version it was
+/// written in.
+#[derive(Debug)]
+pub struct Decoded {
+    pub version: u16,
+    /// The state, migrated to the current schema.
+    pub state: PersistentState,
+}
+
+/// Encodes the state in the current schema, including the trailing digest.
+pub fn encode(state: &PersistentState) -> Vec<u8> {
+    encode_stored(&to_stored(state, None).expect("too many HSMs"))
+}
+
+fn encode_stored(stored: &StoredState) -> Vec<u8> {
+    let mut data = Vec::from(MAGIC);
+    data.extend(CURRENT_VERSION.to_be_bytes());
+    data.extend(marshalling::to_vec(stored).expect("failed to serialize state"));
+    let digest = Blake2s256::digest(&data);
+    data.extend(digest);
+    data
+}
+
+/// Decodes an NVRAM image written in any known schema, migrating it to the
+/// current one.
+///
+/// Returns `None` if the image is empty, meaning nothing has been written
+/// yet.
+pub fn decode(image: &[u8]) -> Result<Option<Decoded>, PersistenceError> {
+    if image.is_empty() {
+        return Ok(None);
+    }
+    if image.len() < DIGEST_LEN {
+        return Err(PersistenceError::InvalidChecksum);
+    }
+    let (data, stored_digest) = image.split_at(image.len() - DIGEST_LEN);
+    if stored_digest != Blake2s256::digest(data).as_slice() {
+        return Err(PersistenceError::InvalidChecksum);
+    }
+
+    let (version, body) = match data.strip_prefix(&MAGIC) {
+        None => (1, data),
+        Some(rest) if data.len() >= HEADER_LEN => {
+            (u16::from_be_bytes([rest[0], rest[1]]), &rest[2..])
+        }
+        Some(_) => return Err(PersistenceError::InvalidChecksum),
+    };
+
+    let state = match version {
+        // Version 2 only added the envelope, so they share a schema.
+        1 | 2 => migrate_v2(deserialize::<v2::PersistentState>(body)?),
+        CURRENT_VERSION => from_stored(deserialize::<StoredState>(body)?)?,
+        _ => return Err(PersistenceError::UnsupportedVersion(version)),
+    };
+    Ok(Some(Decoded { version, state }))
+}
+
+fn deserialize<'a, T: Deserialize<'a>>(body: &'a [u8]) -> Result<T, PersistenceError> {
+    marshalling::from_slice(body).map_err(PersistenceError::Deserialization)
+}
+
+/// Converts the state to the current schema, optionally with an extra group.
+/// Returns `None` if the groups have too many HSMs between them.
+fn to_stored(
+    state: &PersistentState,
+    extra: Option<(GroupId, &GroupConfiguration)>,
+) -> Option<StoredState> {
+    let Some(realm) = &state.realm else {
+        return Some(StoredState(state.id, None));
+    };
+    let groups = || {
+        realm
+            .groups
+            .iter()
+            .map(|(id, group)| (*id, &group.configuration, group.captured.clone()))
+            .chain(extra.map(|(id, configuration)| (id, configuration, None)))
+    };
+
+    let hsms: Vec<HsmId> = groups()
+        .flat_map(|(_, configuration, _)| configuration)
+        .copied()
+        .collect::<BTreeSet<HsmId>>()
+        .into_iter()
+        .collect();
+    if hsms.len() > usize::from(u8::MAX) + 1 {
+        return None;
+    }
+    let mut stored_groups: Vec<StoredGroup> = groups()
+        .map(|(id, configuration, captured)| {
+            let members = configuration
+                .into_iter()
+                .map(|hsm| u8::try_from(hsms.binary_search(hsm).unwrap()).unwrap())
+                .collect();
+            StoredGroup(id, members, captured)
+        })
+        .collect();
+    stored_groups.sort_unstable_by_key(|group| group.0);
+    Some(StoredState(
+        state.id,
+        Some(StoredRealm(
+            realm.id,
+            realm.statement.clone(),
+            hsms,
+            stored_groups,
+        )),
+    ))
+}
+
+fn from_stored(stored: StoredState) -> Result<PersistentState, PersistenceError> {
+    let StoredState(id, realm) = stored;
+    let realm = match realm {
+        None => None,
+        Some(StoredRealm(realm_id, statement, hsms, stored_groups)) => {
+            let mut groups = HashMap::with_capacity(stored_groups.len());
+            for StoredGroup(group_id, members, captured) in stored_groups {
+                let members = members
+                    .into_iter()
+                    .map(|index| hsms.get(usize::from(index)).copied())
+                    .collect::<Option<Vec<HsmId>>>()
+                    .ok_or(PersistenceError::InvalidState("HSM index out of range"))?;
+                let configuration = GroupConfiguration::from_sorted_including_local(members, &id)
+                    .map_err(PersistenceError::InvalidState)?;
+                groups.insert(
+                    group_id,
+                    PersistentGroupState {
+                        configuration,
+                        captured,
+                    },
+                );
+            }
+            Some(PersistentRealmState {
+                id: realm_id,
+                statement,
+                groups,
+            })
+        }
+    };
+    Ok(PersistentState { id, realm })
+}
+
+/// Migrates state from schema version 2 (or 1), which stored the in-memory
+/// types as they were.
+fn migrate_v2(state: v2::PersistentState) -> PersistentState {
+    PersistentState {
+        id: state.id,
+        realm: state.realm.map(|realm| PersistentRealmState {
+            id: realm.id,
+            statement: realm.statement,
+            groups: realm
+                .groups
+                .into_iter()
+                .map(|(id, group)| {
+                    (
+                        id,
+                        PersistentGroupState {
+                            configuration: group.configuration,
+                            captured: group.captured,
+                        },
+                    )
+                })
+                .collect(),
+        }),
+    }
+}
+
+/// Returns the encoded size of the state if every group had captured an entry
+/// with the largest index, which is as large as the state can get without
+/// joining more groups.
+fn worst_case_len(mut stored: StoredState) -> usize {
+    if let Some(realm) = &mut stored.1 {
+        for group in &mut realm.3 {
+            group.2 = Some(worst_case_captured());
+        }
+    }
+    encode_stored(&stored).len()
+}
+
+fn worst_case_captured() -> (LogIndex, EntryMac) {
+    (LogIndex(u64::MAX), EntryMac::from([0xff; 32]))
+}
+
+/// Returns the most that joining one more group can add to the encoded
+/// state, not counting its members' IDs: the group itself, plus the lengths
+/// of the realm's lists growing.
+fn worst_case_group_cost() -> usize {
+    let group = StoredGroup(
+        GroupId([0xff; 16]),
+        vec![u8::MAX; usize::from(CONFIGURATION_LIMIT)],
+        Some(worst_case_captured()),
+    );
+    marshalling::to_vec(&group).unwrap().len() + 2 * 2
+}
+
+/// Returns how much each HSM ID that's new to the HSM adds to the encoded
+/// state.
+fn hsm_id_cost() -> usize {
+    marshalling::to_vec(&HsmId([0xff; 16])).unwrap().len()
+}
+
+/// Returns the most that joining one more group can add to the encoded
+/// state: a group whose members are all new to the HSM.
+fn worst_case_group_len() -> usize {
+    worst_case_group_cost() + usize::from(CONFIGURATION_LIMIT) * hsm_id_cost()
+}
+
+/// Returns true if the HSM's NVRAM has room for it to join a group with the
+/// given configuration, even once all its groups have captured entries.
+pub(super) fn has_room_for_group(
+    state: &PersistentState,
+    configuration: &GroupConfiguration,
+) -> bool {
+    // The group ID doesn't matter, since they all take the same space.
+    match to_stored(state, Some((GroupId([0xff; 16]), configuration))) {
+        Some(stored) => worst_case_len(stored) <= MAX_NVRAM_SIZE,
+        None => false,
+    }
+}
+
+/// Returns how much NVRAM the state takes and how many more groups the HSM
+/// can join. This encodes the state a few times, so callers should cache it.
+pub(super) fn nvram_status(state: &PersistentState) -> NVRamStatus {
+    let stored = to_stored(state, None).expect("too many HSMs");
+    let used = encode_stored(&stored).len();
+    let groups = stored.1.as_ref().map_or(0, |realm| realm.3.len());
+    let worst_case = match stored {
+        StoredState(id, None) => {
+            // Joining a realm takes some space too.
+            let realm = StoredRealm(
+                RealmId([0xff; 16]),
+                HsmRealmStatement::from([0xff; 32]),
+                Vec::new(),
+                Vec::new(),
+            );
+            worst_case_len(StoredState(id, Some(realm)))
+        }
+        stored => worst_case_len(stored),
+    };
+    let room = MAX_NVRAM_SIZE.saturating_sub(worst_case);
+    let group_capacity = min(
+        usize::from(GROUPS_LIMIT).saturating_sub(groups),
+        room / worst_case_group_len(),
+    );
+    NVRamStatus {
+        used: u32::try_from(used).unwrap(),
+        limit: u32::try_from(MAX_NVRAM_SIZE).unwrap(),
+        group_capacity: u8::try_from(group_capacity).unwrap(),
+        free: u32::try_from(room).unwrap(),
+        group_cost: u32::try_from(worst_case_group_cost()).unwrap(),
+        hsm_id_cost: u32::try_from(hsm_id_cost()).unwrap(),
+    }
+}
+
+/// Schema version 1, written without an envelope. Frozen: don't change this.
+mod v1 {
+    use serde::{Deserialize, Serialize};
+
+    use super::GroupConfiguration;
+    use crate::hash::HashMap;
+    use hsm_api::{EntryMac, GroupId, HsmId, HsmRealmStatement, LogIndex};
+    use juicebox_realm_api::types::RealmId;
+
+    #[derive(Deserialize, Serialize)]
+    pub struct PersistentState {
+        pub id: HsmId,
+        pub realm: Option<PersistentRealmState>,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct PersistentRealmState {
+        pub id: RealmId,
+        pub statement: HsmRealmStatement,
+        pub groups: HashMap<GroupId, PersistentGroupState>,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct PersistentGroupState {
+        pub configuration: GroupConfiguration,
+        pub captured: Option<(LogIndex, EntryMac)>,
+    }
+}
+
+/// Schema version 2, which added the envelope to version 1's schema.
+/// Frozen: don't change this.
+mod v2 {
+    pub use super::v1::{PersistentGroupState, PersistentRealmState, PersistentState};
+
+    /// The most groups an HSM could join while this schema was current.
+    #[cfg(test)]
+    pub const GROUPS_LIMIT: u8 = 16;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn array_big<const N: usize>(i: u8) -> [u8; N] {
+        let mut r = [0xff; N];
+        r[N - 1] = 0xff - i;
+        r
+    }
+
+    fn configuration(local: &HsmId, members: impl Iterator<Item = HsmId>) -> GroupConfiguration {
+        let mut members: Vec<HsmId> = members.collect();
+        members.sort_unstable();
+        GroupConfiguration::from_sorted_including_local(members, local).unwrap()
+    }
+
+    // The largest state that could be written in schema versions 1 and 2: the
+    // most groups with the most HSMs each, using IDs and indexes that take the
+    // most space to encode.
+    fn largest_v2() -> v2::PersistentState {
+        let id = HsmId([0xff; 16]);
+        let mut groups = HashMap::new();
+        for group in 0..v2::GROUPS_LIMIT {
+            groups.insert(
+                GroupId(array_big(group)),
+                v2::PersistentGroupState {
+                    configuration: configuration(
+                        &id,
+                        (0..CONFIGURATION_LIMIT).map(|i| HsmId(array_big(i))),
+                    ),
+                    captured: Some((LogIndex(u64::MAX - 1), EntryMac::from([0xff; 32]))),
+                },
+            );
+        }
+        v2::PersistentState {
+            id,
+            realm: Some(v2::PersistentRealmState {
+                id: RealmId([0xff; 16]),
+                statement: HsmRealmStatement::from([0xff; 32]),
+                groups,
+            }),
+        }
+    }
+
+    // How HSMs wrote version 1 state.
+    fn encode_v1(state: &v1::PersistentState) -> Vec<u8> {
+        let mut data = marshalling::to_vec(state).unwrap();
+        let digest = Blake2s256::digest(&data);
+        data.extend(digest);
+        data
+    }
+
+    // How HSMs wrote version 2 state.
+    fn encode_v2(state: &v2::PersistentState) -> Vec<u8> {
+        let mut data = Vec::from(MAGIC);
+        data.extend(2u16.to_be_bytes());
+        data.extend(marshalling::to_vec(state).unwrap());
+        let digest = Blake2s256::digest(&data);
+        data.extend(digest);
+        data
+    }
+
+    fn empty_realm(id: HsmId) -> PersistentState {
+        PersistentState {
+            id,
+            realm: Some(PersistentRealmState {
+                id: RealmId([0xff; 16]),
+                statement: HsmRealmStatement::from([0xff; 32]),
+                groups: HashMap::new(),
+            }),
+        }
+    }
+
+    // Returns true if the costs in the state's NVRAM status say there's room
+    // for the group, working it out the way `StatusResponse::has_room_for_groups`
+    // does.
+    fn reported_room(state: &PersistentState, configuration: &GroupConfiguration) -> bool {
+        let status = nvram_status(state);
+        let known: BTreeSet<HsmId> = state
+            .realm
+            .iter()
+            .flat_map(|realm| realm.groups.values())
+            .flat_map(|group| &group.configuration)
+            .copied()
+            .collect();
+        let new_ids = configuration
+            .into_iter()
+            .filter(|hsm| !known.contains(hsm))
+            .count();
+        status.group_cost + u32::try_from(new_ids).unwrap() * status.hsm_id_cost <= status.free
+    }
+
+    fn add_group(state: &mut PersistentState, configuration: GroupConfiguration) {
+        let realm = state.realm.as_mut().unwrap();
+        let id = GroupId(array_big(u8::try_from(realm.groups.len()).unwrap()));
+        realm.groups.insert(
+            id,
+            PersistentGroupState {
+                configuration,
+                captured: Some(worst_case_captured()),
+            },
+        );
+    }
+
+    // Verify that the largest state fits in NVRAM in every version, so that
+    // migrating it can't fail.
+    #[test]
+    fn persistent_data_size() {
+        let v1 = encode_v1(&largest_v2());
+        assert!(
+            v1.len() <= MAX_NVRAM_SIZE,
+            "version 1 persistent state is {} bytes",
+            v1.len()
+        );
+
+        let v2 = encode_v2(&largest_v2());
+        assert!(
+            v2.len() <= MAX_NVRAM_SIZE,
+            "version 2 persistent state is {} bytes",
+            v2.len()
+        );
+
+        let current = encode(&migrate_v2(largest_v2()));
+        assert!(
+            current.len() <= MAX_NVRAM_SIZE,
+            "version {CURRENT_VERSION} persistent state is {} bytes",
+            current.len()
+        );
+        assert!(current.len() < v2.len());
+    }
+
+    // Verify that the HSM can join GROUPS_LIMIT groups when they share their
+    // members, as they usually do.
+    #[test]
+    fn shared_members_fit_groups_limit() {
+        let id = HsmId([0xff; 16]);
+        let others: Vec<HsmId> = (1..2 * CONFIGURATION_LIMIT)
+            .map(|i| HsmId(array_big(i)))
+            .collect();
+        let mut state = empty_realm(id);
+        for group in 0..GROUPS_LIMIT {
+            let start = usize::from(group % CONFIGURATION_LIMIT);
+            let members = others[start..start + usize::from(CONFIGURATION_LIMIT - 1)]
+                .iter()
+                .copied()
+                .chain([id]);
+            let configuration = configuration(&id, members);
+            assert!(has_room_for_group(&state, &configuration), "group {group}");
+            assert!(reported_room(&state, &configuration), "group {group}");
+            add_group(&mut state, configuration);
+        }
+        let image = encode(&state);
+        assert!(image.len() <= MAX_NVRAM_SIZE, "{} bytes", image.len());
+        assert_eq!(0, nvram_status(&state).group_capacity);
+    }
+
+    // Verify that the NVRAM budget is enforced when every group has different
+    // members, and that the reported capacity is accurate.
+    #[test]
+    fn distinct_members_limited_by_nvram() {
+        let id = HsmId([0xff; 16]);
+        let mut state = empty_realm(id);
+        let mut next_hsm = 0u8;
+        loop {
+            let status = nvram_status(&state);
+            let members = (1..CONFIGURATION_LIMIT).map(|_| {
+                next_hsm += 1;
+                HsmId(array_big(next_hsm))
+            });
+            let configuration = configuration(&id, members.chain([id]));
+            let has_room = has_room_for_group(&state, &configuration);
+            if status.group_capacity > 0 {
+                assert!(has_room);
+            }
+            // The reported costs never promise more room than there is.
+            if reported_room(&state, &configuration) {
+                assert!(has_room);
+            }
+            if !has_room {
+                break;
+            }
+            add_group(&mut state, configuration);
+            let image = encode(&state);
+            assert!(image.len() <= MAX_NVRAM_SIZE, "{} bytes", image.len());
+        }
+        let groups = state.realm.as_ref().unwrap().groups.len();
+        assert!(groups >= usize::from(v2::GROUPS_LIMIT), "{groups} groups");
+        assert!(groups < usize::from(GROUPS_LIMIT), "{groups} groups");
+        assert_eq!(0, nvram_status(&state).group_capacity);
+    }
+
+    #[test]
+    fn decode_v1() {
+        let image = encode_v1(&largest_v2());
+        let decoded = decode(&image).unwrap().unwrap();
+        assert_eq!(1, decoded.version);
+        assert_eq!(HsmId([0xff; 16]), decoded.state.id);
+        let realm = decoded.state.realm.as_ref().unwrap();
+        assert_eq!(RealmId([0xff; 16]), realm.id);
+        assert_eq!(usize::from(v2::GROUPS_LIMIT), realm.groups.len());
+        let group = &realm.groups[&GroupId(array_big(3))];
+        assert_eq!(
+            usize::from(CONFIGURATION_LIMIT),
+            group.configuration.to_vec().len()
+        );
+        assert_eq!(
+            Some(LogIndex(u64::MAX - 1)),
+            group.captured.as_ref().map(|c| c.0)
+        );
+
+        // Version 2 holds the same state.
+        let decoded2 = decode(&encode_v2(&largest_v2())).unwrap().unwrap();
+        assert_eq!(2, decoded2.version);
+        assert_eq!(encode(&decoded.state), encode(&decoded2.state));
+    }
+
+    #[test]
+    fn decode_current() {
+        let decoded = decode(&encode_v1(&largest_v2())).unwrap().unwrap();
+
+        // Once re-encoded, it's in the current version with the same
+        // contents.
+        let image = encode(&decoded.state);
+        assert!(image.starts_with(&MAGIC));
+        let decoded2 = decode(&image).unwrap().unwrap();
+        assert_eq!(CURRENT_VERSION, decoded2.version);
+        assert_eq!(image, encode(&decoded2.state));
+
+        let state = PersistentState {
+            id: HsmId([1; 16]),
+            realm: None,
+        };
+        let decoded = decode(&encode(&state)).unwrap().unwrap();
+        assert_eq!(CURRENT_VERSION, decoded.version);
+        assert_eq!(state.id, decoded.state.id);
+        assert!(decoded.state.realm.is_none());
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert!(decode(&[]).unwrap().is_none());
+        assert!(matches!(
+            decode(&[1, 2, 3]),
+            Err(PersistenceError::InvalidChecksum)
+        ));
+
+        let mut image = encode(&PersistentState {
+            id: HsmId([1; 16]),
+            realm: None,
+        });
+        image[HEADER_LEN] ^= 1;
+        assert!(matches!(
+            decode(&image),
+            Err(PersistenceError::InvalidChecksum)
+        ));
+
+        // A group that refers to an HSM that's not in the realm's list.
+        let stored = StoredState(
+            HsmId([1; 16]),
+            Some(StoredRealm(
+                RealmId([2; 16]),
+                HsmRealmStatement::from([3; 32]),
+                vec![HsmId([1; 16])],
+                vec![StoredGroup(GroupId([4; 16]), vec![0, 1], None)],
+            )),
+        );
+        assert!(matches!(
+            decode(&encode_stored(&stored)),
+            Err(PersistenceError::InvalidState(_))
+        ));
+
+        // State from a newer version of the HSM.
+        let mut data = Vec::from(MAGIC);
+        data.extend((CURRENT_VERSION + 1).to_be_bytes());
+        data.extend([0xa0]);
+        let digest = Blake2s256::digest(&data);
+        data.extend(digest);
+        assert!(matches!(
+            decode(&data),
+            Err(PersistenceError::UnsupportedVersion(v)) if v == CURRENT_VERSION + 1
+        ));
+    }
+}
@@ -12,59 +12,18 @@ use juicebox_noise::client::Handshake;
 use juicebox_realm_api::requests::DeleteResponse;
 use juicebox_realm_api::types::RealmId;
 
-use super::super::hal::MAX_NVRAM_SIZE;
+use super::app::tests::register2_request;
 use super::*;
 use hsm_api::{
     CancelPreparedTransferRequest, CancelPreparedTransferResponse, CaptureNextRequest,
     CaptureNextResponse, CommitRequest, CommitResponse, CommitState, CompleteTransferRequest,
-    CompleteTransferResponse, EntryMac, GroupId, GuessState, HsmId, LogIndex,
-    PrepareTransferRequest, PrepareTransferResponse, PreparedTransfer, TransferInProofs,
-    TransferInRequest, TransferInResponse, TransferOutRequest, TransferOutResponse,
-    TransferStatement, TransferStatementRequest, TransferStatementResponse, CONFIGURATION_LIMIT,
+    CompleteTransferResponse, EntryMac, GroupId, GuessState, HsmId, HsmRealmStatement, LogIndex,
+    PrepareTransferRequest, PrepareTransferResponse, PreparedTransfer, TenantRecordGrant,
+    TenantTag, TransferInProofs, TransferInRequest, TransferInResponse, TransferOutRequest,
+    TransferOutResponse, TransferStatement, TransferStatementRequest, TransferStatementResponse,
+    CONFIGURATION_LIMIT,
 };
 
-fn array_big<const N: usize>(i: u8) -> [u8; N] {
-    let mut r = [0xff; N];
-    r[N - 1] = 0xff - i;
-    r
-}
-
-// Verify that a PersistentState with GROUPS_LIMIT groups with
-// CONFIGURATION_LIMIT HSMs each fits in the NVRAM limit.
-#[test]
-fn persistent_data_size() {
-    let id = HsmId([0xff; 16]);
-    let group = PersistentGroupState {
-        configuration: GroupConfiguration::from_sorted_including_local(
-            (0..CONFIGURATION_LIMIT)
-                .map(|i| HsmId(array_big(i)))
-                .rev()
-                .collect::<Vec<HsmId>>(),
-            &id,
-        )
-        .unwrap(),
-        captured: Some((LogIndex(u64::MAX - 1), EntryMac::from([0xff; 32]))),
-    };
-    let mut groups = HashMap::new();
-    for id in 0..GROUPS_LIMIT {
-        groups.insert(GroupId(array_big(id)), group.clone());
-    }
-    let p = PersistentState {
-        id,
-        realm: Some(PersistentRealmState {
-            id: RealmId([0xff; 16]),
-            statement: HsmRealmStatement::from([0xff; 32]),
-            groups,
-        }),
-    };
-    let s = marshalling::to_vec(&p).unwrap();
-    assert!(
-        s.len() < MAX_NVRAM_SIZE,
-        "serialized persistent state is {} bytes",
-        s.len()
-    );
-}
-
 fn make_leader_log() -> (LeaderLog, [EntryMac; 3]) {
     let hsm = HsmId([8; 16]);
     let e = LogEntry {
@@ -676,6 +635,312 @@ fn app_request_spots_future_log() {
     );
 }
 
+#[test]
+fn app_request_enforces_quota() {
+    let mut cluster = TestCluster::new(1);
+    let (realm, group) = (cluster.realm, cluster.group);
+    let record_id = RecordId([6; 32]);
+    let register = || SecretsRequest::Register2(Box::new(register2_request()));
+    let mut request = |req, quota_exceeded| {
+        let (_, res) = cluster.hsms[0].app_request_with_quota(
+            &cluster.store,
+            realm,
+            group,
+            record_id.clone(),
+            req,
+            quota_exceeded,
+        );
+        if let AppResponse::Ok { entry, delta } = &res {
+            cluster.store.append(group, entry.clone(), delta.clone());
+        }
+        res
+    };
+
+    // New registrations are rejected once the tenant is over its quota.
+    let res = request(register(), true);
+    assert!(matches!(res, AppResponse::QuotaExceeded), "{res:?}");
+    // Other requests aren't affected.
+    let res = request(SecretsRequest::Delete, true);
+    assert!(matches!(res, AppResponse::Ok { .. }), "{res:?}");
+
+    let res = request(register(), false);
+    assert!(matches!(res, AppResponse::Ok { .. }), "{res:?}");
+    // Re-registering an existing secret doesn't add to the tenant's usage.
+    let res = request(register(), true);
+    assert!(matches!(res, AppResponse::Ok { .. }), "{res:?}");
+}
+
+#[test]
+fn app_request_rejects_malformed_noise() {
+    let mut cluster = TestCluster::new(1);
+    let (realm, group) = (cluster.realm, cluster.group);
+    let record_id = RecordId([7; 32]);
+    let public_key = cluster.hsms[0].public_key;
+
+    let mut request = |encrypted| {
+        let (proof, index) = read_proof(&cluster.store, group, &record_id);
+        let leader = cluster.leader(group).unwrap();
+        leader.hsm.handle_app(
+            &mut leader.metrics,
+            AppRequest {
+                realm,
+                group,
+                record_id: record_id.clone(),
+                session_id: SessionId(OsRng.next_u32()),
+                encrypted,
+                proof,
+                index,
+                quota_exceeded: false,
+                tenant: None,
+            },
+        )
+    };
+    let handshake = || {
+        let req = marshalling::to_vec(&SecretsRequest::Delete).unwrap();
+        Handshake::start(&public_key, &req, &mut OsRng).unwrap().1
+    };
+
+    // The load balancer should reject these, but the agent might not be
+    // well behaved, so the HSM returns an error rather than failing.
+    let mut short_key = handshake();
+    short_key.client_ephemeral_public.pop();
+    let res = request(NoiseRequest::Handshake {
+        handshake: short_key,
+    });
+    assert!(matches!(res, AppResponse::SessionError), "{res:?}");
+
+    let mut oversized = handshake();
+    oversized.payload_ciphertext = vec![0; BODY_SIZE_LIMIT + 1];
+    let res = request(NoiseRequest::Handshake {
+        handshake: oversized,
+    });
+    assert!(matches!(res, AppResponse::DecodingError), "{res:?}");
+
+    let res = request(NoiseRequest::Transport {
+        ciphertext: vec![0; BODY_SIZE_LIMIT + 1],
+    });
+    assert!(matches!(res, AppResponse::DecodingError), "{res:?}");
+}
+
+#[test]
+fn sessions_partitioned_by_tenant() {
+    let mut cluster = TestCluster::new(1);
+    let (realm, group) = (cluster.realm, cluster.group);
+    let acme = TenantTag::new("acme");
+    let other = TenantTag::new("other");
+
+    let mut handshake = |tenant: Option<TenantTag>, record_id: RecordId| {
+        let leader = cluster.leader(group).unwrap();
+        let (_, handshake) = Handshake::start(&leader.public_key, &[], &mut OsRng).unwrap();
+        let res = leader.hsm.handle_handshake(
+            &mut leader.metrics,
+            HandshakeRequest {
+                realm,
+                group,
+                record_id,
+                session_id: SessionId(OsRng.next_u32()),
+                handshake,
+                tenant,
+            },
+        );
+        assert!(matches!(res, HandshakeResponse::Ok { .. }), "{res:?}");
+    };
+
+    // While the cache has room, one tenant can take more than its
+    // partition's share.
+    for i in 0..12 {
+        handshake(Some(acme), RecordId([i; 32]));
+    }
+    // Without a tenant, sessions are partitioned by record ID prefix.
+    for _ in 0..3 {
+        handshake(None, RecordId([5; 32]));
+    }
+    // The cache is now full. The tenant is over its partition's limit, so it
+    // evicts its own sessions.
+    handshake(Some(acme), RecordId([12; 32]));
+    // Other tenants evict from the largest partition.
+    for i in 0..3 {
+        handshake(Some(other), RecordId([i; 32]));
+    }
+
+    let sessions = |partition, entries| SessionPartitionStatus { partition, entries };
+    let status = cluster.leader(group).unwrap().status();
+    assert_eq!(
+        status.realm.unwrap().groups[0]
+            .leader
+            .as_ref()
+            .unwrap()
+            .sessions,
+        Some(SessionCacheStatus {
+            entries: 15,
+            limit: 15,
+            partition_limit: 10,
+            partition_count: 3,
+            partitions: vec![
+                sessions(SessionPartition::Tenant(acme), 9),
+                sessions(SessionPartition::RecordPrefix(5), 3),
+                sessions(SessionPartition::Tenant(other), 3),
+            ],
+        })
+    );
+}
+
+#[test]
+fn tenant_record_requires_grant() {
+    let mut cluster = TestCluster::new(1);
+    let (realm, group) = (cluster.realm, cluster.group);
+    let record_id = RecordId([4; 32]);
+    let grant = |operation, signing_key: &[u8; 32]| {
+        TenantRecordGrant::sign(
+            &realm,
+            String::from("acme"),
+            String::from("admin@acme"),
+            record_id.clone(),
+            operation,
+            u64::MAX,
+            signing_key,
+        )
+    };
+
+    // Reading a record's status needs a grant too.
+    for operation in [
+        TenantRecordOperation::GetStatus,
+        TenantRecordOperation::Delete { fingerprint: None },
+    ] {
+        let res =
+            cluster.hsms[0].tenant_record(&cluster.store, realm, group, grant(operation, &[1; 32]));
+        assert!(matches!(res, TenantRecordResponse::Unauthorized), "{res:?}");
+    }
+
+    // A grant for one realm can't be used in another.
+    let other_realm = TenantRecordGrant::sign(
+        &RealmId([0xee; 16]),
+        String::from("acme"),
+        String::from("admin@acme"),
+        record_id.clone(),
+        TenantRecordOperation::Delete { fingerprint: None },
+        u64::MAX,
+        &TENANT_RECORD_SIGNING_KEY,
+    );
+    let res = cluster.hsms[0].tenant_record(&cluster.store, realm, group, other_realm);
+    assert!(matches!(res, TenantRecordResponse::Unauthorized), "{res:?}");
+
+    for operation in [
+        TenantRecordOperation::GetStatus,
+        TenantRecordOperation::Delete { fingerprint: None },
+    ] {
+        let res = cluster.hsms[0].tenant_record(
+            &cluster.store,
+            realm,
+            group,
+            grant(operation, &TENANT_RECORD_SIGNING_KEY),
+        );
+        let TenantRecordResponse::Ok {
+            entry,
+            delta,
+            status,
+            ..
+        } = res
+        else {
+            panic!("tenant_record failed {res:?}");
+        };
+        assert_eq!(status, RegistrationStatus::NotRegistered);
+        cluster.store.append(group, entry, delta);
+    }
+}
+
+#[test]
+fn tenant_record_delete_bound_to_fingerprint() {
+    let mut cluster = TestCluster::new(1);
+    let (realm, group) = (cluster.realm, cluster.group);
+    let record_id = RecordId([5; 32]);
+    let grant = |operation| {
+        TenantRecordGrant::sign(
+            &realm,
+            String::from("acme"),
+            String::from("admin@acme"),
+            record_id.clone(),
+            operation,
+            u64::MAX,
+            &TENANT_RECORD_SIGNING_KEY,
+        )
+    };
+    let register = |cluster: &mut TestCluster| {
+        let (_, res) = cluster.hsms[0].app_request(
+            &cluster.store,
+            realm,
+            group,
+            record_id.clone(),
+            SecretsRequest::Register2(Box::new(register2_request())),
+        );
+        let AppResponse::Ok { entry, delta } = res else {
+            panic!("register failed {res:?}");
+        };
+        cluster.store.append(group, entry, delta);
+    };
+    let get_status = |cluster: &mut TestCluster| {
+        let res = cluster.hsms[0].tenant_record(
+            &cluster.store,
+            realm,
+            group,
+            grant(TenantRecordOperation::GetStatus),
+        );
+        let TenantRecordResponse::Ok {
+            entry,
+            delta,
+            status,
+            fingerprint,
+        } = res
+        else {
+            panic!("tenant_record failed {res:?}");
+        };
+        cluster.store.append(group, entry, delta);
+        (status, fingerprint)
+    };
+
+    register(&mut cluster);
+    let (status, fingerprint) = get_status(&mut cluster);
+    assert_eq!(status, RegistrationStatus::Registered);
+    assert!(fingerprint.is_some());
+    let delete = grant(TenantRecordOperation::Delete { fingerprint });
+
+    // Re-registering changes the stored record, so the old grant no longer
+    // applies.
+    register(&mut cluster);
+    let res = cluster.hsms[0].tenant_record(&cluster.store, realm, group, delete.clone());
+    assert!(
+        matches!(res, TenantRecordResponse::RecordChanged),
+        "{res:?}"
+    );
+
+    let (_, fingerprint) = get_status(&mut cluster);
+    let res = cluster.hsms[0].tenant_record(
+        &cluster.store,
+        realm,
+        group,
+        grant(TenantRecordOperation::Delete { fingerprint }),
+    );
+    let TenantRecordResponse::Ok { entry, delta, .. } = res else {
+        panic!("tenant_record failed {res:?}");
+    };
+    cluster.store.append(group, entry, delta);
+    let (status, _) = get_status(&mut cluster);
+    assert_eq!(status, RegistrationStatus::NotRegistered);
+
+    // Replaying the grant after the user registers again is rejected too.
+    register(&mut cluster);
+    let res = cluster.hsms[0].tenant_record(
+        &cluster.store,
+        realm,
+        group,
+        grant(TenantRecordOperation::Delete { fingerprint }),
+    );
+    assert!(
+        matches!(res, TenantRecordResponse::RecordChanged),
+        "{res:?}"
+    );
+}
+
 #[test]
 fn capture_next_spots_diverged_log_no_inflight_reqs() {
     // During capture_next processing a leading HSM should spot that its in
@@ -1580,6 +1845,9 @@ fn test_cancel_prepare_transfer() {
     }
 }
 
+/// The private key that test HSMs accept tenant record grants from.
+const TENANT_RECORD_SIGNING_KEY: [u8; 32] = [9; 32];
+
 fn unpack_app_response(r: &AppResponse) -> (LogEntry, StoreDelta<DataHash>) {
     if let AppResponse::Ok { entry, delta } = r {
         (entry.clone(), delta.clone())
@@ -1807,7 +2075,10 @@ impl<'a> TestHsm<'a> {
             name: name.into(),
             tree_overlay_size: 15,
             max_sessions: 15,
+            max_sessions_per_partition: 10,
             metrics: MetricsReporting::Disabled,
+            tenant_record_keys: vec![TenantRecordGrant::public_key(&TENANT_RECORD_SIGNING_KEY)],
+            software_version: String::from("test"),
         };
         let public_key = keys.communication.1;
         let hsm = Hsm::new(opt, TestPlatform::default(), keys).unwrap();
@@ -1941,6 +2212,18 @@ impl<'a> TestHsm<'a> {
         group: GroupId,
         record_id: RecordId,
         req: SecretsRequest,
+    ) -> (Handshake, AppResponse) {
+        self.app_request_with_quota(store, realm, group, record_id, req, false)
+    }
+
+    fn app_request_with_quota(
+        &mut self,
+        store: &TestStore,
+        realm: RealmId,
+        group: GroupId,
+        record_id: RecordId,
+        req: SecretsRequest,
+        quota_exceeded: bool,
     ) -> (Handshake, AppResponse) {
         let req_bytes = marshalling::to_vec(&req).unwrap();
         let (handshake, req) = Handshake::start(&self.public_key, &req_bytes, &mut OsRng).unwrap();
@@ -1957,10 +2240,32 @@ impl<'a> TestHsm<'a> {
                     encrypted: NoiseRequest::Handshake { handshake: req },
                     proof,
                     index,
+                    quota_exceeded,
+                    tenant: None,
                 },
             ),
         )
     }
+
+    fn tenant_record(
+        &mut self,
+        store: &TestStore,
+        realm: RealmId,
+        group: GroupId,
+        grant: TenantRecordGrant,
+    ) -> TenantRecordResponse {
+        let (proof, index) = read_proof(store, group, &grant.record_id);
+        self.hsm.handle_tenant_record(
+            &mut self.metrics,
+            TenantRecordRequest {
+                realm,
+                group,
+                proof,
+                index,
+                grant,
+            },
+        )
+    }
 }
 
 // OwnershipTransfer deals with some the things the agent & coordinator deal
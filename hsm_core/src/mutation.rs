@@ -19,6 +19,9 @@ impl<T, F: OnMutationFinished<T>> MutationTracker<T, F> {
     pub fn mutate(&mut self) -> MutationGuard<'_, T, F> {
         MutationGuard { inner: self }
     }
+    pub fn on_finished(&self) -> &F {
+        &self.on_finished
+    }
 }
 
 impl<T, F: OnMutationFinished<T>> Deref for MutationTracker<T, F> {
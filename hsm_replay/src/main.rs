@@ -0,0 +1,522 @@
+//! Replays a trace of an agent's RPCs with its HSM, as recorded with the
+//! agent's `--hsm-trace-dir`, against a software HSM.
+//!
+//! The software HSM starts from a copy of the state the recording HSM had when
+//! the trace starts. Each recorded request is sent to it in order, and its
+//! response is compared with the recorded one. The first difference is
+//! reported, along with both responses.
+//!
+//! Only the HSM's NVRAM state is copied. Its role in each group, the log
+//! entries it has captured but not persisted, and its clients' sessions are
+//! only kept in memory, so the trace must start when the recording HSM
+//! booted, with the state from before it booted. A trace that starts later,
+//! because the agent was restarted or its oldest files were rotated away,
+//! diverges as soon as it relies on that in-memory state.
+//!
+//! Some responses depend on the HSM's randomness: new realm and group IDs,
+//! and the HSM's half of each Noise handshake. For those, only the kind of
+//! response is compared. The sessions set up by the recorded handshakes can't
+//! be recreated, so app requests in them fail on replay. Handshake and app
+//! requests whose kind of response differs are reported as session mismatches
+//! and don't stop the replay, but a group whose app requests failed is
+//! expected to diverge later, once the recorded log entries they produced are
+//! captured or committed.
+//!
+//! The replay also reports how long the HSM took for each type of request,
+//! next to the recorded times, so that it doubles as a benchmark. The recorded
+//! times include the transport, while the replayed times don't.
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use agent_core::hsm::trace::{trace_files, TraceError, TraceReader, TraceRecord};
+use hsm_api::rpc::{HsmRequest, HsmRequestContainer, HsmResponseContainer, HsmRpc};
+use hsm_api::TenantRecordKey;
+use hsm_core::hal::Platform;
+use hsm_core::hsm::Hsm;
+use juicebox_marshalling::{self as marshalling, DeserializationError};
+use software_hsm::host::new_hsm;
+use software_hsm::keys::KeyMaterial;
+use software_hsm::nvram::NVRamFaults;
+use software_hsm::sealing::{Sealer, SealingSecret};
+
+/// Replays a trace of an agent's RPCs with its HSM against a software HSM,
+/// and reports the first response that differs from the recorded one.
+#[derive(Debug, Parser)]
+#[command(version = build_info::clap!())]
+struct Args {
+    /// Trace directories written by the agent's `--hsm-trace-dir`, or
+    /// individual trace files, to replay in order.
+    #[arg(required = true, value_name = "TRACE")]
+    traces: Vec<PathBuf>,
+
+    /// The software HSM's state directory, holding its state from before it
+    /// booted at the start of the trace. The files are copied, not modified.
+    #[arg(short, long)]
+    state_dir: PathBuf,
+
+    /// The software HSM's name, which its files in the state directory are
+    /// named after.
+    #[arg(short, long)]
+    name: String,
+
+    /// Derive realm keys from this input (insecure).
+    #[arg(
+        short,
+        long,
+        required_unless_present_any = ["sealing_key_file", "sealing_passphrase_file"],
+    )]
+    key: Option<String>,
+
+    /// Unseal the state and realm keys with the 32-byte key in this file,
+    /// given in hex.
+    #[arg(long, value_name = "FILE", conflicts_with = "sealing_passphrase_file")]
+    sealing_key_file: Option<PathBuf>,
+
+    /// Unseal the state and realm keys with a key derived from the passphrase
+    /// on the first line of this file.
+    #[arg(long, value_name = "FILE")]
+    sealing_passphrase_file: Option<PathBuf>,
+
+    /// The HSM's `--tenant-record-key`s, so that it accepts the same tenant
+    /// record grants as when the trace was recorded.
+    #[arg(long = "tenant-record-key", value_name = "KEY")]
+    tenant_record_keys: Vec<TenantRecordKey>,
+
+    /// Keep replaying after a divergence, and report how many there were.
+    #[arg(long)]
+    keep_going: bool,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    match replay(&args) {
+        Ok(0) => ExitCode::SUCCESS,
+        Ok(_) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("ERROR: {err:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Returns the number of divergences.
+fn replay(args: &Args) -> anyhow::Result<usize> {
+    let sealer = match (&args.sealing_key_file, &args.sealing_passphrase_file) {
+        (Some(path), _) => Some(SealingSecret::from_key_file(path)?),
+        (None, Some(path)) => Some(SealingSecret::from_passphrase_file(path)?),
+        (None, None) => None,
+    }
+    .map(|secret| Arc::new(Sealer::new(secret)));
+
+    let keys = match (&args.key, &sealer) {
+        (Some(key), _) => KeyMaterial::insecure_derive(key)?,
+        (None, Some(sealer)) => {
+            let keys_file = args.state_dir.join(format!("{}.keys", args.name));
+            KeyMaterial::load(&keys_file, sealer)?
+                .ok_or_else(|| anyhow!("keys file {keys_file:?} doesn't exist"))?
+        }
+        (None, None) => unreachable!("clap requires one of these"),
+    };
+
+    // The HSM writes its NVRAM, so it runs on a copy.
+    let dir = tempfile::tempdir()?;
+    let nvram = args.state_dir.join(&args.name);
+    fs::copy(&nvram, dir.path().join(&args.name))
+        .with_context(|| format!("failed to copy NVRAM file {nvram:?}"))?;
+    let mut hsm = new_hsm(
+        dir.path().to_owned(),
+        args.name.clone(),
+        keys.realm_keys(),
+        NVRamFaults::default(),
+        sealer,
+        args.tenant_record_keys.clone(),
+    )
+    .map_err(|err| anyhow!("failed to load HSM state: {err:?}"))?;
+
+    let mut files = Vec::new();
+    for trace in &args.traces {
+        if trace.is_dir() {
+            files.extend(trace_files(trace)?);
+        } else {
+            files.push(trace.clone());
+        }
+    }
+
+    let mut stats: BTreeMap<&'static str, Stats> = BTreeMap::new();
+    let mut replayed = 0;
+    let mut unverified = 0;
+    let mut session_mismatches = 0;
+    let mut divergences = 0;
+    'files: for file in &files {
+        let reader = TraceReader::open(file).with_context(|| format!("failed to open {file:?}"))?;
+        for (index, record) in reader.enumerate() {
+            let record = match record {
+                Ok(record) => record,
+                Err(TraceError::Truncated) => {
+                    eprintln!("warning: {file:?} ends part way through record {index}");
+                    break;
+                }
+                Err(err) => {
+                    return Err(err).with_context(|| format!("failed to read {file:?}"));
+                }
+            };
+            replayed += 1;
+            let result = replay_one(&mut hsm, &record)
+                .with_context(|| format!("failed to replay record {index} of {file:?}"))?;
+
+            let stats = stats.entry(result.name).or_default();
+            stats.count += 1;
+            stats.recorded += record.duration;
+            stats.replayed += result.duration;
+
+            let Some(divergence) = result.divergence else {
+                if record.response.is_none() {
+                    unverified += 1;
+                }
+                continue;
+            };
+            if result.noise_session {
+                session_mismatches += 1;
+                println!(
+                    "Record {index} of {file:?}, a {} request sent at {}, got a \
+                    {} response on replay instead of {}.",
+                    result.name,
+                    DateTime::<Utc>::from(record.time).format("%+"),
+                    divergence.replayed_kind,
+                    divergence.recorded_kind,
+                );
+                continue;
+            }
+            divergences += 1;
+            println!(
+                "Record {index} of {file:?}, a {} request sent at {}, diverged.",
+                result.name,
+                DateTime::<Utc>::from(record.time).format("%+"),
+            );
+            println!("Recorded response:\n{}", divergence.recorded);
+            println!("Replayed response:\n{}", divergence.replayed);
+            println!();
+            if !args.keep_going {
+                break 'files;
+            }
+        }
+    }
+
+    println!(
+        "Replayed {replayed} requests from {} files, with {divergences} divergences.",
+        files.len()
+    );
+    if session_mismatches > 0 {
+        println!(
+            "{session_mismatches} handshake and app requests got a different kind of \
+            response, because their sessions couldn't be recreated. These aren't \
+            counted as divergences."
+        );
+    }
+    if unverified > 0 {
+        println!(
+            "{unverified} of them had no recorded response, because the transport failed, \
+            so they weren't compared."
+        );
+    }
+    println!();
+    println!(
+        "{:<24} {:>10} {:>16} {:>16}",
+        "request", "count", "recorded mean", "replayed mean"
+    );
+    for (name, stats) in &stats {
+        println!(
+            "{name:<24} {:>10} {:>16} {:>16}",
+            stats.count,
+            format!("{:?}", stats.recorded / stats.count),
+            format!("{:?}", stats.replayed / stats.count),
+        );
+    }
+    Ok(divergences)
+}
+
+#[derive(Default)]
+struct Stats {
+    count: u32,
+    recorded: Duration,
+    replayed: Duration,
+}
+
+struct Replayed {
+    name: &'static str,
+    noise_session: bool,
+    duration: Duration,
+    divergence: Option<Divergence>,
+}
+
+struct Divergence {
+    recorded_kind: String,
+    replayed_kind: String,
+    recorded: String,
+    replayed: String,
+}
+
+fn replay_one<P: Platform>(hsm: &mut Hsm<P>, record: &TraceRecord) -> anyhow::Result<Replayed> {
+    let request: HsmRequestContainer = marshalling::from_slice(&record.request)
+        .map_err(|err| anyhow!("failed to decode request: {err:?}"))?;
+    let name = request.req.name();
+
+    let start = Instant::now();
+    let response = hsm.handle_request(&record.request);
+    let duration = start.elapsed();
+
+    let Some(recorded) = &record.response else {
+        return Ok(Replayed {
+            name,
+            noise_session: is_noise_session(&request.req),
+            duration,
+            divergence: None,
+        });
+    };
+    let recorded = decode_response(&request.req, recorded)
+        .map_err(|err| anyhow!("failed to decode recorded response: {err:?}"))?;
+    let replayed = match response {
+        Ok(bytes) => decode_response(&request.req, &bytes)
+            .map_err(|err| anyhow!("failed to decode replayed response: {err:?}"))?,
+        Err(err) => Response {
+            kind: String::from("HsmError"),
+            encoded: Vec::new(),
+            debug: format!("{err:?}"),
+        },
+    };
+
+    let matches = if is_randomized(&request.req) || is_noise_session(&request.req) {
+        recorded.kind == replayed.kind
+    } else {
+        recorded.encoded == replayed.encoded
+    };
+    Ok(Replayed {
+        name,
+        noise_session: is_noise_session(&request.req),
+        duration,
+        divergence: (!matches).then_some(Divergence {
+            recorded_kind: recorded.kind,
+            replayed_kind: replayed.kind,
+            recorded: recorded.debug,
+            replayed: replayed.debug,
+        }),
+    })
+}
+
+/// Returns true for requests whose responses depend on the HSM's randomness.
+fn is_randomized(request: &HsmRequest) -> bool {
+    matches!(request, HsmRequest::NewRealm(_) | HsmRequest::NewGroup(_))
+}
+
+/// Returns true for requests in a client's Noise session. The replayed HSM
+/// can't recreate the recorded sessions.
+fn is_noise_session(request: &HsmRequest) -> bool {
+    matches!(
+        request,
+        HsmRequest::HandshakeRequest(_) | HsmRequest::AppRequest(_)
+    )
+}
+
+struct Response {
+    // The name of the response's enum variant.
+    kind: String,
+    // The response re-marshalled without the HSM's metrics, which vary from
+    // run to run.
+    encoded: Vec<u8>,
+    debug: String,
+}
+
+fn decode_response(request: &HsmRequest, bytes: &[u8]) -> Result<Response, DeserializationError> {
+    fn decode<R: HsmRpc>(_: &R, bytes: &[u8]) -> Result<Response, DeserializationError> {
+        let container: HsmResponseContainer<R::Response> = marshalling::from_slice(bytes)?;
+        let debug = format!("{:#?}", container.res);
+        Ok(Response {
+            kind: variant_name(&debug).to_owned(),
+            encoded: marshalling::to_vec(&container.res).expect("failed to re-marshal response"),
+            debug,
+        })
+    }
+
+    match request {
+        HsmRequest::Status(r) => decode(r, bytes),
+        HsmRequest::NewRealm(r) => decode(r, bytes),
+        HsmRequest::JoinRealm(r) => decode(r, bytes),
+        HsmRequest::NewGroup(r) => decode(r, bytes),
+        HsmRequest::JoinGroup(r) => decode(r, bytes),
+        HsmRequest::BecomeLeader(r) => decode(r, bytes),
+        HsmRequest::StepDown(r) => decode(r, bytes),
+        HsmRequest::CaptureJump(r) => decode(r, bytes),
+        HsmRequest::CaptureNext(r) => decode(r, bytes),
+        HsmRequest::PersistState(r) => decode(r, bytes),
+        HsmRequest::Commit(r) => decode(r, bytes),
+        HsmRequest::PrepareTransfer(r) => decode(r, bytes),
+        HsmRequest::CancelPreparedTransfer(r) => decode(r, bytes),
+        HsmRequest::TransferOut(r) => decode(r, bytes),
+        HsmRequest::TransferStatement(r) => decode(r, bytes),
+        HsmRequest::TransferIn(r) => decode(r, bytes),
+        HsmRequest::CompleteTransfer(r) => decode(r, bytes),
+        HsmRequest::HandshakeRequest(r) => decode(r, bytes),
+        HsmRequest::AppRequest(r) => decode(r, bytes),
+        HsmRequest::TenantRecord(r) => decode(r, bytes),
+    }
+}
+
+/// Returns the enum variant from a response's `Debug` output, like `Ok` from
+/// `Ok { .. }`.
+fn variant_name(debug: &str) -> &str {
+    let end = debug
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(debug.len());
+    &debug[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use agent_core::hsm::trace::{Recorder, RecorderOptions};
+    use agent_core::hsm::HsmClient;
+    use clap::CommandFactory;
+    use expect_test::expect_file;
+    use hsm_api::{
+        BecomeLeaderRequest, BecomeLeaderResponse, CaptureNextRequest, CaptureNextResponse,
+        CommitRequest, CommitResponse, NewRealmRequest, NewRealmResponse, PersistStateRequest,
+        PersistStateResponse, StatusRequest, StepDownRequest,
+    };
+    use observability::metrics;
+    use software_hsm_client::EmbeddedHsm;
+    use std::path::Path;
+
+    #[test]
+    fn test_usage() {
+        expect_file!["../usage.txt"].assert_eq(
+            &Args::command()
+                .try_get_matches_from(["hsm_replay", "--help"])
+                .unwrap_err()
+                .to_string(),
+        );
+    }
+
+    fn start_hsm(state_dir: &Path, recorder: Option<Recorder>) -> HsmClient<EmbeddedHsm> {
+        let state_dir = state_dir.to_owned();
+        let hsm = EmbeddedHsm::start(String::from("hsm"), move || {
+            new_hsm(
+                state_dir,
+                String::from("hsm"),
+                KeyMaterial::insecure_derive("test").unwrap().realm_keys(),
+                NVRamFaults::default(),
+                None,
+                Vec::new(),
+            )
+        })
+        .unwrap();
+        HsmClient::new(hsm, String::from("hsm"), metrics::Client::NONE, recorder)
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = dir.path().join("state");
+        let before_boot = dir.path().join("before_boot");
+        let trace_dir = dir.path().join("trace");
+        fs::create_dir(&state_dir).unwrap();
+        fs::create_dir(&before_boot).unwrap();
+
+        // Create a realm without recording, then take a copy of the HSM's
+        // state, as if the HSM was about to boot with recording turned on.
+        let hsm = start_hsm(&state_dir, None);
+        let NewRealmResponse::Ok {
+            realm,
+            group,
+            entry,
+            ..
+        } = hsm.send(NewRealmRequest {}).await.unwrap()
+        else {
+            panic!("NewRealm failed");
+        };
+        drop(hsm);
+        fs::copy(state_dir.join("hsm"), before_boot.join("hsm")).unwrap();
+
+        // Boot it again, recording the RPCs an agent makes to bring the group
+        // back up.
+        let recorder = Recorder::new(
+            RecorderOptions {
+                dir: trace_dir.clone(),
+                max_file_bytes: 1 << 20,
+                max_files: 2,
+            },
+            metrics::Client::NONE,
+        )
+        .unwrap();
+        let hsm = start_hsm(&state_dir, Some(recorder));
+        hsm.send(StatusRequest {}).await.unwrap();
+        assert!(matches!(
+            hsm.send(CaptureNextRequest {
+                realm,
+                group,
+                entries: vec![entry.clone()],
+            })
+            .await
+            .unwrap(),
+            CaptureNextResponse::Ok(_)
+        ));
+        assert!(matches!(
+            hsm.send(BecomeLeaderRequest {
+                realm,
+                group,
+                last_entry: entry,
+            })
+            .await
+            .unwrap(),
+            BecomeLeaderResponse::Ok { .. }
+        ));
+        let PersistStateResponse::Ok { captured } = hsm.send(PersistStateRequest {}).await.unwrap();
+        assert!(matches!(
+            hsm.send(CommitRequest {
+                realm,
+                group,
+                captures: captured,
+            })
+            .await
+            .unwrap(),
+            CommitResponse::Ok(_)
+        ));
+        hsm.send(StatusRequest {}).await.unwrap();
+        hsm.send(StepDownRequest {
+            realm,
+            group,
+            force: false,
+        })
+        .await
+        .unwrap();
+        // This waits for the recorder to write everything.
+        drop(hsm);
+
+        let args = Args::try_parse_from([
+            "hsm_replay",
+            "--state-dir",
+            before_boot.to_str().unwrap(),
+            "--name",
+            "hsm",
+            "--key",
+            "test",
+            trace_dir.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(0, replay(&args).unwrap());
+    }
+
+    #[test]
+    fn test_variant_name() {
+        assert_eq!("Ok", variant_name("Ok {\n    entry: ..\n}"));
+        assert_eq!("InvalidRealm", variant_name("InvalidRealm"));
+        assert_eq!("Ok", variant_name("Ok(\n    42,\n)"));
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::store::Store;
+use hsm_api::{
+    Captured, CommitState, EntryMac, GroupId, GroupMemberRole, HsmId, LogEntry, LogIndex,
+    OwnedRange, RecordId, StatusResponse,
+};
+
+/// A broken safety or linearizability invariant.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Violation(pub String);
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A client operation, from the request being sent to the response being
+/// released.
+#[derive(Debug)]
+struct Op {
+    group: GroupId,
+    record: RecordId,
+    issued: u64,
+    proposed: Option<(LogIndex, EntryMac)>,
+    acked: Option<(u64, LogIndex)>,
+}
+
+/// Tracks what the simulated agents and clients have seen, and checks it
+/// against the invariants the HSMs are supposed to uphold.
+#[derive(Default)]
+pub struct Checker {
+    // The highest commit index any leader reported for each group.
+    committed: BTreeMap<GroupId, LogIndex>,
+    ops: BTreeMap<u64, Op>,
+    ops_by_mac: BTreeMap<[u8; 32], u64>,
+    // The highest index each HSM reported as persisted for each group.
+    persisted: BTreeMap<(HsmId, GroupId), LogIndex>,
+}
+
+impl Checker {
+    pub fn committed(&self, group: &GroupId) -> Option<LogIndex> {
+        self.committed.get(group).copied()
+    }
+
+    pub fn acked(&self) -> usize {
+        self.ops.values().filter(|op| op.acked.is_some()).count()
+    }
+
+    pub fn issue(&mut self, op: u64, group: GroupId, record: RecordId, step: u64) {
+        self.ops.insert(
+            op,
+            Op {
+                group,
+                record,
+                issued: step,
+                proposed: None,
+                acked: None,
+            },
+        );
+    }
+
+    /// Records the log entry that a leader generated for an operation.
+    pub fn propose(&mut self, op: u64, entry: &LogEntry) -> Result<(), Violation> {
+        let Some(o) = self.ops.get_mut(&op) else {
+            return violation!("op {op} was proposed but never issued");
+        };
+        if let Some((index, _)) = &o.proposed {
+            return violation!("op {op} was proposed twice, at {index} and {}", entry.index);
+        }
+        o.proposed = Some((entry.index, entry.entry_mac.clone()));
+        if let Some(other) = self.ops_by_mac.insert(*entry.entry_mac.as_bytes(), op) {
+            return violation!("ops {other} and {op} were proposed with the same entry MAC");
+        }
+        Ok(())
+    }
+
+    /// Records the captures an HSM reported after persisting them.
+    pub fn persisted(&mut self, captures: &[Captured]) {
+        for c in captures {
+            let index = self.persisted.entry((c.hsm, c.group)).or_insert(c.index);
+            *index = (*index).max(c.index);
+        }
+    }
+
+    /// Checks a leader's commit and returns the operations whose responses
+    /// it released.
+    pub fn commit(
+        &mut self,
+        store: &Store,
+        group: GroupId,
+        state: &CommitState,
+        step: u64,
+    ) -> Result<Vec<u64>, Violation> {
+        let Some(last) = store.last(&group) else {
+            return violation!("group {group:?} committed without a log");
+        };
+        if state.committed > last.index {
+            return violation!(
+                "group {group:?} committed index {} past the end of the log at {}",
+                state.committed,
+                last.index
+            );
+        }
+        let committed = self.committed.entry(group).or_insert(state.committed);
+        *committed = (*committed).max(state.committed);
+        let committed = *committed;
+
+        for mac in &state.abandoned {
+            if let Some(op) = self.ops_by_mac.get(mac.as_bytes()) {
+                if let Some((index, _)) = &self.ops[op].proposed {
+                    if *index <= committed && store.contains(&group, *index, mac) {
+                        return violation!(
+                            "group {group:?} abandoned op {op}, which committed at {index}"
+                        );
+                    }
+                }
+            }
+        }
+
+        let mut released = Vec::new();
+        for (mac, _, _) in &state.responses {
+            let Some(&id) = self.ops_by_mac.get(mac.as_bytes()) else {
+                return violation!(
+                    "group {group:?} released a response for entry {mac:?}, which no client sent"
+                );
+            };
+            let op = &self.ops[&id];
+            let (index, _) = op.proposed.clone().unwrap();
+            if op.group != group {
+                return violation!("op {id} for group {:?} was released by {group:?}", op.group);
+            }
+            if let Some((at, _)) = op.acked {
+                return violation!("op {id} was released twice, first at step {at}");
+            }
+            if index > state.committed || !store.contains(&group, index, mac) {
+                return violation!(
+                    "op {id} was released but its entry at {index} isn't committed \
+                    (commit index {})",
+                    state.committed
+                );
+            }
+            self.ack(id, index, step)?;
+            released.push(id);
+        }
+        Ok(released)
+    }
+
+    // Acknowledging an operation linearizes it at its log index. Any
+    // operation that was acknowledged before this one was issued must come
+    // earlier in the log.
+    fn ack(&mut self, id: u64, index: LogIndex, step: u64) -> Result<(), Violation> {
+        let op = &self.ops[&id];
+        for (other_id, other) in &self.ops {
+            let Some((acked, other_index)) = other.acked else {
+                continue;
+            };
+            if other.group != op.group {
+                continue;
+            }
+            if other_index == index {
+                return violation!("ops {other_id} and {id} were both acknowledged at {index}");
+            }
+            if acked < op.issued && other_index > index {
+                return violation!(
+                    "op {other_id} on record {:?} was acknowledged at step {acked} with index \
+                    {other_index}, but op {id} on record {:?} that was issued later at step {} \
+                    was ordered before it at {index}",
+                    other.record,
+                    op.record,
+                    op.issued
+                );
+            }
+        }
+        self.ops.get_mut(&id).unwrap().acked = Some((step, index));
+        Ok(())
+    }
+
+    /// Checks the invariants that must hold between every step.
+    pub fn check(
+        &self,
+        store: &Store,
+        groups: &[GroupId],
+        statuses: &[Option<StatusResponse>],
+    ) -> Result<(), Violation> {
+        let mut owners: Vec<(GroupId, OwnedRange)> = Vec::new();
+        for group in groups {
+            let log = store.log(group);
+            for pair in log.windows(2) {
+                if pair[1].index != pair[0].index.next() || pair[1].prev_mac != pair[0].entry_mac {
+                    return violation!("log for group {group:?} is broken at {}", pair[1].index);
+                }
+            }
+
+            let Some(committed) = self.committed(group) else {
+                continue;
+            };
+            let Some(entry) = store.get(group, committed) else {
+                return violation!("group {group:?} committed {committed}, which isn't in the log");
+            };
+            if let Some(partition) = &entry.partition {
+                if let Some((other, _)) = owners
+                    .iter()
+                    .find(|(_, range)| range.overlaps(&partition.range))
+                {
+                    return violation!(
+                        "groups {other:?} and {group:?} both own records in {}",
+                        partition.range
+                    );
+                }
+                owners.push((*group, partition.range.clone()));
+            }
+        }
+
+        for status in statuses.iter().flatten() {
+            let Some(realm) = &status.realm else {
+                continue;
+            };
+            for group in &realm.groups {
+                if let (Some(leader), GroupMemberRole::Leader { .. }) =
+                    (&group.leader, group.role.role)
+                {
+                    let last = store.last(&group.id).map(|e| e.index);
+                    if leader.committed.is_some() && leader.committed > last {
+                        return violation!(
+                            "HSM {:?} thinks {:?} committed {:?}, past the end of the log",
+                            status.id,
+                            group.id,
+                            leader.committed
+                        );
+                    }
+                }
+                if let Some(&persisted) = self.persisted.get(&(status.id, group.id)) {
+                    let captured = group.captured.as_ref().map(|(index, _)| *index);
+                    if captured < Some(persisted) {
+                        return violation!(
+                            "HSM {:?} persisted a capture of {:?} at {persisted}, but now has \
+                            {captured:?}",
+                            status.id,
+                            group.id
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
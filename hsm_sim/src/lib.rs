@@ -0,0 +1,33 @@
+//! A deterministic simulation of a realm of HSMs.
+//!
+//! This runs several [`hsm_core::hsm::Hsm`] instances in a single thread, on
+//! a platform with a seeded RNG, a virtual clock, and in-memory NVRAM. It
+//! stands in for the agents, Bigtable, and clients with a scheduler that
+//! drives the HSMs through leader elections, client requests, captures,
+//! commits, step downs, and ownership transfers, while injecting faults:
+//! restarts, failed NVRAM writes, crashes after NVRAM writes, and lost or
+//! reordered messages.
+//!
+//! After every step, the simulation checks the replication protocol's safety
+//! invariants and that acknowledged client operations are linearizable. Once
+//! the steps run out, it stops injecting faults and checks that every group
+//! recovers.
+//!
+//! Everything in a run follows from its [`Config`], so a failure found with
+//! one seed can be reproduced by running that seed again.
+
+macro_rules! violation {
+    ($($arg:tt)*) => {
+        Err(Violation(format!($($arg)*)))
+    };
+}
+
+mod checker;
+mod platform;
+mod simulation;
+mod store;
+
+pub use checker::Violation;
+pub use platform::{NVRamFault, SimClock, SimPlatform};
+pub use simulation::{run, Config, Failure, Faults, Report};
+pub use store::Store;
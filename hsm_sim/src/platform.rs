@@ -0,0 +1,148 @@
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rand_core::{CryptoRng, RngCore};
+use std::ops::Sub;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use hsm_api::rpc::Nanos;
+use hsm_core::hal::{Clock, IOError, NVRam, MAX_NVRAM_SIZE};
+
+/// A virtual clock shared by all the simulated HSMs. It only moves when the
+/// simulation advances it.
+#[derive(Clone, Debug, Default)]
+pub struct SimClock(Arc<AtomicU64>);
+
+impl SimClock {
+    pub fn advance(&self, nanos: u64) {
+        self.0.fetch_add(nanos, Ordering::SeqCst);
+    }
+
+    pub fn now_nanos(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+pub struct SimInstant(u64);
+
+impl Sub for SimInstant {
+    type Output = Nanos;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Nanos(self.0.saturating_sub(rhs.0).try_into().unwrap_or(u32::MAX))
+    }
+}
+
+/// A fault to inject into an HSM's next NVRAM write.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NVRamFault {
+    /// The write fails, leaving the NVRAM unchanged. The HSM panics, which
+    /// the simulation treats as a crash.
+    Fail,
+    /// The write reaches the NVRAM, then the HSM crashes before it can
+    /// respond.
+    CrashAfterWrite,
+}
+
+#[derive(Debug, Default)]
+struct NVRamState {
+    data: Vec<u8>,
+    fault: Option<NVRamFault>,
+    crashed: bool,
+}
+
+/// A [`hsm_core::hal::Platform`] with a seeded RNG, a virtual clock, and
+/// in-memory NVRAM that survives restarts of the HSM.
+#[derive(Clone)]
+pub struct SimPlatform {
+    clock: SimClock,
+    rng: Arc<Mutex<StdRng>>,
+    nvram: Arc<Mutex<NVRamState>>,
+}
+
+impl SimPlatform {
+    pub fn new(clock: SimClock, seed: u64) -> Self {
+        Self {
+            clock,
+            rng: Arc::new(Mutex::new(StdRng::seed_from_u64(seed))),
+            nvram: Arc::default(),
+        }
+    }
+
+    pub fn inject_nvram_fault(&self, fault: NVRamFault) {
+        self.nvram.lock().unwrap().fault = Some(fault);
+    }
+
+    pub fn clear_nvram_fault(&self) {
+        self.nvram.lock().unwrap().fault = None;
+    }
+
+    /// Returns true if an injected fault crashed the HSM since this was last
+    /// called.
+    pub fn take_crash(&self) -> bool {
+        std::mem::take(&mut self.nvram.lock().unwrap().crashed)
+    }
+}
+
+impl Clock for SimPlatform {
+    type Instant = SimInstant;
+
+    fn now(&self) -> Option<Self::Instant> {
+        Some(SimInstant(self.clock.now_nanos()))
+    }
+
+    fn elapsed(&self, start: Self::Instant) -> Option<Nanos> {
+        Some(SimInstant(self.clock.now_nanos()) - start)
+    }
+}
+
+impl RngCore for SimPlatform {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.lock().unwrap().next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng.lock().unwrap().next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.lock().unwrap().fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.rng.lock().unwrap().try_fill_bytes(dest)
+    }
+}
+
+impl CryptoRng for SimPlatform {}
+
+impl NVRam for SimPlatform {
+    fn read(&self) -> Result<Vec<u8>, IOError> {
+        Ok(self.nvram.lock().unwrap().data.clone())
+    }
+
+    fn write(&self, data: Vec<u8>) -> Result<(), IOError> {
+        if data.len() > MAX_NVRAM_SIZE {
+            return Err(IOError(format!(
+                "data with {} bytes is larger than allowed maximum of {MAX_NVRAM_SIZE}",
+                data.len()
+            )));
+        }
+        let mut nvram = self.nvram.lock().unwrap();
+        match nvram.fault.take() {
+            Some(NVRamFault::Fail) => {
+                nvram.crashed = true;
+                Err(IOError(String::from("injected fault: NVRAM write failed")))
+            }
+            Some(NVRamFault::CrashAfterWrite) => {
+                nvram.data = data;
+                nvram.crashed = true;
+                Ok(())
+            }
+            None => {
+                nvram.data = data;
+                Ok(())
+            }
+        }
+    }
+}
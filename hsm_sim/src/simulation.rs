@@ -0,0 +1,1495 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_core::RngCore;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, PoisonError};
+
+use crate::checker::{Checker, Violation};
+use crate::platform::{NVRamFault, SimClock, SimPlatform};
+use crate::store::Store;
+use hsm_api::merkle::{Dir, StoreDelta};
+use hsm_api::rpc::{HsmRequestContainer, HsmResponseContainer, HsmRpc, MetricsAction};
+use hsm_api::{
+    AppRequest, AppResponse, BecomeLeaderRequest, CaptureNextRequest, CaptureNextResponse,
+    Captured, CommitRequest, CommitResponse, CompleteTransferRequest, CompleteTransferResponse,
+    DataHash, GroupId, GroupMemberRole, HsmId, HsmRealmStatement, JoinGroupRequest,
+    JoinGroupResponse, JoinRealmRequest, JoinRealmResponse, LogEntry, LogIndex, NewGroupRequest,
+    NewGroupResponse, NewRealmRequest, NewRealmResponse, OwnedRange, Partition,
+    PersistStateRequest, PersistStateResponse, PrepareTransferRequest, PrepareTransferResponse,
+    PreparedTransfer, RecordId, StatusRequest, StatusResponse, StepDownRequest, TransferInProofs,
+    TransferInRequest, TransferInResponse, TransferOutRequest, TransferOutResponse,
+    TransferStatement, TransferStatementRequest, TransferStatementResponse,
+};
+use hsm_core::hsm::mac::MacKey;
+use hsm_core::hsm::{Hsm, HsmOptions, MetricsReporting, RealmKeys, RecordEncryptionKey};
+use juicebox_marshalling as marshalling;
+use juicebox_noise::client::Handshake;
+use juicebox_realm_api::requests::{
+    DeleteResponse, NoiseRequest, NoiseResponse, PaddedSecretsResponse, SecretsRequest,
+    SecretsResponse,
+};
+use juicebox_realm_api::types::{RealmId, SessionId};
+
+// The most log entries sent to an HSM in one CaptureNext request.
+const MAX_CAPTURE_BATCH: usize = 8;
+
+// The number of fault-free rounds the groups get to recover in at the end of
+// a run.
+const RECOVERY_ROUNDS: usize = 10;
+
+// How many of the last events to include in a failure's description.
+const FAILURE_TRACE_LEN: usize = 40;
+
+/// Configures a simulation run. Everything that happens in a run follows from
+/// these settings.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub seed: u64,
+    /// The number of HSMs in the realm.
+    pub hsms: usize,
+    /// The number of replication groups to create in addition to the realm's
+    /// first group. Every HSM is a member of each of these. Must be at least
+    /// 1.
+    pub groups: usize,
+    /// The number of distinct records that clients send requests for.
+    pub records: usize,
+    /// The number of scheduler steps to run before checking that the groups
+    /// recover.
+    pub steps: u64,
+    pub faults: Faults,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            hsms: 3,
+            groups: 2,
+            records: 16,
+            steps: 500,
+            faults: Faults::default(),
+        }
+    }
+}
+
+/// The probability of injecting each kind of fault at each step.
+#[derive(Clone, Debug)]
+pub struct Faults {
+    /// An HSM restarts, losing its volatile state.
+    pub restart: f64,
+    /// An HSM's next NVRAM write fails.
+    pub nvram_write_failure: f64,
+    /// An HSM crashes right after its next NVRAM write.
+    pub crash_after_write: f64,
+    /// A message to an HSM is lost.
+    pub drop_message: f64,
+}
+
+impl Faults {
+    pub fn none() -> Self {
+        Self {
+            restart: 0.0,
+            nvram_write_failure: 0.0,
+            crash_after_write: 0.0,
+            drop_message: 0.0,
+        }
+    }
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self {
+            restart: 0.01,
+            nvram_write_failure: 0.01,
+            crash_after_write: 0.01,
+            drop_message: 0.02,
+        }
+    }
+}
+
+/// The outcome of a simulation run that upheld every invariant.
+#[derive(Debug)]
+pub struct Report {
+    /// The number of client operations whose responses were released.
+    pub acked: usize,
+    /// The number of times an HSM restarted, for any reason.
+    pub crashes: usize,
+    /// The number of ownership transfers that completed.
+    pub transfers: usize,
+    /// Everything that happened, one event per line. Two runs with the same
+    /// config produce the same trace.
+    pub trace: Vec<String>,
+}
+
+/// A simulation run that broke an invariant.
+#[derive(Debug)]
+pub struct Failure {
+    pub seed: u64,
+    pub step: u64,
+    pub violation: Violation,
+    pub trace: Vec<String>,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "simulation with seed {} broke an invariant at step {}: {}",
+            self.seed, self.step, self.violation
+        )?;
+        writeln!(f, "last events:")?;
+        let start = self.trace.len().saturating_sub(FAILURE_TRACE_LEN);
+        for event in &self.trace[start..] {
+            writeln!(f, "  {event}")?;
+        }
+        write!(f, "rerun with HSM_SIM_SEED={}", self.seed)
+    }
+}
+
+/// Runs a simulation to completion.
+///
+/// Runs are serialized within a process, because hsm_core seeds its hash
+/// tables from a process-global RNG.
+pub fn run(config: Config) -> Result<Report, Failure> {
+    static RUNNING: Mutex<()> = Mutex::new(());
+    let _running = RUNNING.lock().unwrap_or_else(PoisonError::into_inner);
+
+    let mut sim = Simulation::new(config);
+    match sim
+        .setup()
+        .and_then(|()| sim.run())
+        .and_then(|()| sim.recover())
+    {
+        Ok(()) => Ok(Report {
+            acked: sim.checker.acked(),
+            crashes: sim.crashes,
+            transfers: sim.transfers,
+            trace: sim.trace,
+        }),
+        Err(violation) => Err(Failure {
+            seed: sim.config.seed,
+            step: sim.step,
+            violation,
+            trace: sim.trace,
+        }),
+    }
+}
+
+struct SimHsm {
+    hsm: Hsm<SimPlatform>,
+    platform: SimPlatform,
+    // The index of the next log entry the agent will ask this HSM to capture,
+    // for each group.
+    next_capture: BTreeMap<GroupId, LogIndex>,
+}
+
+enum Message {
+    CaptureNext(CaptureNextRequest),
+    PersistState,
+    Commit(CommitRequest),
+    BecomeLeader(BecomeLeaderRequest),
+    StepDown(StepDownRequest),
+    App { op: u64, request: AppRequest },
+    Transfer(TransferMessage),
+}
+
+impl Message {
+    fn name(&self) -> &'static str {
+        match self {
+            Message::CaptureNext(_) => "CaptureNext",
+            Message::PersistState => "PersistState",
+            Message::Commit(_) => "Commit",
+            Message::BecomeLeader(_) => "BecomeLeader",
+            Message::StepDown(_) => "StepDown",
+            Message::App { .. } => "App",
+            Message::Transfer(m) => m.phase().name(),
+        }
+    }
+}
+
+enum TransferMessage {
+    Prepare(PrepareTransferRequest),
+    Out(TransferOutRequest),
+    Statement(TransferStatementRequest),
+    In(TransferInRequest),
+    Complete(CompleteTransferRequest),
+}
+
+impl TransferMessage {
+    fn phase(&self) -> Phase {
+        match self {
+            TransferMessage::Prepare(_) => Phase::Prepare,
+            TransferMessage::Out(_) => Phase::Out,
+            TransferMessage::Statement(_) => Phase::Statement,
+            TransferMessage::In(_) => Phase::In,
+            TransferMessage::Complete(_) => Phase::Complete,
+        }
+    }
+}
+
+// A message that the scheduler has sent but not yet delivered.
+struct InFlight {
+    hsm: usize,
+    message: Message,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Phase {
+    Prepare,
+    Out,
+    Statement,
+    In,
+    Complete,
+    Done,
+}
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Prepare => "PrepareTransfer",
+            Phase::Out => "TransferOut",
+            Phase::Statement => "TransferStatement",
+            Phase::In => "TransferIn",
+            Phase::Complete => "CompleteTransfer",
+            Phase::Done => "Done",
+        }
+    }
+}
+
+// An ownership transfer, driven through its phases the way the cluster
+// manager does it.
+struct Transfer {
+    source: GroupId,
+    destination: GroupId,
+    range: OwnedRange,
+    phase: Phase,
+    in_flight: bool,
+    // A log entry that has to commit before the next phase can start.
+    waiting: Option<(GroupId, LogIndex)>,
+    prepared: Option<PreparedTransfer>,
+    partition: Option<Partition>,
+    statement: Option<TransferStatement>,
+}
+
+enum TransferOutcome {
+    Prepared(PreparedTransfer),
+    Out {
+        entry: Option<LogEntry>,
+        delta: StoreDelta<DataHash>,
+        partition: Partition,
+        wait_til_committed: LogIndex,
+    },
+    Statement(TransferStatement),
+    In {
+        entry: LogEntry,
+        delta: StoreDelta<DataHash>,
+    },
+    Complete {
+        entry: LogEntry,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Action {
+    Deliver,
+    Capture,
+    PersistState,
+    Commit,
+    BecomeLeader,
+    StepDown,
+    App,
+    Transfer,
+}
+
+// How often the scheduler picks each action, relative to the others.
+const ACTIONS: [(Action, u32); 8] = [
+    (Action::Deliver, 40),
+    (Action::Capture, 12),
+    (Action::PersistState, 10),
+    (Action::Commit, 10),
+    (Action::BecomeLeader, 3),
+    (Action::StepDown, 1),
+    (Action::App, 18),
+    (Action::Transfer, 6),
+];
+
+struct Simulation {
+    config: Config,
+    rng: StdRng,
+    clock: SimClock,
+    keys: RealmKeys,
+    hsms: Vec<SimHsm>,
+    // The most recent status of each HSM, or None if it crashed while
+    // reporting it.
+    statuses: Vec<Option<StatusResponse>>,
+    realm: RealmId,
+    groups: Vec<GroupId>,
+    // The indexes into `hsms` of each group's members.
+    members: BTreeMap<GroupId, Vec<usize>>,
+    store: Store,
+    checker: Checker,
+    network: Vec<InFlight>,
+    // The latest persisted capture from each HSM of each group.
+    captures: BTreeMap<(GroupId, HsmId), Captured>,
+    // Clients waiting for the responses to their operations.
+    clients: BTreeMap<u64, Handshake>,
+    next_op: u64,
+    transfer: Option<Transfer>,
+    step: u64,
+    crashes: usize,
+    transfers: usize,
+    trace: Vec<String>,
+}
+
+impl Simulation {
+    fn new(config: Config) -> Self {
+        assert!(config.hsms > 0, "need at least 1 HSM");
+        assert!(config.groups > 0, "need at least 1 group");
+        assert!(config.records > 0, "need at least 1 record");
+
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        hsm_core::hash::set_global_rng(Box::new(StdRng::seed_from_u64(rng.gen())));
+
+        let mut secrets = [[0u8; 32]; 3];
+        for secret in &mut secrets {
+            rng.fill_bytes(secret);
+        }
+        let noise_private = x25519_dalek::StaticSecret::from(secrets[0]);
+        let noise_public = x25519_dalek::PublicKey::from(&noise_private);
+        let keys = RealmKeys {
+            communication: (noise_private, noise_public),
+            record: RecordEncryptionKey::from(secrets[1]),
+            mac: MacKey::from(secrets[2]),
+        };
+
+        let clock = SimClock::default();
+        let hsms = (0..config.hsms)
+            .map(|i| {
+                let platform = SimPlatform::new(clock.clone(), rng.gen());
+                let hsm = Hsm::new(hsm_options(i), platform.clone(), keys.clone())
+                    .expect("failed to start HSM with empty NVRAM");
+                SimHsm {
+                    hsm,
+                    platform,
+                    next_capture: BTreeMap::new(),
+                }
+            })
+            .collect();
+
+        Self {
+            statuses: vec![None; config.hsms],
+            config,
+            rng,
+            clock,
+            keys,
+            hsms,
+            realm: RealmId([0; 16]),
+            groups: Vec::new(),
+            members: BTreeMap::new(),
+            store: Store::default(),
+            checker: Checker::default(),
+            network: Vec::new(),
+            captures: BTreeMap::new(),
+            clients: BTreeMap::new(),
+            next_op: 0,
+            transfer: None,
+            step: 0,
+            crashes: 0,
+            transfers: 0,
+            trace: Vec::new(),
+        }
+    }
+
+    fn event(&mut self, event: impl fmt::Display) {
+        self.trace.push(format!("{:>5} {event}", self.step));
+    }
+
+    // Creates the realm and its groups, and moves every record out of the
+    // realm's first group, without injecting any faults.
+    fn setup(&mut self) -> Result<(), Violation> {
+        let Some(NewRealmResponse::Ok {
+            realm,
+            group,
+            entry,
+            delta,
+            ..
+        }) = self.call(0, NewRealmRequest {})?
+        else {
+            return violation!("hsm0 couldn't create a realm");
+        };
+        self.realm = realm;
+        self.append(group, entry, delta);
+        self.groups.push(group);
+        self.members.insert(group, vec![0]);
+        self.refresh_statuses()?;
+
+        let statement = self.realm_statement(0);
+        let peer = self.id(0);
+        for hsm in 1..self.hsms.len() {
+            match self.call(
+                hsm,
+                JoinRealmRequest {
+                    realm,
+                    peer,
+                    statement: statement.clone(),
+                },
+            )? {
+                Some(JoinRealmResponse::Ok { .. }) => {}
+                other => return violation!("hsm{hsm} couldn't join the realm: {other:?}"),
+            }
+        }
+        self.refresh_statuses()?;
+
+        let mut members: Vec<(HsmId, HsmRealmStatement)> = (0..self.hsms.len())
+            .map(|hsm| (self.id(hsm), self.realm_statement(hsm)))
+            .collect();
+        members.sort_by_key(|(id, _)| *id);
+        for g in 0..self.config.groups {
+            let creator = g % self.hsms.len();
+            let Some(NewGroupResponse::Ok {
+                group,
+                statement,
+                entry,
+                ..
+            }) = self.call(
+                creator,
+                NewGroupRequest {
+                    realm,
+                    members: members.clone(),
+                },
+            )?
+            else {
+                return violation!("hsm{creator} couldn't create a group");
+            };
+            self.append(group, entry, StoreDelta::default());
+            for hsm in (0..self.hsms.len()).filter(|hsm| *hsm != creator) {
+                match self.call(
+                    hsm,
+                    JoinGroupRequest {
+                        realm,
+                        group,
+                        configuration: members.iter().map(|(id, _)| *id).collect(),
+                        statement: statement.clone(),
+                    },
+                )? {
+                    Some(JoinGroupResponse::Ok(_)) => {}
+                    other => return violation!("hsm{hsm} couldn't join a group: {other:?}"),
+                }
+            }
+            self.groups.push(group);
+            self.members.insert(group, (0..self.hsms.len()).collect());
+        }
+        self.settle()?;
+
+        self.transfer = Some(Transfer::new(
+            self.groups[0],
+            self.groups[1],
+            OwnedRange::full(),
+        ));
+        for _ in 0..RECOVERY_ROUNDS {
+            if self.transfer.is_none() {
+                self.event("setup done");
+                return Ok(());
+            }
+            self.step_transfer();
+            self.settle()?;
+        }
+        violation!("setup couldn't move the records out of the realm's first group")
+    }
+
+    fn run(&mut self) -> Result<(), Violation> {
+        while self.step < self.config.steps {
+            self.step += 1;
+            self.clock.advance(self.rng.gen_range(1_000..10_000_000));
+            self.inject_faults()?;
+            self.act()?;
+            self.refresh_statuses()?;
+            self.checker
+                .check(&self.store, &self.groups, &self.statuses)?;
+        }
+        Ok(())
+    }
+
+    // Stops injecting faults and checks that every group gets back to having
+    // a leader that has committed its whole log.
+    fn recover(&mut self) -> Result<(), Violation> {
+        self.event("recovering");
+        for hsm in &self.hsms {
+            hsm.platform.clear_nvram_fault();
+        }
+        for _ in 0..RECOVERY_ROUNDS {
+            self.settle()?;
+            self.checker
+                .check(&self.store, &self.groups, &self.statuses)?;
+            if self.groups.iter().all(|group| self.recovered(group)) {
+                return Ok(());
+            }
+        }
+        let stuck: Vec<GroupId> = self
+            .groups
+            .iter()
+            .filter(|group| !self.recovered(group))
+            .copied()
+            .collect();
+        violation!("groups {stuck:?} didn't recover once the faults stopped")
+    }
+
+    fn recovered(&self, group: &GroupId) -> bool {
+        let last = self.store.last(group).map(|entry| entry.index);
+        self.statuses
+            .iter()
+            .flatten()
+            .filter_map(|status| status.realm.as_ref())
+            .flat_map(|realm| &realm.groups)
+            .any(|status| {
+                status.id == *group
+                    && matches!(status.role.role, GroupMemberRole::Leader { .. })
+                    && status.leader.as_ref().is_some_and(|leader| {
+                        leader.committed.is_some() && leader.committed == last
+                    })
+            })
+    }
+
+    // Brings every HSM up to date with the logs, elects leaders where needed,
+    // and commits, delivering messages in order.
+    fn settle(&mut self) -> Result<(), Violation> {
+        self.drain()?;
+        for group in self.groups.clone() {
+            for hsm in self.members[&group].clone() {
+                self.send_capture(hsm, group);
+            }
+        }
+        self.drain()?;
+        for hsm in 0..self.hsms.len() {
+            self.send(hsm, Message::PersistState);
+        }
+        self.drain()?;
+        for group in self.groups.clone() {
+            if self.leaders(group).is_empty() {
+                let witness = self
+                    .roles(group)
+                    .find_map(|(hsm, role)| (role == GroupMemberRole::Witness).then_some(hsm));
+                if let Some(hsm) = witness {
+                    self.send_become_leader(hsm, group);
+                }
+            }
+            for hsm in self.leading(group) {
+                self.send_commit(hsm, group);
+            }
+        }
+        self.drain()
+    }
+
+    fn drain(&mut self) -> Result<(), Violation> {
+        while !self.network.is_empty() {
+            let message = self.network.remove(0);
+            self.deliver(message)?;
+        }
+        self.refresh_statuses()
+    }
+
+    fn inject_faults(&mut self) -> Result<(), Violation> {
+        let faults = self.config.faults.clone();
+        if self.rng.gen_bool(faults.restart) {
+            let hsm = self.rng.gen_range(0..self.hsms.len());
+            self.event(format_args!("restart hsm{hsm}"));
+            self.restart(hsm)?;
+        }
+        for (probability, fault) in [
+            (faults.nvram_write_failure, NVRamFault::Fail),
+            (faults.crash_after_write, NVRamFault::CrashAfterWrite),
+        ] {
+            if self.rng.gen_bool(probability) {
+                let hsm = self.rng.gen_range(0..self.hsms.len());
+                self.event(format_args!("arm {fault:?} on hsm{hsm}"));
+                self.hsms[hsm].platform.inject_nvram_fault(fault);
+            }
+        }
+        if !self.network.is_empty() && self.rng.gen_bool(faults.drop_message) {
+            let i = self.rng.gen_range(0..self.network.len());
+            let InFlight { hsm, message } = self.network.remove(i);
+            self.event(format_args!("drop {} to hsm{hsm}", message.name()));
+            if let (Message::Transfer(_), Some(transfer)) = (&message, &mut self.transfer) {
+                transfer.in_flight = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn act(&mut self) -> Result<(), Violation> {
+        let total: u32 = ACTIONS.iter().map(|(_, weight)| weight).sum();
+        let mut roll = self.rng.gen_range(0..total);
+        let (action, _) = *ACTIONS
+            .iter()
+            .find(|(_, weight)| {
+                if roll < *weight {
+                    true
+                } else {
+                    roll -= weight;
+                    false
+                }
+            })
+            .unwrap();
+        let group = self.groups[self.rng.gen_range(0..self.groups.len())];
+
+        match action {
+            // Picking any in-flight message, rather than the oldest, reorders
+            // them.
+            Action::Deliver => {
+                if !self.network.is_empty() {
+                    let i = self.rng.gen_range(0..self.network.len());
+                    let message = self.network.remove(i);
+                    self.deliver(message)?;
+                }
+            }
+            Action::Capture => {
+                for hsm in self.members[&group].clone() {
+                    self.send_capture(hsm, group);
+                }
+            }
+            Action::PersistState => {
+                let hsm = self.rng.gen_range(0..self.hsms.len());
+                self.send(hsm, Message::PersistState);
+            }
+            Action::Commit => {
+                let leading = self.leading(group);
+                if let Some(hsm) = self.pick(&leading) {
+                    self.send_commit(hsm, group);
+                }
+            }
+            // Usually an election only happens when a group has no leader,
+            // but the agents' views of the group can be stale, so sometimes
+            // one starts anyway.
+            Action::BecomeLeader => {
+                let members = self.members[&group].clone();
+                let hsm = self.pick(&members).unwrap();
+                if self.leaders(group).is_empty() || self.rng.gen_bool(0.1) {
+                    self.send_become_leader(hsm, group);
+                }
+            }
+            Action::StepDown => {
+                let leaders = self.leaders(group);
+                if let Some(hsm) = self.pick(&leaders) {
+                    let force = self.rng.gen_bool(0.2);
+                    self.send(
+                        hsm,
+                        Message::StepDown(StepDownRequest {
+                            realm: self.realm,
+                            group,
+                            force,
+                        }),
+                    );
+                }
+            }
+            Action::App => self.send_app_request(),
+            Action::Transfer => self.step_transfer(),
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, hsm: usize, message: Message) {
+        self.network.push(InFlight { hsm, message });
+    }
+
+    fn send_capture(&mut self, hsm: usize, group: GroupId) {
+        let next = self.hsms[hsm]
+            .next_capture
+            .get(&group)
+            .copied()
+            .unwrap_or(LogIndex::FIRST);
+        let entries: Vec<LogEntry> = self
+            .store
+            .log(&group)
+            .iter()
+            .filter(|entry| entry.index >= next)
+            .take(MAX_CAPTURE_BATCH)
+            .cloned()
+            .collect();
+        if !entries.is_empty() {
+            self.send(
+                hsm,
+                Message::CaptureNext(CaptureNextRequest {
+                    realm: self.realm,
+                    group,
+                    entries,
+                }),
+            );
+        }
+    }
+
+    fn send_commit(&mut self, hsm: usize, group: GroupId) {
+        let captures: Vec<Captured> = self
+            .captures
+            .iter()
+            .filter(|((g, _), _)| *g == group)
+            .map(|(_, captured)| captured.clone())
+            .collect();
+        if !captures.is_empty() {
+            self.send(
+                hsm,
+                Message::Commit(CommitRequest {
+                    realm: self.realm,
+                    group,
+                    captures,
+                }),
+            );
+        }
+    }
+
+    fn send_become_leader(&mut self, hsm: usize, group: GroupId) {
+        if let Some(last_entry) = self.store.last(&group).cloned() {
+            self.send(
+                hsm,
+                Message::BecomeLeader(BecomeLeaderRequest {
+                    realm: self.realm,
+                    group,
+                    last_entry,
+                }),
+            );
+        }
+    }
+
+    // Sends a Delete request for a random record to the group that owns it,
+    // as a new client operation.
+    fn send_app_request(&mut self) {
+        let record = self.record(self.rng.gen_range(0..self.config.records));
+        let Some((group, partition, index)) = self.groups.iter().find_map(|group| {
+            let last = self.store.last(group)?;
+            let partition = last.partition.as_ref()?;
+            partition
+                .range
+                .contains(&record)
+                .then(|| (*group, partition.clone(), last.index))
+        }) else {
+            return;
+        };
+        // Clients usually reach the leader, but sometimes a stale view of the
+        // cluster sends them to some other member.
+        let leaders = self.leaders(group);
+        let hsm = match self.pick(&leaders) {
+            Some(hsm) if !self.rng.gen_bool(0.1) => hsm,
+            _ => {
+                let members = self.members[&group].clone();
+                self.pick(&members).unwrap()
+            }
+        };
+        let Ok(proof) = self
+            .store
+            .tree
+            .read(&partition.range, &partition.root_hash, &record)
+        else {
+            return;
+        };
+
+        let request = marshalling::to_vec(&SecretsRequest::Delete).unwrap();
+        let (handshake, noise) =
+            Handshake::start(&self.keys.communication.1, &request, &mut self.rng)
+                .expect("failed to start Noise handshake");
+        let op = self.next_op;
+        self.next_op += 1;
+        self.checker.issue(op, group, record.clone(), self.step);
+        self.clients.insert(op, handshake);
+        let session_id = SessionId(self.rng.gen());
+        self.send(
+            hsm,
+            Message::App {
+                op,
+                request: AppRequest {
+                    realm: self.realm,
+                    group,
+                    record_id: record,
+                    session_id,
+                    encrypted: NoiseRequest::Handshake { handshake: noise },
+                    proof,
+                    index,
+                    quota_exceeded: false,
+                    tenant: None,
+                },
+            },
+        );
+    }
+
+    fn deliver(&mut self, InFlight { hsm, message }: InFlight) -> Result<(), Violation> {
+        match message {
+            Message::CaptureNext(request) => {
+                let group = request.group;
+                let next = request.entries.last().unwrap().index.next();
+                let response = self.call(hsm, request)?;
+                self.event_response(hsm, "CaptureNext", &response);
+                if let Some(CaptureNextResponse::Ok(_)) = response {
+                    self.hsms[hsm].next_capture.insert(group, next);
+                }
+            }
+            Message::PersistState => {
+                let response = self.call(hsm, PersistStateRequest {})?;
+                self.event_response(hsm, "PersistState", &response);
+                if let Some(PersistStateResponse::Ok { captured }) = response {
+                    self.checker.persisted(&captured);
+                    for c in captured {
+                        let latest = self
+                            .captures
+                            .entry((c.group, c.hsm))
+                            .or_insert_with(|| c.clone());
+                        if c.index > latest.index {
+                            *latest = c;
+                        }
+                    }
+                }
+            }
+            Message::Commit(request) => {
+                let group = request.group;
+                let response = self.call(hsm, request)?;
+                self.event_response(hsm, "Commit", &response);
+                if let Some(CommitResponse::Ok(state)) = response {
+                    let released = self.checker.commit(&self.store, group, &state, self.step)?;
+                    for (op, (_, response, _)) in released.into_iter().zip(&state.responses) {
+                        self.release(op, response)?;
+                    }
+                }
+            }
+            Message::BecomeLeader(request) => {
+                let response = self.call(hsm, request)?;
+                self.event_response(hsm, "BecomeLeader", &response);
+            }
+            Message::StepDown(request) => {
+                let response = self.call(hsm, request)?;
+                self.event_response(hsm, "StepDown", &response);
+            }
+            Message::App { op, request } => {
+                let group = request.group;
+                let response = self.call(hsm, request)?;
+                self.event_response(hsm, "App", &response);
+                if let Some(AppResponse::Ok { entry, delta }) = response {
+                    self.checker.propose(op, &entry)?;
+                    self.append(group, entry, delta);
+                }
+            }
+            Message::Transfer(message) => self.deliver_transfer(hsm, message)?,
+        }
+        Ok(())
+    }
+
+    // Hands a committed response to the client that's waiting for it.
+    fn release(&mut self, op: u64, response: &NoiseResponse) -> Result<(), Violation> {
+        let Some(handshake) = self.clients.remove(&op) else {
+            return violation!("op {op} was released but no client is waiting for it");
+        };
+        let NoiseResponse::Handshake {
+            handshake: result, ..
+        } = response
+        else {
+            return violation!("op {op} got a Noise transport response to a handshake");
+        };
+        let decoded = handshake
+            .finish(result)
+            .ok()
+            .and_then(|(_, payload)| {
+                marshalling::from_slice::<PaddedSecretsResponse>(&payload).ok()
+            })
+            .and_then(|padded| SecretsResponse::try_from(&padded).ok());
+        match decoded {
+            Some(SecretsResponse::Delete(DeleteResponse::Ok)) => Ok(()),
+            other => violation!("op {op} got an unexpected response: {other:?}"),
+        }
+    }
+
+    // Appends an entry to a group's log, returning false if another entry got
+    // there first.
+    fn append(&mut self, group: GroupId, entry: LogEntry, delta: StoreDelta<DataHash>) -> bool {
+        let index = entry.index;
+        let appended = self.store.append(group, entry, delta);
+        if !appended {
+            self.event(format_args!("append to {group:?} at {index} lost the race"));
+        }
+        appended
+    }
+
+    // Moves an ownership transfer along, starting a new one if none is in
+    // progress.
+    fn step_transfer(&mut self) {
+        if self.transfer.is_none() {
+            self.transfer = self.plan_transfer();
+            if let Some(t) = &self.transfer {
+                let event = format!(
+                    "start transfer of {} from {:?} to {:?}",
+                    t.range, t.source, t.destination
+                );
+                self.event(event);
+            }
+        }
+        let Some(mut t) = self.transfer.take() else {
+            return;
+        };
+        if t.in_flight {
+            self.transfer = Some(t);
+            return;
+        }
+        if let Some((group, index)) = t.waiting {
+            if !self.checker.committed(&group).is_some_and(|c| c >= index) {
+                self.transfer = Some(t);
+                return;
+            }
+            t.waiting = None;
+        }
+        if t.phase == Phase::Done {
+            self.transfers += 1;
+            self.event(format_args!(
+                "finished transfer of {} from {:?} to {:?}",
+                t.range, t.source, t.destination
+            ));
+            return;
+        }
+
+        let group = match t.phase {
+            Phase::Prepare | Phase::In => t.destination,
+            Phase::Out | Phase::Statement | Phase::Complete | Phase::Done => t.source,
+        };
+        let leaders = self.leaders(group);
+        let message = match self.pick(&leaders) {
+            None => None,
+            Some(hsm) => self.transfer_message(&t).map(|message| (hsm, message)),
+        };
+        if let Some((hsm, message)) = message {
+            t.in_flight = true;
+            self.send(hsm, Message::Transfer(message));
+        }
+        self.transfer = Some(t);
+    }
+
+    fn transfer_message(&self, t: &Transfer) -> Option<TransferMessage> {
+        let realm = self.realm;
+        let (source, destination) = (t.source, t.destination);
+        Some(match t.phase {
+            Phase::Prepare => TransferMessage::Prepare(PrepareTransferRequest {
+                realm,
+                source,
+                destination,
+                range: t.range.clone(),
+            }),
+            Phase::Out => {
+                let prepared = t.prepared.as_ref()?;
+                let partition = self.store.last(&source)?.partition.clone()?;
+                // A retried transfer out may have already moved the range out
+                // of the partition, in which case there's nothing to split and
+                // the HSM works it out.
+                let proof = if t.range == partition.range {
+                    None
+                } else {
+                    partition.range.split_at(&t.range).and_then(|id| {
+                        self.store
+                            .tree
+                            .read(&partition.range, &partition.root_hash, &id)
+                            .ok()
+                    })
+                };
+                TransferMessage::Out(TransferOutRequest {
+                    realm,
+                    source,
+                    destination,
+                    range: t.range.clone(),
+                    nonce: prepared.nonce,
+                    statement: prepared.statement.clone(),
+                    proof,
+                })
+            }
+            Phase::Statement => TransferMessage::Statement(TransferStatementRequest {
+                realm,
+                source,
+                destination,
+                nonce: t.prepared.as_ref()?.nonce,
+            }),
+            Phase::In => {
+                let transferring = t.partition.clone()?;
+                let proofs = match &self.store.last(&destination)?.partition {
+                    None => None,
+                    Some(owned) => {
+                        let joined = owned.range.join(&transferring.range)?;
+                        let dir = if joined.start == transferring.range.start {
+                            Dir::Right
+                        } else {
+                            Dir::Left
+                        };
+                        Some(TransferInProofs {
+                            owned: self
+                                .store
+                                .tree
+                                .read_tree_side(&owned.range, &owned.root_hash, dir.opposite())
+                                .ok()?,
+                            transferring: self
+                                .store
+                                .tree
+                                .read_tree_side(&transferring.range, &transferring.root_hash, dir)
+                                .ok()?,
+                        })
+                    }
+                };
+                TransferMessage::In(TransferInRequest {
+                    realm,
+                    source,
+                    destination,
+                    transferring,
+                    proofs,
+                    nonce: t.prepared.as_ref()?.nonce,
+                    statement: t.statement.clone()?,
+                })
+            }
+            Phase::Complete => TransferMessage::Complete(CompleteTransferRequest {
+                realm,
+                source,
+                destination,
+                range: t.range.clone(),
+            }),
+            Phase::Done => return None,
+        })
+    }
+
+    fn deliver_transfer(&mut self, hsm: usize, message: TransferMessage) -> Result<(), Violation> {
+        let phase = message.phase();
+        let outcome = match message {
+            TransferMessage::Prepare(request) => {
+                let response = self.call(hsm, request)?;
+                self.event_response(hsm, phase.name(), &response);
+                match response {
+                    Some(PrepareTransferResponse::Ok(prepared)) => {
+                        Some(TransferOutcome::Prepared(prepared))
+                    }
+                    _ => None,
+                }
+            }
+            TransferMessage::Out(request) => {
+                let response = self.call(hsm, request)?;
+                self.event_response(hsm, phase.name(), &response);
+                match response {
+                    Some(TransferOutResponse::Ok {
+                        entry,
+                        delta,
+                        partition,
+                        wait_til_committed,
+                        ..
+                    }) => Some(TransferOutcome::Out {
+                        entry,
+                        delta,
+                        partition,
+                        wait_til_committed,
+                    }),
+                    _ => None,
+                }
+            }
+            TransferMessage::Statement(request) => {
+                let response = self.call(hsm, request)?;
+                self.event_response(hsm, phase.name(), &response);
+                match response {
+                    Some(TransferStatementResponse::Ok(statement)) => {
+                        Some(TransferOutcome::Statement(statement))
+                    }
+                    _ => None,
+                }
+            }
+            TransferMessage::In(request) => {
+                let response = self.call(hsm, request)?;
+                self.event_response(hsm, phase.name(), &response);
+                match response {
+                    Some(TransferInResponse::Ok { entry, delta, .. }) => {
+                        Some(TransferOutcome::In { entry, delta })
+                    }
+                    _ => None,
+                }
+            }
+            TransferMessage::Complete(request) => {
+                let response = self.call(hsm, request)?;
+                self.event_response(hsm, phase.name(), &response);
+                match response {
+                    Some(CompleteTransferResponse::Ok { entry, .. }) => {
+                        Some(TransferOutcome::Complete { entry })
+                    }
+                    _ => None,
+                }
+            }
+        };
+
+        let mut t = match self.transfer.take() {
+            Some(t) if t.phase == phase => t,
+            other => {
+                self.transfer = other;
+                return Ok(());
+            }
+        };
+        t.in_flight = false;
+        match outcome {
+            None => {}
+            Some(TransferOutcome::Prepared(prepared)) => {
+                let wait = match &prepared.entry {
+                    Some(entry) => {
+                        self.try_append(t.destination, entry.clone(), StoreDelta::default())
+                    }
+                    None => Some(prepared.wait_til_committed),
+                };
+                if let Some(index) = wait {
+                    t.advance(t.destination, index);
+                    t.prepared = Some(prepared);
+                }
+            }
+            Some(TransferOutcome::Out {
+                entry,
+                delta,
+                partition,
+                wait_til_committed,
+            }) => {
+                let wait = match entry {
+                    Some(entry) => self.try_append(t.source, entry, delta),
+                    None => Some(wait_til_committed),
+                };
+                if let Some(index) = wait {
+                    t.advance(t.source, index);
+                    t.partition = Some(partition);
+                }
+            }
+            Some(TransferOutcome::Statement(statement)) => {
+                t.statement = Some(statement);
+                t.phase = Phase::In;
+            }
+            Some(TransferOutcome::In { entry, delta }) => {
+                if let Some(index) = self.try_append(t.destination, entry, delta) {
+                    t.advance(t.destination, index);
+                }
+            }
+            Some(TransferOutcome::Complete { entry }) => {
+                if let Some(index) = self.try_append(t.source, entry, StoreDelta::default()) {
+                    t.advance(t.source, index);
+                }
+            }
+        }
+        self.transfer = Some(t);
+        Ok(())
+    }
+
+    fn try_append(
+        &mut self,
+        group: GroupId,
+        entry: LogEntry,
+        delta: StoreDelta<DataHash>,
+    ) -> Option<LogIndex> {
+        let index = entry.index;
+        self.append(group, entry, delta).then_some(index)
+    }
+
+    // Picks the next transfer: either splitting off the lower half of a
+    // group's records to a group that owns none, or merging a group's records
+    // into a group that owns an adjacent range.
+    fn plan_transfer(&mut self) -> Option<Transfer> {
+        let owned: Vec<(GroupId, Option<OwnedRange>)> = self
+            .groups
+            .iter()
+            .filter_map(|group| {
+                let last = self.store.last(group)?;
+                if last.transferring.is_some() {
+                    return None;
+                }
+                let range = last.partition.as_ref().map(|p| p.range.clone());
+                Some((*group, range))
+            })
+            .collect();
+        let sources: Vec<usize> = (0..owned.len()).filter(|i| owned[*i].1.is_some()).collect();
+        let source = self.pick(&sources)?;
+        let destinations: Vec<usize> = (0..owned.len()).filter(|i| *i != source).collect();
+        let destination = self.pick(&destinations)?;
+
+        let source_range = owned[source].1.clone().unwrap();
+        let range = match &owned[destination].1 {
+            None => {
+                let mid = midpoint(&source_range.start, &source_range.end);
+                if mid >= source_range.end {
+                    return None;
+                }
+                OwnedRange {
+                    start: source_range.start.clone(),
+                    end: mid,
+                }
+            }
+            Some(destination_range) => {
+                destination_range.join(&source_range)?;
+                source_range
+            }
+        };
+        Some(Transfer::new(owned[source].0, owned[destination].0, range))
+    }
+
+    // Sends a request to an HSM. Returns `None` if the HSM crashed handling
+    // it, in which case it's been restarted.
+    fn call<R: HsmRpc>(
+        &mut self,
+        hsm: usize,
+        request: R,
+    ) -> Result<Option<R::Response>, Violation> {
+        let request = marshalling::to_vec(&HsmRequestContainer {
+            req: request.to_req(),
+            metrics: MetricsAction::Skip,
+        })
+        .expect("failed to serialize HSM request");
+
+        let sim_hsm = &mut self.hsms[hsm];
+        let result = panic::catch_unwind(AssertUnwindSafe(|| sim_hsm.hsm.handle_request(&request)));
+        if sim_hsm.platform.take_crash() {
+            self.event(format_args!("hsm{hsm} crashed"));
+            self.restart(hsm)?;
+            return Ok(None);
+        }
+        match result {
+            Ok(Ok(response)) => {
+                let response: HsmResponseContainer<R::Response> =
+                    marshalling::from_slice(&response).expect("failed to deserialize HSM response");
+                Ok(Some(response.res))
+            }
+            Ok(Err(err)) => violation!("hsm{hsm} failed to handle a request: {err}"),
+            Err(panic) => violation!("hsm{hsm} panicked: {}", panic_message(&*panic)),
+        }
+    }
+
+    // Restarts an HSM from its NVRAM, losing everything else.
+    fn restart(&mut self, hsm: usize) -> Result<(), Violation> {
+        self.crashes += 1;
+        let sim_hsm = &mut self.hsms[hsm];
+        sim_hsm.hsm = match Hsm::new(
+            hsm_options(hsm),
+            sim_hsm.platform.clone(),
+            self.keys.clone(),
+        ) {
+            Ok(restarted) => restarted,
+            Err(err) => return violation!("hsm{hsm} couldn't restart from its NVRAM: {err:?}"),
+        };
+
+        // The agent learns where to resume capturing from the HSM's status.
+        let status = self.call(hsm, StatusRequest {})?;
+        self.hsms[hsm].next_capture = status
+            .iter()
+            .filter_map(|status| status.realm.as_ref())
+            .flat_map(|realm| &realm.groups)
+            .map(|group| {
+                let next = group
+                    .captured
+                    .as_ref()
+                    .map_or(LogIndex::FIRST, |(index, _)| index.next());
+                (group.id, next)
+            })
+            .collect();
+        self.statuses[hsm] = status;
+        Ok(())
+    }
+
+    fn refresh_statuses(&mut self) -> Result<(), Violation> {
+        for hsm in 0..self.hsms.len() {
+            self.statuses[hsm] = self.call(hsm, StatusRequest {})?;
+        }
+        Ok(())
+    }
+
+    fn event_response<T: Debug>(&mut self, hsm: usize, name: &str, response: &Option<T>) {
+        let outcome = match response {
+            Some(response) => variant(response),
+            None => String::from("crashed"),
+        };
+        self.event(format_args!("hsm{hsm} {name} -> {outcome}"));
+    }
+
+    fn id(&self, hsm: usize) -> HsmId {
+        self.statuses[hsm].as_ref().unwrap().id
+    }
+
+    fn realm_statement(&self, hsm: usize) -> HsmRealmStatement {
+        let status = self.statuses[hsm].as_ref().unwrap();
+        status.realm.as_ref().unwrap().statement.clone()
+    }
+
+    // Returns the role each HSM last reported in the group.
+    fn roles(&self, group: GroupId) -> impl Iterator<Item = (usize, GroupMemberRole)> + '_ {
+        self.statuses
+            .iter()
+            .enumerate()
+            .filter_map(move |(hsm, status)| {
+                let realm = status.as_ref()?.realm.as_ref()?;
+                let status = realm.groups.iter().find(|g| g.id == group)?;
+                Some((hsm, status.role.role))
+            })
+    }
+
+    fn leaders(&self, group: GroupId) -> Vec<usize> {
+        self.roles(group)
+            .filter(|(_, role)| matches!(role, GroupMemberRole::Leader { .. }))
+            .map(|(hsm, _)| hsm)
+            .collect()
+    }
+
+    // Returns the leaders and the HSMs that are stepping down, which both
+    // need commits.
+    fn leading(&self, group: GroupId) -> Vec<usize> {
+        self.roles(group)
+            .filter(|(_, role)| *role != GroupMemberRole::Witness)
+            .map(|(hsm, _)| hsm)
+            .collect()
+    }
+
+    fn pick<T: Copy>(&mut self, items: &[T]) -> Option<T> {
+        if items.is_empty() {
+            None
+        } else {
+            Some(items[self.rng.gen_range(0..items.len())])
+        }
+    }
+
+    // Spreads the records evenly over the record ID space.
+    fn record(&self, i: usize) -> RecordId {
+        let prefix = u16::try_from(i * 0x10000 / self.config.records).unwrap();
+        RecordId::min_id().with(&prefix.to_be_bytes())
+    }
+}
+
+impl Transfer {
+    fn new(source: GroupId, destination: GroupId, range: OwnedRange) -> Self {
+        Self {
+            source,
+            destination,
+            range,
+            phase: Phase::Prepare,
+            in_flight: false,
+            waiting: None,
+            prepared: None,
+            partition: None,
+            statement: None,
+        }
+    }
+
+    // Moves on to the next phase once the entry at `index` in `group` commits.
+    fn advance(&mut self, group: GroupId, index: LogIndex) {
+        self.waiting = Some((group, index));
+        self.phase = match self.phase {
+            Phase::Prepare => Phase::Out,
+            Phase::Out => Phase::Statement,
+            Phase::Statement => Phase::In,
+            Phase::In => Phase::Complete,
+            Phase::Complete | Phase::Done => Phase::Done,
+        };
+    }
+}
+
+fn hsm_options(hsm: usize) -> HsmOptions {
+    HsmOptions {
+        name: format!("hsm{hsm}"),
+        tree_overlay_size: 32,
+        max_sessions: 32,
+        max_sessions_per_partition: 16,
+        metrics: MetricsReporting::Disabled,
+        tenant_record_keys: Vec::new(),
+        software_version: String::from("sim"),
+    }
+}
+
+// Returns the midpoint of two record IDs, treating them as big-endian
+// integers.
+fn midpoint(a: &RecordId, b: &RecordId) -> RecordId {
+    let mut sum = [0u16; RecordId::NUM_BYTES];
+    let mut carry = 0;
+    for i in (0..RecordId::NUM_BYTES).rev() {
+        let s = u16::from(a.0[i]) + u16::from(b.0[i]) + carry;
+        sum[i] = s & 0xff;
+        carry = s >> 8;
+    }
+    let mut mid = [0u8; RecordId::NUM_BYTES];
+    for i in 0..RecordId::NUM_BYTES {
+        let v = (carry << 8) | sum[i];
+        mid[i] = (v >> 1) as u8;
+        carry = v & 1;
+    }
+    RecordId(mid)
+}
+
+// Returns just the enum variant name from a value's debug output, to keep
+// the trace short.
+fn variant<T: Debug>(value: &T) -> String {
+    let debug = format!("{value:?}");
+    debug
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or_default()
+        .to_owned()
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown panic"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // Set HSM_SIM_SEED to rerun a single seed, or HSM_SIM_RUNS to try more
+    // seeds.
+    fn seeds() -> Vec<u64> {
+        if let Ok(seed) = env::var("HSM_SIM_SEED") {
+            return vec![seed.parse().expect("HSM_SIM_SEED should be a number")];
+        }
+        let runs = env::var("HSM_SIM_RUNS")
+            .map(|runs| runs.parse().expect("HSM_SIM_RUNS should be a number"))
+            .unwrap_or(8);
+        (0..runs).collect()
+    }
+
+    #[test]
+    fn test_simulation() {
+        for seed in seeds() {
+            if let Err(failure) = run(Config {
+                seed,
+                ..Config::default()
+            }) {
+                panic!("{failure}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_without_faults() {
+        let report = run(Config {
+            seed: 1,
+            faults: Faults::none(),
+            ..Config::default()
+        })
+        .unwrap_or_else(|failure| panic!("{failure}"));
+        assert_eq!(0, report.crashes);
+        assert!(report.acked > 0, "no client operations completed");
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let config = Config {
+            seed: 42,
+            steps: 200,
+            ..Config::default()
+        };
+        match (run(config.clone()), run(config)) {
+            (Ok(a), Ok(b)) => assert_eq!(a.trace, b.trace),
+            (Err(a), Err(b)) => assert_eq!(a.to_string(), b.to_string()),
+            (a, b) => panic!("runs with the same seed diverged: {a:?} vs {b:?}"),
+        }
+    }
+
+    #[test]
+    fn test_midpoint() {
+        let mid = midpoint(&RecordId::min_id(), &RecordId::max_id());
+        assert_eq!(0x7f, mid.0[0]);
+        assert!(mid.0[1..].iter().all(|b| *b == 0xff));
+
+        let a = RecordId::min_id().with(&[0, 2]);
+        let b = RecordId::min_id().with(&[0, 4]);
+        assert_eq!(RecordId::min_id().with(&[0, 3]), midpoint(&a, &b));
+        assert_eq!(a, midpoint(&a, &a));
+    }
+}
@@ -0,0 +1,55 @@
+use std::collections::BTreeMap;
+
+use hsm_api::merkle::StoreDelta;
+use hsm_api::{DataHash, EntryMac, GroupId, LogEntry, LogIndex};
+use hsm_core::merkle::testing::MemStore;
+
+/// The simulation's stand-in for the logs and Merkle trees that the agents
+/// keep in Bigtable.
+#[derive(Default)]
+pub struct Store {
+    logs: BTreeMap<GroupId, Vec<LogEntry>>,
+    pub tree: MemStore<DataHash>,
+}
+
+impl Store {
+    /// Appends an entry to a group's log, like the agents' conditional write
+    /// to Bigtable: it only succeeds if the entry follows on from the current
+    /// last entry. Returns false if some other entry got there first.
+    pub fn append(&mut self, group: GroupId, entry: LogEntry, delta: StoreDelta<DataHash>) -> bool {
+        let log = self.logs.entry(group).or_default();
+        let follows = match log.last() {
+            None => entry.index == LogIndex::FIRST && entry.prev_mac == EntryMac::zero(),
+            Some(last) => entry.index == last.index.next() && entry.prev_mac == last.entry_mac,
+        };
+        if !follows {
+            return false;
+        }
+        if let Some(partition) = &entry.partition {
+            self.tree.apply_store_delta(partition.root_hash, delta);
+        }
+        log.push(entry);
+        true
+    }
+
+    pub fn log(&self, group: &GroupId) -> &[LogEntry] {
+        self.logs.get(group).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    pub fn last(&self, group: &GroupId) -> Option<&LogEntry> {
+        self.log(group).last()
+    }
+
+    pub fn get(&self, group: &GroupId, index: LogIndex) -> Option<&LogEntry> {
+        let log = self.log(group);
+        let first = log.first()?.index;
+        let offset = usize::try_from(index.0.checked_sub(first.0)?).ok()?;
+        log.get(offset)
+    }
+
+    /// Returns true if the entry is in the log at its index.
+    pub fn contains(&self, group: &GroupId, index: LogIndex, mac: &EntryMac) -> bool {
+        self.get(group, index)
+            .is_some_and(|entry| entry.entry_mac == *mac)
+    }
+}
@@ -0,0 +1,459 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time;
+
+use store::admission::AdmissionUsage;
+use store::tenant_config::TenantConfiguration;
+
+/// Limits how many client requests the load balancer forwards to agents at
+/// once, in total and for each tenant.
+///
+/// Requests over a limit wait in a queue for their tenant. Whenever a request
+/// finishes, the next one admitted comes from the tenant that's been served
+/// the least relative to its weight (weighted fair queuing), so a tenant
+/// flooding the load balancer mostly delays its own requests. Requests that
+/// aren't admitted within the queue timeout are shed.
+///
+/// Tenants' concurrency limits are for the whole cluster. Each load balancer
+/// periodically shares its demand from each tenant (its requests in flight and
+/// queued) through the store, and lets a tenant have a share of its limit in
+/// proportion to the demand here versus the demand the other load balancers
+/// last reported. A tenant whose requests all arrive at one load balancer gets
+/// its whole limit there. The reports are only a second or so old, so this is
+/// coarse: the tenant can briefly go over its limit when its requests shift
+/// between load balancers.
+pub struct AdmissionController(Arc<Mutex<Inner>>);
+
+#[derive(Clone, Copy, Debug)]
+pub struct AdmissionOptions {
+    /// The maximum number of requests this load balancer forwards at once.
+    pub max_concurrent: usize,
+    /// The cluster-wide concurrency limit for tenants without a
+    /// configuration.
+    pub default_tenant_concurrency: usize,
+    /// How long a request may wait to be admitted before it's shed.
+    pub queue_timeout: Duration,
+}
+
+/// Why a request wasn't admitted.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Rejected {
+    /// The tenant already has as many requests queued as it's allowed in
+    /// flight.
+    QueueFull,
+    /// The request waited for the whole queue timeout.
+    Timeout,
+}
+
+impl Rejected {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rejected::QueueFull => "queue_full",
+            Rejected::Timeout => "timeout",
+        }
+    }
+}
+
+/// Counts towards the concurrency limits until dropped.
+#[must_use]
+pub struct Permit {
+    // This is `None` for a permit that was never handed out.
+    controller: Option<Arc<Mutex<Inner>>>,
+    tenant: String,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(controller) = self.controller.take() {
+            let mut inner = controller.lock().unwrap();
+            inner.release(&self.tenant);
+            inner.dispatch(&controller);
+        }
+    }
+}
+
+struct Inner {
+    options: AdmissionOptions,
+    /// Cluster-wide concurrency limits for configured tenants.
+    tenant_limits: HashMap<String, usize>,
+    /// The demand from each tenant at the other load balancers, as they last
+    /// reported it.
+    remote_usage: HashMap<String, usize>,
+    in_flight: usize,
+    /// The tenants with requests in flight or queued.
+    active: HashMap<String, TenantState>,
+    /// The virtual start time of the last request admitted from a queue.
+    vtime: f64,
+    next_waiter_id: u64,
+}
+
+#[derive(Default)]
+struct TenantState {
+    in_flight: usize,
+    queue: VecDeque<Waiter>,
+    /// The virtual finish time of the tenant's last request admitted from its
+    /// queue.
+    finish: f64,
+}
+
+struct Waiter {
+    id: u64,
+    tx: oneshot::Sender<Permit>,
+}
+
+impl AdmissionController {
+    pub fn new(options: AdmissionOptions) -> Self {
+        Self(Arc::new(Mutex::new(Inner {
+            options,
+            tenant_limits: HashMap::new(),
+            remote_usage: HashMap::new(),
+            in_flight: 0,
+            active: HashMap::new(),
+            vtime: 0.0,
+            next_waiter_id: 0,
+        })))
+    }
+
+    /// Replaces the tenants' concurrency limits with those implied by their
+    /// configuration. A tenant may have as many requests in flight as its
+    /// burst allowance, since the agents would reject any more than that.
+    pub fn update_tenants(&self, tenants: &[(String, TenantConfiguration)]) {
+        let limits = tenants
+            .iter()
+            .map(|(tenant, config)| {
+                let limit = config.burst_reqs()
+                    + config
+                        .realm_capacities
+                        .iter()
+                        .map(|rc| rc.burst_reqs())
+                        .sum::<usize>();
+                (tenant.clone(), limit)
+            })
+            .collect();
+        let mut inner = self.0.lock().unwrap();
+        inner.tenant_limits = limits;
+        inner.dispatch(&self.0);
+    }
+
+    /// Returns whether the tenant has a configuration, as of the last
+    /// [`Self::update_tenants`].
+    pub fn is_configured(&self, tenant: &str) -> bool {
+        self.0.lock().unwrap().tenant_limits.contains_key(tenant)
+    }
+
+    /// Returns this load balancer's current demand from each tenant, for the
+    /// other load balancers.
+    pub fn usage(&self) -> AdmissionUsage {
+        let inner = self.0.lock().unwrap();
+        AdmissionUsage {
+            tenants: inner
+                .active
+                .iter()
+                .map(|(tenant, state)| (tenant.clone(), state.demand()))
+                .collect(),
+        }
+    }
+
+    /// Replaces the demand from each tenant at the other load balancers,
+    /// summed across them.
+    pub fn set_remote_usage(&self, usage: HashMap<String, usize>) {
+        let mut inner = self.0.lock().unwrap();
+        inner.remote_usage = usage;
+        inner.dispatch(&self.0);
+    }
+
+    /// Waits for the tenant's request to be admitted.
+    pub async fn admit(&self, tenant: &str) -> Result<Permit, Rejected> {
+        let (id, mut rx, timeout) = {
+            let mut locked = self.0.lock().unwrap();
+            let inner = &mut *locked;
+            let demand = inner.active.get(tenant).map_or(0, TenantState::demand) + 1;
+            let share = inner.tenant_share(tenant, demand);
+            let global_available = inner.in_flight < inner.options.max_concurrent;
+            let state = inner.active.entry(tenant.to_owned()).or_default();
+            if state.queue.is_empty() && state.in_flight < share && global_available {
+                state.in_flight += 1;
+                inner.in_flight += 1;
+                return Ok(Permit {
+                    controller: Some(self.0.clone()),
+                    tenant: tenant.to_owned(),
+                });
+            }
+            if state.queue.len() >= share {
+                return Err(Rejected::QueueFull);
+            }
+            let id = inner.next_waiter_id;
+            inner.next_waiter_id += 1;
+            let (tx, rx) = oneshot::channel();
+            state.queue.push_back(Waiter { id, tx });
+            (id, rx, inner.options.queue_timeout)
+        };
+
+        match time::timeout(timeout, &mut rx).await {
+            Ok(Ok(permit)) => Ok(permit),
+            Ok(Err(_)) | Err(_) => {
+                if self.0.lock().unwrap().remove_waiter(tenant, id) {
+                    return Err(Rejected::Timeout);
+                }
+                // The request was admitted just as the timeout fired, and
+                // the permit is waiting in the channel.
+                rx.try_recv().map_err(|_| Rejected::Timeout)
+            }
+        }
+    }
+}
+
+impl TenantState {
+    fn demand(&self) -> usize {
+        self.in_flight + self.queue.len()
+    }
+}
+
+impl Inner {
+    /// Returns the tenant's cluster-wide concurrency limit.
+    fn tenant_limit(&self, tenant: &str) -> usize {
+        match self.tenant_limits.get(tenant) {
+            Some(limit) => *limit,
+            None => self.options.default_tenant_concurrency,
+        }
+    }
+
+    /// Returns this load balancer's share of the tenant's concurrency limit,
+    /// given the tenant's demand here.
+    fn tenant_share(&self, tenant: &str, demand: usize) -> usize {
+        let limit = self.tenant_limit(tenant);
+        match self.remote_usage.get(tenant) {
+            None | Some(0) => limit,
+            Some(remote) => (limit * demand).div_ceil(demand + remote).max(1),
+        }
+    }
+
+    fn release(&mut self, tenant: &str) {
+        self.in_flight -= 1;
+        let state = self
+            .active
+            .get_mut(tenant)
+            .expect("tenants with requests in flight are active");
+        state.in_flight -= 1;
+        if state.in_flight == 0 && state.queue.is_empty() {
+            self.active.remove(tenant);
+        }
+    }
+
+    /// Removes a waiter that's given up. Returns false if it's no longer
+    /// queued because it was admitted.
+    fn remove_waiter(&mut self, tenant: &str, id: u64) -> bool {
+        let Some(state) = self.active.get_mut(tenant) else {
+            return false;
+        };
+        let Some(index) = state.queue.iter().position(|w| w.id == id) else {
+            return false;
+        };
+        state.queue.remove(index);
+        if state.in_flight == 0 && state.queue.is_empty() {
+            self.active.remove(tenant);
+        }
+        true
+    }
+
+    /// Admits queued requests while there's room, picking the tenant with the
+    /// earliest virtual finish time each time. Ties go to the request that's
+    /// been waiting longest.
+    fn dispatch(&mut self, controller: &Arc<Mutex<Inner>>) {
+        while self.in_flight < self.options.max_concurrent {
+            let next = self
+                .active
+                .iter()
+                .filter_map(|(tenant, state)| {
+                    if state.queue.is_empty()
+                        || state.in_flight >= self.tenant_share(tenant, state.demand())
+                    {
+                        return None;
+                    }
+                    let limit = self.tenant_limit(tenant);
+                    let start = state.finish.max(self.vtime);
+                    let waiter_id = state.queue.front().unwrap().id;
+                    Some((start + 1.0 / limit as f64, waiter_id, start, tenant))
+                })
+                .min_by(|(a, a_id, _, _), (b, b_id, _, _)| a.total_cmp(b).then(a_id.cmp(b_id)))
+                .map(|(finish, _, start, tenant)| (finish, start, tenant.clone()));
+            let Some((finish, start, tenant)) = next else {
+                return;
+            };
+
+            let state = self.active.get_mut(&tenant).unwrap();
+            let waiter = state.queue.pop_front().unwrap();
+            state.finish = finish;
+            state.in_flight += 1;
+            self.in_flight += 1;
+            self.vtime = start;
+            let permit = Permit {
+                controller: Some(controller.clone()),
+                tenant,
+            };
+            if let Err(mut permit) = waiter.tx.send(permit) {
+                // The request was cancelled while it waited. Releasing the
+                // permit here avoids locking the mutex again in drop.
+                permit.controller = None;
+                self.release(&permit.tenant);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    fn options(max_concurrent: usize) -> AdmissionOptions {
+        AdmissionOptions {
+            max_concurrent,
+            default_tenant_concurrency: 2,
+            queue_timeout: Duration::from_secs(60),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tenant_limit() {
+        let ac = AdmissionController::new(options(100));
+        let a1 = ac.admit("a").await.unwrap();
+        let _a2 = ac.admit("a").await.unwrap();
+        let _b1 = ac.admit("b").await.unwrap();
+
+        // "a" is at its limit, so its next requests queue, up to the limit.
+        let mut a3 = Box::pin(ac.admit("a"));
+        assert!((&mut a3).now_or_never().is_none());
+        let mut a4 = Box::pin(ac.admit("a"));
+        assert!((&mut a4).now_or_never().is_none());
+        assert!(matches!(
+            ac.admit("a").now_or_never(),
+            Some(Err(Rejected::QueueFull))
+        ));
+
+        drop(a1);
+        let _a3 = (&mut a3).now_or_never().unwrap().unwrap();
+        assert!((&mut a4).now_or_never().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_configured_limits() {
+        let ac = AdmissionController::new(options(100));
+        // 1 op/s is a burst of 3 requests.
+        ac.update_tenants(&[(String::from("a"), TenantConfiguration::new(1))]);
+        let mut permits = Vec::new();
+        for _ in 0..3 {
+            permits.push(ac.admit("a").await.unwrap());
+        }
+        assert!(Box::pin(ac.admit("a")).now_or_never().is_none());
+
+        assert!(ac.is_configured("a"));
+        assert!(!ac.is_configured("b"));
+    }
+
+    #[tokio::test]
+    async fn test_shared_limits() {
+        let ac = AdmissionController::new(options(100));
+        // 2 ops/s is a burst of 6 requests.
+        ac.update_tenants(&[(String::from("a"), TenantConfiguration::new(2))]);
+
+        // Another load balancer has 6 requests from "a", so this one gets a
+        // share in proportion to its own demand.
+        ac.set_remote_usage(HashMap::from([(String::from("a"), 6)]));
+        let mut permits = Vec::new();
+        for _ in 0..2 {
+            permits.push(ac.admit("a").await.unwrap());
+        }
+        let mut queued = Box::pin(ac.admit("a"));
+        assert!((&mut queued).now_or_never().is_none());
+        assert_eq!(HashMap::from([(String::from("a"), 3)]), ac.usage().tenants);
+
+        // Once the other load balancer's demand goes away, this one can use
+        // the whole limit.
+        ac.set_remote_usage(HashMap::new());
+        permits.push((&mut queued).now_or_never().unwrap().unwrap());
+        for _ in 0..3 {
+            permits.push(ac.admit("a").await.unwrap());
+        }
+        assert!(Box::pin(ac.admit("a")).now_or_never().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fair_queuing() {
+        let ac = AdmissionController::new(options(2));
+        ac.update_tenants(&[
+            (String::from("heavy"), TenantConfiguration::new(10)),
+            (String::from("light"), TenantConfiguration::new(10)),
+        ]);
+        let mut running = vec![
+            ac.admit("heavy").await.unwrap(),
+            ac.admit("heavy").await.unwrap(),
+        ];
+
+        // "heavy" queues up lots of requests before "light" queues a few.
+        let mut queued: VecDeque<(&str, _)> = VecDeque::new();
+        for _ in 0..6 {
+            queued.push_back(("heavy", Box::pin(ac.admit("heavy"))));
+        }
+        for _ in 0..3 {
+            queued.push_back(("light", Box::pin(ac.admit("light"))));
+        }
+        for (_, admit) in &mut queued {
+            assert!(admit.now_or_never().is_none());
+        }
+
+        // As requests finish one at a time, the two tenants alternate.
+        let mut order = Vec::new();
+        while !queued.is_empty() {
+            running.remove(0);
+            let mut admitted = None;
+            for (i, (tenant, admit)) in queued.iter_mut().enumerate() {
+                if let Some(result) = admit.now_or_never() {
+                    running.push(result.unwrap());
+                    admitted = Some((i, *tenant));
+                    break;
+                }
+            }
+            let (i, tenant) = admitted.expect("a queued request should be admitted");
+            order.push(tenant);
+            queued.remove(i);
+        }
+        assert_eq!(
+            vec!["heavy", "light", "heavy", "light", "heavy", "light", "heavy", "heavy", "heavy"],
+            order
+        );
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let ac = AdmissionController::new(AdmissionOptions {
+            queue_timeout: Duration::from_millis(10),
+            ..options(1)
+        });
+        let permit = ac.admit("a").await.unwrap();
+        assert_eq!(Err(Rejected::Timeout), ac.admit("b").await.map(|_| ()));
+        drop(permit);
+        let _permit = ac.admit("b").await.unwrap();
+        assert!(ac.0.lock().unwrap().active.get("a").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_waiter() {
+        let ac = AdmissionController::new(options(1));
+        let permit = ac.admit("a").await.unwrap();
+        let mut cancelled = Box::pin(ac.admit("b"));
+        assert!((&mut cancelled).now_or_never().is_none());
+        let mut waiting = Box::pin(ac.admit("c"));
+        assert!((&mut waiting).now_or_never().is_none());
+        drop(cancelled);
+
+        // The slot skips over the cancelled request.
+        drop(permit);
+        assert!(waiting.now_or_never().unwrap().is_ok());
+        let inner = ac.0.lock().unwrap();
+        assert_eq!(0, inner.in_flight);
+        assert!(inner.active.is_empty());
+    }
+}
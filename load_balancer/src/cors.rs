@@ -0,0 +1,151 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use store::tenant_config::TenantConfiguration;
+
+/// Tracks which browser origins each tenant allows to make requests, as
+/// configured in [`TenantConfiguration::allowed_origins`].
+///
+/// Tenants without an allowlist accept requests from any origin.
+pub struct CorsPolicy {
+    /// If set, preflight requests are only allowed from origins that some
+    /// tenant has allowed. Otherwise, tenants that haven't configured their
+    /// origins would break, since preflight requests don't identify the
+    /// tenant.
+    restrict_preflight: bool,
+    origins: Mutex<Arc<Origins>>,
+}
+
+#[derive(Debug, Default)]
+struct Origins {
+    /// The allowed origins for each tenant that restricts them.
+    by_tenant: HashMap<String, HashSet<String>>,
+    /// The tenants that allow each origin.
+    by_origin: HashMap<String, Vec<String>>,
+}
+
+impl CorsPolicy {
+    pub fn new(restrict_preflight: bool) -> Self {
+        Self {
+            restrict_preflight,
+            origins: Mutex::new(Arc::new(Origins::default())),
+        }
+    }
+
+    /// Replaces the allowed origins with those from the tenant configuration.
+    pub fn update(&self, tenants: &[(String, TenantConfiguration)]) {
+        let mut origins = Origins::default();
+        for (tenant, config) in tenants {
+            let Some(allowed) = &config.allowed_origins else {
+                continue;
+            };
+            let allowed: HashSet<String> = allowed.iter().map(|o| normalize(o)).collect();
+            for origin in &allowed {
+                origins
+                    .by_origin
+                    .entry(origin.clone())
+                    .or_default()
+                    .push(tenant.clone());
+            }
+            origins.by_tenant.insert(tenant.clone(), allowed);
+        }
+        *self.origins.lock().unwrap() = Arc::new(origins);
+    }
+
+    /// Returns the tenants that have allowed this origin, or `None` if the
+    /// origin may not make requests before the tenant is known.
+    ///
+    /// An empty list means no tenant has allowed the origin, but preflight
+    /// requests aren't restricted.
+    pub fn preflight_allows(&self, origin: &str) -> Option<Vec<String>> {
+        let origins = self.origins.lock().unwrap().clone();
+        match origins.by_origin.get(&normalize(origin)) {
+            Some(tenants) => Some(tenants.clone()),
+            None if self.restrict_preflight => None,
+            None => Some(Vec::new()),
+        }
+    }
+
+    /// Returns true if the tenant accepts requests from this origin.
+    pub fn tenant_allows(&self, tenant: &str, origin: &str) -> bool {
+        let origins = self.origins.lock().unwrap().clone();
+        match origins.by_tenant.get(tenant) {
+            None => true,
+            Some(allowed) => allowed.contains(&normalize(origin)),
+        }
+    }
+}
+
+// Origins are compared case-insensitively and without a trailing slash, which
+// browsers never send but is easy to include in configuration.
+fn normalize(origin: &str) -> String {
+    origin.trim_end_matches('/').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tenants() -> Vec<(String, TenantConfiguration)> {
+        vec![
+            (
+                String::from("acme"),
+                TenantConfiguration {
+                    allowed_origins: Some(vec![
+                        String::from("https://acme.com"),
+                        String::from("https://Shared.example/"),
+                    ]),
+                    ..TenantConfiguration::new(10)
+                },
+            ),
+            (
+                String::from("bigco"),
+                TenantConfiguration {
+                    allowed_origins: Some(vec![String::from("https://shared.example")]),
+                    ..TenantConfiguration::new(10)
+                },
+            ),
+            (String::from("open"), TenantConfiguration::new(10)),
+        ]
+    }
+
+    #[test]
+    fn test_tenant_allows() {
+        let policy = CorsPolicy::new(false);
+        assert!(policy.tenant_allows("acme", "https://acme.com"));
+
+        policy.update(&tenants());
+        assert!(policy.tenant_allows("acme", "https://acme.com"));
+        assert!(policy.tenant_allows("acme", "https://ACME.com"));
+        assert!(policy.tenant_allows("acme", "https://shared.example"));
+        assert!(!policy.tenant_allows("acme", "https://evil.com"));
+        assert!(!policy.tenant_allows("bigco", "https://acme.com"));
+        assert!(policy.tenant_allows("open", "https://evil.com"));
+        assert!(policy.tenant_allows("unconfigured", "https://evil.com"));
+
+        policy.update(&[]);
+        assert!(policy.tenant_allows("acme", "https://evil.com"));
+    }
+
+    #[test]
+    fn test_preflight_allows() {
+        let open = CorsPolicy::new(false);
+        let restricted = CorsPolicy::new(true);
+        assert_eq!(Some(vec![]), open.preflight_allows("https://acme.com"));
+        assert_eq!(None, restricted.preflight_allows("https://acme.com"));
+
+        open.update(&tenants());
+        restricted.update(&tenants());
+        for policy in [&open, &restricted] {
+            assert_eq!(
+                Some(vec![String::from("acme")]),
+                policy.preflight_allows("https://acme.com")
+            );
+            let mut shared = policy.preflight_allows("https://shared.example").unwrap();
+            shared.sort();
+            assert_eq!(vec![String::from("acme"), String::from("bigco")], shared);
+        }
+        assert_eq!(Some(vec![]), open.preflight_allows("https://evil.com"));
+        assert_eq!(None, restricted.preflight_allows("https://evil.com"));
+    }
+}
@@ -6,32 +6,40 @@ use futures::future::join_all;
 use futures::{select_biased, FutureExt};
 use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
 use hyper::body::Incoming as IncomingBody;
+use hyper::header::{ACCESS_CONTROL_ALLOW_ORIGIN, ORIGIN, VARY};
 use hyper::http::HeaderValue;
 use hyper::server::conn::{http1, http2};
 use hyper::service::Service;
 use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
+use rand_core::{OsRng, RngCore};
 use rustls::server::ResolvesServerCert;
 use semver::Version;
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::future::Future;
 use std::iter::zip;
 use std::net::SocketAddr;
 use std::pin::{pin, Pin};
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::net::TcpListener;
 use tokio::task::JoinHandle;
 use tokio::time;
 use tokio_rustls::{rustls, TlsAcceptor};
-use tracing::{span, trace, warn, Instrument, Level, Span};
+use tracing::{info, span, trace, warn, Instrument, Level, Span};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
+use super::admission::{AdmissionController, AdmissionOptions, Permit, Rejected};
+use super::cors::CorsPolicy;
+use super::routing::{AgentRoutes, Partition, RoutingTable};
 use super::server::{HealthCheckStatus, ManagerOptions, ServiceManager};
-use agent_api::{AppRequest, AppResponse, HashedUserId, StatusRequest, StatusResponse};
-use hsm_api::{GroupId, OwnedRange, RecordId};
+use agent_api::{
+    AppRequest, AppResponse, HashedSlotId, HashedUserId, LeaderPartition, PartitionWatchRequest,
+    StatusRequest, StatusResponse,
+};
+use hsm_api::RecordId;
 use jburl::Url;
 use juicebox_marshalling as marshalling;
 use juicebox_networking::reqwest::ClientOptions;
@@ -48,8 +56,12 @@ use secret_manager::{
     record_id_randomization_key_name, tenant_secret_name, Secret, SecretAlgorithm, SecretManager,
 };
 use service_core::http::ReqwestClientMetrics;
+use store::tenant_config::TenantConfiguration;
 use store::{ServiceKind, StoreClient};
 
+mod slot;
+mod tenant;
+
 #[derive(Clone)]
 pub struct LoadBalancer(Arc<State>);
 
@@ -58,11 +70,14 @@ struct State {
     store: StoreClient,
     secret_manager: Box<dyn SecretManager>,
     agent_client: ReqwestClientMetrics,
-    realms: Mutex<Arc<HashMap<RealmId, Vec<Partition>>>>,
+    routes: RoutingTable,
     metrics: metrics::Client,
     semver: Version,
     svc_mgr: ServiceManager,
     record_id_randomization_key: RecordIdRandomizationKey,
+    cors: CorsPolicy,
+    suspended: SuspendedTenants,
+    admission: AdmissionController,
 }
 
 impl LoadBalancer {
@@ -72,6 +87,8 @@ impl LoadBalancer {
         secret_manager: Box<dyn SecretManager>,
         metrics: metrics::Client,
         svc_cfg: ManagerOptions,
+        restrict_cors_preflight: bool,
+        admission: AdmissionOptions,
     ) -> Result<Self, anyhow::Error> {
         let (_version, secret) = secret_manager
             .get_latest_secret_version(&record_id_randomization_key_name())
@@ -87,10 +104,13 @@ impl LoadBalancer {
                 record_id_randomization_key_name().0
             ))?,
             agent_client: ReqwestClientMetrics::new(metrics.clone(), ClientOptions::default()),
-            realms: Mutex::new(Arc::new(HashMap::new())),
+            routes: RoutingTable::default(),
             metrics: metrics.clone(),
             semver: Version::parse(env!("CARGO_PKG_VERSION")).unwrap(),
             svc_mgr: ServiceManager::new(svc_cfg, metrics),
+            cors: CorsPolicy::new(restrict_cors_preflight),
+            suspended: SuspendedTenants::default(),
+            admission: AdmissionController::new(admission),
         })))
     }
 
@@ -104,6 +124,7 @@ impl LoadBalancer {
             .with_context(|| format!("failed to bind to {address}"))?;
         let url = Url::parse(&format!("https://{address}")).unwrap();
         self.start_refresher().await;
+        self.start_usage_sharing();
 
         let mut config = rustls::ServerConfig::builder()
             .with_no_client_auth()
@@ -188,38 +209,238 @@ impl LoadBalancer {
         self.0.svc_mgr.shut_down().await;
     }
 
+    /// Periodically shares this load balancer's demand from each tenant with
+    /// the other load balancers through the store, and reads theirs back, so
+    /// that they can split the tenants' concurrency limits between them.
+    fn start_usage_sharing(&self) {
+        let state = self.0.clone();
+        // Load balancer names and listen addresses aren't necessarily unique,
+        // so the usage is reported under a random ID.
+        let id = format!("{}/{:016x}", state.name, OsRng.next_u64());
+        tokio::spawn(async move {
+            loop {
+                let usage = state.admission.usage();
+                if let Err(err) = state
+                    .store
+                    .put_admission_usage(&id, &usage, SystemTime::now())
+                    .await
+                {
+                    warn!(
+                        load_balancer = state.name,
+                        ?err,
+                        "failed to report admission usage"
+                    );
+                }
+                match state.store.get_admission_usage().await {
+                    Ok(reports) => {
+                        let mut remote: HashMap<String, usize> = HashMap::new();
+                        for (_, usage) in reports.into_iter().filter(|(lb, _)| *lb != id) {
+                            for (tenant, demand) in usage.tenants {
+                                *remote.entry(tenant).or_default() += demand;
+                            }
+                        }
+                        state.admission.set_remote_usage(remote);
+                    }
+                    // The previous reports stay in effect.
+                    Err(err) => {
+                        warn!(
+                            load_balancer = state.name,
+                            ?err,
+                            "failed to read admission usage"
+                        );
+                    }
+                }
+                time::sleep(USAGE_SHARING_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Keeps the routing table and tenant configuration up to date.
+    ///
+    /// Each agent is watched for changes to the partitions it's leading. The
+    /// set of agents to watch comes from service discovery. As a fallback,
+    /// such as for agents that don't support watching, every agent is
+    /// periodically asked for its full status.
     async fn start_refresher(&self) {
         let state = self.0.clone();
         tokio::spawn(async move {
             let cx = opentelemetry::Context::new().with_value(TracingSource::BackgroundJob);
+            let mut watchers: HashMap<Url, JoinHandle<()>> = HashMap::new();
+            let mut last_full_refresh: Option<Instant> = None;
 
             loop {
                 let span = span!(Level::TRACE, "refresher_loop");
                 span.set_parent(cx.clone());
 
-                let updated = refresh(&state.name, &state.store, &state.agent_client)
-                    .instrument(span)
-                    .await;
-                *state.realms.lock().unwrap() = Arc::new(updated);
-                time::sleep(Duration::from_millis(100)).await;
+                async {
+                    let refresh_due =
+                        last_full_refresh.map_or(true, |t| t.elapsed() >= FULL_REFRESH_INTERVAL);
+                    if refresh_due {
+                        refresh(
+                            &state.name,
+                            &state.store,
+                            &state.agent_client,
+                            &state.routes,
+                        )
+                        .await;
+                        refresh_tenant_config(&state).await;
+                        last_full_refresh = Some(Instant::now());
+                    }
+
+                    match state.store.get_addresses(Some(ServiceKind::Agent)).await {
+                        Err(err) => {
+                            warn!(
+                                load_balancer = state.name,
+                                ?err,
+                                "failed to discover agents"
+                            );
+                        }
+                        Ok(addresses) => {
+                            let agents: HashSet<Url> =
+                                addresses.into_iter().map(|(url, _)| url).collect();
+                            watchers.retain(|agent, watcher| {
+                                let keep = agents.contains(agent);
+                                if !keep {
+                                    watcher.abort();
+                                }
+                                keep
+                            });
+                            state.routes.retain(|agent| agents.contains(agent));
+                            for agent in agents {
+                                watchers.entry(agent.clone()).or_insert_with(|| {
+                                    tokio::spawn(watch_partitions(state.clone(), agent))
+                                });
+                            }
+                        }
+                    }
+                }
+                .instrument(span)
+                .await;
+                time::sleep(DISCOVERY_INTERVAL).await;
             }
         });
     }
 }
 
-#[derive(Debug)]
-struct Partition {
-    group: GroupId,
-    owned_range: OwnedRange,
-    leader: Url,
+/// Limits how long the load balancer spends failing over a client's request
+/// to a group's new leader.
+const REQUEST_DEADLINE: Duration = Duration::from_secs(10);
+
+/// How often the load balancer checks service discovery for new or removed
+/// agents.
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the load balancer shares its demand from each tenant with the
+/// other load balancers.
+const USAGE_SHARING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the load balancer asks every agent for its full status, in case
+/// it missed updates from watching them.
+const FULL_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long an agent holds a partition watch request open when nothing's
+/// changed.
+const PARTITION_WATCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Reloads the tenants' allowed origins, suspensions and concurrency limits
+/// from the store. On failure, the previous configuration stays in effect.
+async fn refresh_tenant_config(state: &State) {
+    match state.store.get_tenants().await {
+        Ok(tenants) => {
+            state.cors.update(&tenants);
+            state.suspended.update(&tenants);
+            state.admission.update_tenants(&tenants);
+        }
+        Err(err) => {
+            warn!(
+                load_balancer = state.name,
+                ?err,
+                "failed to read tenant configuration"
+            );
+        }
+    }
 }
 
-#[tracing::instrument(level = "trace", skip(store, agent_client))]
+/// The tenants that were suspended as of the last refresh (see
+/// [`TenantConfiguration::suspended`]).
+///
+/// Agents also reject requests for suspended tenants, and they're told about
+/// changes right away, so this only saves them the work.
+#[derive(Default)]
+struct SuspendedTenants(Mutex<HashSet<String>>);
+
+impl SuspendedTenants {
+    fn update(&self, tenants: &[(String, TenantConfiguration)]) {
+        *self.0.lock().unwrap() = tenants
+            .iter()
+            .filter(|(_, config)| config.is_suspended())
+            .map(|(tenant, _)| tenant.clone())
+            .collect();
+    }
+
+    fn contains(&self, tenant: &str) -> bool {
+        self.0.lock().unwrap().contains(tenant)
+    }
+}
+
+/// Long-polls the agent for changes to the partitions it's leading and records
+/// them in the routing table. Runs until aborted.
+async fn watch_partitions(state: Arc<State>, agent: Url) {
+    loop {
+        let since = state.routes.version(&agent);
+        match rpc::send_with_options(
+            &state.agent_client,
+            &agent,
+            PartitionWatchRequest {
+                since,
+                timeout: PARTITION_WATCH_TIMEOUT,
+            },
+            SendOptions::default().with_timeout(PARTITION_WATCH_TIMEOUT + Duration::from_secs(5)),
+        )
+        .await
+        {
+            Ok(response) => {
+                if since != Some(response.version) {
+                    trace!(
+                        load_balancer = state.name,
+                        %agent,
+                        version = response.version,
+                        "agent partitions changed"
+                    );
+                }
+                if since.is_some_and(|since| response.version < since) {
+                    // The agent restarted and its clock went backwards. Its
+                    // new versions would otherwise be ignored as stale.
+                    state.routes.forget_version(&agent);
+                }
+                state.routes.update(
+                    &agent,
+                    Some(response.version),
+                    AgentRoutes {
+                        partitions: response.partitions,
+                        groups: response.groups,
+                    },
+                );
+            }
+            Err(err) => {
+                // The periodic full refresh covers this agent in the meantime.
+                warn!(load_balancer = state.name, %agent, %err, "could not watch partitions");
+                state.routes.forget_version(&agent);
+                time::sleep(FULL_REFRESH_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// Asks every agent for its status and records the partitions they're leading
+/// in the routing table.
+#[tracing::instrument(level = "trace", skip(store, agent_client, routes))]
 async fn refresh(
     name: &str,
     store: &StoreClient,
     agent_client: &ReqwestClientMetrics,
-) -> HashMap<RealmId, Vec<Partition>> {
+    routes: &RoutingTable,
+) {
     match store.get_addresses(Some(ServiceKind::Agent)).await {
         Err(err) => todo!("{err:?}"),
         Ok(addresses) => {
@@ -233,9 +454,8 @@ async fn refresh(
             }))
             .await;
 
-            let mut realms: HashMap<RealmId, Vec<Partition>> = HashMap::new();
-            for ((agent, _), response) in zip(addresses, responses) {
-                match response {
+            for ((agent, _), response) in zip(&addresses, responses) {
+                let agent_routes = match response {
                     Ok(StatusResponse {
                         hsm:
                             Some(hsm_api::StatusResponse {
@@ -243,29 +463,33 @@ async fn refresh(
                                 ..
                             }),
                         ..
-                    }) => {
-                        let realm = realms.entry(status.id).or_default();
-                        for group in status.groups {
-                            if let Some(leader) = group.leader {
-                                if let Some(owned_range) = leader.owned_range {
-                                    realm.push(Partition {
-                                        group: group.id,
-                                        owned_range,
-                                        leader: agent.clone(),
-                                    });
-                                }
-                            }
-                        }
-                    }
+                    }) => AgentRoutes {
+                        partitions: status
+                            .groups
+                            .iter()
+                            .filter_map(|group| {
+                                LeaderPartition::from_group_status(status.id, group)
+                            })
+                            .collect(),
+                        groups: status
+                            .groups
+                            .iter()
+                            .map(|group| (status.id, group.id))
+                            .collect(),
+                    },
 
-                    Ok(_) => {}
+                    Ok(_) => AgentRoutes::default(),
 
                     Err(err) => {
                         warn!(load_balancer = name, %agent, %err, "could not get status");
+                        AgentRoutes::default()
                     }
-                }
+                };
+                // The status doesn't come with a version, so this only
+                // applies to agents whose partition watch isn't working.
+                routes.update(agent, None, agent_routes);
             }
-            realms
+            routes.retain(|agent| addresses.iter().any(|(address, _)| address == agent));
         }
     }
 }
@@ -285,18 +509,24 @@ impl Service<Request<IncomingBody>> for LoadBalancer {
                     ("/req", &Method::POST) => state.handle_req(request).await,
                     ("/livez", &Method::GET) => state.handle_livez(request).await,
                     ("/rttest", &Method::POST) => state.handle_rttest(request).await,
+                    ("/tenant/user/delete", &Method::POST) => {
+                        state
+                            .handle_tenant_user(request, tenant::TenantUserOperation::Delete)
+                            .await
+                    }
+                    ("/tenant/user/status", &Method::POST) => {
+                        state
+                            .handle_tenant_user(request, tenant::TenantUserOperation::GetStatus)
+                            .await
+                    }
 
-                    ("/req", &Method::OPTIONS) => Ok(Response::builder()
-                        .header("Access-Control-Allow-Origin", "*")
-                        .header("Access-Control-Allow-Headers", "*")
-                        .header("Access-Control-Allow-Methods", "POST")
-                        .status(StatusCode::OK)
-                        .body(Full::from(Bytes::from("No Content")))
-                        .unwrap()),
-                    ("/livez" | "/req", _) => Ok(Response::builder()
-                        .status(StatusCode::METHOD_NOT_ALLOWED)
-                        .body(Full::from(Bytes::from("Not Allowed")))
-                        .unwrap()),
+                    ("/req", &Method::OPTIONS) => Ok(state.handle_req_preflight(&request)),
+                    ("/livez" | "/req" | "/tenant/user/delete" | "/tenant/user/status", _) => {
+                        Ok(Response::builder()
+                            .status(StatusCode::METHOD_NOT_ALLOWED)
+                            .body(Full::from(Bytes::from("Not Allowed")))
+                            .unwrap())
+                    }
                     _ => Ok(Response::builder()
                         .status(StatusCode::NOT_FOUND)
                         .body(Full::from(Bytes::from("Not Found")))
@@ -360,10 +590,71 @@ name: {name}
             .unwrap())
     }
 
+    fn handle_req_preflight(&self, request: &Request<IncomingBody>) -> Response<Full<Bytes>> {
+        let response = Response::builder().header(VARY, "Origin");
+        let Some(origin) = request.headers().get(ORIGIN) else {
+            // Not a CORS request.
+            return response
+                .status(StatusCode::OK)
+                .body(Full::from(Bytes::from("No Content")))
+                .unwrap();
+        };
+        match origin
+            .to_str()
+            .ok()
+            .and_then(|origin| self.0.cors.preflight_allows(origin))
+        {
+            Some(tenants) => {
+                trace!(
+                    load_balancer = self.0.name,
+                    ?origin,
+                    ?tenants,
+                    "CORS preflight allowed"
+                );
+                response
+                    .header(ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+                    .header("Access-Control-Allow-Headers", "*")
+                    .header("Access-Control-Allow-Methods", "POST")
+                    .status(StatusCode::OK)
+                    .body(Full::from(Bytes::from("No Content")))
+                    .unwrap()
+            }
+            None => {
+                self.0
+                    .metrics
+                    .incr("load_balancer.cors.rejected", [tag!("stage": "preflight")]);
+                response
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Full::from(Bytes::from("Origin Not Allowed")))
+                    .unwrap()
+            }
+        }
+    }
+
+    /// Returns the request's origin if the preflight policy allows it. This
+    /// is only called once the request's tenant is known and its allowlist
+    /// has accepted the origin.
+    fn allowed_origin(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        let origin = origin?;
+        self.0
+            .cors
+            .preflight_allows(origin.to_str().ok()?)
+            .map(|_| origin.clone())
+    }
+
     async fn handle_req(
         &self,
         request: Request<IncomingBody>,
     ) -> Result<Response<Full<Bytes>>, Box<dyn Error + Send + Sync>> {
+        let origin_header = request.headers().get(ORIGIN).cloned();
+        let mut response_builder = Response::builder().header(VARY, "Origin");
+        // An origin that isn't valid text can't be on any tenant's allowlist.
+        let origin = origin_header
+            .as_ref()
+            .map(|origin| origin.to_str().unwrap_or_default());
+        // Set once the request's tenant is known and allows the origin.
+        let mut tenant_allows_origin = false;
+
         let has_valid_version = request
             .headers()
             .get(JUICEBOX_VERSION_HEADER)
@@ -379,8 +670,7 @@ name: {name}
             });
 
         if !has_valid_version {
-            return Ok(Response::builder()
-                .header("Access-Control-Allow-Origin", "*")
+            return Ok(response_builder
                 .status(StatusCode::UPGRADE_REQUIRED)
                 .body(Full::from(Bytes::from(format!(
                     "SDK upgrade required to version >={}.{}",
@@ -399,34 +689,48 @@ name: {name}
                 match marshalling::from_slice(request_bytes.as_ref()) {
                     Err(_) => ClientResponse::DecodingError,
                     Ok(request) => {
-                        let realms = self.0.realms.lock().unwrap().clone();
+                        let deadline = Instant::now() + REQUEST_DEADLINE;
                         match handle_client_request(
                             &request,
                             &self.0.name,
-                            &realms,
+                            &self.0.routes,
                             self.0.secret_manager.as_ref(),
                             &self.0.record_id_randomization_key,
                             &self.0.agent_client,
                             &self.0.metrics,
+                            &self.0.cors,
+                            &self.0.suspended,
+                            &self.0.admission,
+                            origin,
+                            &mut tenant_allows_origin,
+                            deadline,
                         )
                         .await
                         {
                             ClientResponse::Unavailable => {
                                 // retry with refreshed info about realm endpoints
-                                let refreshed_realms = Arc::new(
-                                    refresh(&self.0.name, &self.0.store, &self.0.agent_client)
-                                        .await,
-                                );
-                                *self.0.realms.lock().unwrap() = refreshed_realms.clone();
+                                refresh(
+                                    &self.0.name,
+                                    &self.0.store,
+                                    &self.0.agent_client,
+                                    &self.0.routes,
+                                )
+                                .await;
 
                                 handle_client_request(
                                     &request,
                                     &self.0.name,
-                                    &refreshed_realms,
+                                    &self.0.routes,
                                     self.0.secret_manager.as_ref(),
                                     &self.0.record_id_randomization_key,
                                     &self.0.agent_client,
                                     &self.0.metrics,
+                                    &self.0.cors,
+                                    &self.0.suspended,
+                                    &self.0.admission,
+                                    origin,
+                                    &mut tenant_allows_origin,
+                                    deadline,
                                 )
                                 .await
                             }
@@ -438,8 +742,12 @@ name: {name}
         };
 
         trace!(load_balancer = self.0.name, ?response);
-        Ok(Response::builder()
-            .header("Access-Control-Allow-Origin", "*")
+        if tenant_allows_origin {
+            if let Some(allowed) = self.allowed_origin(origin_header.as_ref()) {
+                response_builder = response_builder.header(ACCESS_CONTROL_ALLOW_ORIGIN, allowed);
+            }
+        }
+        Ok(response_builder
             .status(match response {
                 ClientResponse::Ok(_) => StatusCode::OK,
                 ClientResponse::DecodingError
@@ -461,31 +769,49 @@ name: {name}
     level = "trace",
     skip(
         request,
-        realms,
+        routes,
         secret_manager,
         record_id_randomization_key,
         agent_client,
-        metrics
+        metrics,
+        cors,
+        suspended,
+        admission,
+        tenant_allows_origin
     )
 )]
+#[allow(clippy::too_many_arguments)]
 async fn handle_client_request(
     request: &ClientRequest,
     name: &str,
-    realms: &HashMap<RealmId, Vec<Partition>>,
+    routes: &RoutingTable,
     secret_manager: &dyn SecretManager,
     record_id_randomization_key: &RecordIdRandomizationKey,
     agent_client: &ReqwestClientMetrics,
     metrics: &metrics::Client,
+    cors: &CorsPolicy,
+    suspended: &SuspendedTenants,
+    admission: &AdmissionController,
+    origin: Option<&str>,
+    tenant_allows_origin: &mut bool,
+    deadline: Instant,
 ) -> ClientResponse {
     let mut tags = Vec::with_capacity(5);
     let start = Instant::now();
     let result = handle_client_request_inner(
         request,
         name,
-        realms,
+        routes,
         secret_manager,
         record_id_randomization_key,
         agent_client,
+        metrics,
+        cors,
+        suspended,
+        admission,
+        origin,
+        tenant_allows_origin,
+        deadline,
         &mut tags,
     )
     .await;
@@ -497,6 +823,35 @@ async fn handle_client_request(
     result
 }
 
+/// Waits for a request from a tenant to be admitted, recording metrics. The
+/// tenant must come from a validated token. Only configured tenants are named
+/// in the metrics, which keeps their cardinality down.
+async fn admit(
+    admission: &AdmissionController,
+    metrics: &metrics::Client,
+    tenant: &str,
+) -> Result<Permit, Rejected> {
+    let start = Instant::now();
+    let admitted = admission.admit(tenant).await;
+    let tenant = if admission.is_configured(tenant) {
+        tenant
+    } else {
+        "unconfigured"
+    };
+    metrics.timing(
+        "load_balancer.admission.time",
+        start.elapsed(),
+        [tag!(tenant)],
+    );
+    if let Err(rejected) = &admitted {
+        metrics.incr(
+            "load_balancer.admission.shed",
+            [tag!(tenant), tag!("reason": rejected.as_str())],
+        );
+    }
+    admitted
+}
+
 fn add_client_response(tags: &mut Vec<Tag>, response: &ClientResponse) {
     let (result, success) = match response {
         ClientResponse::Ok(_) => ("Ok", true),
@@ -512,18 +867,27 @@ fn add_client_response(tags: &mut Vec<Tag>, response: &ClientResponse) {
     tags.push(tag!(success));
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_client_request_inner(
     request: &ClientRequest,
     name: &str,
-    realms: &HashMap<RealmId, Vec<Partition>>,
+    routes: &RoutingTable,
     secret_manager: &dyn SecretManager,
     record_id_randomization_key: &RecordIdRandomizationKey,
     agent_client: &ReqwestClientMetrics,
+    metrics: &metrics::Client,
+    cors: &CorsPolicy,
+    suspended: &SuspendedTenants,
+    admission: &AdmissionController,
+    origin: Option<&str>,
+    tenant_allows_origin: &mut bool,
+    deadline: Instant,
     request_tags: &mut Vec<Tag>,
 ) -> ClientResponse {
     type Response = ClientResponse;
 
-    let Some(partitions) = realms.get(&request.realm) else {
+    let realms = routes.realms();
+    let Some(realm_routes) = realms.get(&request.realm) else {
         return Response::Unavailable;
     };
 
@@ -551,77 +915,262 @@ async fn handle_client_request_inner(
             return Response::Unavailable;
         }
     };
+    if let Some(origin) = origin {
+        if !cors.tenant_allows(&claims.issuer, origin) {
+            metrics.incr(
+                "load_balancer.cors.rejected",
+                [tag!("stage": "request"), tag!("tenant": claims.issuer)],
+            );
+            return Response::InvalidAuth;
+        }
+        *tenant_allows_origin = true;
+    }
+    if suspended.contains(&claims.issuer) {
+        metrics.incr(
+            "load_balancer.tenant.suspended",
+            [tag!("tenant": claims.issuer)],
+        );
+        return Response::InvalidAuth;
+    }
+    // Requests are only admitted once the token is validated, so that no one
+    // else can use up a tenant's share by sending requests in its name.
+    let Ok(_permit) = admit(admission, metrics, &claims.issuer).await else {
+        return Response::RateLimitExceeded;
+    };
+    // The token has been validated above, so its slot claim can be trusted.
+    let Ok(slot) = slot::slot_claim(&request.auth_token) else {
+        return Response::InvalidAuth;
+    };
     let record_id = RecordIdBuilder {
         tenant: &claims.issuer,
         user: &claims.subject,
+        slot: slot.as_deref(),
     }
     .build(record_id_randomization_key);
     request_tags.push(tag!("missing_scope": claims.scope.is_none()));
+    request_tags.push(tag!("has_slot": slot.is_some()));
     request_tags.push(tag!("tenant": claims.issuer));
 
-    for partition in partitions {
-        if !partition.owned_range.contains(&record_id) {
-            continue;
-        }
+    let mut failed_over = false;
+    for partition in realm_routes.lookup(&record_id) {
         request_tags.push(tag!("group": partition.group));
 
-        match rpc::send(
+        let reason = match send_app_request(
+            request,
+            name,
             agent_client,
-            &partition.leader,
-            AppRequest {
-                realm: request.realm,
-                group: partition.group,
-                record_id: record_id.clone(),
-                session_id: request.session_id,
-                kind: request.kind,
-                encrypted: request.encrypted.clone(),
-                tenant: claims.issuer.clone(),
-                user: HashedUserId::new(&claims.issuer, &claims.subject),
-            },
+            partition,
+            &record_id,
+            &claims.issuer,
+            &claims.subject,
+            slot.as_deref(),
         )
         .await
         {
-            Err(err) => {
-                warn!(
-                    load_balancer = name,
-                    agent = %partition.leader,
-                    realm = ?request.realm,
-                    group = ?partition.group,
-                    %err,
-                    "http error",
-                );
-            }
+            AppAttempt::Done(response) => return response,
+            AppAttempt::Failed => continue,
+            AppAttempt::NotLeader(reason) => reason,
+        };
 
-            Ok(
-                r @ AppResponse::InvalidRealm
-                | r @ AppResponse::InvalidGroup
-                | r @ AppResponse::NoHsm
-                | r @ AppResponse::NoStore
-                | r @ AppResponse::NoPubSub
-                | r @ AppResponse::NotLeader
-                | r @ AppResponse::InvalidProof,
-            ) => {
-                warn!(
-                    load_balancer = name,
-                    agent = %partition.leader,
-                    realm = ?request.realm,
-                    group = ?partition.group,
-                    response = ?r,
-                    "AppRequest not ok",
-                );
+        // The routing table is out of date for this group. Rather than only
+        // trying other entries that are likely just as stale, go find the
+        // group's current leader and retry there once.
+        if failed_over {
+            continue;
+        }
+        failed_over = true;
+        let (outcome, response) = if Instant::now() >= deadline {
+            ("deadline", None)
+        } else {
+            match find_new_leader(
+                routes,
+                agent_client,
+                request.realm,
+                &record_id,
+                partition,
+                deadline,
+            )
+            .await
+            {
+                None => ("no_leader", None),
+                Some(leader) => match send_app_request(
+                    request,
+                    name,
+                    agent_client,
+                    &leader,
+                    &record_id,
+                    &claims.issuer,
+                    &claims.subject,
+                    slot.as_deref(),
+                )
+                .await
+                {
+                    AppAttempt::Done(response) => ("ok", Some(response)),
+                    AppAttempt::Failed | AppAttempt::NotLeader(_) => ("failed", None),
+                },
             }
-
-            Ok(AppResponse::Ok(response)) => return Response::Ok(response),
-            Ok(AppResponse::MissingSession) => return Response::MissingSession,
-            Ok(AppResponse::SessionError) => return Response::SessionError,
-            Ok(AppResponse::DecodingError) => return Response::DecodingError,
-            Ok(AppResponse::RateLimitExceeded) => return Response::RateLimitExceeded,
         };
+        metrics.incr(
+            "load_balancer.failover.count",
+            [
+                tag!("realm": ?request.realm),
+                tag!("group": partition.group),
+                tag!(reason),
+                tag!(outcome),
+            ],
+        );
+        if let Some(response) = response {
+            return response;
+        }
     }
 
     Response::Unavailable
 }
 
+/// The result of sending a client's request to a single agent.
+enum AppAttempt {
+    /// The agent handled the request, successfully or not.
+    Done(ClientResponse),
+    /// The agent isn't leading the group, so the routing table is stale.
+    NotLeader(&'static str),
+    /// The request couldn't be handled by this agent right now.
+    Failed,
+}
+
+async fn send_app_request(
+    request: &ClientRequest,
+    name: &str,
+    agent_client: &ReqwestClientMetrics,
+    partition: &Partition,
+    record_id: &RecordId,
+    tenant: &str,
+    user: &str,
+    slot: Option<&str>,
+) -> AppAttempt {
+    type Response = ClientResponse;
+
+    match rpc::send(
+        agent_client,
+        &partition.leader,
+        AppRequest {
+            realm: request.realm,
+            group: partition.group,
+            record_id: record_id.clone(),
+            session_id: request.session_id,
+            kind: request.kind,
+            encrypted: request.encrypted.clone(),
+            tenant: tenant.to_owned(),
+            user: HashedUserId::new(tenant, user),
+            slot: slot.map(|slot| HashedSlotId::new(tenant, user, slot)),
+        },
+    )
+    .await
+    {
+        Err(err) => {
+            warn!(
+                load_balancer = name,
+                agent = %partition.leader,
+                realm = ?request.realm,
+                group = ?partition.group,
+                %err,
+                "http error",
+            );
+            AppAttempt::Failed
+        }
+
+        Ok(
+            r @ AppResponse::InvalidRealm
+            | r @ AppResponse::InvalidGroup
+            | r @ AppResponse::NoHsm
+            | r @ AppResponse::NoStore
+            | r @ AppResponse::NoPubSub
+            | r @ AppResponse::NotLeader
+            | r @ AppResponse::InvalidProof,
+        ) => {
+            warn!(
+                load_balancer = name,
+                agent = %partition.leader,
+                realm = ?request.realm,
+                group = ?partition.group,
+                response = ?r,
+                "AppRequest not ok",
+            );
+            match r {
+                AppResponse::NotLeader => AppAttempt::NotLeader("NotLeader"),
+                AppResponse::InvalidGroup => AppAttempt::NotLeader("InvalidGroup"),
+                _ => AppAttempt::Failed,
+            }
+        }
+
+        Ok(AppResponse::Ok(response)) => AppAttempt::Done(Response::Ok(response)),
+        Ok(AppResponse::MissingSession) => AppAttempt::Done(Response::MissingSession),
+        Ok(AppResponse::SessionError) => AppAttempt::Done(Response::SessionError),
+        Ok(AppResponse::DecodingError) => AppAttempt::Done(Response::DecodingError),
+        Ok(AppResponse::RateLimitExceeded) => AppAttempt::Done(Response::RateLimitExceeded),
+        // Clients don't understand a separate quota error yet, so this is
+        // reported the same way as a rate limit. The log tells them apart.
+        Ok(AppResponse::QuotaExceeded) => {
+            info!(
+                load_balancer = name,
+                realm = ?request.realm,
+                tenant,
+                "rejected registration over the tenant's quota",
+            );
+            AppAttempt::Done(Response::RateLimitExceeded)
+        }
+        // The tenant was suspended since this load balancer last refreshed its
+        // configuration. Its tokens are no longer accepted.
+        Ok(AppResponse::TenantSuspended) => AppAttempt::Done(Response::InvalidAuth),
+    }
+}
+
+/// Asks the members of the group for the partitions they're leading, after
+/// `stale` turned out not to be leading the group anymore. Returns the group's
+/// new leader for the record, if one is found before the deadline.
+async fn find_new_leader(
+    routes: &RoutingTable,
+    agent_client: &ReqwestClientMetrics,
+    realm: RealmId,
+    record_id: &RecordId,
+    stale: &Partition,
+    deadline: Instant,
+) -> Option<Partition> {
+    let members = routes.group_members(realm, stale.group);
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    let responses = join_all(members.iter().map(|agent| {
+        rpc::send_with_options(
+            agent_client,
+            agent,
+            PartitionWatchRequest {
+                since: None,
+                timeout: Duration::ZERO,
+            },
+            SendOptions::default().with_timeout(timeout),
+        )
+    }))
+    .await;
+    for (agent, response) in zip(&members, responses) {
+        if let Ok(response) = response {
+            routes.update(
+                agent,
+                Some(response.version),
+                AgentRoutes {
+                    partitions: response.partitions,
+                    groups: response.groups,
+                },
+            );
+        }
+    }
+
+    routes
+        .realms()
+        .get(&realm)?
+        .lookup(record_id)
+        .iter()
+        .find(|p| p.group == stale.group && p.term > stale.term)
+        .cloned()
+}
+
 #[derive(Debug)]
 pub struct RecordIdRandomizationKey(SecretBytesArray<32>);
 
@@ -648,13 +1197,17 @@ impl TryFrom<Secret> for RecordIdRandomizationKey {
 struct RecordIdBuilder<'a> {
     tenant: &'a str,
     user: &'a str,
+    // This is skipped when absent so that records created before slots
+    // existed keep the same IDs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    slot: Option<&'a str>,
 }
 
 // This uses a MAC with a per-realm key so that tenants/users can't cause
 // unbalanced Merkle trees (the same way hash tables are randomized).
 //
-// TODO: we may need a way to enumerate all the users for a given tenant if
-// that tenant wanted to delete all their data.
+// Record IDs can't be mapped back to users. Offboarding a tenant finds its
+// records through the user accounting table instead.
 impl<'a> RecordIdBuilder<'a> {
     fn build(&self, randomization_key: &RecordIdRandomizationKey) -> RecordId {
         let mut h = Blake2sMac256::new(randomization_key.expose_secret().into());
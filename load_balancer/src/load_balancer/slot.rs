@@ -0,0 +1,136 @@
+//! Secret slots let a user store several independent secrets in a realm, such
+//! as one per device. The slot is named by an optional `slot` claim in the
+//! user's auth token, and it's folded into the record ID.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::Deserialize;
+
+use juicebox_realm_api::types::AuthToken;
+
+/// The maximum length of a slot name, in bytes.
+const MAX_SLOT_LEN: usize = 64;
+
+#[derive(Debug, Eq, PartialEq)]
+pub(super) struct InvalidSlot;
+
+#[derive(Deserialize)]
+struct SlotClaim {
+    #[serde(default)]
+    slot: Option<String>,
+}
+
+/// Returns the token's `slot` claim, if it has one. Slot names must be 1 to
+/// 64 characters from `[A-Za-z0-9._-]`.
+///
+/// [`juicebox_realm_auth::Claims`] doesn't carry the slot, so this reads it
+/// from the token's payload. It doesn't check the token's signature, so it
+/// must only be called on tokens that have already been validated.
+pub(super) fn slot_claim(token: &AuthToken) -> Result<Option<String>, InvalidSlot> {
+    let payload = token.expose_secret().split('.').nth(1).ok_or(InvalidSlot)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| InvalidSlot)?;
+    let claim: SlotClaim = serde_json::from_slice(&payload).map_err(|_| InvalidSlot)?;
+    match claim.slot {
+        None => Ok(None),
+        Some(slot) if is_valid_slot(&slot) => Ok(Some(slot)),
+        Some(_) => Err(InvalidSlot),
+    }
+}
+
+pub(super) fn is_valid_slot(slot: &str) -> bool {
+    !slot.is_empty()
+        && slot.len() <= MAX_SLOT_LEN
+        && slot
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'))
+}
+
+#[cfg(test)]
+mod tests {
+    use blake2::Blake2sMac256;
+    use digest::{FixedOutput, KeyInit};
+    use serde::Serialize;
+
+    use super::super::{DigestWriter, RecordIdBuilder, RecordIdRandomizationKey};
+    use super::*;
+    use hsm_api::RecordId;
+    use juicebox_realm_api::types::SecretBytesArray;
+
+    fn token(claims: &str) -> AuthToken {
+        AuthToken::from(format!(
+            "{}.{}.signature",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"HS256","typ":"JWT"}"#),
+            URL_SAFE_NO_PAD.encode(claims)
+        ))
+    }
+
+    #[test]
+    fn test_slot_claim() {
+        assert_eq!(Ok(None), slot_claim(&token(r#"{"sub":"alice"}"#)));
+        assert_eq!(
+            Ok(Some(String::from("phone-1"))),
+            slot_claim(&token(r#"{"sub":"alice","slot":"phone-1"}"#))
+        );
+        assert_eq!(
+            Err(InvalidSlot),
+            slot_claim(&token(r#"{"sub":"alice","slot":""}"#))
+        );
+        assert_eq!(
+            Err(InvalidSlot),
+            slot_claim(&token(r#"{"sub":"alice","slot":"a/b"}"#))
+        );
+        assert_eq!(
+            Err(InvalidSlot),
+            slot_claim(&token(r#"{"sub":"alice","slot":42}"#))
+        );
+        assert_eq!(
+            Err(InvalidSlot),
+            slot_claim(&AuthToken::from(String::from("garbage")))
+        );
+    }
+
+    #[test]
+    fn test_is_valid_slot() {
+        assert!(is_valid_slot("a"));
+        assert!(is_valid_slot("Laptop_2.backup-key"));
+        assert!(is_valid_slot(&"x".repeat(MAX_SLOT_LEN)));
+        assert!(!is_valid_slot(""));
+        assert!(!is_valid_slot(&"x".repeat(MAX_SLOT_LEN + 1)));
+        assert!(!is_valid_slot("a b"));
+        assert!(!is_valid_slot("a:b"));
+        assert!(!is_valid_slot("ü"));
+    }
+
+    #[test]
+    fn test_record_id_without_slot_is_unchanged() {
+        #[derive(Serialize)]
+        struct RecordIdBuilderWithoutSlots<'a> {
+            tenant: &'a str,
+            user: &'a str,
+        }
+
+        let key = RecordIdRandomizationKey(SecretBytesArray::from([7; 32]));
+        let mut h = Blake2sMac256::new(key.expose_secret().into());
+        ciborium::ser::into_writer(
+            &RecordIdBuilderWithoutSlots {
+                tenant: "acme",
+                user: "alice",
+            },
+            DigestWriter(&mut h),
+        )
+        .unwrap();
+        let expected = RecordId(h.finalize_fixed().into());
+
+        let build = |slot| {
+            RecordIdBuilder {
+                tenant: "acme",
+                user: "alice",
+                slot,
+            }
+            .build(&key)
+        };
+        assert_eq!(expected, build(None));
+        assert_ne!(expected, build(Some("phone")));
+        assert_ne!(build(Some("phone")), build(Some("laptop")));
+    }
+}
@@ -0,0 +1,598 @@
+//! Administrative API for tenants to manage their users' records.
+//!
+//! Unlike `/req`, these requests aren't sent by end users over Noise
+//! sessions. They're sent by tenants, authorized with a token signed by the
+//! tenant's admin key (see [`secret_manager::tenant_admin_secret_name`]), and
+//! the request and response bodies are JSON. Once the token checks out, the
+//! load balancer signs a [`TenantRecordGrant`] for the agent and HSM to check.
+//!
+//! Deleting a user takes two grants. The first reads the record's status and
+//! fingerprint, and the second deletes the record only if it still has that
+//! fingerprint. This stops a leaked delete grant from being replayed later.
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::Incoming as IncomingBody;
+use hyper::header::{HeaderMap, AUTHORIZATION};
+use hyper::{Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use super::slot::is_valid_slot;
+use super::{admit, find_new_leader, LoadBalancer, RecordIdBuilder, REQUEST_DEADLINE};
+use crate::routing::Partition;
+use agent_api::{TenantRecordRequest, TenantRecordResponse};
+use hsm_api::{
+    RecordFingerprint, RecordId, RegistrationStatus, TenantRecordGrant, TenantRecordOperation,
+};
+use juicebox_networking::rpc;
+use juicebox_realm_api::requests::BODY_SIZE_LIMIT;
+use juicebox_realm_api::types::{AuthToken, RealmId};
+use juicebox_realm_auth::Claims;
+use observability::metrics_tag as tag;
+use secret_manager::{
+    get_tenant_record_signing_key, validate_tenant_admin_token, SecretManager,
+    TenantAdminTokenError,
+};
+
+/// How long the grant signed for each request is valid for. This only needs
+/// to cover the request's retries.
+const GRANT_LIFETIME: Duration = Duration::from_secs(60);
+
+/// The number of times a delete is attempted when the user's record changes
+/// between reading its fingerprint and deleting it.
+const MAX_RECORD_CHANGED_ATTEMPTS: usize = 3;
+
+/// The operations offered by the tenant user endpoints.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum TenantUserOperation {
+    GetStatus,
+    Delete,
+}
+
+/// The JSON request body for the tenant user endpoints.
+#[derive(Debug, Deserialize)]
+struct TenantUserRequest {
+    /// The realm ID, in hex.
+    realm: String,
+    /// The tenant's ID for the user, as used as the subject of the user's
+    /// auth tokens.
+    user: String,
+    /// The secret slot, as used in the `slot` claim of the user's auth
+    /// tokens. Each slot is a separate record.
+    #[serde(default)]
+    slot: Option<String>,
+}
+
+/// The JSON response body for the tenant user endpoints.
+#[derive(Debug, Serialize)]
+struct TenantUserResponse {
+    /// The user's registration status, from before the operation.
+    status: &'static str,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum TenantError {
+    BadRequest(&'static str),
+    PayloadTooLarge,
+    InvalidAuth,
+    RateLimitExceeded,
+    /// The user's record kept changing while trying to delete it.
+    RecordChanged,
+    Unavailable,
+}
+
+impl LoadBalancer {
+    pub(super) async fn handle_tenant_user(
+        &self,
+        request: Request<IncomingBody>,
+        operation: TenantUserOperation,
+    ) -> Result<Response<Full<Bytes>>, Box<dyn Error + Send + Sync>> {
+        let start = Instant::now();
+        let result = self.handle_tenant_user_inner(request, operation).await;
+
+        let (result_tag, status_code, body) = match result {
+            Ok(status) => (
+                "Ok",
+                StatusCode::OK,
+                Bytes::from(
+                    serde_json::to_vec(&TenantUserResponse {
+                        status: match status {
+                            RegistrationStatus::NotRegistered => "not_registered",
+                            RegistrationStatus::Registered => "registered",
+                            RegistrationStatus::NoGuesses => "no_guesses",
+                        },
+                    })
+                    .expect("TODO"),
+                ),
+            ),
+            Err(TenantError::BadRequest(reason)) => {
+                ("BadRequest", StatusCode::BAD_REQUEST, Bytes::from(reason))
+            }
+            Err(TenantError::PayloadTooLarge) => (
+                "PayloadTooLarge",
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Bytes::from("Payload Too Large"),
+            ),
+            Err(TenantError::InvalidAuth) => (
+                "InvalidAuth",
+                StatusCode::UNAUTHORIZED,
+                Bytes::from("Unauthorized"),
+            ),
+            Err(TenantError::RateLimitExceeded) => (
+                "RateLimitExceeded",
+                StatusCode::TOO_MANY_REQUESTS,
+                Bytes::from("Too Many Requests"),
+            ),
+            Err(TenantError::RecordChanged) => (
+                "RecordChanged",
+                StatusCode::CONFLICT,
+                Bytes::from("Conflict"),
+            ),
+            Err(TenantError::Unavailable) => (
+                "Unavailable",
+                StatusCode::SERVICE_UNAVAILABLE,
+                Bytes::from("Unavailable"),
+            ),
+        };
+        self.0.metrics.timing(
+            "load_balancer.tenant_request.time",
+            start.elapsed(),
+            [tag!(?operation), tag!("result": result_tag)],
+        );
+        Ok(Response::builder()
+            .status(status_code)
+            .body(Full::from(body))
+            .expect("TODO"))
+    }
+
+    async fn handle_tenant_user_inner(
+        &self,
+        request: Request<IncomingBody>,
+        operation: TenantUserOperation,
+    ) -> Result<RegistrationStatus, TenantError> {
+        let token = bearer_token(request.headers()).ok_or(TenantError::InvalidAuth)?;
+        let body = match Limited::new(request, BODY_SIZE_LIMIT).collect().await {
+            Ok(collected) => collected.to_bytes(),
+            Err(err) if err.downcast_ref::<LengthLimitError>().is_some() => {
+                return Err(TenantError::PayloadTooLarge)
+            }
+            Err(_) => return Err(TenantError::BadRequest("couldn't read request body")),
+        };
+        let (realm, body) = parse_tenant_user_request(&body)?;
+
+        let claims =
+            check_tenant_admin_token(self.0.secret_manager.as_ref(), realm, &token).await?;
+        if self.0.suspended.contains(&claims.issuer) {
+            self.0.metrics.incr(
+                "load_balancer.tenant.suspended",
+                [tag!("tenant": claims.issuer)],
+            );
+            return Err(TenantError::InvalidAuth);
+        }
+        // These requests are forwarded to the same agents as `/req`, so they
+        // count towards the tenant's concurrency limit too.
+        let Ok(_permit) = admit(&self.0.admission, &self.0.metrics, &claims.issuer).await else {
+            return Err(TenantError::RateLimitExceeded);
+        };
+        let record_id = RecordIdBuilder {
+            tenant: &claims.issuer,
+            user: &body.user,
+            slot: body.slot.as_deref(),
+        }
+        .build(&self.0.record_id_randomization_key);
+
+        let secret_manager = self.0.secret_manager.as_ref();
+        let get_status = || async {
+            let grant = sign_grant(
+                secret_manager,
+                realm,
+                &record_id,
+                &claims,
+                TenantRecordOperation::GetStatus,
+            )
+            .await?;
+            self.send_tenant_record_request(realm, grant).await
+        };
+        let (mut status, mut fingerprint) = get_status().await?;
+        if operation == TenantUserOperation::Delete {
+            let mut attempt = 1;
+            loop {
+                let grant = sign_grant(
+                    secret_manager,
+                    realm,
+                    &record_id,
+                    &claims,
+                    TenantRecordOperation::Delete { fingerprint },
+                )
+                .await?;
+                match self.send_tenant_record_request(realm, grant).await {
+                    Ok((deleted, _)) => {
+                        status = deleted;
+                        break;
+                    }
+                    Err(TenantError::RecordChanged) if attempt < MAX_RECORD_CHANGED_ATTEMPTS => {
+                        attempt += 1;
+                        (status, fingerprint) = get_status().await?;
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        // The agent also logs this, but only the load balancer knows which
+        // user the record belongs to.
+        info!(
+            ?realm,
+            tenant = %claims.issuer,
+            requested_by = %claims.subject,
+            user = %body.user,
+            slot = ?body.slot,
+            ?record_id,
+            ?operation,
+            ?status,
+            "handled tenant user request"
+        );
+        Ok(status)
+    }
+
+    /// Sends the request to the leader of the group that owns the record,
+    /// finding the group's new leader if the routing table is stale.
+    async fn send_tenant_record_request(
+        &self,
+        realm: RealmId,
+        grant: TenantRecordGrant,
+    ) -> Result<(RegistrationStatus, Option<RecordFingerprint>), TenantError> {
+        let record_id = &grant.record_id;
+        let deadline = Instant::now() + REQUEST_DEADLINE;
+        let realms = self.0.routes.realms();
+        let Some(realm_routes) = realms.get(&realm) else {
+            return Err(TenantError::Unavailable);
+        };
+
+        for partition in realm_routes.lookup(record_id) {
+            let mut partition = partition.clone();
+            for attempt in 0..2 {
+                match self
+                    .send_tenant_record_attempt(&partition, realm, &grant)
+                    .await
+                {
+                    Some(TenantRecordResponse::Ok {
+                        status,
+                        fingerprint,
+                    }) => return Ok((status, fingerprint)),
+                    Some(TenantRecordResponse::RecordChanged) => {
+                        return Err(TenantError::RecordChanged)
+                    }
+                    Some(TenantRecordResponse::NotLeader | TenantRecordResponse::InvalidGroup)
+                        if attempt == 0 && Instant::now() < deadline =>
+                    {
+                        match find_new_leader(
+                            &self.0.routes,
+                            &self.0.agent_client,
+                            realm,
+                            record_id,
+                            &partition,
+                            deadline,
+                        )
+                        .await
+                        {
+                            Some(leader) => partition = leader,
+                            None => break,
+                        }
+                    }
+                    _ => break,
+                }
+            }
+        }
+        Err(TenantError::Unavailable)
+    }
+
+    async fn send_tenant_record_attempt(
+        &self,
+        partition: &Partition,
+        realm: RealmId,
+        grant: &TenantRecordGrant,
+    ) -> Option<TenantRecordResponse> {
+        match rpc::send(
+            &self.0.agent_client,
+            &partition.leader,
+            TenantRecordRequest {
+                realm,
+                group: partition.group,
+                grant: grant.clone(),
+            },
+        )
+        .await
+        {
+            Ok(response @ TenantRecordResponse::Ok { .. }) => Some(response),
+            Ok(response) => {
+                warn!(
+                    load_balancer = self.0.name,
+                    agent = %partition.leader,
+                    ?realm,
+                    group = ?partition.group,
+                    ?response,
+                    "TenantRecordRequest not ok",
+                );
+                Some(response)
+            }
+            Err(err) => {
+                warn!(
+                    load_balancer = self.0.name,
+                    agent = %partition.leader,
+                    ?realm,
+                    group = ?partition.group,
+                    %err,
+                    "http error",
+                );
+                None
+            }
+        }
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<AuthToken> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| AuthToken::from(token.to_owned()))
+}
+
+fn parse_tenant_user_request(body: &[u8]) -> Result<(RealmId, TenantUserRequest), TenantError> {
+    let body: TenantUserRequest = serde_json::from_slice(body)
+        .map_err(|_| TenantError::BadRequest("invalid JSON request body"))?;
+    let realm = hex::decode(&body.realm)
+        .ok()
+        .and_then(|bytes| <[u8; 16]>::try_from(bytes).ok())
+        .map(RealmId)
+        .ok_or(TenantError::BadRequest("invalid realm ID"))?;
+    if body
+        .slot
+        .as_deref()
+        .is_some_and(|slot| !is_valid_slot(slot))
+    {
+        return Err(TenantError::BadRequest("invalid slot"));
+    }
+    Ok((realm, body))
+}
+
+/// Checks that the token was signed with the tenant's admin key. User tokens
+/// are signed with a different key, so they're rejected here.
+async fn check_tenant_admin_token(
+    secret_manager: &dyn SecretManager,
+    realm: RealmId,
+    token: &AuthToken,
+) -> Result<Claims, TenantError> {
+    match validate_tenant_admin_token(secret_manager, realm, token).await {
+        Ok(claims) => Ok(claims),
+        Err(TenantAdminTokenError::Invalid) => Err(TenantError::InvalidAuth),
+        Err(err @ TenantAdminTokenError::SecretManager(_)) => {
+            warn!(?err, "failed to validate tenant admin token");
+            Err(TenantError::Unavailable)
+        }
+    }
+}
+
+/// Signs a grant for the operation, which the agent and HSM check.
+async fn sign_grant(
+    secret_manager: &dyn SecretManager,
+    realm: RealmId,
+    record_id: &RecordId,
+    claims: &Claims,
+    operation: TenantRecordOperation,
+) -> Result<TenantRecordGrant, TenantError> {
+    let signing_key = match get_tenant_record_signing_key(secret_manager).await {
+        Ok(Some(key)) => key,
+        Ok(None) => {
+            warn!("no tenant record signing key");
+            return Err(TenantError::Unavailable);
+        }
+        Err(err) => {
+            warn!(?err, "failed to get tenant record signing key");
+            return Err(TenantError::Unavailable);
+        }
+    };
+    let expires = (SystemTime::now() + GRANT_LIFETIME)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    Ok(TenantRecordGrant::sign(
+        &realm,
+        claims.issuer.clone(),
+        claims.subject.clone(),
+        record_id.clone(),
+        operation,
+        expires,
+        &signing_key,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HeaderValue;
+    use juicebox_realm_auth::creation::create_token;
+    use juicebox_realm_auth::{AuthKeyVersion, Scope};
+    use secret_manager::{
+        tenant_admin_secret_name, tenant_record_signing_key_name, Secret, SecretAlgorithm,
+        SecretName, SecretVersion,
+    };
+    use std::collections::HashMap;
+
+    use super::*;
+
+    const REALM: RealmId = RealmId([3; 16]);
+
+    fn secrets() -> HashMap<SecretName, HashMap<SecretVersion, Secret>> {
+        // An Ed25519 private key in PKCS #8 DER.
+        let mut signing_key = vec![
+            0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22,
+            0x04, 0x20,
+        ];
+        signing_key.extend([4; 32]);
+        HashMap::from([
+            (
+                tenant_admin_secret_name("acme"),
+                HashMap::from([(
+                    SecretVersion(1),
+                    Secret {
+                        data: b"acme admin key".to_vec().into(),
+                        algorithm: SecretAlgorithm::HmacSha256,
+                    },
+                )]),
+            ),
+            (
+                tenant_record_signing_key_name(),
+                HashMap::from([(
+                    SecretVersion(1),
+                    Secret {
+                        data: signing_key.into(),
+                        algorithm: SecretAlgorithm::Edwards25519,
+                    },
+                )]),
+            ),
+        ])
+    }
+
+    fn token(realm: RealmId, key: &[u8]) -> AuthToken {
+        create_token(
+            &Claims {
+                issuer: String::from("acme"),
+                subject: String::from("ops@acme"),
+                audience: realm,
+                scope: Some(Scope::User),
+            },
+            &Secret {
+                data: key.to_vec().into(),
+                algorithm: SecretAlgorithm::HmacSha256,
+            }
+            .try_into()
+            .unwrap(),
+            AuthKeyVersion(1),
+        )
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let mut headers = HeaderMap::new();
+        assert!(bearer_token(&headers).is_none());
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Basic abc"));
+        assert!(bearer_token(&headers).is_none());
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer abc"));
+        assert_eq!(bearer_token(&headers).unwrap().expose_secret(), "abc");
+    }
+
+    #[test]
+    fn test_parse_tenant_user_request() {
+        let realm = hex::encode(REALM.0);
+        let (parsed, body) =
+            parse_tenant_user_request(format!(r#"{{"realm":"{realm}","user":"bob"}}"#).as_bytes())
+                .unwrap();
+        assert_eq!(parsed, REALM);
+        assert_eq!(body.user, "bob");
+        assert_eq!(body.slot, None);
+
+        let (_, body) = parse_tenant_user_request(
+            format!(r#"{{"realm":"{realm}","user":"bob","slot":"phone-1"}}"#).as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(body.slot.as_deref(), Some("phone-1"));
+
+        for (body, error) in [
+            (String::from("bob"), "invalid JSON request body"),
+            (
+                format!(r#"{{"realm":"{realm}"}}"#),
+                "invalid JSON request body",
+            ),
+            (
+                String::from(r#"{"realm":"0303","user":"bob"}"#),
+                "invalid realm ID",
+            ),
+            (
+                format!(r#"{{"realm":"{realm}","user":"bob","slot":"a/b"}}"#),
+                "invalid slot",
+            ),
+        ] {
+            assert_eq!(
+                parse_tenant_user_request(body.as_bytes()).unwrap_err(),
+                TenantError::BadRequest(error),
+                "{body}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_tenant_admin_token() {
+        let secrets = secrets();
+        let claims = check_tenant_admin_token(&secrets, REALM, &token(REALM, b"acme admin key"))
+            .await
+            .unwrap();
+        assert_eq!(claims.issuer, "acme");
+        assert_eq!(claims.subject, "ops@acme");
+
+        // User tokens are signed with a different key, so they aren't
+        // accepted.
+        assert_eq!(
+            check_tenant_admin_token(&secrets, REALM, &token(REALM, b"acme user key"))
+                .await
+                .unwrap_err(),
+            TenantError::InvalidAuth
+        );
+        assert_eq!(
+            check_tenant_admin_token(&secrets, RealmId([4; 16]), &token(REALM, b"acme admin key"))
+                .await
+                .unwrap_err(),
+            TenantError::InvalidAuth
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sign_grant() {
+        let claims = Claims {
+            issuer: String::from("acme"),
+            subject: String::from("ops@acme"),
+            audience: REALM,
+            scope: Some(Scope::User),
+        };
+        let record_id = RecordId([5; 32]);
+
+        let mut secrets = secrets();
+        for operation in [
+            TenantRecordOperation::GetStatus,
+            TenantRecordOperation::Delete { fingerprint: None },
+            TenantRecordOperation::Delete {
+                fingerprint: Some(RecordFingerprint::of_leaf(b"leaf")),
+            },
+        ] {
+            let grant = sign_grant(&secrets, REALM, &record_id, &claims, operation)
+                .await
+                .unwrap();
+            assert_eq!(grant.tenant, "acme");
+            assert_eq!(grant.requested_by, "ops@acme");
+            assert_eq!(grant.record_id, record_id);
+            assert_eq!(grant.operation, operation);
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            assert!(grant.expires > now.as_secs());
+            assert!(grant.expires <= (now + GRANT_LIFETIME).as_secs() + 1);
+            let key = TenantRecordGrant::public_key(&[4; 32]);
+            assert!(grant.verify(&REALM, &[key]));
+            assert!(!grant.verify(&RealmId([4; 16]), &[key]));
+        }
+
+        secrets.remove(&tenant_record_signing_key_name());
+        assert_eq!(
+            sign_grant(
+                &secrets,
+                REALM,
+                &record_id,
+                &claims,
+                TenantRecordOperation::GetStatus
+            )
+            .await
+            .unwrap_err(),
+            TenantError::Unavailable
+        );
+    }
+}
@@ -10,17 +10,21 @@ use tracing::{info, warn};
 
 use google::{auth, GrpcConnectionOptions};
 use observability::{logging, metrics};
-use secret_manager::{new_google_secret_manager, Periodic, SecretManager, SecretsFile};
+use secret_manager::{new_google_secret_manager, Jwks, Periodic, SecretManager, SecretsFile};
 use server::ManagerOptions;
 use service_core::clap_parsers::{parse_duration, parse_listen};
 use service_core::metrics::start_uptime_reporter;
 use service_core::panic;
 use service_core::term::install_termination_handler;
 
+mod admission;
 mod cert;
+mod cors;
 mod load_balancer;
+mod routing;
 mod server;
 
+use admission::AdmissionOptions;
 use cert::CertificateResolver;
 use load_balancer::LoadBalancer;
 
@@ -49,6 +53,12 @@ struct Args {
     #[arg(long)]
     secrets_file: Option<PathBuf>,
 
+    /// Name of JSON file mapping tenant names to the URLs of their JWKS
+    /// documents. These tenants' auth keys are loaded from the JWKS instead
+    /// of the secrets file or Google Secret Manager.
+    #[arg(long)]
+    tenant_jwks_file: Option<PathBuf>,
+
     /// Max length of time to wait for a graceful shutdown to complete.
     #[arg(long, default_value="60s", value_parser=parse_duration)]
     shutdown_timeout: Duration,
@@ -70,6 +80,36 @@ struct Args {
     #[arg(long)]
     tls_cert: PathBuf,
 
+    /// Reject CORS preflight requests from origins that no tenant has
+    /// allowed. Only enable this once every tenant with browser users has
+    /// configured its allowed origins.
+    #[arg(long)]
+    restrict_cors_preflight: bool,
+
+    /// The maximum number of client requests to forward to agents at once.
+    /// Requests over this limit wait in per-tenant queues, and are taken from
+    /// the tenants in turn.
+    #[arg(long, default_value_t = 1000)]
+    max_concurrent_requests: usize,
+
+    /// The maximum number of requests in flight across all load balancers for
+    /// a tenant without a configuration. Configured tenants get their burst
+    /// capacity. The load balancers split this according to the demand each
+    /// one sees.
+    #[arg(long, default_value_t = 10)]
+    default_tenant_concurrency: usize,
+
+    /// How long a request may wait to be forwarded before it's rejected as
+    /// over the rate limit.
+    #[arg(
+        long,
+        default_value = "1s",
+        value_parser = parse_duration,
+        name = "admission-queue-timeout",
+        value_name = "DURATION"
+    )]
+    admission_queue_timeout: Duration,
+
     /// The secrets manager gRPC request timeout setting.
     #[arg(long="secrets-manager-timeout",
             value_parser=parse_duration,
@@ -204,14 +244,37 @@ async fn main() {
             )
         }
     };
+    let secret_manager: Box<dyn SecretManager> = match args.tenant_jwks_file {
+        Some(jwks_file) => {
+            info!(path = ?jwks_file, "loading tenant JWKS config from JSON file");
+            Box::new(
+                Jwks::from_config_file(&jwks_file, Duration::from_secs(60), secret_manager)
+                    .await
+                    .expect("failed to load tenant JWKS config"),
+            )
+        }
+        None => secret_manager,
+    };
 
     let svc_cfg = ManagerOptions {
         idle_timeout: args.idle_timeout,
         shutdown_notice_period: args.shutdown_notice_period,
     };
-    let lb = LoadBalancer::new(name, store, secret_manager, metrics.clone(), svc_cfg)
-        .await
-        .expect("failed to start LoadBalancer");
+    let lb = LoadBalancer::new(
+        name,
+        store,
+        secret_manager,
+        metrics.clone(),
+        svc_cfg,
+        args.restrict_cors_preflight,
+        AdmissionOptions {
+            max_concurrent: args.max_concurrent_requests,
+            default_tenant_concurrency: args.default_tenant_concurrency,
+            queue_timeout: args.admission_queue_timeout,
+        },
+    )
+    .await
+    .expect("failed to start LoadBalancer");
     let lb_clone = lb.clone();
     shutdown_tasks.add(Box::pin(async move { lb_clone.shut_down().await }));
 
@@ -0,0 +1,408 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use agent_api::LeaderPartition;
+use hsm_api::{GroupId, LogIndex, OwnedRange, RecordId};
+use jburl::Url;
+use juicebox_realm_api::types::RealmId;
+
+/// A range of records owned by a group, and the agent whose HSM is leading
+/// that group.
+#[derive(Clone, Debug)]
+pub(crate) struct Partition {
+    pub group: GroupId,
+    pub owned_range: OwnedRange,
+    pub leader: Url,
+    /// See [`LeaderPartition::term`].
+    pub term: LogIndex,
+}
+
+/// The partitions in a realm, indexed by record ID.
+#[derive(Debug)]
+pub(crate) struct RealmRoutes {
+    /// Splits the record ID space into intervals that are each covered by the
+    /// same set of partitions. Each interval is keyed by its first record ID
+    /// and extends up to the next key. Intervals that aren't covered by any
+    /// partitions have an empty list.
+    ///
+    /// Each group has at most one partition in an interval, from its most
+    /// recently elected leader. The partitions within an interval are ordered
+    /// by group ID. Terms are log indexes of each group's own log, so they
+    /// can't be compared across groups.
+    intervals: BTreeMap<RecordId, Vec<Partition>>,
+}
+
+impl RealmRoutes {
+    fn new(mut partitions: Vec<Partition>) -> Self {
+        // Only the most recent leader of each group is useful. Older entries
+        // are from agents that haven't reported stepping down yet.
+        let mut latest: HashMap<GroupId, LogIndex> = HashMap::new();
+        for p in &partitions {
+            let term = latest.entry(p.group).or_insert(p.term);
+            *term = (*term).max(p.term);
+        }
+        partitions.retain(|p| latest[&p.group] == p.term);
+        partitions.sort_by_key(|p| p.group);
+
+        let mut boundaries: Vec<RecordId> = Vec::with_capacity(partitions.len() * 2);
+        for p in &partitions {
+            boundaries.push(p.owned_range.start.clone());
+            if let Some(after) = p.owned_range.end.next() {
+                boundaries.push(after);
+            }
+        }
+        boundaries.sort_unstable();
+        boundaries.dedup();
+
+        let intervals = boundaries
+            .into_iter()
+            .map(|start| {
+                let covering = partitions
+                    .iter()
+                    .filter(|p| p.owned_range.contains(&start))
+                    .cloned()
+                    .collect();
+                (start, covering)
+            })
+            .collect();
+        Self { intervals }
+    }
+
+    /// Returns the partitions that contain the record, one per group, ordered
+    /// by group ID.
+    pub fn lookup(&self, record_id: &RecordId) -> &[Partition] {
+        match self.intervals.range(..=record_id).next_back() {
+            Some((_, partitions)) => partitions,
+            None => &[],
+        }
+    }
+}
+
+/// Tracks the partitions that each agent is leading.
+///
+/// This is updated one agent at a time, as agents report changes to the load
+/// balancer. Readers get an immutable snapshot of the partitions for every
+/// realm, which is rebuilt whenever an agent's partitions change.
+///
+/// Several writers race to update the same agent, so each update says where
+/// its routes came from. Routes from the agent's partition watch are versioned
+/// and replace anything older. Routes from the agent's status aren't
+/// versioned, so they only fill in for agents that aren't being watched
+/// successfully, and can't overwrite newer routes from the watch.
+#[derive(Debug, Default)]
+pub(crate) struct RoutingTable(Mutex<Inner>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    agents: HashMap<Url, AgentState>,
+    realms: Arc<HashMap<RealmId, RealmRoutes>>,
+}
+
+#[derive(Debug)]
+struct AgentState {
+    /// The version of the routes as reported by the agent, or `None` if they
+    /// were learned some other way or the watch has since failed.
+    version: Option<u64>,
+    routes: AgentRoutes,
+}
+
+/// What an agent has reported about the groups its HSM is in.
+#[derive(Debug, Default)]
+pub(crate) struct AgentRoutes {
+    /// The partitions that the agent's HSM is leading.
+    pub partitions: Vec<LeaderPartition>,
+    /// Every group that the agent's HSM is a member of.
+    pub groups: Vec<(RealmId, GroupId)>,
+}
+
+impl RoutingTable {
+    /// Returns a snapshot of the partitions in each realm.
+    pub fn realms(&self) -> Arc<HashMap<RealmId, RealmRoutes>> {
+        self.0.lock().unwrap().realms.clone()
+    }
+
+    /// Returns the version of the routes last recorded for the agent.
+    pub fn version(&self, agent: &Url) -> Option<u64> {
+        self.0
+            .lock()
+            .unwrap()
+            .agents
+            .get(agent)
+            .and_then(|state| state.version)
+    }
+
+    /// Returns the agents whose HSMs are members of the group.
+    pub fn group_members(&self, realm: RealmId, group: GroupId) -> Vec<Url> {
+        self.0
+            .lock()
+            .unwrap()
+            .agents
+            .iter()
+            .filter(|(_, state)| state.routes.groups.contains(&(realm, group)))
+            .map(|(agent, _)| agent.clone())
+            .collect()
+    }
+
+    /// Records the routes that the agent currently has.
+    ///
+    /// Versioned routes come from the agent's partition watch and replace any
+    /// previously recorded for it, unless those have a newer version.
+    /// Unversioned routes are ignored if versioned routes are already
+    /// recorded, since they may have been fetched before those. See
+    /// [`Self::forget_version`].
+    pub fn update(&self, agent: &Url, version: Option<u64>, routes: AgentRoutes) {
+        let mut locked = self.0.lock().unwrap();
+        let changed = match locked.agents.get_mut(agent) {
+            Some(state) => {
+                match (state.version, version) {
+                    (Some(_), None) => return,
+                    (Some(recorded), Some(version)) if recorded > version => return,
+                    _ => {}
+                }
+                state.version = version;
+                let changed = state.routes.partitions != routes.partitions;
+                state.routes = routes;
+                changed
+            }
+            None => {
+                locked
+                    .agents
+                    .insert(agent.clone(), AgentState { version, routes });
+                true
+            }
+        };
+        if changed {
+            locked.rebuild();
+        }
+    }
+
+    /// Marks the agent's recorded routes as no longer being kept up to date by
+    /// its partition watch, so that unversioned routes can replace them.
+    pub fn forget_version(&self, agent: &Url) {
+        if let Some(state) = self.0.lock().unwrap().agents.get_mut(agent) {
+            state.version = None;
+        }
+    }
+
+    /// Forgets about the agents for which `keep` returns false.
+    pub fn retain(&self, mut keep: impl FnMut(&Url) -> bool) {
+        let mut locked = self.0.lock().unwrap();
+        let before = locked.agents.len();
+        locked.agents.retain(|agent, _| keep(agent));
+        if locked.agents.len() != before {
+            locked.rebuild();
+        }
+    }
+}
+
+impl Inner {
+    fn rebuild(&mut self) {
+        let mut realms: HashMap<RealmId, Vec<Partition>> = HashMap::new();
+        for (agent, state) in &self.agents {
+            for p in &state.routes.partitions {
+                realms.entry(p.realm).or_default().push(Partition {
+                    group: p.group,
+                    owned_range: p.owned_range.clone(),
+                    leader: agent.clone(),
+                    term: p.term,
+                });
+            }
+        }
+        self.realms = Arc::new(
+            realms
+                .into_iter()
+                .map(|(realm, partitions)| (realm, RealmRoutes::new(partitions)))
+                .collect(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REALM: RealmId = RealmId([1; 16]);
+
+    fn id(first: u8) -> RecordId {
+        RecordId::min_id().with(&[first])
+    }
+
+    fn range(start: u8, end: u8) -> OwnedRange {
+        OwnedRange {
+            start: id(start),
+            end: RecordId::max_id().with(&[end]),
+        }
+    }
+
+    fn partition(group: u8, owned_range: OwnedRange, term: u64) -> LeaderPartition {
+        LeaderPartition {
+            realm: REALM,
+            group: GroupId([group; 16]),
+            owned_range,
+            term: LogIndex(term),
+        }
+    }
+
+    fn routes(partitions: Vec<LeaderPartition>) -> AgentRoutes {
+        let groups = partitions.iter().map(|p| (p.realm, p.group)).collect();
+        AgentRoutes { partitions, groups }
+    }
+
+    fn lookup(table: &RoutingTable, record_id: &RecordId) -> Vec<(Url, GroupId)> {
+        table.realms()[&REALM]
+            .lookup(record_id)
+            .iter()
+            .map(|p| (p.leader.clone(), p.group))
+            .collect()
+    }
+
+    #[test]
+    fn lookup_by_range() {
+        let table = RoutingTable::default();
+        let a = Url::parse("http://agent-a:8082").unwrap();
+        let b = Url::parse("http://agent-b:8082").unwrap();
+        assert!(table.realms().is_empty());
+
+        // Group 1 owns [0x00, 0x7f], group 2 owns [0xa0, 0xff], and group 3
+        // is mid-transfer, overlapping both.
+        table.update(
+            &a,
+            Some(4),
+            routes(vec![
+                partition(1, range(0x00, 0x7f), 10),
+                partition(2, range(0xa0, 0xff), 10),
+            ]),
+        );
+        table.update(&b, None, routes(vec![partition(3, range(0x70, 0xaf), 2)]));
+        assert_eq!(Some(4), table.version(&a));
+        assert_eq!(None, table.version(&b));
+
+        let g = |n: u8| GroupId([n; 16]);
+        assert_eq!(vec![(a.clone(), g(1))], lookup(&table, &id(0x00)));
+        assert_eq!(vec![(a.clone(), g(1))], lookup(&table, &id(0x6f)));
+        assert_eq!(
+            vec![(a.clone(), g(1)), (b.clone(), g(3))],
+            lookup(&table, &id(0x75))
+        );
+        assert_eq!(vec![(b.clone(), g(3))], lookup(&table, &id(0x80)));
+        assert_eq!(
+            vec![(a.clone(), g(2)), (b.clone(), g(3))],
+            lookup(&table, &RecordId::max_id().with(&[0xaf]))
+        );
+        assert_eq!(vec![(a.clone(), g(2))], lookup(&table, &id(0xb0)));
+        assert_eq!(vec![(a.clone(), g(2))], lookup(&table, &RecordId::max_id()));
+
+        // A gap that no partition covers.
+        table.update(&b, None, routes(Vec::new()));
+        assert!(lookup(&table, &id(0x80)).is_empty());
+        assert_eq!(vec![(a.clone(), g(1))], lookup(&table, &id(0x75)));
+    }
+
+    #[test]
+    fn terms_not_compared_across_groups() {
+        let table = RoutingTable::default();
+        let a = Url::parse("http://agent-a:8082").unwrap();
+        let b = Url::parse("http://agent-b:8082").unwrap();
+
+        // Group 2's log is much longer than group 1's, but that says nothing
+        // about which group's leader was elected more recently, so both are
+        // kept and ordered by group.
+        table.update(&a, None, routes(vec![partition(2, range(0x00, 0xff), 900)]));
+        table.update(&b, None, routes(vec![partition(1, range(0x40, 0x7f), 3)]));
+        assert_eq!(
+            vec![(b.clone(), GroupId([1; 16])), (a.clone(), GroupId([2; 16]))],
+            lookup(&table, &id(0x50))
+        );
+        assert_eq!(vec![(a, GroupId([2; 16]))], lookup(&table, &id(0x90)));
+    }
+
+    #[test]
+    fn newer_leader_wins() {
+        let table = RoutingTable::default();
+        let a = Url::parse("http://agent-a:8082").unwrap();
+        let b = Url::parse("http://agent-b:8082").unwrap();
+
+        table.update(
+            &a,
+            Some(1),
+            routes(vec![partition(1, OwnedRange::full(), 10)]),
+        );
+        let realms = table.realms();
+        assert_eq!(vec![(a.clone(), GroupId([1; 16]))], lookup(&table, &id(5)));
+
+        // Agent b's HSM took over leadership, but agent a hasn't reported
+        // stepping down yet.
+        table.update(
+            &b,
+            Some(1),
+            routes(vec![partition(1, OwnedRange::full(), 20)]),
+        );
+        assert_eq!(vec![(b.clone(), GroupId([1; 16]))], lookup(&table, &id(5)));
+        assert_eq!(
+            vec![a.clone(), b.clone()],
+            sorted(table.group_members(REALM, GroupId([1; 16])))
+        );
+
+        // An update that doesn't change the partitions doesn't rebuild the
+        // snapshot.
+        let before = table.realms();
+        table.update(
+            &b,
+            Some(2),
+            routes(vec![partition(1, OwnedRange::full(), 20)]),
+        );
+        assert!(Arc::ptr_eq(&before, &table.realms()));
+        assert!(!Arc::ptr_eq(&realms, &before));
+        assert_eq!(Some(2), table.version(&b));
+
+        table.retain(|agent| agent == &a);
+        assert_eq!(vec![(a.clone(), GroupId([1; 16]))], lookup(&table, &id(5)));
+        assert_eq!(None, table.version(&b));
+        assert_eq!(vec![a], table.group_members(REALM, GroupId([1; 16])));
+    }
+
+    #[test]
+    fn newest_update_wins() {
+        let table = RoutingTable::default();
+        let a = Url::parse("http://agent-a:8082").unwrap();
+        let g = GroupId([1; 16]);
+
+        // A full refresh learns the partitions from the agent's status.
+        table.update(&a, None, routes(vec![partition(1, range(0x00, 0x7f), 10)]));
+        assert_eq!(vec![(a.clone(), g)], lookup(&table, &id(0x10)));
+
+        // The watch reports a newer version that replaces them.
+        table.update(
+            &a,
+            Some(7),
+            routes(vec![partition(1, range(0x00, 0x3f), 12)]),
+        );
+        assert!(lookup(&table, &id(0x50)).is_empty());
+
+        // A status that was fetched before the watch responded arrives late.
+        // It mustn't undo the watch's update.
+        table.update(&a, None, routes(vec![partition(1, range(0x00, 0x7f), 10)]));
+        assert!(lookup(&table, &id(0x50)).is_empty());
+        assert_eq!(Some(7), table.version(&a));
+
+        // Nor can an older versioned response.
+        table.update(
+            &a,
+            Some(6),
+            routes(vec![partition(1, range(0x00, 0x7f), 10)]),
+        );
+        assert!(lookup(&table, &id(0x50)).is_empty());
+        assert_eq!(Some(7), table.version(&a));
+
+        // Once the watch fails, the full refresh takes over again.
+        table.forget_version(&a);
+        assert_eq!(None, table.version(&a));
+        table.update(&a, None, routes(vec![partition(1, range(0x00, 0x7f), 13)]));
+        assert_eq!(vec![(a.clone(), g)], lookup(&table, &id(0x50)));
+    }
+
+    fn sorted(mut urls: Vec<Url>) -> Vec<Url> {
+        urls.sort();
+        urls
+    }
+}
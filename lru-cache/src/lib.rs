@@ -2,7 +2,8 @@
 
 extern crate alloc;
 
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::cmp::Reverse;
 use core::fmt::Debug;
 use core::hash::{BuildHasher, Hash};
 use hashbrown::HashMap;
@@ -145,6 +146,222 @@ where
     }
 }
 
+/// Statistics for one partition, returned by [`PartitionedCache::partitions`].
+#[derive(Debug, Eq, PartialEq)]
+pub struct PartitionStats<Time = <LogicalClock as Clock>::Time>
+where
+    Time: Clone + Copy + Debug + Eq + Ord + PartialEq + PartialOrd,
+{
+    /// The number of items in the partition. This is never 0.
+    pub entries: usize,
+    /// When the partition's least recently accessed entry was inserted or
+    /// accessed.
+    pub lru_time: Time,
+}
+
+/// An LRU cache whose entries are divided into partitions, so that filling
+/// one partition can't evict every other partition's entries.
+///
+/// The cache as a whole holds up to `limit` entries. Until it's full, any
+/// partition may grow as large as it likes. Once it's full, a new entry
+/// evicts the least recently used entry of its own partition if that
+/// partition already has `partition_limit` or more entries. Otherwise, it
+/// evicts the least recently used entry of the largest partition, preferring
+/// the partition with the oldest entry among equally large ones. This keeps
+/// partitions that use little of the cache from losing entries to partitions
+/// that use a lot, without leaving the cache idle when only a few partitions
+/// are busy.
+///
+/// Partitions are created by inserting into them and are dropped once they're
+/// empty. Like [`Cache`], it's `O(log(N))` but not particularly efficient.
+pub struct PartitionedCache<P, K, V, C: Clock, H> {
+    map: HashMap<K, (P, C::Time, V), H>,
+    partitions: HashMap<P, BTreeMap<C::Time, K>, H>,
+    /// Orders the (non-empty) partitions by their number of entries, then by
+    /// the reverse of their LRU times, so that the last one is the next
+    /// partition to evict from when the cache is full.
+    by_size: BTreeSet<(usize, Reverse<C::Time>, P)>,
+    clock: C,
+    limit: usize,
+    partition_limit: usize,
+}
+
+impl<P, K, V, C, H> PartitionedCache<P, K, V, C, H>
+where
+    P: Clone + Eq + Hash + Ord,
+    K: Clone + Eq + Hash,
+    C: Clock,
+    H: BuildHasher + Default,
+{
+    pub fn new(limit: usize, partition_limit: usize) -> Self
+    where
+        C: Default,
+    {
+        assert!(limit > 0);
+        assert!(partition_limit > 0 && partition_limit <= limit);
+        Self {
+            map: HashMap::with_hasher(H::default()),
+            partitions: HashMap::with_hasher(H::default()),
+            by_size: BTreeSet::new(),
+            clock: C::default(),
+            limit,
+            partition_limit,
+        }
+    }
+
+    fn check_invariants(&self) {
+        assert_eq!(self.partitions.len(), self.by_size.len());
+        let mut entries = 0;
+        for (partition, lru) in &self.partitions {
+            let (lru_time, _) = lru
+                .first_key_value()
+                .expect("partitions shouldn't be empty");
+            assert!(self
+                .by_size
+                .contains(&(lru.len(), Reverse(*lru_time), partition.clone())));
+            entries += lru.len();
+        }
+        assert_eq!(self.map.len(), entries);
+        for (k, (partition, mtime, _)) in &self.map {
+            assert!(self.partitions[partition].get(mtime) == Some(k));
+        }
+        assert!(self.map.len() <= self.limit);
+    }
+
+    /// Adds an entry to a partition's LRU list, keeping `by_size` up to date.
+    fn add_to_partition(&mut self, partition: P, mtime: C::Time, k: K) {
+        let lru = self.partitions.entry(partition.clone()).or_default();
+        if let Some((lru_time, _)) = lru.first_key_value() {
+            self.by_size
+                .remove(&(lru.len(), Reverse(*lru_time), partition.clone()));
+        }
+        lru.insert(mtime, k);
+        let (lru_time, _) = lru.first_key_value().unwrap();
+        self.by_size
+            .insert((lru.len(), Reverse(*lru_time), partition));
+    }
+
+    /// Removes an entry from a partition's LRU list, keeping `by_size` up to
+    /// date and dropping the partition if it's now empty.
+    fn remove_from_partition(&mut self, partition: &P, mtime: &C::Time) -> K {
+        let lru = self
+            .partitions
+            .get_mut(partition)
+            .expect("entry's partition should exist");
+        let (lru_time, _) = lru.first_key_value().unwrap();
+        self.by_size
+            .remove(&(lru.len(), Reverse(*lru_time), partition.clone()));
+        let k = lru.remove(mtime).expect("entry should be in its partition");
+        match lru.first_key_value() {
+            Some((lru_time, _)) => {
+                self.by_size
+                    .insert((lru.len(), Reverse(*lru_time), partition.clone()));
+            }
+            None => {
+                self.partitions.remove(partition);
+            }
+        }
+        k
+    }
+
+    /// Evicts the least recently used entry from the given partition.
+    fn evict_from(&mut self, partition: &P) {
+        let lru_time = match self.partitions.get(partition) {
+            Some(lru) => *lru.first_key_value().unwrap().0,
+            None => return,
+        };
+        let k = self.remove_from_partition(partition, &lru_time);
+        self.map.remove(&k);
+    }
+
+    pub fn insert(&mut self, partition: P, k: K, v: V) {
+        // If this overwrites an existing entry, remove that first. The entry
+        // may be moving to a different partition, so the limits need to be
+        // checked either way.
+        if let Some((prev_partition, prev_mtime, _)) = self.map.remove(&k) {
+            self.remove_from_partition(&prev_partition, &prev_mtime);
+        }
+
+        if self.map.len() >= self.limit {
+            let partition_len = self.partitions.get(&partition).map_or(0, BTreeMap::len);
+            if partition_len >= self.partition_limit {
+                self.evict_from(&partition);
+            } else if let Some((_, _, largest)) = self.by_size.last().cloned() {
+                self.evict_from(&largest);
+            }
+        }
+
+        let now = self.clock.time();
+        self.add_to_partition(partition.clone(), now, k.clone());
+        self.map.insert(k, (partition, now, v));
+        if cfg!(debug_assertions) {
+            self.check_invariants();
+        }
+    }
+
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        if let Some((partition, mtime, v)) = self.map.remove(k) {
+            self.remove_from_partition(&partition, &mtime);
+            if cfg!(debug_assertions) {
+                self.check_invariants();
+            }
+            Some(v)
+        } else {
+            None
+        }
+    }
+
+    pub fn get(&mut self, k: &K) -> Option<&V> {
+        // Remove and reinsert so that the timestamp gets updated. This can't
+        // evict anything, since the entry stays in the same partition.
+        let partition = self.map.get(k).map(|(partition, _, _)| partition.clone())?;
+        let v = self.remove(k)?;
+        self.insert(partition, k.clone(), v);
+        let (_, _, v) = self.map.get(k).unwrap();
+        Some(v)
+    }
+
+    pub fn stats(&self) -> Stats<C::Time> {
+        Stats {
+            entries: self.map.len(),
+            limit: self.limit,
+            lru_time: self
+                .by_size
+                .iter()
+                .map(|(_, Reverse(lru_time), _)| *lru_time)
+                .min(),
+        }
+    }
+
+    /// The number of items at which a partition evicts its own entries rather
+    /// than other partitions' when the cache is full.
+    pub fn partition_limit(&self) -> usize {
+        self.partition_limit
+    }
+
+    /// The number of non-empty partitions.
+    pub fn partition_count(&self) -> usize {
+        self.partitions.len()
+    }
+
+    /// Iterates over the non-empty partitions, largest first. This is the
+    /// order in which the cache evicts from them when it's full.
+    pub fn partitions(&self) -> impl Iterator<Item = (&P, PartitionStats<C::Time>)> {
+        self.by_size
+            .iter()
+            .rev()
+            .map(|(entries, Reverse(lru_time), partition)| {
+                (
+                    partition,
+                    PartitionStats {
+                        entries: *entries,
+                        lru_time: *lru_time,
+                    },
+                )
+            })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -219,4 +436,104 @@ mod tests {
         assert_eq!(cache.get(&'3'), None);
         assert_eq!(cache.get(&'1'), Some(&1.0));
     }
+
+    type PartitionedFloatCache = PartitionedCache<u8, char, f64, LogicalClock, RandomState>;
+
+    fn partition_sizes(cache: &PartitionedFloatCache) -> Vec<(u8, usize)> {
+        cache
+            .partitions()
+            .map(|(partition, stats)| (*partition, stats.entries))
+            .collect()
+    }
+
+    #[test]
+    fn test_partitioned_basic() {
+        let mut cache = PartitionedFloatCache::new(4, 2);
+        cache.insert(1, 'a', 1.0); // t=1
+        cache.insert(2, 'b', 2.0); // t=2
+        cache.insert(1, 'c', 3.0); // t=3
+        assert_eq!(partition_sizes(&cache), vec![(1, 2), (2, 1)]);
+        assert_eq!(
+            cache.stats(),
+            Stats {
+                entries: 3,
+                limit: 4,
+                lru_time: Some(1),
+            }
+        );
+
+        // The cache has room, so partition 1 can go over its limit.
+        cache.insert(1, 'd', 4.0); // t=4
+        assert_eq!(partition_sizes(&cache), vec![(1, 3), (2, 1)]);
+
+        // The cache is full and partition 1 is over its limit, so it evicts
+        // its own LRU entry, even though partition 2's entry is older.
+        cache.insert(1, 'e', 5.0); // t=5
+        assert_eq!(cache.remove(&'a'), None);
+        assert_eq!(partition_sizes(&cache), vec![(1, 3), (2, 1)]);
+
+        // Moving an entry to another partition counts against the new one.
+        cache.insert(2, 'c', 3.5); // t=6
+        assert_eq!(partition_sizes(&cache), vec![(2, 2), (1, 2)]);
+        assert_eq!(cache.get(&'c'), Some(&3.5));
+
+        assert_eq!(cache.remove(&'b'), Some(2.0));
+        assert_eq!(cache.remove(&'c'), Some(3.5));
+        assert_eq!(partition_sizes(&cache), vec![(1, 2)]);
+        assert_eq!(cache.remove(&'d'), Some(4.0));
+        assert_eq!(cache.remove(&'e'), Some(5.0));
+        assert_eq!(cache.partition_count(), 0);
+        assert_eq!(
+            cache.stats(),
+            Stats {
+                entries: 0,
+                limit: 4,
+                lru_time: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_partitioned_fair_eviction() {
+        let mut cache = PartitionedFloatCache::new(4, 3);
+        cache.insert(1, 'a', 1.0);
+        cache.insert(2, 'b', 2.0);
+        cache.insert(1, 'c', 3.0);
+        cache.insert(1, 'd', 4.0);
+
+        // The cache is full, so a new partition evicts from the largest one,
+        // not the globally least recently used entry ('b' is older than 'c').
+        cache.insert(3, 'e', 5.0);
+        assert_eq!(partition_sizes(&cache), vec![(1, 2), (2, 1), (3, 1)]);
+        assert!(!cache.map.contains_key(&'a'));
+        cache.insert(2, 'f', 6.0);
+        assert!(!cache.map.contains_key(&'c'));
+        cache.insert(4, 'g', 7.0);
+        assert!(!cache.map.contains_key(&'b'));
+
+        // Among equally large partitions, the one with the oldest entry loses
+        // it.
+        assert_eq!(
+            partition_sizes(&cache),
+            vec![(1, 1), (3, 1), (2, 1), (4, 1)]
+        );
+        cache.insert(5, 'h', 8.0);
+        assert!(!cache.map.contains_key(&'d'));
+        assert_eq!(
+            partition_sizes(&cache),
+            vec![(3, 1), (2, 1), (4, 1), (5, 1)]
+        );
+    }
+
+    #[test]
+    fn test_partitioned_tiny() {
+        let mut cache = PartitionedFloatCache::new(1, 1);
+        cache.insert(1, '1', 1.0);
+        cache.insert(2, '3', 3.0);
+        assert_eq!(cache.remove(&'1'), None);
+        assert_eq!(cache.remove(&'3'), Some(3.0));
+        assert_eq!(cache.remove(&'3'), None);
+        assert_eq!(cache.map.len(), 0);
+        assert_eq!(cache.partitions.len(), 0);
+    }
 }
@@ -0,0 +1,119 @@
+//! Decodes a captured HSM NVRAM image and reports its schema version and
+//! contents.
+//!
+//! This is a dry run of what an HSM does with the image when it starts: the
+//! image is checked and migrated to the current schema in memory, but it's
+//! never written back. Use it to check that an HSM will be able to read its
+//! state before upgrading it.
+
+use anyhow::anyhow;
+use clap::Parser;
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use hsm_core::hal::MAX_NVRAM_SIZE;
+use hsm_core::hsm::persistence::{self, CURRENT_VERSION};
+use software_hsm::nvram::{NVRamFaults, NVRamFile};
+use software_hsm::sealing::{Sealer, SealingSecret};
+
+/// Decodes a captured HSM NVRAM image and reports its schema version and
+/// contents. The image is not modified.
+#[derive(Debug, Parser)]
+#[command(version = build_info::clap!())]
+struct Args {
+    /// The NVRAM image: the raw bytes the HSM wrote, or a software HSM's
+    /// state file with `--software-hsm`.
+    image: PathBuf,
+
+    /// Read the image as a software HSM's state file, which wraps the NVRAM
+    /// contents in slots and may seal them.
+    #[arg(long)]
+    software_hsm: bool,
+
+    /// Unseal a software HSM's state file with the 32-byte key in this file,
+    /// given in hex.
+    #[arg(
+        long,
+        value_name = "FILE",
+        requires = "software_hsm",
+        conflicts_with = "sealing_passphrase_file"
+    )]
+    sealing_key_file: Option<PathBuf>,
+
+    /// Unseal a software HSM's state file with a key derived from the
+    /// passphrase on the first line of this file.
+    #[arg(long, value_name = "FILE", requires = "software_hsm")]
+    sealing_passphrase_file: Option<PathBuf>,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    match decode(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("ERROR: {err:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn decode(args: &Args) -> anyhow::Result<()> {
+    let image = if args.software_hsm {
+        let sealer = match (&args.sealing_key_file, &args.sealing_passphrase_file) {
+            (Some(path), _) => Some(SealingSecret::from_key_file(path)?),
+            (None, Some(path)) => Some(SealingSecret::from_passphrase_file(path)?),
+            (None, None) => None,
+        }
+        .map(|secret| Arc::new(Sealer::new(secret)));
+        NVRamFile::new(args.image.clone(), NVRamFaults::default(), sealer)
+            .read()
+            .map_err(|err| anyhow!("failed to read {:?}: {}", args.image, err.0))?
+    } else {
+        fs::read(&args.image).map_err(|err| anyhow!("failed to read {:?}: {err}", args.image))?
+    };
+
+    // The state holds hash maps, which need randomness.
+    hsm_core::hash::set_global_rng(Box::new(rand_core::OsRng));
+
+    println!("image size: {} of {MAX_NVRAM_SIZE} bytes", image.len());
+    let decoded = persistence::decode(&image)
+        .map_err(|err| anyhow!("failed to decode the NVRAM image: {err:?}"))?;
+    let Some(decoded) = decoded else {
+        println!("The image is empty: the HSM hasn't written its state yet.");
+        return Ok(());
+    };
+
+    println!("schema version: {}", decoded.version);
+    if decoded.version == CURRENT_VERSION {
+        println!("This is the current schema version.");
+    } else {
+        let migrated = persistence::encode(&decoded.state);
+        println!(
+            "The HSM will migrate this to schema version {CURRENT_VERSION}, \
+            which takes {} of {MAX_NVRAM_SIZE} bytes.",
+            migrated.len()
+        );
+    }
+    println!();
+    println!("{:#?}", decoded.state);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+    use expect_test::expect_file;
+
+    #[test]
+    fn test_usage() {
+        expect_file!["../usage.txt"].assert_eq(
+            &Args::command()
+                .try_get_matches_from(["nvram_decode", "--help"])
+                .unwrap_err()
+                .to_string(),
+        );
+    }
+}
@@ -0,0 +1,679 @@
+//! Tenant auth keys published by the tenants themselves, as JSON Web Key Sets
+//! (RFC 7517).
+//!
+//! This lets a tenant that signs its tokens with a public-key algorithm
+//! rotate its keys without contacting us: it adds the new key to its JWKS
+//! document, starts signing with it, and later removes the old key.
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use futures::future::join_all;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+use tokio::time::sleep;
+use tracing::{info, warn};
+use url::Url;
+
+use super::{
+    tenant_secret_name, Error, Secret, SecretAlgorithm, SecretManager, SecretName, SecretVersion,
+};
+use async_util::ScopedTask;
+
+/// JWKS documents larger than this are rejected.
+const MAX_DOCUMENT_SIZE: usize = 64 * 1024;
+
+/// A [`SecretManager`] that loads tenant auth keys from JWKS documents and
+/// falls back to another [`SecretManager`] for everything else.
+///
+/// Each tenant's document is fetched from a `file:` URL (intended for tests)
+/// or an `http(s):` URL, and it's re-fetched on a time interval. If fetching
+/// fails, the previously loaded keys stay in use until they expire.
+///
+/// Only public keys are accepted: Ed25519 keys (`"kty": "OKP"`) and RSA keys
+/// (`"kty": "RSA"`). Each key's `kid` must be the key's version number, as
+/// used in the `kid` header of the tenant's tokens. It may optionally be
+/// prefixed by the tenant name and a colon. A key may also have an `exp`
+/// member, a Unix timestamp in seconds after which the key is not used.
+#[derive(Debug)]
+pub struct Jwks {
+    /// Maps each tenant's auth key secret name to the tenant name.
+    tenants: HashMap<SecretName, String>,
+    cache: Cache,
+    fallback: Box<dyn SecretManager>,
+
+    // This is included for its `Drop` implementation, which aborts the
+    // background task.
+    #[allow(unused)]
+    task: ScopedTask<()>,
+}
+
+type Cache = Arc<Mutex<HashMap<String, HashMap<SecretVersion, JwksKey>>>>;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct JwksKey {
+    secret: Secret,
+    expires: Option<SystemTime>,
+}
+
+impl Jwks {
+    /// Loads the tenants' JWKS documents and starts refreshing them.
+    ///
+    /// `sources` maps each tenant name to the URL of its JWKS document. This
+    /// doesn't fail if some documents can't be loaded, since they're loaded
+    /// again on the next refresh.
+    pub async fn new(
+        sources: HashMap<String, Url>,
+        refresh_interval: Duration,
+        fallback: Box<dyn SecretManager>,
+    ) -> Result<Self, Error> {
+        for (tenant, url) in &sources {
+            if !matches!(url.scheme(), "file" | "http" | "https") {
+                return Err(anyhow!("unsupported JWKS URL for tenant {tenant:?}: {url}"));
+            }
+        }
+        let fetcher = Fetcher {
+            http: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+            sources: Arc::new(sources),
+        };
+        let cache = Cache::default();
+        fetcher.refresh(&cache).await;
+
+        let tenants = fetcher
+            .sources
+            .keys()
+            .map(|tenant| (tenant_secret_name(tenant), tenant.clone()))
+            .collect();
+        let task = ScopedTask::spawn(refresh_loop(fetcher, cache.clone(), refresh_interval));
+        Ok(Self {
+            tenants,
+            cache,
+            fallback,
+            task,
+        })
+    }
+
+    /// Like [`Jwks::new`], but reads the tenants' JWKS URLs from a JSON file.
+    ///
+    /// The file should look like this:
+    ///
+    /// ```json
+    /// {
+    ///     "tenant-a": "https://a.example.com/.well-known/jwks.json",
+    ///     "tenant-b": "file:///etc/juicebox/tenant-b-jwks.json"
+    /// }
+    /// ```
+    pub async fn from_config_file(
+        path: &Path,
+        refresh_interval: Duration,
+        fallback: Box<dyn SecretManager>,
+    ) -> Result<Self, Error> {
+        let contents = fs::read(path)
+            .await
+            .with_context(|| format!("failed to read JWKS config from {path:?}"))?;
+        let sources: HashMap<String, Url> = serde_json::from_slice(&contents)
+            .with_context(|| format!("failed to parse JWKS config from {path:?}"))?;
+        Self::new(sources, refresh_interval, fallback).await
+    }
+
+    fn live_keys(&self, tenant: &str) -> HashMap<SecretVersion, Secret> {
+        let now = SystemTime::now();
+        let locked = self.cache.lock().unwrap();
+        match locked.get(tenant) {
+            None => HashMap::new(),
+            Some(keys) => keys
+                .iter()
+                .filter(|(_, key)| key.expires.map_or(true, |expires| now < expires))
+                .map(|(version, key)| (*version, key.secret.clone()))
+                .collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl SecretManager for Jwks {
+    async fn get_secret_version(
+        &self,
+        name: &SecretName,
+        version: SecretVersion,
+    ) -> Result<Option<Secret>, Error> {
+        match self.tenants.get(name) {
+            Some(tenant) => Ok(self.live_keys(tenant).remove(&version)),
+            None => self.fallback.get_secret_version(name, version).await,
+        }
+    }
+
+    async fn get_secrets(
+        &self,
+        name: &SecretName,
+    ) -> Result<HashMap<SecretVersion, Secret>, Error> {
+        match self.tenants.get(name) {
+            Some(tenant) => Ok(self.live_keys(tenant)),
+            None => self.fallback.get_secrets(name).await,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Fetcher {
+    http: reqwest::Client,
+    sources: Arc<HashMap<String, Url>>,
+}
+
+impl Fetcher {
+    /// Re-fetches every tenant's keys. Tenants whose documents can't be
+    /// loaded keep their previous keys.
+    async fn refresh(&self, cache: &Cache) {
+        let results = join_all(self.sources.iter().map(|(tenant, url)| async move {
+            let result = match self.fetch(url).await {
+                Ok(document) => parse_jwks(tenant, &document),
+                Err(err) => Err(err),
+            };
+            (tenant, url, result)
+        }))
+        .await;
+
+        let mut locked = cache.lock().unwrap();
+        for (tenant, url, result) in results {
+            match result {
+                Ok(keys) => {
+                    if locked.get(tenant) != Some(&keys) {
+                        info!(%tenant, %url, versions = ?keys.keys(), "loaded tenant JWKS");
+                    }
+                    locked.insert(tenant.clone(), keys);
+                }
+                Err(err) => warn!(%tenant, %url, ?err, "failed to load tenant JWKS"),
+            }
+        }
+    }
+
+    /// Reads the document, giving up as soon as it's known to be larger than
+    /// [`MAX_DOCUMENT_SIZE`].
+    async fn fetch(&self, url: &Url) -> Result<Vec<u8>, Error> {
+        let too_large = || anyhow!("JWKS document is larger than {MAX_DOCUMENT_SIZE} bytes");
+        let mut document = Vec::new();
+        if url.scheme() == "file" {
+            let path = url
+                .to_file_path()
+                .map_err(|()| anyhow!("invalid file URL: {url}"))?;
+            fs::File::open(path)
+                .await?
+                .take(MAX_DOCUMENT_SIZE as u64 + 1)
+                .read_to_end(&mut document)
+                .await?;
+            if document.len() > MAX_DOCUMENT_SIZE {
+                return Err(too_large());
+            }
+        } else {
+            let mut response = self
+                .http
+                .get(url.clone())
+                .send()
+                .await?
+                .error_for_status()?;
+            if response
+                .content_length()
+                .is_some_and(|len| len > MAX_DOCUMENT_SIZE as u64)
+            {
+                return Err(too_large());
+            }
+            while let Some(chunk) = response.chunk().await? {
+                if document.len() + chunk.len() > MAX_DOCUMENT_SIZE {
+                    return Err(too_large());
+                }
+                document.extend_from_slice(&chunk);
+            }
+        }
+        Ok(document)
+    }
+}
+
+async fn refresh_loop(fetcher: Fetcher, cache: Cache, interval: Duration) {
+    loop {
+        sleep(interval).await;
+        fetcher.refresh(&cache).await;
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JwksDocument {
+    keys: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    key_use: Option<String>,
+    exp: Option<u64>,
+    // Ed25519 (OKP) members.
+    crv: Option<String>,
+    x: Option<String>,
+    // RSA members.
+    n: Option<String>,
+    e: Option<String>,
+    // Private key members. These must not be present.
+    d: Option<String>,
+}
+
+/// Parses a tenant's JWKS document into its auth keys, by version.
+///
+/// Keys that can't be used are skipped with a warning, so that a tenant can
+/// publish keys for other purposes alongside its auth keys.
+fn parse_jwks(tenant: &str, document: &[u8]) -> Result<HashMap<SecretVersion, JwksKey>, Error> {
+    let document: JwksDocument =
+        serde_json::from_slice(document).context("failed to parse JWKS document")?;
+    let mut keys = HashMap::new();
+    for value in document.keys {
+        let kid = value.get("kid").cloned();
+        match serde_json::from_value(value)
+            .map_err(Error::from)
+            .and_then(|jwk| parse_jwk(tenant, jwk))
+        {
+            Ok((version, key)) => {
+                if keys.insert(version, key).is_some() {
+                    return Err(anyhow!("JWKS has multiple keys with version {version:?}"));
+                }
+            }
+            Err(err) => warn!(tenant, ?kid, ?err, "skipping unusable JWK"),
+        }
+    }
+    Ok(keys)
+}
+
+fn parse_jwk(tenant: &str, jwk: Jwk) -> Result<(SecretVersion, JwksKey), Error> {
+    let kid = jwk.kid.ok_or_else(|| anyhow!("missing kid"))?;
+    let version = match kid.split_once(':') {
+        Some((issuer, version)) if issuer == tenant => version,
+        Some(_) => return Err(anyhow!("kid names another tenant")),
+        None => &kid,
+    };
+    let version = SecretVersion(version.parse().context("invalid kid")?);
+
+    if jwk
+        .key_use
+        .as_deref()
+        .is_some_and(|key_use| key_use != "sig")
+    {
+        return Err(anyhow!("key is not for signatures"));
+    }
+    if jwk.d.is_some() {
+        return Err(anyhow!("JWKS must not contain private keys"));
+    }
+
+    // These use the encodings that the token validator expects for public
+    // keys: the raw 32 bytes for Ed25519, and a PKCS #1 RSAPublicKey for RSA.
+    let secret = match (jwk.kty.as_str(), jwk.alg.as_deref()) {
+        ("OKP", None | Some("EdDSA")) => {
+            if jwk.crv.as_deref() != Some("Ed25519") {
+                return Err(anyhow!("unsupported curve"));
+            }
+            let x = decode_member(jwk.x, "x")?;
+            if x.len() != 32 {
+                return Err(anyhow!("invalid Ed25519 public key length"));
+            }
+            Secret {
+                data: x.into(),
+                algorithm: SecretAlgorithm::Edwards25519,
+            }
+        }
+        ("RSA", None | Some("RS256")) => {
+            let n = decode_member(jwk.n, "n")?;
+            let e = decode_member(jwk.e, "e")?;
+            Secret {
+                data: rsa_public_key_der(&n, &e).into(),
+                algorithm: SecretAlgorithm::RsaPkcs1Sha256,
+            }
+        }
+        (kty, alg) => return Err(anyhow!("unsupported key type {kty:?} with alg {alg:?}")),
+    };
+
+    let expires = jwk
+        .exp
+        .map(|exp| SystemTime::UNIX_EPOCH + Duration::from_secs(exp));
+    Ok((version, JwksKey { secret, expires }))
+}
+
+fn decode_member(value: Option<String>, name: &str) -> Result<Vec<u8>, Error> {
+    let value = value.ok_or_else(|| anyhow!("missing {name:?}"))?;
+    URL_SAFE_NO_PAD
+        .decode(value)
+        .with_context(|| format!("invalid base64url in {name:?}"))
+}
+
+/// Encodes an RSA public key as a DER PKCS#1 RSAPublicKey.
+fn rsa_public_key_der(n: &[u8], e: &[u8]) -> Vec<u8> {
+    let mut integers = der_unsigned_integer(n);
+    integers.extend(der_unsigned_integer(e));
+    der_tlv(0x30, &integers)
+}
+
+fn der_unsigned_integer(bytes: &[u8]) -> Vec<u8> {
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    let bytes = &bytes[start..];
+    let mut content = Vec::with_capacity(bytes.len() + 1);
+    // DER integers are signed, so positive values with the high bit set need
+    // a leading zero byte.
+    if bytes.first().map_or(true, |b| b & 0x80 != 0) {
+        content.push(0);
+    }
+    content.extend_from_slice(bytes);
+    der_tlv(0x02, &content)
+}
+
+fn der_tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut der = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        der.push(len as u8);
+    } else {
+        let len_bytes = len.to_be_bytes();
+        let skip = len_bytes.iter().position(|b| *b != 0).unwrap();
+        der.push(0x80 | (len_bytes.len() - skip) as u8);
+        der.extend_from_slice(&len_bytes[skip..]);
+    }
+    der.extend_from_slice(content);
+    der
+}
+
+#[cfg(test)]
+mod tests {
+    use juicebox_realm_api::types::RealmId;
+    use juicebox_realm_auth::creation::create_token;
+    use juicebox_realm_auth::validation::{Require, Validator};
+    use juicebox_realm_auth::{AuthKey, AuthKeyAlgorithm, AuthKeyVersion, Claims, Scope};
+    use tempfile::NamedTempFile;
+
+    use super::super::tenant_admin::ED25519_PKCS8_PREFIX;
+    use super::*;
+
+    /// An RSA private key in PKCS #1 DER, whose modulus is [`RSA_N`].
+    const RSA_PRIVATE_KEY: &[u8] = include_bytes!("../testdata/rsa_key.der");
+    const RSA_N: &str = concat!(
+        "mVybAv6_U1NwDKJA4VNyHG0Uj3Y8LxJQzCgR4onvkNIjaFaPZo3-1GcF5ctmnYRDFpD5bsa0gujlPFQ-",
+        "NOcTcyp4GbDeazQ461H4vVZWRSqr5bb5PI896mW2ZXn-Z6u3YIRDpjC_cQ4-Ts5p99wtMg56WloVLxSI",
+        "IAgz67FlQBgqgEfDF_TQ-BIeFOxF_GYaDdCAkKvpGIfQkZU6tx1U2-MHtCSJn7ZlSHG2EV-5O9JSYSRW",
+        "43FEkzd3ePhBWLUbsjWd3mi3p-oYLt4wt4DgSm4h0578YSfyd3nyJmymneBGJNNq1aaDkvvWHYCJu_rl",
+        "8ER_BqfulEUIEJdVn_Rtvw",
+    );
+
+    const ED25519_X: &str = "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo";
+
+    fn ed25519_jwk(kid: &str) -> serde_json::Value {
+        serde_json::json!({"kty": "OKP", "crv": "Ed25519", "x": ED25519_X, "kid": kid})
+    }
+
+    #[test]
+    fn test_parse_jwks() {
+        let document = serde_json::json!({
+            "keys": [
+                ed25519_jwk("1"),
+                ed25519_jwk("acme:2"),
+                {"kty": "RSA", "alg": "RS256", "use": "sig", "n": "AQAB", "e": "AQAB", "kid": "3", "exp": 2000000000},
+                // Skipped: wrong tenant, not numeric, wrong use, EC key,
+                // private key.
+                ed25519_jwk("bigco:4"),
+                ed25519_jwk("four"),
+                {"kty": "OKP", "crv": "Ed25519", "x": ED25519_X, "kid": "5", "use": "enc"},
+                {"kty": "EC", "crv": "P-256", "x": "AA", "y": "AA", "kid": "6"},
+                {"kty": "OKP", "crv": "Ed25519", "x": ED25519_X, "d": ED25519_X, "kid": "7"},
+            ]
+        });
+        let keys = parse_jwks("acme", document.to_string().as_bytes()).unwrap();
+
+        let mut versions: Vec<u64> = keys.keys().map(|v| v.0).collect();
+        versions.sort_unstable();
+        assert_eq!(versions, [1, 2, 3]);
+
+        let ed25519 = &keys[&SecretVersion(1)];
+        assert_eq!(ed25519.secret.algorithm, SecretAlgorithm::Edwards25519);
+        assert_eq!(
+            hex::encode(ed25519.secret.data.expose_secret()),
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a"
+        );
+        assert_eq!(ed25519.expires, None);
+
+        let rsa = &keys[&SecretVersion(3)];
+        assert_eq!(rsa.secret.algorithm, SecretAlgorithm::RsaPkcs1Sha256);
+        assert_eq!(
+            hex::encode(rsa.secret.data.expose_secret()),
+            "300a02030100010203010001"
+        );
+        assert_eq!(
+            rsa.expires,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(2_000_000_000))
+        );
+    }
+
+    #[test]
+    fn test_parse_jwks_errors() {
+        assert!(parse_jwks("acme", b"{]").is_err());
+        assert!(parse_jwks("acme", br#"{"nope": []}"#).is_err());
+        let duplicate = serde_json::json!({"keys": [ed25519_jwk("1"), ed25519_jwk("acme:1")]});
+        assert!(parse_jwks("acme", duplicate.to_string().as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_der_unsigned_integer() {
+        assert_eq!(der_unsigned_integer(&[]), [0x02, 0x01, 0x00]);
+        assert_eq!(der_unsigned_integer(&[0, 0, 5]), [0x02, 0x01, 0x05]);
+        assert_eq!(der_unsigned_integer(&[0x80]), [0x02, 0x02, 0x00, 0x80]);
+
+        let long = der_unsigned_integer(&[0xff; 256]);
+        assert_eq!(long[..5], [0x02, 0x82, 0x01, 0x01, 0x00]);
+        assert_eq!(long.len(), 5 + 256);
+    }
+
+    #[tokio::test]
+    async fn test_jwks_secret_manager() {
+        let file = NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            serde_json::json!({
+                "keys": [
+                    ed25519_jwk("1"),
+                    {"kty": "OKP", "crv": "Ed25519", "x": ED25519_X, "kid": "2", "exp": 1000},
+                ]
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        let fallback_secret = Secret {
+            data: b"fallback".to_vec().into(),
+            algorithm: SecretAlgorithm::HmacSha256,
+        };
+        let fallback: HashMap<SecretName, HashMap<SecretVersion, Secret>> = HashMap::from([
+            (
+                tenant_secret_name("bigco"),
+                HashMap::from([(SecretVersion(1), fallback_secret.clone())]),
+            ),
+            (
+                tenant_secret_name("acme"),
+                HashMap::from([(SecretVersion(9), fallback_secret.clone())]),
+            ),
+        ]);
+
+        let jwks = Jwks::new(
+            HashMap::from([(
+                String::from("acme"),
+                Url::from_file_path(file.path()).unwrap(),
+            )]),
+            Duration::from_secs(60),
+            Box::new(fallback),
+        )
+        .await
+        .unwrap();
+
+        let acme = tenant_secret_name("acme");
+        let secret = jwks
+            .get_secret_version(&acme, SecretVersion(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(secret.algorithm, SecretAlgorithm::Edwards25519);
+        // Version 2 has expired, and tenants with a JWKS don't use the
+        // fallback.
+        for version in [2, 9] {
+            assert_eq!(
+                jwks.get_secret_version(&acme, SecretVersion(version))
+                    .await
+                    .unwrap(),
+                None
+            );
+        }
+        assert_eq!(
+            jwks.get_secrets(&acme)
+                .await
+                .unwrap()
+                .into_keys()
+                .collect::<Vec<_>>(),
+            [SecretVersion(1)]
+        );
+
+        assert_eq!(
+            jwks.get_secret_version(&tenant_secret_name("bigco"), SecretVersion(1))
+                .await
+                .unwrap(),
+            Some(fallback_secret)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_jwks_keeps_keys_when_refresh_fails() {
+        let file = NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            serde_json::json!({"keys": [ed25519_jwk("1")]}).to_string(),
+        )
+        .unwrap();
+        let fetcher = Fetcher {
+            http: reqwest::Client::new(),
+            sources: Arc::new(HashMap::from([(
+                String::from("acme"),
+                Url::from_file_path(file.path()).unwrap(),
+            )])),
+        };
+        let cache = Cache::default();
+        fetcher.refresh(&cache).await;
+        assert_eq!(cache.lock().unwrap()["acme"].len(), 1);
+
+        std::fs::write(file.path(), b"{]").unwrap();
+        fetcher.refresh(&cache).await;
+        assert_eq!(cache.lock().unwrap()["acme"].len(), 1);
+
+        std::fs::write(file.path(), br#"{"keys": []}"#).unwrap();
+        fetcher.refresh(&cache).await;
+        assert!(cache.lock().unwrap()["acme"].is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_rejects_large_documents() {
+        let file = NamedTempFile::new().unwrap();
+        let fetcher = Fetcher {
+            http: reqwest::Client::new(),
+            sources: Arc::new(HashMap::new()),
+        };
+        let url = Url::from_file_path(file.path()).unwrap();
+
+        std::fs::write(file.path(), vec![b' '; MAX_DOCUMENT_SIZE]).unwrap();
+        assert_eq!(fetcher.fetch(&url).await.unwrap().len(), MAX_DOCUMENT_SIZE);
+        std::fs::write(file.path(), vec![b' '; MAX_DOCUMENT_SIZE + 1]).unwrap();
+        assert!(fetcher.fetch(&url).await.is_err());
+    }
+
+    /// Checks that the keys loaded from a JWKS document work with the token
+    /// validator, for tokens signed with the matching private keys.
+    #[tokio::test]
+    async fn test_validate_tokens_with_jwks_keys() {
+        let seed = [3; 32];
+        let ed25519_x = ed25519_dalek::SigningKey::from_bytes(&seed)
+            .verifying_key()
+            .to_bytes();
+        let mut ed25519_private = ED25519_PKCS8_PREFIX.to_vec();
+        ed25519_private.extend(seed);
+
+        let file = NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            serde_json::json!({
+                "keys": [
+                    {
+                        "kty": "OKP",
+                        "crv": "Ed25519",
+                        "x": URL_SAFE_NO_PAD.encode(ed25519_x),
+                        "kid": "1",
+                    },
+                    {"kty": "RSA", "alg": "RS256", "n": RSA_N, "e": "AQAB", "kid": "2"},
+                ]
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let jwks = Jwks::new(
+            HashMap::from([(
+                String::from("acme"),
+                Url::from_file_path(file.path()).unwrap(),
+            )]),
+            Duration::from_secs(60),
+            Box::<HashMap<SecretName, HashMap<SecretVersion, Secret>>>::default(),
+        )
+        .await
+        .unwrap();
+
+        let realm = RealmId([1; 16]);
+        let validator = Validator::new(realm, Require::ScopeOrMissing(Scope::User));
+        for (version, private_key, algorithm) in [
+            (1, ed25519_private, AuthKeyAlgorithm::EdDSA),
+            (2, RSA_PRIVATE_KEY.to_vec(), AuthKeyAlgorithm::RS256),
+        ] {
+            let token = create_token(
+                &Claims {
+                    issuer: String::from("acme"),
+                    subject: String::from("alice"),
+                    audience: realm,
+                    scope: Some(Scope::User),
+                },
+                &AuthKey {
+                    data: private_key.into(),
+                    algorithm,
+                },
+                AuthKeyVersion(version),
+            );
+
+            let (tenant, key_version) = validator.parse_key_id(&token).unwrap();
+            assert_eq!(tenant, "acme");
+            assert_eq!(key_version.0, version);
+            let key: AuthKey = jwks
+                .get_secret_version(&tenant_secret_name(&tenant), key_version.into())
+                .await
+                .unwrap()
+                .unwrap()
+                .try_into()
+                .unwrap();
+            let claims = validator.validate(&token, &key).unwrap();
+            assert_eq!(claims.issuer, "acme");
+            assert_eq!(claims.subject, "alice");
+
+            // The other tenant key doesn't validate the token.
+            let other: AuthKey = jwks
+                .get_secret_version(&tenant_secret_name("acme"), SecretVersion(3 - version))
+                .await
+                .unwrap()
+                .unwrap()
+                .try_into()
+                .unwrap();
+            assert!(validator.validate(&token, &other).is_err());
+        }
+    }
+}
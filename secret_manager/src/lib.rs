@@ -6,8 +6,10 @@ use std::fmt::Debug;
 use std::time::Duration;
 
 mod google_secret_manager;
+mod jwks;
 mod periodic;
 mod secrets_file;
+mod tenant_admin;
 
 use google::GrpcConnectionOptions;
 use juicebox_realm_api::types::SecretBytesVec;
@@ -16,8 +18,12 @@ use observability::metrics;
 
 pub use anyhow::{anyhow, Error};
 pub use google_secret_manager::Client as GoogleSecretManagerClient;
+pub use jwks::Jwks;
 pub use periodic::{BulkLoad, Periodic};
 pub use secrets_file::SecretsFile;
+pub use tenant_admin::{
+    get_tenant_record_signing_key, validate_tenant_admin_token, TenantAdminTokenError,
+};
 
 /// A value that should remain confidential.
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -188,7 +194,8 @@ impl SecretManager for HashMap<SecretName, HashMap<SecretVersion, Secret>> {
 }
 
 /// Constructs a new Google Cloud Secret Manager client that's limited to
-/// accessing tenant auth keys.
+/// accessing tenant auth keys, tenant admin keys, and the realm keys listed
+/// below.
 pub async fn new_google_secret_manager(
     project: &str,
     auth_manager: gcp_auth::AuthenticationManager,
@@ -200,12 +207,17 @@ pub async fn new_google_secret_manager(
         project,
         auth_manager,
         Some(format!(
-            "({}) OR ({})",
+            "({}) OR ({}) OR ({}) OR ({})",
             format_args!(
                 "name:{} AND labels.kind=record_id_randomization_key",
                 record_id_randomization_key_name().0
             ),
+            format_args!(
+                "name:{} AND labels.kind=tenant_record_signing_key",
+                tenant_record_signing_key_name().0
+            ),
             "name:tenant- AND labels.kind=tenant_auth_key",
+            "name:admin-tenant- AND labels.kind=tenant_admin_key",
         )),
         options,
         metrics,
@@ -225,10 +237,28 @@ pub fn record_id_randomization_key_name() -> SecretName {
     SecretName(String::from("record-id-randomization"))
 }
 
+/// The name of a per-realm Ed25519 key that load balancers and cluster
+/// managers sign tenant record grants with. The secret must use the
+/// `Edwards25519` algorithm and hold the private key in PKCS #8 DER, like
+/// other Ed25519 secrets.
+///
+/// Agents and HSMs are given the public key with `--tenant-record-key`. `cluster
+/// tenant record-key` prints it.
+pub fn tenant_record_signing_key_name() -> SecretName {
+    SecretName(String::from("tenant-record-signing"))
+}
+
 pub fn tenant_secret_name(tenant: &str) -> SecretName {
     SecretName(format!("tenant-{tenant}"))
 }
 
+/// The name of the key a tenant uses to sign tokens for administrative
+/// operations, like offboarding. This is kept separate from the tenant's auth
+/// key so that user tokens can't be used for these operations.
+pub fn tenant_admin_secret_name(tenant: &str) -> SecretName {
+    SecretName(format!("admin-tenant-{tenant}"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
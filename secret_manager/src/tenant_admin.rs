@@ -0,0 +1,228 @@
+//! Support for administrative operations that tenants perform on their users'
+//! records, such as deleting them.
+//!
+//! Tenants authorize these with tokens signed by their admin key. The auth
+//! tokens don't have an admin scope, so it's the key that tells these apart
+//! from user tokens: admin tokens take the same scopes as user tokens, but
+//! they're only accepted if signed by the tenant's admin key. The realm
+//! services that accept these tokens then sign a grant for each record with
+//! the realm's tenant record signing key, which agents and HSMs check.
+
+use thiserror::Error;
+use tracing::warn;
+
+use super::{
+    tenant_admin_secret_name, tenant_record_signing_key_name, Error, SecretAlgorithm, SecretManager,
+};
+use juicebox_realm_api::types::{AuthToken, RealmId};
+use juicebox_realm_auth::validation::{Require, Validator};
+use juicebox_realm_auth::{AuthKey, Claims, Scope};
+
+/// The PKCS #8 v1 DER encoding of an Ed25519 private key is this prefix
+/// followed by the 32-byte seed. This is how the other Edwards25519 secrets
+/// are stored.
+pub(crate) const ED25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+#[derive(Debug, Error)]
+pub enum TenantAdminTokenError {
+    #[error("invalid tenant admin token")]
+    Invalid,
+    #[error("failed to get tenant admin key: {0}")]
+    SecretManager(Error),
+}
+
+/// Checks that the token was signed with the tenant's admin key for this
+/// realm. The tenant is the issuer of the returned
+/// claims, and the subject identifies who is acting on its behalf.
+pub async fn validate_tenant_admin_token(
+    secret_manager: &(impl SecretManager + ?Sized),
+    realm: RealmId,
+    token: &AuthToken,
+) -> Result<Claims, TenantAdminTokenError> {
+    let validator = Validator::new(realm, Require::ScopeOrMissing(Scope::User));
+    let Ok((tenant, version)) = validator.parse_key_id(token) else {
+        return Err(TenantAdminTokenError::Invalid);
+    };
+    let secret = secret_manager
+        .get_secret_version(&tenant_admin_secret_name(&tenant), version.into())
+        .await
+        .map_err(TenantAdminTokenError::SecretManager)?
+        .ok_or(TenantAdminTokenError::Invalid)?;
+    let key: AuthKey = secret.try_into().map_err(|err| {
+        warn!(?tenant, ?version, ?err, "invalid tenant admin key");
+        TenantAdminTokenError::Invalid
+    })?;
+    validator
+        .validate(token, &key)
+        .map_err(|_| TenantAdminTokenError::Invalid)
+}
+
+/// Returns the newest version of the tenant record signing key, as a 32-byte
+/// Ed25519 seed, or `None` if the realm doesn't have one.
+pub async fn get_tenant_record_signing_key(
+    secret_manager: &(impl SecretManager + ?Sized),
+) -> Result<Option<[u8; 32]>, Error> {
+    let name = tenant_record_signing_key_name();
+    let Some((_version, secret)) = secret_manager.get_latest_secret_version(&name).await? else {
+        return Ok(None);
+    };
+    if secret.algorithm != SecretAlgorithm::Edwards25519 {
+        return Err(Error::msg(format!(
+            "secret {} should use the Edwards25519 algorithm",
+            name.0
+        )));
+    }
+    secret
+        .data
+        .expose_secret()
+        .strip_prefix(&ED25519_PKCS8_PREFIX)
+        .and_then(|seed| <[u8; 32]>::try_from(seed).ok())
+        .map(Some)
+        .ok_or_else(|| {
+            Error::msg(format!(
+                "secret {} should be an Ed25519 private key in PKCS #8 DER",
+                name.0
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use juicebox_realm_auth::creation::create_token;
+    use juicebox_realm_auth::AuthKeyVersion;
+    use std::collections::HashMap;
+
+    use super::super::{tenant_secret_name, Secret, SecretName, SecretVersion};
+    use super::*;
+
+    const REALM: RealmId = RealmId([5; 16]);
+
+    fn secrets() -> HashMap<SecretName, HashMap<SecretVersion, Secret>> {
+        let hmac = |data: &[u8]| Secret {
+            data: data.to_vec().into(),
+            algorithm: SecretAlgorithm::HmacSha256,
+        };
+        HashMap::from([
+            (
+                tenant_admin_secret_name("acme"),
+                HashMap::from([(SecretVersion(1), hmac(b"acme admin key"))]),
+            ),
+            (
+                tenant_secret_name("acme"),
+                HashMap::from([(SecretVersion(1), hmac(b"acme user key"))]),
+            ),
+        ])
+    }
+
+    fn token(tenant: &str, realm: RealmId, key: &[u8], version: u64) -> AuthToken {
+        token_with_scope(tenant, realm, key, version, Some(Scope::User))
+    }
+
+    fn token_with_scope(
+        tenant: &str,
+        realm: RealmId,
+        key: &[u8],
+        version: u64,
+        scope: Option<Scope>,
+    ) -> AuthToken {
+        create_token(
+            &Claims {
+                issuer: tenant.to_owned(),
+                subject: String::from("ops@acme"),
+                audience: realm,
+                scope,
+            },
+            &Secret {
+                data: key.to_vec().into(),
+                algorithm: SecretAlgorithm::HmacSha256,
+            }
+            .try_into()
+            .unwrap(),
+            AuthKeyVersion(version),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_validate_tenant_admin_token() {
+        let secrets = secrets();
+
+        let claims = validate_tenant_admin_token(
+            &secrets,
+            REALM,
+            &token("acme", REALM, b"acme admin key", 1),
+        )
+        .await
+        .unwrap();
+        assert_eq!(claims.issuer, "acme");
+        assert_eq!(claims.subject, "ops@acme");
+        assert!(validate_tenant_admin_token(
+            &secrets,
+            REALM,
+            &token_with_scope("acme", REALM, b"acme admin key", 1, None),
+        )
+        .await
+        .is_ok());
+
+        for bad in [
+            // Signed with the tenant's user auth key.
+            token("acme", REALM, b"acme user key", 1),
+            // For a different realm.
+            token("acme", RealmId([6; 16]), b"acme admin key", 1),
+            // Claims to be from a different tenant.
+            token("bigcorp", REALM, b"acme admin key", 1),
+            // Unknown key version.
+            token("acme", REALM, b"acme admin key", 2),
+            AuthToken::from(String::from("not a token")),
+        ] {
+            assert!(matches!(
+                validate_tenant_admin_token(&secrets, REALM, &bad).await,
+                Err(TenantAdminTokenError::Invalid)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_tenant_record_signing_key() {
+        let mut secrets = secrets();
+        assert_eq!(get_tenant_record_signing_key(&secrets).await.unwrap(), None);
+
+        let mut versions = HashMap::new();
+        let mut der = ED25519_PKCS8_PREFIX.to_vec();
+        der.extend([1; 32]);
+        versions.insert(
+            SecretVersion(1),
+            Secret {
+                data: der.into(),
+                algorithm: SecretAlgorithm::Edwards25519,
+            },
+        );
+        let mut der = ED25519_PKCS8_PREFIX.to_vec();
+        der.extend([2; 32]);
+        versions.insert(
+            SecretVersion(2),
+            Secret {
+                data: der.into(),
+                algorithm: SecretAlgorithm::Edwards25519,
+            },
+        );
+        secrets.insert(tenant_record_signing_key_name(), versions);
+        assert_eq!(
+            get_tenant_record_signing_key(&secrets).await.unwrap(),
+            Some([2; 32])
+        );
+
+        secrets.insert(
+            tenant_record_signing_key_name(),
+            HashMap::from([(
+                SecretVersion(3),
+                Secret {
+                    data: vec![3; 32].into(),
+                    algorithm: SecretAlgorithm::Edwards25519,
+                },
+            )]),
+        );
+        assert!(get_tenant_record_signing_key(&secrets).await.is_err());
+    }
+}
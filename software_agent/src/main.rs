@@ -1,7 +1,8 @@
-use clap::Args;
+use clap::{Args, Parser};
 use nix::libc::pid_t;
 use nix::sys::signal::{kill, Signal};
 use nix::unistd::Pid;
+use std::fs;
 use std::path::PathBuf;
 use std::process::Command;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -13,7 +14,11 @@ use agent_core::service::{AgentArgs, HsmTransportConstructor};
 use jburl::Url;
 use observability::metrics;
 use service_core::future_task::FutureTask;
-use software_hsm_client::HsmHttpClient;
+use software_hsm::host::new_hsm;
+use software_hsm::keys::KeyMaterial;
+use software_hsm::nvram::NVRamFaults;
+use software_hsm::random_tmp_dir;
+use software_hsm_client::{EmbeddedHsm, HsmHttpClient};
 
 /// A host agent that embeds an insecure software HSM.
 #[derive(Debug, Args)]
@@ -25,12 +30,27 @@ struct SoftwareAgentArgs {
     /// Directory to store the persistent state file in [default: a random temp dir]
     #[arg(short, long)]
     state_dir: Option<PathBuf>,
+
+    /// Run the HSM on a thread in this process, instead of starting a
+    /// separate software_hsm process and talking to it over HTTP.
+    #[arg(long)]
+    embedded_hsm: bool,
 }
 
 #[tokio::main]
 async fn main() {
-    let mut tf = TransportConstructor;
-    let h = agent_core::service::main("software-agent", build_info::get!(), &mut tf).await;
+    // The transport's type is fixed at compile time, so this peeks at the
+    // args to pick one. If they don't parse, it doesn't matter which:
+    // `agent_core::service::main` parses them again and reports the error.
+    let embedded =
+        AgentArgs::<SoftwareAgentArgs>::try_parse().is_ok_and(|args| args.service.embedded_hsm);
+    let h = if embedded {
+        let mut tf = EmbeddedTransportConstructor;
+        agent_core::service::main("software-agent", build_info::get!(), &mut tf).await
+    } else {
+        let mut tf = TransportConstructor;
+        agent_core::service::main("software-agent", build_info::get!(), &mut tf).await
+    };
     h.await.unwrap();
 }
 
@@ -61,6 +81,9 @@ impl HsmTransportConstructor<SoftwareAgentArgs, HsmHttpClient> for TransportCons
         if let Some(n) = &args.name {
             cmd.arg("--name").arg(n);
         }
+        for key in &args.tenant_record_keys {
+            cmd.arg("--tenant-record-key").arg(format!("{key:?}"));
+        }
         let mut l = args.listen;
         l.set_port(l.port() + 10000);
         cmd.arg("--listen").arg(l.to_string());
@@ -95,6 +118,49 @@ impl HsmTransportConstructor<SoftwareAgentArgs, HsmHttpClient> for TransportCons
     }
 }
 
+struct EmbeddedTransportConstructor;
+
+impl HsmTransportConstructor<SoftwareAgentArgs, EmbeddedHsm> for EmbeddedTransportConstructor {
+    async fn construct(
+        &mut self,
+        args: &AgentArgs<SoftwareAgentArgs>,
+        _metrics: &metrics::Client,
+    ) -> (EmbeddedHsm, Option<FutureTask<()>>) {
+        let dir = match &args.service.state_dir {
+            None => random_tmp_dir(),
+            Some(path) => path.clone(),
+        };
+        fs::create_dir_all(&dir).unwrap_or_else(|e| {
+            panic!("failed to create directory {dir:?} for persistent state: {e:?}")
+        });
+        let name = match &args.name {
+            Some(n) => n.clone(),
+            None => format!("hsm{}", args.listen),
+        };
+        let keys = KeyMaterial::insecure_derive(&args.service.key)
+            .unwrap_or_else(|e| panic!("failed to derive realm keys: {e:?}"))
+            .realm_keys();
+
+        let state_dir = dir.clone();
+        let tenant_record_keys = args.tenant_record_keys.clone();
+        let hsm = EmbeddedHsm::start(name.clone(), move || {
+            new_hsm(
+                state_dir,
+                name,
+                keys,
+                NVRamFaults::default(),
+                None,
+                tenant_record_keys,
+            )
+        })
+        .expect("embedded HSM failed to initialize from prior state");
+        info!(dir = %dir.display(), "embedded HSM started");
+        // The HSM thread stops with the process, and every NVRAM write is
+        // already durable, so there's nothing to shut down.
+        (hsm, None)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -8,9 +8,7 @@ use hyper_util::rt::TokioIo;
 use observability::tracing::TracingMiddleware;
 use rand::rngs::OsRng;
 use rand::RngCore;
-use std::fs;
 use std::future::Future;
-use std::io::ErrorKind;
 use std::net::SocketAddr;
 use std::ops::Sub;
 use std::path::PathBuf;
@@ -22,11 +20,15 @@ use tokio::task::JoinHandle;
 use tracing::warn;
 
 use hsm_api::rpc::Nanos;
-use hsm_core::hal::{Clock, IOError, NVRam, MAX_NVRAM_SIZE};
+use hsm_api::TenantRecordKey;
+use hsm_core::hal::{Clock, IOError, NVRam};
 use hsm_core::hsm::{Hsm, HsmError, HsmOptions, MetricsReporting, PersistenceError, RealmKeys};
 use jburl::Url;
 
-struct HalInstant(Instant);
+use crate::nvram::{NVRamFaults, NVRamFile};
+use crate::sealing::Sealer;
+
+pub struct HalInstant(Instant);
 impl Sub for HalInstant {
     type Output = Nanos;
 
@@ -41,10 +43,18 @@ impl Sub for HalInstant {
     }
 }
 
+/// The [`hsm_core::hal::Platform`] for running the HSM as a regular
+/// process, using the system clock and RNG and a file for NVRAM.
 #[derive(Clone)]
-struct StdPlatform {
-    // The location of the backing file for 'NVRam'
-    state_file: PathBuf,
+pub struct StdPlatform {
+    // The backing file for 'NVRam'
+    nvram: NVRamFile,
+}
+
+impl StdPlatform {
+    pub fn new(nvram: NVRamFile) -> Self {
+        Self { nvram }
+    }
 }
 
 impl Clock for StdPlatform {
@@ -80,36 +90,42 @@ impl rand::CryptoRng for StdPlatform {}
 
 impl NVRam for StdPlatform {
     fn read(&self) -> Result<Vec<u8>, IOError> {
-        match fs::read(&self.state_file) {
-            Ok(data) => Ok(data),
-            Err(e) => {
-                if e.kind() == ErrorKind::NotFound {
-                    return Ok(Vec::new());
-                }
-                Err(IOError(format!(
-                    "IO Error reading state from {}: {e}",
-                    self.state_file.display()
-                )))
-            }
-        }
+        self.nvram.read()
     }
 
     fn write(&self, data: Vec<u8>) -> Result<(), IOError> {
-        if data.len() > MAX_NVRAM_SIZE {
-            return Err(IOError(format!(
-                "data with {} bytes is larger than allowed maximum of {MAX_NVRAM_SIZE}",
-                data.len()
-            )));
-        }
-        fs::write(&self.state_file, data).map_err(|e| {
-            IOError(format!(
-                "IO Error writing to state file {}: {e}",
-                self.state_file.display()
-            ))
-        })
+        self.nvram.write(data)
     }
 }
 
+/// Creates an HSM that keeps its NVRAM in a file named after it in
+/// `state_dir`, resuming from any state already there. The HSM accepts
+/// tenant record grants signed by any of `tenant_record_keys`.
+pub fn new_hsm(
+    state_dir: PathBuf,
+    name: String,
+    realm_keys: RealmKeys,
+    nvram_faults: NVRamFaults,
+    sealer: Option<Arc<Sealer>>,
+    tenant_record_keys: Vec<TenantRecordKey>,
+) -> Result<Hsm<StdPlatform>, PersistenceError> {
+    hsm_core::hash::set_global_rng(Box::new(OsRng));
+    let state_file = state_dir.join(&name);
+    Hsm::new(
+        HsmOptions {
+            name,
+            tree_overlay_size: 1024,
+            max_sessions: 8192,
+            max_sessions_per_partition: 2048,
+            metrics: MetricsReporting::Enabled,
+            tenant_record_keys,
+            software_version: build_info::get!().git_hash.unwrap_or("unknown").to_owned(),
+        },
+        StdPlatform::new(NVRamFile::new(state_file, nvram_faults, sealer)),
+        realm_keys,
+    )
+}
+
 #[derive(Clone)]
 pub struct HttpHsm(Arc<Mutex<Hsm<StdPlatform>>>);
 
@@ -118,18 +134,17 @@ impl HttpHsm {
         state_dir: PathBuf,
         name: String,
         realm_keys: RealmKeys,
+        nvram_faults: NVRamFaults,
+        sealer: Option<Arc<Sealer>>,
+        tenant_record_keys: Vec<TenantRecordKey>,
     ) -> Result<Self, PersistenceError> {
-        hsm_core::hash::set_global_rng(Box::new(OsRng));
-        let state_file = state_dir.join(&name);
-        Ok(HttpHsm(Arc::new(Mutex::new(Hsm::new(
-            HsmOptions {
-                name,
-                tree_overlay_size: 1024,
-                max_sessions: 8192,
-                metrics: MetricsReporting::Enabled,
-            },
-            StdPlatform { state_file },
+        Ok(HttpHsm(Arc::new(Mutex::new(new_hsm(
+            state_dir,
+            name,
             realm_keys,
+            nvram_faults,
+            sealer,
+            tenant_record_keys,
         )?))))
     }
 
@@ -0,0 +1,201 @@
+use anyhow::{anyhow, Context};
+use blake2::Blake2s256;
+use hkdf::hmac::SimpleHmac;
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::sealing::Sealer;
+use hsm_core::hsm::mac::MacKey;
+use hsm_core::hsm::{RealmKeys, RecordEncryptionKey};
+
+const KEYS_PURPOSE: &str = "realm keys";
+const BUNDLE_PURPOSE: &str = "realm key bundle";
+const VERSION: u8 = 1;
+const ENCODED_LEN: usize = 1 + 3 * 32;
+
+/// The secrets that a software HSM's realm keys are built from.
+///
+/// Every HSM in a realm needs the same realm keys. They're generated
+/// randomly by the first HSM, and exported in a sealed bundle to be imported
+/// by the others.
+#[derive(Clone, Eq, PartialEq)]
+pub struct KeyMaterial {
+    communication: [u8; 32],
+    record: [u8; 32],
+    mac: [u8; 32],
+}
+
+impl fmt::Debug for KeyMaterial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyMaterial(redacted)")
+    }
+}
+
+impl KeyMaterial {
+    pub fn random() -> Self {
+        let mut keys = Self {
+            communication: [0; 32],
+            record: [0; 32],
+            mac: [0; 32],
+        };
+        OsRng.fill_bytes(&mut keys.communication);
+        OsRng.fill_bytes(&mut keys.record);
+        OsRng.fill_bytes(&mut keys.mac);
+        keys
+    }
+
+    /// Derives the keys from a string, so that HSMs started with the same
+    /// string share a realm. This is insecure and only suitable for testing.
+    pub fn insecure_derive(s: &str) -> anyhow::Result<Self> {
+        if s.is_empty() {
+            return Err(anyhow!("the key can't be empty"));
+        }
+        let salts = [
+            // from /dev/urandom
+            hex::decode("12DC3D4454D4FFFDBCD5F3484DC23D6BD4CB1323DB3D5BFB53DE88589FD48D34")?,
+            hex::decode("591ABF589B93E8F75EEA54F2BE94360C5BCA05903AA85C7DE6847F4E48A50EED")?,
+            hex::decode("B9782DBCA82235A2871226DD05807C955592FD5FC29280A536DFD2E02D2A9BFE")?,
+        ];
+        Ok(Self {
+            mac: derive_from(s.as_bytes(), &salts[0]),
+            record: derive_from(s.as_bytes(), &salts[1]),
+            communication: derive_from(s.as_bytes(), &salts[2]),
+        })
+    }
+
+    pub fn realm_keys(&self) -> RealmKeys {
+        let noise_priv = x25519_dalek::StaticSecret::from(self.communication);
+        let noise_pub = x25519_dalek::PublicKey::from(&noise_priv);
+        RealmKeys {
+            communication: (noise_priv, noise_pub),
+            record: RecordEncryptionKey::from(self.record),
+            mac: MacKey::from(self.mac),
+        }
+    }
+
+    /// Reads the HSM's sealed keys file, returning `None` if it doesn't exist.
+    pub fn load(path: &Path, sealer: &Sealer) -> anyhow::Result<Option<Self>> {
+        match fs::read(path) {
+            Ok(sealed) => Self::unseal(path, KEYS_PURPOSE, &sealed, sealer).map(Some),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("failed to read keys file {path:?}")),
+        }
+    }
+
+    /// Writes the HSM's sealed keys file.
+    pub fn save(&self, path: &Path, sealer: &Sealer) -> anyhow::Result<()> {
+        let sealed = sealer.seal(KEYS_PURPOSE, &self.encode())?;
+        write_atomically(path, &sealed)
+            .with_context(|| format!("failed to write keys file {path:?}"))
+    }
+
+    /// Reads a bundle written by [`KeyMaterial::export`].
+    pub fn import(path: &Path, sealer: &Sealer) -> anyhow::Result<Self> {
+        let sealed =
+            fs::read(path).with_context(|| format!("failed to read key bundle {path:?}"))?;
+        Self::unseal(path, BUNDLE_PURPOSE, &sealed, sealer)
+    }
+
+    /// Writes the keys to a bundle, which other HSMs with the same sealing
+    /// secret can import to join the realm.
+    pub fn export(&self, path: &Path, sealer: &Sealer) -> anyhow::Result<()> {
+        let sealed = sealer.seal(BUNDLE_PURPOSE, &self.encode())?;
+        write_atomically(path, &sealed)
+            .with_context(|| format!("failed to write key bundle {path:?}"))
+    }
+
+    fn unseal(path: &Path, purpose: &str, sealed: &[u8], sealer: &Sealer) -> anyhow::Result<Self> {
+        let encoded = sealer
+            .unseal(purpose, sealed)
+            .with_context(|| format!("failed to unseal {path:?}"))?;
+        Self::decode(&encoded).ok_or_else(|| anyhow!("{path:?} doesn't hold valid realm keys"))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(ENCODED_LEN);
+        encoded.push(VERSION);
+        encoded.extend_from_slice(&self.communication);
+        encoded.extend_from_slice(&self.record);
+        encoded.extend_from_slice(&self.mac);
+        encoded
+    }
+
+    fn decode(encoded: &[u8]) -> Option<Self> {
+        if encoded.len() != ENCODED_LEN || encoded[0] != VERSION {
+            return None;
+        }
+        Some(Self {
+            communication: encoded[1..33].try_into().unwrap(),
+            record: encoded[33..65].try_into().unwrap(),
+            mac: encoded[65..97].try_into().unwrap(),
+        })
+    }
+}
+
+fn derive_from<const N: usize>(b: &[u8], salt: &[u8]) -> [u8; N] {
+    let kdf = Hkdf::<Blake2s256, SimpleHmac<Blake2s256>>::new(Some(salt), b);
+    let mut out = [0u8; N];
+    kdf.expand(&[], &mut out).unwrap();
+    out
+}
+
+fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut tmp = path.to_owned().into_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sealing::SealingSecret;
+
+    #[test]
+    fn test_save_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hsm.keys");
+        let sealer = Sealer::new(SealingSecret::KeyFile([3; 32]));
+        assert_eq!(None, KeyMaterial::load(&path, &sealer).unwrap());
+
+        let keys = KeyMaterial::random();
+        keys.save(&path, &sealer).unwrap();
+        assert_eq!(
+            Some(keys.clone()),
+            KeyMaterial::load(&path, &sealer).unwrap()
+        );
+
+        let other = Sealer::new(SealingSecret::KeyFile([4; 32]));
+        assert!(KeyMaterial::load(&path, &other).is_err());
+        // The keys file can't be passed off as a bundle.
+        assert!(KeyMaterial::import(&path, &sealer).is_err());
+    }
+
+    #[test]
+    fn test_export_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bundle");
+        let keys = KeyMaterial::insecure_derive("test").unwrap();
+        keys.export(
+            &path,
+            &Sealer::new(SealingSecret::Passphrase(String::from("pass"))),
+        )
+        .unwrap();
+
+        let importer = Sealer::new(SealingSecret::Passphrase(String::from("pass")));
+        let imported = KeyMaterial::import(&path, &importer).unwrap();
+        assert_eq!(keys, imported);
+        assert_eq!(
+            keys.realm_keys().communication.1,
+            imported.realm_keys().communication.1
+        );
+    }
+}
@@ -0,0 +1,28 @@
+//! An insecure software implementation of the HSM, used for testing the HSM
+//! realm code without an HSM.
+//!
+//! The `software_hsm` binary serves it over HTTP. The pieces are also
+//! exported here so that the software agent can run the HSM in-process.
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+pub mod host;
+pub mod keys;
+pub mod nvram;
+pub mod sealing;
+
+/// Returns a new, not yet created, directory under the system temp dir to
+/// keep the HSM's state in.
+pub fn random_tmp_dir() -> PathBuf {
+    let tmp = std::env::temp_dir();
+    let mut n = [0u8; 10];
+    OsRng.fill_bytes(&mut n);
+    let mut dn = String::from("agent_hsm_");
+    for b in n {
+        write!(dn, "{b:02x}").unwrap()
+    }
+    tmp.join(dn)
+}
@@ -1,35 +1,63 @@
 use anyhow::anyhow;
-use blake2::Blake2s256;
 use clap::{command, Parser};
-use hkdf::hmac::SimpleHmac;
-use hkdf::Hkdf;
-use rand::rngs::OsRng;
-use rand::RngCore;
-use std::fmt::Write;
 use std::fs;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 use tracing::info;
 
-use hsm_core::hsm::mac::MacKey;
-use hsm_core::hsm::{RealmKeys, RecordEncryptionKey};
+use hsm_api::TenantRecordKey;
 use observability::logging;
-use service_core::clap_parsers::parse_listen;
+use service_core::clap_parsers::{parse_duration, parse_listen};
 use service_core::panic;
 use service_core::term::install_termination_handler;
 
-use crate::host::HttpHsm;
-
-mod host;
+use software_hsm::host::HttpHsm;
+use software_hsm::keys::KeyMaterial;
+use software_hsm::nvram::{NVRamFaults, NVRamFile};
+use software_hsm::random_tmp_dir;
+use software_hsm::sealing::{Sealer, SealingSecret};
 
 /// Software HSM, used for testing the HSM realm code without an HSM.
 #[derive(Debug, Parser)]
 #[command(version = build_info::clap!())]
 struct Args {
-    /// Derive realm keys from this input (insecure).
-    #[arg(short, long)]
-    key: String,
+    /// Derive realm keys from this input (insecure). Without this, the realm
+    /// keys are generated randomly or imported, and kept in a sealed keys
+    /// file in the state directory.
+    #[arg(
+        short,
+        long,
+        required_unless_present_any = ["sealing_key_file", "sealing_passphrase_file"],
+    )]
+    key: Option<String>,
+
+    /// Seal the persistent state and realm keys with the 32-byte key in this
+    /// file, given in hex.
+    #[arg(long, value_name = "FILE", conflicts_with = "sealing_passphrase_file")]
+    sealing_key_file: Option<PathBuf>,
+
+    /// Seal the persistent state and realm keys with a key derived from the
+    /// passphrase on the first line of this file.
+    #[arg(long, value_name = "FILE")]
+    sealing_passphrase_file: Option<PathBuf>,
+
+    /// On first start, join a realm by importing its keys from a bundle
+    /// written by `--export-keys`. The bundle must be sealed with the same
+    /// secret as this HSM.
+    #[arg(long, value_name = "BUNDLE", conflicts_with = "key")]
+    import_keys: Option<PathBuf>,
+
+    /// Write the realm keys to a sealed bundle, then exit.
+    #[arg(long, value_name = "BUNDLE")]
+    export_keys: Option<PathBuf>,
+
+    /// Seal a persistent state file that was written before sealing was
+    /// turned on, then exit. The HSM won't start from unsealed state when
+    /// given a sealing secret, so this is needed once when turning it on.
+    #[arg(long)]
+    seal_existing_state: bool,
 
     /// Directory to store the persistent state file in [default: a random temp dir]
     #[arg(short, long)]
@@ -47,6 +75,36 @@ struct Args {
     /// Name of the hsm in logging [default: hsm{listen}].
     #[arg(short, long)]
     name: Option<String>,
+
+    /// An Ed25519 public key, in hex, that tenant record grants may be signed
+    /// with. May be repeated.
+    #[arg(long = "tenant-record-key", value_name = "KEY")]
+    tenant_record_keys: Vec<TenantRecordKey>,
+
+    /// For testing, the probability that a write to the persistent state
+    /// file is torn, as if the HSM crashed partway through.
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        value_name = "PROBABILITY",
+        value_parser = parse_probability,
+    )]
+    nvram_torn_write_rate: f64,
+
+    /// For testing, the probability that syncing the persistent state file
+    /// fails after a write.
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        value_name = "PROBABILITY",
+        value_parser = parse_probability,
+    )]
+    nvram_fsync_failure_rate: f64,
+
+    /// For testing, how long each write to the persistent state file takes,
+    /// like a real HSM's NVRAM (about 1ms on the Entrust SoloXC).
+    #[arg(long, value_parser = parse_duration)]
+    nvram_write_delay: Option<Duration>,
 }
 
 #[tokio::main]
@@ -75,51 +133,103 @@ async fn main() {
     }
 
     let name = args.name.unwrap_or_else(|| format!("hsm{}", args.listen));
-    let keys = insecure_derive_realm_keys(&args.key).unwrap();
-    let hsm = HttpHsm::new(dir.clone(), name, keys)
-        .expect("HttpHsm failed to initialize from prior state");
+    let sealer = match (&args.sealing_key_file, &args.sealing_passphrase_file) {
+        (Some(path), _) => Some(SealingSecret::from_key_file(path)),
+        (None, Some(path)) => Some(SealingSecret::from_passphrase_file(path)),
+        (None, None) => None,
+    }
+    .transpose()
+    .unwrap_or_else(|e| panic!("failed to read sealing secret: {e:?}"))
+    .map(|secret| Arc::new(Sealer::new(secret)));
+
+    let key_material = load_key_material(
+        args.key.as_deref(),
+        args.import_keys.as_deref(),
+        &dir.join(format!("{name}.keys")),
+        sealer.as_deref(),
+    )
+    .unwrap_or_else(|e| panic!("failed to load realm keys: {e:?}"));
+    if let Some(bundle) = &args.export_keys {
+        let Some(sealer) = &sealer else {
+            panic!("--export-keys needs --sealing-key-file or --sealing-passphrase-file");
+        };
+        key_material
+            .export(bundle, sealer)
+            .unwrap_or_else(|e| panic!("failed to export realm keys: {e:?}"));
+        info!(bundle = %bundle.display(), "exported realm keys");
+        return;
+    }
+
+    if args.seal_existing_state {
+        if sealer.is_none() {
+            panic!("--seal-existing-state needs --sealing-key-file or --sealing-passphrase-file");
+        }
+        let state_file = dir.join(&name);
+        let nvram = NVRamFile::new(state_file.clone(), NVRamFaults::default(), sealer);
+        match nvram.seal_existing() {
+            Ok(true) => info!(state_file = %state_file.display(), "sealed existing state"),
+            Ok(false) => info!(state_file = %state_file.display(), "no unsealed state to seal"),
+            Err(e) => panic!("failed to seal existing state: {e:?}"),
+        }
+        return;
+    }
+
+    let keys = key_material.realm_keys();
+    let faults = NVRamFaults {
+        torn_write_rate: args.nvram_torn_write_rate,
+        fsync_failure_rate: args.nvram_fsync_failure_rate,
+        write_delay: args.nvram_write_delay,
+    };
+    let hsm = HttpHsm::new(
+        dir.clone(),
+        name,
+        keys,
+        faults,
+        sealer,
+        args.tenant_record_keys,
+    )
+    .expect("HttpHsm failed to initialize from prior state");
     let (hsm_url, hsm_handle) = hsm.listen(args.listen).await.unwrap();
     info!(url = %hsm_url, dir=%dir.display(), "HSM started");
     let _ = hsm_handle.await;
 }
 
-fn insecure_derive_realm_keys(s: &str) -> anyhow::Result<RealmKeys> {
-    if s.is_empty() {
-        return Err(anyhow!("the key can't be empty"));
+/// Returns the HSM's realm keys, creating its sealed keys file on first
+/// start.
+fn load_key_material(
+    key: Option<&str>,
+    import: Option<&Path>,
+    keys_file: &Path,
+    sealer: Option<&Sealer>,
+) -> anyhow::Result<KeyMaterial> {
+    let sealer = match (key, sealer) {
+        (Some(key), _) => return KeyMaterial::insecure_derive(key),
+        (None, Some(sealer)) => sealer,
+        (None, None) => return Err(anyhow!("either --key or a sealing secret is needed")),
+    };
+    let imported = import
+        .map(|bundle| KeyMaterial::import(bundle, sealer))
+        .transpose()?;
+    match (KeyMaterial::load(keys_file, sealer)?, imported) {
+        (Some(existing), Some(imported)) if existing != imported => Err(anyhow!(
+            "the HSM already has different realm keys in {keys_file:?}"
+        )),
+        (Some(existing), _) => Ok(existing),
+        (None, imported) => {
+            let keys = imported.unwrap_or_else(KeyMaterial::random);
+            keys.save(keys_file, sealer)?;
+            info!(keys_file = %keys_file.display(), "created realm keys file");
+            Ok(keys)
+        }
     }
-    let salts = [
-        // from /dev/urandom
-        hex::decode("12DC3D4454D4FFFDBCD5F3484DC23D6BD4CB1323DB3D5BFB53DE88589FD48D34")?,
-        hex::decode("591ABF589B93E8F75EEA54F2BE94360C5BCA05903AA85C7DE6847F4E48A50EED")?,
-        hex::decode("B9782DBCA82235A2871226DD05807C955592FD5FC29280A536DFD2E02D2A9BFE")?,
-    ];
-    let mac = MacKey::from(derive_from(s.as_bytes(), &salts[0]));
-    let record = RecordEncryptionKey::from(derive_from(s.as_bytes(), &salts[1]));
-    let noise_priv = x25519_dalek::StaticSecret::from(derive_from(s.as_bytes(), &salts[2]));
-    let noise_pub = x25519_dalek::PublicKey::from(&noise_priv);
-    Ok(RealmKeys {
-        communication: (noise_priv, noise_pub),
-        record,
-        mac,
-    })
-}
-
-fn derive_from<const N: usize>(b: &[u8], salt: &[u8]) -> [u8; N] {
-    let kdf = Hkdf::<Blake2s256, SimpleHmac<Blake2s256>>::new(Some(salt), b);
-    let mut out = [0u8; N];
-    kdf.expand(&[], &mut out).unwrap();
-    out
 }
 
-fn random_tmp_dir() -> PathBuf {
-    let tmp = std::env::temp_dir();
-    let mut n = [0u8; 10];
-    OsRng.fill_bytes(&mut n);
-    let mut dn = String::from("agent_hsm_");
-    for b in n {
-        write!(dn, "{b:02x}").unwrap()
+fn parse_probability(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+        Ok(_) => Err(String::from("must be between 0 and 1")),
+        Err(e) => Err(e.to_string()),
     }
-    tmp.join(dn)
 }
 
 #[cfg(test)]
@@ -0,0 +1,481 @@
+use blake2::{Blake2s256, Digest};
+use rand::rngs::OsRng;
+use rand::Rng;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::sealing::{is_sealed, Sealer, SEALING_OVERHEAD};
+use hsm_core::hal::{IOError, MAX_NVRAM_SIZE};
+
+// The file holds two copies (slots) of the state, and writes alternate
+// between them. Each slot has a header with a magic number, a sequence
+// number, the data length, and a checksum over all of those and the data. A
+// write only ever overwrites the older copy, so if it's torn the newer copy
+// is still intact. Slots are sized to hold sealed data.
+const MAGIC: [u8; 4] = *b"JBNV";
+const CHECKSUM_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + 8 + 4 + CHECKSUM_LEN;
+const SLOT_LEN: usize = HEADER_LEN + MAX_NVRAM_SIZE + SEALING_OVERHEAD;
+const FILE_LEN: usize = 2 * SLOT_LEN;
+const SEALING_PURPOSE: &str = "nvram";
+
+/// Faults to inject into NVRAM writes, for testing how the HSM and the rest
+/// of the cluster recover.
+#[derive(Clone, Debug, Default)]
+pub struct NVRamFaults {
+    /// The probability that a write is torn: only part of it reaches the
+    /// file, and then it fails as if the process had crashed.
+    pub torn_write_rate: f64,
+    /// The probability that a write's fsync fails. The data may or may not
+    /// have been written.
+    pub fsync_failure_rate: f64,
+    /// How long each write takes. Writes to the Entrust SoloXC's NVRAM take
+    /// about 1ms.
+    pub write_delay: Option<Duration>,
+}
+
+/// Emulates an HSM's NVRAM with a file that's safe against crashes.
+///
+/// The file holds two copies of the data, and reads return the newest valid
+/// one. Files written by older software HSMs, which held just the data, are
+/// read as-is and replaced on the next write.
+///
+/// With a [`Sealer`], the data is encrypted at rest. Data written before
+/// sealing was turned on can't be read until it's sealed with
+/// [`NVRamFile::seal_existing`].
+#[derive(Clone, Debug)]
+pub struct NVRamFile {
+    path: PathBuf,
+    faults: NVRamFaults,
+    sealer: Option<Arc<Sealer>>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum Contents {
+    Missing,
+    Legacy(Vec<u8>),
+    Slot { seq: u64, data: Vec<u8> },
+}
+
+impl NVRamFile {
+    pub fn new(path: PathBuf, faults: NVRamFaults, sealer: Option<Arc<Sealer>>) -> Self {
+        Self {
+            path,
+            faults,
+            sealer,
+        }
+    }
+
+    pub fn read(&self) -> Result<Vec<u8>, IOError> {
+        match self.read_contents()? {
+            Contents::Missing => Ok(Vec::new()),
+            Contents::Legacy(data) | Contents::Slot { data, .. } => self.unseal(data),
+        }
+    }
+
+    pub fn write(&self, data: Vec<u8>) -> Result<(), IOError> {
+        if data.len() > MAX_NVRAM_SIZE {
+            return Err(IOError(format!(
+                "data with {} bytes is larger than allowed maximum of {MAX_NVRAM_SIZE}",
+                data.len()
+            )));
+        }
+        if let Some(delay) = self.faults.write_delay {
+            thread::sleep(delay);
+        }
+        let data = match &self.sealer {
+            Some(sealer) => sealer
+                .seal(SEALING_PURPOSE, &data)
+                .map_err(|e| IOError(format!("failed to seal NVRAM state: {e}")))?,
+            None => data,
+        };
+
+        let seq = match self.read_contents()? {
+            Contents::Slot { seq, .. } => seq + 1,
+            Contents::Missing | Contents::Legacy(_) => return self.create(&data),
+        };
+        let slot = encode_slot(seq, &data);
+        let io_error = |e: std::io::Error| {
+            IOError(format!(
+                "IO Error writing to state file {}: {e}",
+                self.path.display()
+            ))
+        };
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&self.path)
+            .map_err(io_error)?;
+        file.seek(SeekFrom::Start(slot_offset(seq)))
+            .map_err(io_error)?;
+        if OsRng.gen_bool(self.faults.torn_write_rate) {
+            // The rest of the slot is padding, so stopping after the data
+            // wouldn't be torn at all.
+            let len = OsRng.gen_range(0..HEADER_LEN + data.len());
+            file.write_all(&slot[..len]).map_err(io_error)?;
+            file.sync_data().map_err(io_error)?;
+            return Err(IOError(format!(
+                "injected fault: write to state file {} torn after {len} bytes",
+                self.path.display()
+            )));
+        }
+        file.write_all(&slot).map_err(io_error)?;
+        if OsRng.gen_bool(self.faults.fsync_failure_rate) {
+            return Err(IOError(format!(
+                "injected fault: fsync of state file {} failed",
+                self.path.display()
+            )));
+        }
+        file.sync_data().map_err(io_error)
+    }
+
+    /// Seals data written before sealing was turned on, returning whether
+    /// there was any. This has to be asked for explicitly, so that a
+    /// plaintext state file can't quietly take the place of sealed state.
+    pub fn seal_existing(&self) -> Result<bool, IOError> {
+        if self.sealer.is_none() {
+            return Err(IOError(String::from(
+                "sealing the state file needs a sealing secret",
+            )));
+        }
+        match self.read_contents()? {
+            Contents::Legacy(data) | Contents::Slot { data, .. } if !is_sealed(&data) => {
+                self.write(data)?;
+                Ok(true)
+            }
+            Contents::Missing | Contents::Legacy(_) | Contents::Slot { .. } => Ok(false),
+        }
+    }
+
+    /// Writes a new file with the data in its first slot, then moves it into
+    /// place. This is used for the first write, and to replace a file in the
+    /// legacy format.
+    fn create(&self, data: &[u8]) -> Result<(), IOError> {
+        let mut contents = vec![0; FILE_LEN];
+        let seq = 1;
+        let offset = usize::try_from(slot_offset(seq)).unwrap();
+        contents[offset..offset + SLOT_LEN].copy_from_slice(&encode_slot(seq, data));
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let io_error = |e: std::io::Error| {
+            IOError(format!(
+                "IO Error creating state file {}: {e}",
+                self.path.display()
+            ))
+        };
+        let mut file = File::create(&tmp).map_err(io_error)?;
+        file.write_all(&contents).map_err(io_error)?;
+        file.sync_all().map_err(io_error)?;
+        fs::rename(&tmp, &self.path).map_err(io_error)?;
+        sync_dir(&self.path).map_err(io_error)
+    }
+
+    fn read_contents(&self) -> Result<Contents, IOError> {
+        let file = match fs::read(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Contents::Missing),
+            Err(e) => {
+                return Err(IOError(format!(
+                    "IO Error reading state from {}: {e}",
+                    self.path.display()
+                )))
+            }
+        };
+        if file.is_empty() {
+            return Ok(Contents::Missing);
+        }
+        // Older software HSMs wrote the data to the file directly. A file in
+        // the current format starts with either the first slot's header or,
+        // if that slot hasn't been written yet, zeros. Neither is the start
+        // of valid legacy data. This holds even if the file was truncated
+        // partway through the magic number.
+        let magic_prefix = &file[..file.len().min(MAGIC.len())];
+        let has_slots =
+            MAGIC.starts_with(magic_prefix) || file.iter().take(HEADER_LEN).all(|b| *b == 0);
+        if !has_slots {
+            if file.len() > MAX_NVRAM_SIZE {
+                return Err(IOError(format!(
+                    "state file {} is corrupt: it has no header and is too large for legacy data",
+                    self.path.display()
+                )));
+            }
+            return Ok(Contents::Legacy(file));
+        }
+        if file.len() != FILE_LEN {
+            return Err(IOError(format!(
+                "state file {} is corrupt: it's {} bytes instead of {FILE_LEN}, so it was probably truncated",
+                self.path.display(),
+                file.len()
+            )));
+        }
+
+        let mut newest: Option<(u64, Vec<u8>)> = None;
+        for (index, slot) in file.chunks_exact(SLOT_LEN).enumerate() {
+            match decode_slot(slot) {
+                Some((seq, data)) => {
+                    if newest.as_ref().map_or(true, |(newest, _)| seq > *newest) {
+                        newest = Some((seq, data.to_vec()));
+                    }
+                }
+                // A slot is all zeros until it's first written.
+                None if slot.iter().all(|b| *b == 0) => {}
+                None => warn!(
+                    path = %self.path.display(),
+                    slot = index,
+                    "ignoring invalid copy of NVRAM state (probably a torn write)"
+                ),
+            }
+        }
+        match newest {
+            Some((seq, data)) => Ok(Contents::Slot { seq, data }),
+            None => Err(IOError(format!(
+                "state file {} is corrupt: neither copy of the data is valid",
+                self.path.display()
+            ))),
+        }
+    }
+
+    fn unseal(&self, data: Vec<u8>) -> Result<Vec<u8>, IOError> {
+        match (&self.sealer, is_sealed(&data)) {
+            (Some(sealer), true) => sealer.unseal(SEALING_PURPOSE, &data).map_err(|e| {
+                IOError(format!(
+                    "failed to unseal state file {}: {e}",
+                    self.path.display()
+                ))
+            }),
+            (Some(_), false) => Err(IOError(format!(
+                "state file {} isn't sealed: seal it once with --seal-existing-state",
+                self.path.display()
+            ))),
+            (None, true) => Err(IOError(format!(
+                "state file {} is sealed, but no sealing secret was given",
+                self.path.display()
+            ))),
+            (None, false) => Ok(data),
+        }
+    }
+}
+
+fn slot_offset(seq: u64) -> u64 {
+    (seq % 2) * SLOT_LEN as u64
+}
+
+fn checksum(seq: u64, data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    Blake2s256::new()
+        .chain_update(MAGIC)
+        .chain_update(seq.to_be_bytes())
+        .chain_update(u32::try_from(data.len()).unwrap().to_be_bytes())
+        .chain_update(data)
+        .finalize()
+        .into()
+}
+
+fn encode_slot(seq: u64, data: &[u8]) -> Vec<u8> {
+    let mut slot = Vec::with_capacity(SLOT_LEN);
+    slot.extend_from_slice(&MAGIC);
+    slot.extend_from_slice(&seq.to_be_bytes());
+    slot.extend_from_slice(&u32::try_from(data.len()).unwrap().to_be_bytes());
+    slot.extend_from_slice(&checksum(seq, data));
+    slot.extend_from_slice(data);
+    slot.resize(SLOT_LEN, 0);
+    slot
+}
+
+/// Returns the sequence number and data in the slot, or `None` if it's not
+/// valid.
+fn decode_slot(slot: &[u8]) -> Option<(u64, &[u8])> {
+    let (magic, rest) = slot.split_at(MAGIC.len());
+    if magic != MAGIC {
+        return None;
+    }
+    let (seq, rest) = rest.split_at(8);
+    let (len, rest) = rest.split_at(4);
+    let (stored_checksum, rest) = rest.split_at(CHECKSUM_LEN);
+    let seq = u64::from_be_bytes(seq.try_into().unwrap());
+    let len = usize::try_from(u32::from_be_bytes(len.try_into().unwrap())).unwrap();
+    let data = rest.get(..len)?;
+    (*stored_checksum == checksum(seq, data)).then_some((seq, data))
+}
+
+/// Makes a rename in the file's directory durable.
+fn sync_dir(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sealing::SealingSecret;
+
+    fn nvram(dir: &tempfile::TempDir, faults: NVRamFaults) -> NVRamFile {
+        NVRamFile::new(dir.path().join("hsm"), faults, None)
+    }
+
+    fn sealed_nvram(dir: &tempfile::TempDir, key: [u8; 32]) -> NVRamFile {
+        let sealer = Sealer::new(SealingSecret::KeyFile(key));
+        NVRamFile::new(
+            dir.path().join("hsm"),
+            NVRamFaults::default(),
+            Some(Arc::new(sealer)),
+        )
+    }
+
+    #[test]
+    fn test_read_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let nv = nvram(&dir, NVRamFaults::default());
+        assert_eq!(Vec::<u8>::new(), nv.read().unwrap());
+
+        for i in 0..5u8 {
+            let data = vec![i; 100 + usize::from(i)];
+            nv.write(data.clone()).unwrap();
+            assert_eq!(data, nv.read().unwrap());
+        }
+        assert_eq!(
+            Contents::Slot {
+                seq: 5,
+                data: vec![4; 104]
+            },
+            nv.read_contents().unwrap()
+        );
+
+        nv.write(Vec::new()).unwrap();
+        assert_eq!(Vec::<u8>::new(), nv.read().unwrap());
+        nv.write(vec![7; MAX_NVRAM_SIZE]).unwrap();
+        assert_eq!(vec![7; MAX_NVRAM_SIZE], nv.read().unwrap());
+        assert!(nv.write(vec![7; MAX_NVRAM_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn test_legacy_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let nv = nvram(&dir, NVRamFaults::default());
+        fs::write(&nv.path, b"legacy state").unwrap();
+        assert_eq!(b"legacy state".to_vec(), nv.read().unwrap());
+
+        nv.write(b"new state".to_vec()).unwrap();
+        assert_eq!(b"new state".to_vec(), nv.read().unwrap());
+        assert_eq!(FILE_LEN, fs::read(&nv.path).unwrap().len());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let nv = nvram(&dir, NVRamFaults::default());
+
+        // After the first write, only the second slot has been written.
+        nv.write(b"one".to_vec()).unwrap();
+        let first = fs::read(&nv.path).unwrap();
+        nv.write(b"two".to_vec()).unwrap();
+        let second = fs::read(&nv.path).unwrap();
+
+        for file in [&first, &second] {
+            for len in [1, 10, HEADER_LEN, 1000, SLOT_LEN, FILE_LEN - 1] {
+                fs::write(&nv.path, &file[..len]).unwrap();
+                let err = nv.read().unwrap_err();
+                assert!(err.0.contains("truncated"), "{len}: {err:?}");
+                assert!(nv.write(b"three".to_vec()).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_sealed() {
+        let dir = tempfile::tempdir().unwrap();
+        let nv = nvram(&dir, NVRamFaults::default());
+        nv.write(b"plaintext state".to_vec()).unwrap();
+
+        // Turning on sealing doesn't accept the existing plaintext state
+        // until it's explicitly sealed.
+        let sealed = sealed_nvram(&dir, [1; 32]);
+        let err = sealed.read().unwrap_err();
+        assert!(err.0.contains("isn't sealed"), "{err:?}");
+        assert!(nv.seal_existing().is_err());
+        assert!(sealed.seal_existing().unwrap());
+        assert!(!sealed.seal_existing().unwrap());
+        assert_eq!(b"plaintext state".to_vec(), sealed.read().unwrap());
+
+        sealed.write(b"secret state".to_vec()).unwrap();
+        assert_eq!(b"secret state".to_vec(), sealed.read().unwrap());
+        let file = fs::read(&nv.path).unwrap();
+        assert!(!file
+            .windows(b"secret state".len())
+            .any(|w| w == b"secret state"));
+
+        let err = nv.read().unwrap_err();
+        assert!(err.0.contains("no sealing secret"), "{err:?}");
+        let err = sealed_nvram(&dir, [2; 32]).read().unwrap_err();
+        assert!(err.0.contains("failed to unseal"), "{err:?}");
+    }
+
+    #[test]
+    fn test_torn_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let nv = nvram(&dir, NVRamFaults::default());
+        nv.write(b"one".to_vec()).unwrap();
+        nv.write(b"two".to_vec()).unwrap();
+
+        let torn = nvram(
+            &dir,
+            NVRamFaults {
+                torn_write_rate: 1.0,
+                ..NVRamFaults::default()
+            },
+        );
+        for _ in 0..10 {
+            assert!(torn.write(b"three".to_vec()).is_err());
+            assert_eq!(b"two".to_vec(), nv.read().unwrap());
+        }
+        nv.write(b"three".to_vec()).unwrap();
+        assert_eq!(b"three".to_vec(), nv.read().unwrap());
+    }
+
+    #[test]
+    fn test_failed_fsync() {
+        let dir = tempfile::tempdir().unwrap();
+        let nv = nvram(&dir, NVRamFaults::default());
+        nv.write(b"one".to_vec()).unwrap();
+
+        let failing = nvram(
+            &dir,
+            NVRamFaults {
+                fsync_failure_rate: 1.0,
+                ..NVRamFaults::default()
+            },
+        );
+        assert!(failing.write(b"two".to_vec()).is_err());
+        // The data made it to the file anyway. Either outcome is valid.
+        assert_eq!(b"two".to_vec(), nv.read().unwrap());
+    }
+
+    #[test]
+    fn test_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let nv = nvram(&dir, NVRamFaults::default());
+        nv.write(b"one".to_vec()).unwrap();
+        nv.write(b"two".to_vec()).unwrap();
+
+        // Flip a bit in the newest copy's data: the older copy is used.
+        let mut file = fs::read(&nv.path).unwrap();
+        file[HEADER_LEN] ^= 1;
+        fs::write(&nv.path, &file).unwrap();
+        assert_eq!(b"one".to_vec(), nv.read().unwrap());
+
+        // With both copies damaged, reads fail.
+        file[SLOT_LEN + HEADER_LEN] ^= 1;
+        fs::write(&nv.path, &file).unwrap();
+        let err = nv.read().unwrap_err();
+        assert!(err.0.contains("is corrupt"), "{err:?}");
+        assert!(nv.write(b"three".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,339 @@
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+// Sealed data starts with this header, followed by the ciphertext. The header
+// is authenticated along with the purpose of the data, so that one sealed
+// file can't be substituted for another.
+const MAGIC: [u8; 4] = *b"JBSL";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN + NONCE_LEN;
+const TAG_LEN: usize = 16;
+
+/// The number of bytes that sealing adds to the data.
+pub const SEALING_OVERHEAD: usize = HEADER_LEN + TAG_LEN;
+
+/// What the sealing keys are derived from.
+pub enum SealingSecret {
+    /// A random 32-byte key, like one held by a KMS.
+    KeyFile([u8; 32]),
+    /// A passphrase, stretched with Argon2id.
+    Passphrase(String),
+}
+
+impl fmt::Debug for SealingSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::KeyFile(_) => f.write_str("KeyFile(redacted)"),
+            Self::Passphrase(_) => f.write_str("Passphrase(redacted)"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+enum Kdf {
+    KeyFile = 1,
+    Argon2id = 2,
+}
+
+#[derive(Debug, thiserror::Error, Eq, PartialEq)]
+pub enum SealingError {
+    #[error("data is not sealed")]
+    NotSealed,
+    #[error("data was sealed with a {0}, but a {1} was given")]
+    WrongSecretKind(&'static str, &'static str),
+    #[error("data could not be unsealed: the secret is wrong or the data is corrupt")]
+    Unseal,
+    #[error("failed to derive a key from the passphrase: {0}")]
+    Kdf(String),
+}
+
+impl SealingSecret {
+    /// Reads a key file, which holds 32 bytes in hex.
+    pub fn from_key_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let mut key = [0; 32];
+        hex::decode_to_slice(contents.trim(), &mut key)
+            .map_err(|e| anyhow::anyhow!("key file should hold 32 bytes in hex: {e}"))?;
+        Ok(Self::KeyFile(key))
+    }
+
+    /// Reads a passphrase from the first line of a file.
+    pub fn from_passphrase_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let passphrase = contents.lines().next().unwrap_or_default();
+        if passphrase.is_empty() {
+            return Err(anyhow::anyhow!("the passphrase can't be empty"));
+        }
+        Ok(Self::Passphrase(passphrase.to_owned()))
+    }
+
+    /// Returns a key for sealing new data, using a fresh salt.
+    fn new_key(&self) -> Result<SealingKey, SealingError> {
+        let mut salt = [0; SALT_LEN];
+        if matches!(self, Self::Passphrase(_)) {
+            OsRng.fill_bytes(&mut salt);
+        }
+        self.derive(salt)
+    }
+
+    /// Returns the key that `sealed` was sealed with.
+    fn key_for(&self, sealed: &[u8]) -> Result<SealingKey, SealingError> {
+        let header = Header::parse(sealed)?;
+        if header.kdf != self.kdf() {
+            return Err(SealingError::WrongSecretKind(
+                header.kdf.describe(),
+                self.kdf().describe(),
+            ));
+        }
+        self.derive(header.salt)
+    }
+
+    fn kdf(&self) -> Kdf {
+        match self {
+            Self::KeyFile(_) => Kdf::KeyFile,
+            Self::Passphrase(_) => Kdf::Argon2id,
+        }
+    }
+
+    fn derive(&self, salt: [u8; SALT_LEN]) -> Result<SealingKey, SealingError> {
+        let mut key = [0; 32];
+        match self {
+            Self::KeyFile(k) => key = *k,
+            Self::Passphrase(passphrase) => Argon2::default()
+                .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+                .map_err(|e| SealingError::Kdf(e.to_string()))?,
+        }
+        Ok(SealingKey {
+            kdf: self.kdf(),
+            salt,
+            cipher: XChaCha20Poly1305::new(&key.into()),
+        })
+    }
+}
+
+impl Kdf {
+    fn describe(&self) -> &'static str {
+        match self {
+            Kdf::KeyFile => "key file",
+            Kdf::Argon2id => "passphrase",
+        }
+    }
+}
+
+/// Seals and unseals data with keys derived from a [`SealingSecret`].
+///
+/// Deriving a key from a passphrase is deliberately slow, so the key is kept
+/// and reused for as long as the data being unsealed has the same salt.
+#[derive(Debug)]
+pub struct Sealer {
+    secret: SealingSecret,
+    key: Mutex<Option<SealingKey>>,
+}
+
+impl Sealer {
+    pub fn new(secret: SealingSecret) -> Self {
+        Self {
+            secret,
+            key: Mutex::new(None),
+        }
+    }
+
+    /// Encrypts `plaintext`. The same `purpose` must be given to unseal it.
+    pub fn seal(&self, purpose: &str, plaintext: &[u8]) -> Result<Vec<u8>, SealingError> {
+        let mut key = self.key.lock().unwrap();
+        if key.is_none() {
+            *key = Some(self.secret.new_key()?);
+        }
+        Ok(key.as_ref().unwrap().seal(purpose, plaintext))
+    }
+
+    pub fn unseal(&self, purpose: &str, sealed: &[u8]) -> Result<Vec<u8>, SealingError> {
+        let header = Header::parse(sealed)?;
+        let mut key = self.key.lock().unwrap();
+        if !key
+            .as_ref()
+            .is_some_and(|key| key.kdf == header.kdf && key.salt == header.salt)
+        {
+            *key = Some(self.secret.key_for(sealed)?);
+        }
+        key.as_ref().unwrap().unseal(purpose, sealed)
+    }
+}
+
+/// A key derived from a [`SealingSecret`], which encrypts and authenticates
+/// data with XChaCha20-Poly1305.
+#[derive(Clone)]
+struct SealingKey {
+    kdf: Kdf,
+    salt: [u8; SALT_LEN],
+    cipher: XChaCha20Poly1305,
+}
+
+impl fmt::Debug for SealingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SealingKey")
+            .field("kdf", &self.kdf)
+            .finish_non_exhaustive()
+    }
+}
+
+struct Header {
+    kdf: Kdf,
+    salt: [u8; SALT_LEN],
+    nonce: [u8; NONCE_LEN],
+}
+
+impl Header {
+    fn parse(sealed: &[u8]) -> Result<Self, SealingError> {
+        if !is_sealed(sealed) || sealed.len() < HEADER_LEN + TAG_LEN {
+            return Err(SealingError::NotSealed);
+        }
+        let kdf = match sealed[MAGIC.len()] {
+            1 => Kdf::KeyFile,
+            2 => Kdf::Argon2id,
+            _ => return Err(SealingError::NotSealed),
+        };
+        let salt_start = MAGIC.len() + 1;
+        let nonce_start = salt_start + SALT_LEN;
+        Ok(Self {
+            kdf,
+            salt: sealed[salt_start..nonce_start].try_into().unwrap(),
+            nonce: sealed[nonce_start..HEADER_LEN].try_into().unwrap(),
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(&MAGIC);
+        header.push(self.kdf as u8);
+        header.extend_from_slice(&self.salt);
+        header.extend_from_slice(&self.nonce);
+        header
+    }
+}
+
+/// Returns true if the data looks like it was sealed. This is used to read
+/// data written before sealing was turned on.
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+impl SealingKey {
+    fn seal(&self, purpose: &str, plaintext: &[u8]) -> Vec<u8> {
+        let mut nonce = [0; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let mut sealed = Header {
+            kdf: self.kdf,
+            salt: self.salt,
+            nonce,
+        }
+        .to_bytes();
+        let aad = [sealed.as_slice(), purpose.as_bytes()].concat();
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            )
+            .expect("XChaCha20Poly1305 encryption failed");
+        sealed.extend(ciphertext);
+        sealed
+    }
+
+    fn unseal(&self, purpose: &str, sealed: &[u8]) -> Result<Vec<u8>, SealingError> {
+        let header = Header::parse(sealed)?;
+        if header.kdf != self.kdf {
+            return Err(SealingError::WrongSecretKind(
+                header.kdf.describe(),
+                self.kdf.describe(),
+            ));
+        }
+        let (header_bytes, ciphertext) = sealed.split_at(HEADER_LEN);
+        let aad = [header_bytes, purpose.as_bytes()].concat();
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(&header.nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| SealingError::Unseal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_unseal() {
+        for secret in [
+            SealingSecret::KeyFile([7; 32]),
+            SealingSecret::Passphrase(String::from("correct horse battery staple")),
+        ] {
+            let key = secret.new_key().unwrap();
+            let sealed = key.seal("test", b"hello");
+            assert!(is_sealed(&sealed));
+            assert_eq!(b"hello".len() + SEALING_OVERHEAD, sealed.len());
+            assert_eq!(b"hello".to_vec(), key.unseal("test", &sealed).unwrap());
+
+            // The key can be derived again from the sealed data.
+            let key2 = secret.key_for(&sealed).unwrap();
+            assert_eq!(b"hello".to_vec(), key2.unseal("test", &sealed).unwrap());
+
+            assert_eq!(Err(SealingError::Unseal), key.unseal("other", &sealed));
+            let mut tampered = sealed.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert_eq!(Err(SealingError::Unseal), key.unseal("test", &tampered));
+            assert_eq!(Err(SealingError::NotSealed), key.unseal("test", b"hello"));
+        }
+    }
+
+    #[test]
+    fn test_sealer() {
+        let sealer = Sealer::new(SealingSecret::Passphrase(String::from("pass")));
+        let sealed = sealer.seal("test", b"one").unwrap();
+        assert_eq!(b"one".to_vec(), sealer.unseal("test", &sealed).unwrap());
+
+        // Another sealer with the same passphrase derives the key from the
+        // salt in the sealed data, then keeps using it.
+        let other = Sealer::new(SealingSecret::Passphrase(String::from("pass")));
+        assert_eq!(b"one".to_vec(), other.unseal("test", &sealed).unwrap());
+        let sealed2 = other.seal("test", b"two").unwrap();
+        assert_eq!(
+            sealed[..HEADER_LEN - NONCE_LEN],
+            sealed2[..HEADER_LEN - NONCE_LEN]
+        );
+        assert_eq!(b"two".to_vec(), sealer.unseal("test", &sealed2).unwrap());
+    }
+
+    #[test]
+    fn test_wrong_secret() {
+        let sealed = SealingSecret::Passphrase(String::from("one"))
+            .new_key()
+            .unwrap()
+            .seal("test", b"hello");
+
+        let wrong = SealingSecret::Passphrase(String::from("two"));
+        let key = wrong.key_for(&sealed).unwrap();
+        assert_eq!(Err(SealingError::Unseal), key.unseal("test", &sealed));
+
+        assert_eq!(
+            Err(SealingError::WrongSecretKind("passphrase", "key file")),
+            SealingSecret::KeyFile([1; 32]).key_for(&sealed).map(|_| ())
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use std::fmt::{self, Debug, Display};
+use std::thread;
+use tokio::sync::oneshot;
+use tracing::info;
+
+use agent_core::hsm::Transport;
+use hsm_core::hal::Platform;
+use hsm_core::hsm::{Hsm, HsmError, PersistenceError};
+use juicebox_marshalling::{DeserializationError, SerializationError};
+use observability::metrics_tag as tag;
+use retry_loop::AttemptError;
+
+/// A transport to an HSM that runs in the agent's process.
+///
+/// The HSM is owned by a dedicated thread, and requests are passed to it over
+/// a channel. This skips the HTTP round trip to a separate `software_hsm`
+/// process, but it's just as insecure, so it's only for non-production
+/// realms.
+#[derive(Clone)]
+pub struct EmbeddedHsm {
+    name: String,
+    sender: async_channel::Sender<Request>,
+}
+
+struct Request {
+    msg: Vec<u8>,
+    respond_to: oneshot::Sender<Result<Vec<u8>, HsmError>>,
+}
+
+impl EmbeddedHsm {
+    /// Starts the HSM thread, which creates the HSM by calling `new_hsm`.
+    ///
+    /// The HSM is created on its own thread so that it never has to move
+    /// between threads. This waits for it to load its state, returning any
+    /// error from doing so.
+    pub fn start<P, F>(name: String, new_hsm: F) -> Result<Self, PersistenceError>
+    where
+        P: Platform + 'static,
+        F: FnOnce() -> Result<Hsm<P>, PersistenceError> + Send + 'static,
+    {
+        let (sender, receiver) = async_channel::bounded::<Request>(128);
+        let (started_tx, started_rx) = std::sync::mpsc::sync_channel(1);
+        let thread_name = name.clone();
+        thread::Builder::new()
+            .name(name.clone())
+            .spawn(move || {
+                let mut hsm = match new_hsm() {
+                    Ok(hsm) => {
+                        _ = started_tx.send(Ok(()));
+                        hsm
+                    }
+                    Err(err) => {
+                        _ = started_tx.send(Err(err));
+                        return;
+                    }
+                };
+                while let Ok(request) = receiver.recv_blocking() {
+                    _ = request.respond_to.send(hsm.handle_request(&request.msg));
+                }
+                info!(name = %thread_name, "embedded HSM thread stopping");
+            })
+            .expect("failed to spawn the embedded HSM thread");
+
+        started_rx
+            .recv()
+            .expect("embedded HSM thread exited during startup")?;
+        Ok(Self { name, sender })
+    }
+}
+
+impl Debug for EmbeddedHsm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EmbeddedHsm {}", self.name)
+    }
+}
+
+impl Transport for EmbeddedHsm {
+    type FatalError = EmbeddedHsmError;
+    type RetryableError = EmbeddedHsmError;
+
+    async fn send_rpc_msg(
+        &self,
+        _msg_name: &'static str,
+        msg: Vec<u8>,
+    ) -> Result<Vec<u8>, AttemptError<EmbeddedHsmError>> {
+        let (respond_to, receiver) = oneshot::channel();
+        self.sender
+            .send(Request { msg, respond_to })
+            .await
+            .expect("embedded HSM thread appears to be gone");
+        receiver
+            .await
+            .expect("embedded HSM thread dropped a request")
+            .map_err(|err| EmbeddedHsmError::Hsm(err).into())
+    }
+}
+
+#[derive(Debug)]
+pub enum EmbeddedHsmError {
+    // These are agent side marshalling errors.
+    Serialization(SerializationError),
+    Deserialization(DeserializationError),
+    // The HSM couldn't handle the request.
+    Hsm(HsmError),
+}
+
+impl Display for EmbeddedHsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Serialization(err) => write!(f, "Serialization error: {err:?}"),
+            Self::Deserialization(err) => write!(f, "Deserialization error: {err:?}"),
+            Self::Hsm(err) => write!(f, "HSM error: {err}"),
+        }
+    }
+}
+
+impl From<SerializationError> for EmbeddedHsmError {
+    fn from(value: SerializationError) -> Self {
+        Self::Serialization(value)
+    }
+}
+
+impl From<DeserializationError> for EmbeddedHsmError {
+    fn from(value: DeserializationError) -> Self {
+        Self::Deserialization(value)
+    }
+}
+
+impl From<EmbeddedHsmError> for AttemptError<EmbeddedHsmError> {
+    fn from(error: EmbeddedHsmError) -> Self {
+        // None of these can be fixed by trying again: the HSM is in-process,
+        // so there's no network to fail.
+        let kind = match &error {
+            EmbeddedHsmError::Serialization(_) => "serialization",
+            EmbeddedHsmError::Deserialization(_) => "deserialization",
+            EmbeddedHsmError::Hsm(HsmError::Serialization(_)) => "hsm_serialization",
+            EmbeddedHsmError::Hsm(HsmError::Deserialization(_)) => "hsm_deserialization",
+        };
+        AttemptError::Fatal {
+            error,
+            tags: vec![tag!("kind": kind)],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use software_hsm::host::new_hsm;
+    use software_hsm::keys::KeyMaterial;
+    use software_hsm::nvram::NVRamFaults;
+
+    #[tokio::test]
+    async fn test_embedded_hsm() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = dir.path().to_owned();
+        let keys = KeyMaterial::insecure_derive("test").unwrap().realm_keys();
+        let hsm = EmbeddedHsm::start(String::from("hsm"), move || {
+            new_hsm(
+                state_dir,
+                String::from("hsm"),
+                keys,
+                NVRamFaults::default(),
+                None,
+                Vec::new(),
+            )
+        })
+        .unwrap();
+
+        // Requests go through to the HSM, which rejects this one.
+        let err = hsm
+            .send_rpc_msg("garbage", vec![1, 2, 3])
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            AttemptError::Fatal {
+                error: EmbeddedHsmError::Hsm(HsmError::Deserialization(_)),
+                ..
+            }
+        ));
+
+        // Clones share the HSM thread.
+        let err = hsm
+            .clone()
+            .send_rpc_msg("garbage", Vec::new())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            AttemptError::Fatal {
+                error: EmbeddedHsmError::Hsm(HsmError::Deserialization(_)),
+                ..
+            }
+        ));
+    }
+}
@@ -11,6 +11,10 @@ use juicebox_marshalling::{self as marshalling, DeserializationError, Serializat
 use observability::metrics_tag as tag;
 use retry_loop::AttemptError;
 
+mod embedded;
+
+pub use embedded::{EmbeddedHsm, EmbeddedHsmError};
+
 #[derive(Clone)]
 pub struct HsmHttpClient {
     hsm: Url,
@@ -0,0 +1,191 @@
+use bigtable::read::Reader;
+use bigtable::{bigtable_retries, inspect_grpc_error, Instance};
+use google::bigtable::admin::v2::gc_rule::Rule;
+use google::bigtable::admin::v2::table::TimestampGranularity;
+use google::bigtable::admin::v2::{ColumnFamily, CreateTableRequest, GcRule, Table};
+use google::bigtable::v2::{
+    mutation, read_rows_request, MutateRowRequest, Mutation, ReadRowsRequest,
+};
+use retry_loop::{retry_logging, Retry, RetryError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str;
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+use super::{to_micros, BigtableTableAdminClient, StoreClient};
+
+const FAMILY: &str = "f";
+const COLUMN_NAME: &[u8] = b"u";
+const TABLE_NAME: &str = "admission";
+
+/// Usage that hasn't been updated in at least this long is ignored. Load
+/// balancers report their usage every second or so.
+pub const USAGE_EXPIRY_AGE: Duration = Duration::from_secs(10);
+
+/// Bigtable garbage collects usage from load balancers that have gone away
+/// after this long.
+const USAGE_GC_AGE: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// A load balancer's demand from each tenant, shared so that the load
+/// balancers can split the tenants' concurrency limits between them.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AdmissionUsage {
+    /// The number of requests in flight or queued for each tenant that has
+    /// any.
+    pub tenants: HashMap<String, usize>,
+}
+
+pub fn admission_table(instance: &Instance) -> String {
+    format!("{path}/tables/{TABLE_NAME}", path = instance.path())
+}
+
+pub(crate) async fn initialize(
+    bigtable: &mut BigtableTableAdminClient,
+    instance: &Instance,
+) -> Result<(), tonic::Status> {
+    // This is not realm-specific, so it might already exist.
+    if let Err(err) = bigtable
+        .create_table(CreateTableRequest {
+            parent: instance.path(),
+            table_id: String::from(TABLE_NAME),
+            table: Some(Table {
+                name: String::from(""),
+                cluster_states: HashMap::new(),
+                column_families: HashMap::from([(
+                    FAMILY.to_string(),
+                    ColumnFamily {
+                        gc_rule: Some(GcRule {
+                            rule: Some(Rule::MaxAge(prost_types::Duration {
+                                seconds: USAGE_GC_AGE.as_secs().try_into().unwrap(),
+                                nanos: 0,
+                            })),
+                        }),
+                    },
+                )]),
+                granularity: TimestampGranularity::Unspecified.into(),
+                restore_info: None,
+                change_stream_config: None,
+                deletion_protection: false,
+            }),
+            initial_splits: Vec::new(),
+        })
+        .await
+    {
+        if err.code() != tonic::Code::AlreadyExists {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+impl StoreClient {
+    /// Returns the usage most recently reported by each load balancer,
+    /// leaving out usage older than [`USAGE_EXPIRY_AGE`].
+    pub async fn get_admission_usage(
+        &self,
+    ) -> Result<Vec<(String, AdmissionUsage)>, RetryError<tonic::Status>> {
+        let rows = match Reader::read_rows(
+            &mut self.0.bigtable.clone(),
+            Retry::new("read Bigtable admission table")
+                .with(bigtable_retries)
+                .with_metrics(&self.0.metrics, "store_client.admission.read", &[]),
+            ReadRowsRequest {
+                table_name: admission_table(&self.0.instance),
+                app_profile_id: String::new(),
+                rows: None,
+                filter: None,
+                rows_limit: 0,
+                request_stats_view: read_rows_request::RequestStatsView::RequestStatsNone.into(),
+                reversed: false,
+            },
+        )
+        .await
+        {
+            Ok(rows) => rows,
+            Err(RetryError::Fatal { error }) if error.code() == tonic::Code::NotFound => {
+                warn!(
+                    error = error.message(),
+                    "couldn't read from Bigtable admission table \
+                (the cluster manager should create it)"
+                );
+                return Ok(Vec::new());
+            }
+            Err(e) => return Err(e),
+        };
+
+        let expire_when_before = SystemTime::now() - USAGE_EXPIRY_AGE;
+        Ok(rows
+            .into_iter()
+            .filter_map(|(key, cells)| {
+                let cell = cells
+                    .into_iter()
+                    .find(|c| c.family == FAMILY && c.qualifier == COLUMN_NAME)?;
+                let written_at =
+                    SystemTime::UNIX_EPOCH + Duration::from_micros(cell.timestamp as u64);
+                if written_at < expire_when_before {
+                    return None;
+                }
+                let load_balancer = str::from_utf8(&key.0).ok()?;
+                match juicebox_marshalling::from_slice(&cell.value) {
+                    Ok(usage) => Some((load_balancer.to_owned(), usage)),
+                    Err(err) => {
+                        warn!(load_balancer, ?err, "couldn't deserialize admission usage");
+                        None
+                    }
+                }
+            })
+            .collect())
+    }
+
+    /// Records the load balancer's current usage, replacing what it reported
+    /// before. `load_balancer` must be unique to this load balancer process.
+    pub async fn put_admission_usage(
+        &self,
+        load_balancer: &str,
+        usage: &AdmissionUsage,
+        // timestamp of the report, typically SystemTime::now()
+        timestamp: SystemTime,
+    ) -> Result<(), RetryError<tonic::Status>> {
+        let timestamp_micros = to_micros(timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap());
+        let value = juicebox_marshalling::to_vec(usage).expect("TODO");
+
+        let run = |_| async {
+            let request = MutateRowRequest {
+                table_name: admission_table(&self.0.instance),
+                app_profile_id: String::new(),
+                row_key: load_balancer.as_bytes().to_vec(),
+                mutations: vec![
+                    Mutation {
+                        mutation: Some(mutation::Mutation::DeleteFromFamily(
+                            mutation::DeleteFromFamily {
+                                family_name: String::from(FAMILY),
+                            },
+                        )),
+                    },
+                    Mutation {
+                        mutation: Some(mutation::Mutation::SetCell(mutation::SetCell {
+                            family_name: String::from(FAMILY),
+                            column_qualifier: COLUMN_NAME.to_vec(),
+                            timestamp_micros,
+                            value: value.clone(),
+                        })),
+                    },
+                ],
+            };
+            self.0
+                .bigtable
+                .clone()
+                .mutate_row(request)
+                .await
+                .map_err(inspect_grpc_error)?;
+            Ok(())
+        };
+
+        Retry::new("updating admission usage")
+            .with(bigtable_retries)
+            .with_metrics(&self.0.metrics, "store_client.admission.write", &[])
+            .retry(run, retry_logging!())
+            .await
+    }
+}
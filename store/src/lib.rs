@@ -28,11 +28,16 @@ use observability::{metrics, metrics_tag as tag};
 use retry_loop::{Retry, RetryError};
 use service_core::clap_parsers::parse_duration;
 
+pub mod admission;
 mod base128;
 pub mod discovery;
 mod lease;
 pub mod log;
+pub mod log_archive;
 mod merkle;
+pub mod merkle_gc;
+pub mod offboarding;
+pub mod snapshot;
 pub mod tenant_config;
 pub mod tenants;
 
@@ -214,8 +219,10 @@ impl StoreAdminClient {
 
     pub async fn initialize_shared_tables(&self) -> Result<(), tonic::Status> {
         let mut bigtable = self.bigtable.clone();
+        admission::initialize(&mut bigtable, &self.instance).await?;
         discovery::initialize(&mut bigtable, &self.instance).await?;
         lease::initialize(&mut bigtable, &self.instance).await?;
+        offboarding::initialize(&mut bigtable, &self.instance).await?;
         tenant_config::initialize(&mut bigtable, &self.instance).await
     }
 
@@ -596,6 +596,75 @@ impl StoreClient {
             .await
     }
 
+    /// Returns the IDs of every group that has rows in the realm's log table,
+    /// in row key order.
+    ///
+    /// This does one small read per group, seeking past all of the previous
+    /// group's rows each time, so it doesn't scan the whole log.
+    #[instrument(level = "trace", skip(self), fields(groups))]
+    pub async fn list_log_groups(
+        &self,
+        realm: &RealmId,
+    ) -> Result<Vec<GroupId>, RetryError<tonic::Status>> {
+        let mut groups: Vec<GroupId> = Vec::new();
+        loop {
+            let request = ReadRowsRequest {
+                table_name: log_table(&self.0.instance, realm),
+                app_profile_id: String::new(),
+                rows: Some(RowSet {
+                    row_keys: Vec::new(),
+                    row_ranges: vec![RowRange {
+                        // `LogIndex::FIRST` has the largest row key that a
+                        // group can use, so this skips to the next group.
+                        start_key: groups
+                            .last()
+                            .map(|group| StartKeyOpen(log_key(group, LogIndex::FIRST))),
+                        end_key: None,
+                    }],
+                }),
+                filter: Some(RowFilter {
+                    filter: Some(Filter::Chain(Chain {
+                        filters: vec![
+                            RowFilter {
+                                filter: Some(Filter::CellsPerRowLimitFilter(1)),
+                            },
+                            RowFilter {
+                                filter: Some(Filter::StripValueTransformer(true)),
+                            },
+                        ],
+                    })),
+                }),
+                rows_limit: 1,
+                request_stats_view: read_rows_request::RequestStatsView::RequestStatsNone.into(),
+                reversed: false,
+            };
+
+            let row = Reader::read_row(
+                &mut self.0.bigtable.clone(),
+                Retry::new("listing groups in log table")
+                    .with(bigtable_retries)
+                    .with_metrics(
+                        &self.0.metrics,
+                        "store_client.list_log_groups",
+                        &[tag!(?realm)],
+                    ),
+                request,
+            )
+            .await?;
+
+            match row {
+                Some((key, _)) => {
+                    let (group, _) = parse_log_key(&key).unwrap();
+                    groups.push(group);
+                }
+                None => {
+                    Span::current().record("groups", groups.len());
+                    return Ok(groups);
+                }
+            }
+        }
+    }
+
     /// Returns an iterator-style object that can read the log starting from
     /// the supplied log index.
     ///
@@ -794,6 +863,67 @@ impl StoreClient {
         Ok((rows, more))
     }
 
+    /// Reads the log entries stored in the given rows, in forwards log order.
+    ///
+    /// Tombstone rows, and rows that have been replaced with tombstones since
+    /// they were listed, contribute no entries.
+    #[instrument(level = "trace", skip(self, rows), fields(rows = rows.len(), entries))]
+    pub async fn read_log_rows(
+        &self,
+        realm: &RealmId,
+        group: &GroupId,
+        rows: &[LogRow],
+    ) -> Result<Vec<LogEntry>, RetryError<tonic::Status>> {
+        let row_keys: Vec<Vec<u8>> = rows
+            .iter()
+            .filter(|row| !row.is_tombstone)
+            .map(|row| log_key(group, row.index))
+            .collect();
+        if row_keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let request = ReadRowsRequest {
+            table_name: log_table(&self.0.instance, realm),
+            app_profile_id: String::new(),
+            rows: Some(RowSet {
+                row_keys,
+                row_ranges: Vec::new(),
+            }),
+            filter: Some(RowFilter {
+                filter: Some(Filter::ColumnRangeFilter(ColumnRange {
+                    family_name: LogFamily::EntryBatch.name_string(),
+                    start_qualifier: None,
+                    end_qualifier: None,
+                })),
+            }),
+            rows_limit: 0,
+            request_stats_view: read_rows_request::RequestStatsView::RequestStatsNone.into(),
+            reversed: false,
+        };
+
+        let mut entries: Vec<LogEntry> = Reader::read_rows(
+            &mut self.0.bigtable.clone(),
+            Retry::new("reading log rows")
+                .with(bigtable_retries)
+                .with_metrics(
+                    &self.0.metrics,
+                    "store_client.read_log_rows",
+                    &[tag!(?realm), tag!(?group)],
+                ),
+            request,
+        )
+        .await?
+        .into_iter()
+        .flat_map(|(_key, cells)| cells)
+        .map(|cell| marshalling::from_slice(&cell.value).expect("TODO"))
+        .collect();
+        entries.sort_unstable_by_key(|entry| entry.index);
+
+        Span::current().record("entries", entries.len());
+        Ok(entries)
+    }
+
     /// Overwrite the start of the log with tombstones.
     ///
     /// `rows` must represent a consecutive sequence of rows in the log,
@@ -0,0 +1,618 @@
+//! Cold archival of compacted log entries.
+//!
+//! The log compactor normally replaces old log rows with tombstones, after
+//! which their entries are gone. When a [`LogArchiver`] is configured, the
+//! compactor first writes those entries to append-only segments in an
+//! [`ArchiveStorage`] backend.
+//!
+//! Each segment holds a consecutive run of entries from one group's log. The
+//! segments are named `{realm}/{group}/{first}-{last}.seg`, with the first and
+//! last log indexes zero-padded, so listing a group's directory in
+//! lexicographic order also orders the segments by log index. That naming is
+//! the index used by [`ArchiveReader`].
+//!
+//! A segment's contents are a small header followed by the marshalled entries.
+//! The header holds a magic value, a format version, and a SHA-256 checksum of
+//! the body.
+//!
+//! Segments may overlap when leadership changes part way through compaction,
+//! since the new leader re-archives rows that the old leader didn't get to
+//! tombstone. The log is immutable once committed, so overlapping segments
+//! hold identical entries and the reader skips the duplicates.
+
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use std::fmt::Debug;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{info, instrument};
+
+use hsm_api::{GroupId, LogEntry, LogIndex};
+use juicebox_marshalling as marshalling;
+use juicebox_realm_api::types::RealmId;
+use rand_core::{OsRng, RngCore};
+
+/// Identifies a segment file.
+const MAGIC: &[u8; 8] = b"jblogseg";
+
+/// The current segment format version.
+const SEGMENT_VERSION: u16 = 1;
+
+const CHECKSUM_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + 2 + CHECKSUM_LEN;
+
+const SEGMENT_SUFFIX: &str = ".seg";
+
+/// Somewhere to keep archived log segments, such as a local directory or an
+/// object store.
+///
+/// Objects are identified by `/`-separated names and are never modified once
+/// written.
+#[async_trait]
+pub trait ArchiveStorage: Send + Sync + Debug {
+    /// Writes a new object. Returns [`ArchiveStorageError::AlreadyExists`] if
+    /// an object with this name was already written.
+    async fn put(&self, name: &str, data: Vec<u8>) -> Result<(), ArchiveStorageError>;
+
+    /// Reads an object, returning `None` if it doesn't exist.
+    async fn get(&self, name: &str) -> Result<Option<Vec<u8>>, ArchiveStorageError>;
+
+    /// Returns the names of the objects directly under the `/`-terminated
+    /// `prefix`, in lexicographic order. Nested names are returned with a
+    /// trailing `/`.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, ArchiveStorageError>;
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveStorageError {
+    #[error("archive object {0:?} already exists")]
+    AlreadyExists(String),
+
+    #[error("archive storage I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("archive storage error: {0}")]
+    Other(String),
+}
+
+/// An [`ArchiveStorage`] backed by a local directory.
+#[derive(Clone, Debug)]
+pub struct LocalDirectory {
+    root: PathBuf,
+}
+
+impl LocalDirectory {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        let mut path = self.root.clone();
+        path.extend(name.split('/').filter(|part| !part.is_empty()));
+        path
+    }
+}
+
+#[async_trait]
+impl ArchiveStorage for LocalDirectory {
+    async fn put(&self, name: &str, data: Vec<u8>) -> Result<(), ArchiveStorageError> {
+        let path = self.path(name);
+        let name = name.to_owned();
+        tokio::task::spawn_blocking(move || {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // Write to a temporary file first so that a crash can't leave a
+            // partial segment behind under the final name. The temporary name
+            // is unique so that concurrent writers don't clobber each other's.
+            let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+            tmp_name.push(format!(".{:016x}.tmp", OsRng.next_u64()));
+            let tmp = path.with_file_name(tmp_name);
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&tmp)?;
+            file.write_all(&data)?;
+            file.sync_all()?;
+            drop(file);
+            // Unlike a rename, linking fails if the final name already
+            // exists, so an existing segment is never overwritten.
+            let linked = fs::hard_link(&tmp, &path);
+            fs::remove_file(&tmp)?;
+            match linked {
+                Ok(()) => Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    Err(ArchiveStorageError::AlreadyExists(name))
+                }
+                Err(err) => Err(err.into()),
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn get(&self, name: &str) -> Result<Option<Vec<u8>>, ArchiveStorageError> {
+        let path = self.path(name);
+        tokio::task::spawn_blocking(move || match fs::read(path) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, ArchiveStorageError> {
+        let path = self.path(prefix);
+        tokio::task::spawn_blocking(move || {
+            let dir = match fs::read_dir(path) {
+                Ok(dir) => dir,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(err) => return Err(err.into()),
+            };
+            let mut names = Vec::new();
+            for entry in dir {
+                let entry = entry?;
+                let Ok(mut name) = entry.file_name().into_string() else {
+                    continue;
+                };
+                if entry.file_type()?.is_dir() {
+                    name.push('/');
+                } else if name.ends_with(".tmp") {
+                    continue;
+                }
+                names.push(name);
+            }
+            names.sort();
+            Ok(names)
+        })
+        .await
+        .unwrap()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error(transparent)]
+    Storage(#[from] ArchiveStorageError),
+
+    /// A segment with the same name but different contents already exists.
+    #[error("archive segment {0:?} conflicts with an existing segment")]
+    Conflict(String),
+
+    #[error("archive segment {name:?} is corrupt: {reason}")]
+    Corrupt { name: String, reason: String },
+
+    /// The entry at `missing` wasn't archived, but later entries were,
+    /// starting at `resumes`. This is returned by
+    /// [`ArchivedEntriesIter::next`], which can be restarted at `resumes`.
+    #[error("log entry {missing} is not archived (the archive resumes at {resumes})")]
+    Gap {
+        missing: LogIndex,
+        resumes: LogIndex,
+    },
+}
+
+/// Writes compacted log entries to an [`ArchiveStorage`].
+#[derive(Clone, Debug)]
+pub struct LogArchiver {
+    storage: Arc<dyn ArchiveStorage>,
+}
+
+impl LogArchiver {
+    pub fn new(storage: Arc<dyn ArchiveStorage>) -> Self {
+        Self { storage }
+    }
+
+    /// Writes a consecutive run of a group's log entries as a new segment.
+    ///
+    /// Archiving the same entries again is a no-op, so this can be retried.
+    #[instrument(level = "trace", skip(self, entries), fields(entries = entries.len()))]
+    pub async fn archive(
+        &self,
+        realm: &RealmId,
+        group: &GroupId,
+        entries: &[LogEntry],
+    ) -> Result<(), ArchiveError> {
+        let (Some(first), Some(last)) = (entries.first(), entries.last()) else {
+            return Ok(());
+        };
+        for pair in entries.windows(2) {
+            assert_eq!(pair[0].index.next(), pair[1].index);
+        }
+
+        let name = segment_name(realm, group, first.index, last.index);
+        let data = encode_segment(entries);
+        match self.storage.put(&name, data.clone()).await {
+            Ok(()) => {
+                info!(
+                    ?realm,
+                    ?group,
+                    first = %first.index,
+                    last = %last.index,
+                    "archived log entries"
+                );
+                Ok(())
+            }
+            Err(ArchiveStorageError::AlreadyExists(_)) => match self.storage.get(&name).await? {
+                Some(existing) if existing == data => Ok(()),
+                _ => Err(ArchiveError::Conflict(name)),
+            },
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Reads archived log entries back, as if they were the live log.
+#[derive(Clone, Debug)]
+pub struct ArchiveReader {
+    storage: Arc<dyn ArchiveStorage>,
+}
+
+impl ArchiveReader {
+    pub fn new(storage: Arc<dyn ArchiveStorage>) -> Self {
+        Self { storage }
+    }
+
+    /// Returns the groups in the realm that have archived entries.
+    pub async fn list_groups(&self, realm: &RealmId) -> Result<Vec<GroupId>, ArchiveError> {
+        Ok(self
+            .storage
+            .list(&format!("{}/", hex::encode(realm.0)))
+            .await?
+            .into_iter()
+            .filter_map(|name| {
+                let bytes = hex::decode(name.strip_suffix('/')?).ok()?;
+                Some(GroupId(bytes.try_into().ok()?))
+            })
+            .collect())
+    }
+
+    /// Returns the range of log indexes in each of the group's segments, in
+    /// log order.
+    pub async fn list_segments(
+        &self,
+        realm: &RealmId,
+        group: &GroupId,
+    ) -> Result<Vec<(LogIndex, LogIndex)>, ArchiveError> {
+        let mut segments: Vec<(LogIndex, LogIndex)> = self
+            .storage
+            .list(&group_prefix(realm, group))
+            .await?
+            .iter()
+            .filter_map(|name| parse_segment_name(name))
+            .collect();
+        segments.sort_unstable();
+        Ok(segments)
+    }
+
+    /// Returns an iterator over the group's archived entries, starting at
+    /// `index`.
+    pub fn read_entries_iter(
+        &self,
+        realm: RealmId,
+        group: GroupId,
+        index: LogIndex,
+    ) -> ArchivedEntriesIter {
+        ArchivedEntriesIter {
+            reader: self.clone(),
+            realm,
+            group,
+            next: index,
+            segments: None,
+        }
+    }
+
+    async fn read_segment(
+        &self,
+        realm: &RealmId,
+        group: &GroupId,
+        (first, last): (LogIndex, LogIndex),
+    ) -> Result<Vec<LogEntry>, ArchiveError> {
+        let name = segment_name(realm, group, first, last);
+        let data = self
+            .storage
+            .get(&name)
+            .await?
+            .ok_or_else(|| ArchiveError::Corrupt {
+                name: name.clone(),
+                reason: String::from("listed segment not found"),
+            })?;
+        let entries = decode_segment(&data).map_err(|reason| ArchiveError::Corrupt {
+            name: name.clone(),
+            reason,
+        })?;
+        if entries.first().map(|e| e.index) != Some(first)
+            || entries.last().map(|e| e.index) != Some(last)
+        {
+            return Err(ArchiveError::Corrupt {
+                name,
+                reason: String::from("entries don't match the segment name"),
+            });
+        }
+        Ok(entries)
+    }
+}
+
+/// Iterates over a group's archived log entries in log order, one segment at
+/// a time. Returned by [`ArchiveReader::read_entries_iter`].
+///
+/// Like [`super::log::LogEntriesIter`], each call to [`Self::next`] returns
+/// the next batch of entries, and an empty batch at the end. A gap in the
+/// archive is reported as [`ArchiveError::Gap`].
+pub struct ArchivedEntriesIter {
+    reader: ArchiveReader,
+    realm: RealmId,
+    group: GroupId,
+    next: LogIndex,
+    segments: Option<Vec<(LogIndex, LogIndex)>>,
+}
+
+impl ArchivedEntriesIter {
+    pub async fn next(&mut self) -> Result<Vec<LogEntry>, ArchiveError> {
+        if self.segments.is_none() {
+            self.segments = Some(self.reader.list_segments(&self.realm, &self.group).await?);
+        }
+        let segments = self.segments.as_ref().unwrap();
+
+        // Find the first segment containing `next`. Segments that end before
+        // it (including ones that overlap what was already returned) are
+        // skipped.
+        let Some(segment) = segments
+            .iter()
+            .find(|(first, last)| *first <= self.next && self.next <= *last)
+            .copied()
+        else {
+            return match segments.iter().find(|(first, _)| *first > self.next) {
+                Some((first, _)) => Err(ArchiveError::Gap {
+                    missing: self.next,
+                    resumes: *first,
+                }),
+                None => Ok(Vec::new()),
+            };
+        };
+
+        let mut entries = self
+            .reader
+            .read_segment(&self.realm, &self.group, segment)
+            .await?;
+        entries.retain(|entry| entry.index >= self.next);
+        if let Some(last) = entries.last() {
+            self.next = last.index.next();
+        }
+        Ok(entries)
+    }
+}
+
+fn group_prefix(realm: &RealmId, group: &GroupId) -> String {
+    format!("{}/{}/", hex::encode(realm.0), hex::encode(group.0))
+}
+
+fn segment_name(realm: &RealmId, group: &GroupId, first: LogIndex, last: LogIndex) -> String {
+    format!(
+        "{}{:020}-{:020}{SEGMENT_SUFFIX}",
+        group_prefix(realm, group),
+        first.0,
+        last.0
+    )
+}
+
+fn parse_segment_name(name: &str) -> Option<(LogIndex, LogIndex)> {
+    let (first, last) = name.strip_suffix(SEGMENT_SUFFIX)?.split_once('-')?;
+    Some((LogIndex(first.parse().ok()?), LogIndex(last.parse().ok()?)))
+}
+
+fn encode_segment(entries: &[LogEntry]) -> Vec<u8> {
+    let body = marshalling::to_vec(entries).expect("TODO");
+    let mut out = Vec::with_capacity(HEADER_LEN + body.len());
+    out.extend(MAGIC);
+    out.extend(SEGMENT_VERSION.to_be_bytes());
+    out.extend(Sha256::digest(&body));
+    out.extend(body);
+    out
+}
+
+fn decode_segment(data: &[u8]) -> Result<Vec<LogEntry>, String> {
+    if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+        return Err(String::from("bad magic"));
+    }
+    let (version, rest) = data[MAGIC.len()..].split_at(2);
+    let version = u16::from_be_bytes(version.try_into().unwrap());
+    if version != SEGMENT_VERSION {
+        return Err(format!("unsupported version {version}"));
+    }
+    let (checksum, body) = rest.split_at(CHECKSUM_LEN);
+    if Sha256::digest(body).as_slice() != checksum {
+        return Err(String::from("checksum mismatch"));
+    }
+    marshalling::from_slice(body).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hsm_api::{EntryMac, HsmId};
+
+    const REALM: RealmId = RealmId([1; 16]);
+    const GROUP: GroupId = GroupId([2; 16]);
+
+    fn entries(first: u64, last: u64) -> Vec<LogEntry> {
+        (first..=last)
+            .map(|i| LogEntry {
+                index: LogIndex(i),
+                partition: None,
+                transferring: None,
+                prev_mac: EntryMac::from([u8::try_from(i - 1).unwrap(); 32]),
+                entry_mac: EntryMac::from([u8::try_from(i).unwrap(); 32]),
+                hsm: HsmId([3; 16]),
+            })
+            .collect()
+    }
+
+    fn storage() -> (tempfile::TempDir, Arc<dyn ArchiveStorage>) {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(LocalDirectory::new(dir.path()));
+        (dir, storage)
+    }
+
+    async fn read_all(reader: &ArchiveReader, start: LogIndex) -> Vec<LogEntry> {
+        let mut it = reader.read_entries_iter(REALM, GROUP, start);
+        let mut all = Vec::new();
+        loop {
+            let batch = it.next().await.unwrap();
+            if batch.is_empty() {
+                return all;
+            }
+            all.extend(batch);
+        }
+    }
+
+    #[test]
+    fn segment_names() {
+        let name = segment_name(&REALM, &GROUP, LogIndex(3), LogIndex(17));
+        assert_eq!(
+            "01010101010101010101010101010101/02020202020202020202020202020202/\
+            00000000000000000003-00000000000000000017.seg",
+            name
+        );
+        assert_eq!(
+            Some((LogIndex(3), LogIndex(17))),
+            parse_segment_name(name.rsplit('/').next().unwrap())
+        );
+        assert_eq!(None, parse_segment_name("garbage.seg"));
+    }
+
+    #[test]
+    fn segment_encoding() {
+        let e = entries(1, 4);
+        let mut data = encode_segment(&e);
+        assert_eq!(Ok(e), decode_segment(&data));
+        *data.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            Err(String::from("checksum mismatch")),
+            decode_segment(&data)
+        );
+    }
+
+    #[tokio::test]
+    async fn archive_and_read_back() {
+        let (_dir, storage) = storage();
+        let archiver = LogArchiver::new(storage.clone());
+        let reader = ArchiveReader::new(storage);
+
+        archiver
+            .archive(&REALM, &GROUP, &entries(1, 4))
+            .await
+            .unwrap();
+        archiver
+            .archive(&REALM, &GROUP, &entries(5, 9))
+            .await
+            .unwrap();
+        // Re-archiving the same segment is fine, and an overlapping one is
+        // handled by the reader.
+        archiver
+            .archive(&REALM, &GROUP, &entries(5, 9))
+            .await
+            .unwrap();
+        archiver
+            .archive(&REALM, &GROUP, &entries(8, 12))
+            .await
+            .unwrap();
+
+        assert_eq!(vec![GROUP], reader.list_groups(&REALM).await.unwrap());
+        assert_eq!(
+            vec![
+                (LogIndex(1), LogIndex(4)),
+                (LogIndex(5), LogIndex(9)),
+                (LogIndex(8), LogIndex(12))
+            ],
+            reader.list_segments(&REALM, &GROUP).await.unwrap()
+        );
+        assert_eq!(entries(1, 12), read_all(&reader, LogIndex(1)).await);
+        assert_eq!(entries(7, 12), read_all(&reader, LogIndex(7)).await);
+        assert!(read_all(&reader, LogIndex(13)).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn read_gap() {
+        let (_dir, storage) = storage();
+        let archiver = LogArchiver::new(storage.clone());
+        let reader = ArchiveReader::new(storage);
+        archiver
+            .archive(&REALM, &GROUP, &entries(5, 9))
+            .await
+            .unwrap();
+        archiver
+            .archive(&REALM, &GROUP, &entries(12, 14))
+            .await
+            .unwrap();
+
+        let mut it = reader.read_entries_iter(REALM, GROUP, LogIndex(1));
+        assert!(matches!(
+            it.next().await,
+            Err(ArchiveError::Gap {
+                missing: LogIndex(1),
+                resumes: LogIndex(5)
+            })
+        ));
+
+        let mut it = reader.read_entries_iter(REALM, GROUP, LogIndex(5));
+        assert_eq!(entries(5, 9), it.next().await.unwrap());
+        assert!(matches!(
+            it.next().await,
+            Err(ArchiveError::Gap {
+                missing: LogIndex(10),
+                resumes: LogIndex(12)
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn local_directory_put_doesnt_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalDirectory::new(dir.path());
+        let name = "a/b/1-2.seg";
+
+        let results =
+            futures::future::join_all((0..8u8).map(|i| storage.put(name, vec![i; 1000]))).await;
+        let written: Vec<u8> = results
+            .iter()
+            .enumerate()
+            .filter_map(|(i, result)| match result {
+                Ok(()) => Some(u8::try_from(i).unwrap()),
+                Err(ArchiveStorageError::AlreadyExists(_)) => None,
+                Err(err) => panic!("unexpected error {err:?}"),
+            })
+            .collect();
+        assert_eq!(1, written.len());
+        assert_eq!(
+            Some(vec![written[0]; 1000]),
+            storage.get(name).await.unwrap()
+        );
+        // No temporary files are left behind.
+        assert_eq!(
+            vec![String::from("1-2.seg")],
+            storage.list("a/b/").await.unwrap()
+        );
+        assert_eq!(1, fs::read_dir(dir.path().join("a/b")).unwrap().count());
+    }
+
+    #[tokio::test]
+    async fn archive_conflict() {
+        let (_dir, storage) = storage();
+        let archiver = LogArchiver::new(storage);
+        archiver
+            .archive(&REALM, &GROUP, &entries(1, 4))
+            .await
+            .unwrap();
+
+        let mut different = entries(1, 4);
+        different[2].hsm = HsmId([9; 16]);
+        assert!(matches!(
+            archiver.archive(&REALM, &GROUP, &different).await,
+            Err(ArchiveError::Conflict(_))
+        ));
+    }
+}
@@ -0,0 +1,665 @@
+//! Garbage collection of orphaned Merkle tree nodes.
+//!
+//! [`StoreClient::append`] hands the nodes that a log entry's delta removes to
+//! an in-memory delete queue, which deletes them after a short delay. If the
+//! agent crashes before that happens, or a leader writes new nodes and then
+//! loses the race to append its log entry, those nodes are never deleted.
+//!
+//! The collector here finds them. It reads the recent roots of every group's
+//! Merkle tree from the log, scans the realm's Merkle table, walks the trees
+//! from those roots, and deletes the cells of unreachable nodes that are older
+//! than a safety age.
+//!
+//! The whole Merkle table is held in memory while walking the trees, so this
+//! is intended to run as an occasional background job rather than on the
+//! agents.
+
+use google::bigtable::v2::{
+    mutate_rows_request, mutation, read_rows_request, MutateRowsRequest, Mutation, ReadRowsRequest,
+};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, instrument, warn};
+
+use super::log::{LogEntriesIterError, ReadLastLogEntryError, ReadLastLogEntryFatal};
+use super::merkle::{merkle_table, StoreKey};
+use super::{to_micros, StoreClient};
+use bigtable::bigtable_retries;
+use bigtable::mutate::{mutate_rows, MutateRowsError};
+use bigtable::read::Reader;
+use bitvec::Bits;
+use hsm_api::merkle::{KeyVec, Node, NodeKey};
+use hsm_api::{DataHash, GroupId, LogEntry, LogIndex, Transferring};
+use juicebox_marshalling as marshalling;
+use juicebox_realm_api::types::RealmId;
+use observability::metrics_tag as tag;
+use retry_loop::{retry_logging, Retry, RetryError};
+
+/// The maximum number of rows to delete in a single Bigtable request.
+const DELETE_BATCH_SIZE: usize = 1000;
+
+/// Controls a single run of [`StoreClient::collect_merkle_garbage`].
+#[derive(Clone, Debug)]
+pub struct MerkleGcOptions {
+    /// Unreachable nodes are only deleted if they were written at least this
+    /// long before the collection started.
+    ///
+    /// This protects nodes that an agent has written for a log entry that it
+    /// hasn't appended yet. It must be much longer than it takes to append a
+    /// log entry, and longer than the collection itself takes to run.
+    pub min_age: Duration,
+
+    /// The number of log entries at the end of each group's log whose Merkle
+    /// roots are treated as live.
+    ///
+    /// An HSM will accept proofs from any of the roots in its tree overlay,
+    /// so this should be at least as large as the HSMs' `tree_overlay_size`.
+    pub roots_per_group: u16,
+
+    /// If set, report what would be deleted but don't delete anything.
+    pub dry_run: bool,
+}
+
+impl Default for MerkleGcOptions {
+    fn default() -> Self {
+        Self {
+            min_age: Duration::from_secs(60 * 60),
+            roots_per_group: 1024,
+            dry_run: false,
+        }
+    }
+}
+
+/// Summarizes a run of [`StoreClient::collect_merkle_garbage`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MerkleGcReport {
+    /// The number of groups found in the realm's log.
+    pub groups: usize,
+    /// The number of distinct Merkle roots that the trees were walked from.
+    pub roots: usize,
+    /// The number of rows read from the Merkle table.
+    pub nodes_scanned: usize,
+    /// The number of rows reachable from at least one root.
+    pub nodes_reachable: usize,
+    /// The number of rows not reachable from any root.
+    pub nodes_unreachable: usize,
+    /// The number of unreachable rows that had cells younger than
+    /// [`MerkleGcOptions::min_age`]. Those cells are kept.
+    pub nodes_too_young: usize,
+    /// The number of cells that were deleted (or would have been, for a dry
+    /// run).
+    pub cells_deleted: usize,
+    /// The number of nodes referenced by older roots that no longer exist.
+    /// This is normal, since the agents delete those nodes themselves.
+    pub missing_from_older_roots: usize,
+    /// The number of reachable rows whose node couldn't be deserialized. The
+    /// nodes below them can't be marked as live, so nothing is deleted when
+    /// this is non-zero.
+    pub nodes_corrupt: usize,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MerkleGcError {
+    #[error("failed to list the groups in the log: {0}")]
+    ListGroups(RetryError<tonic::Status>),
+
+    #[error("failed to read the last log entry for group {group:?}: {error}")]
+    ReadLastEntry {
+        group: GroupId,
+        error: ReadLastLogEntryError,
+    },
+
+    #[error("failed to read log entries for group {group:?}: {error}")]
+    ReadEntries {
+        group: GroupId,
+        error: RetryError<LogEntriesIterError, tonic::Status>,
+    },
+
+    #[error("failed to scan the Merkle table: {0}")]
+    Scan(RetryError<tonic::Status>),
+
+    /// A node reachable from the latest root of a group wasn't found in the
+    /// Merkle table. This can happen if the tree changed while the table was
+    /// being scanned. Nothing is deleted, since the nodes below it can't be
+    /// reached to be marked as live.
+    #[error("Merkle node {key:?} reachable from the latest root of group {group:?} is missing")]
+    IncompleteTree { group: GroupId, key: StoreKey },
+
+    #[error("failed to delete Merkle nodes: {0}")]
+    Delete(RetryError<MutateRowsError>),
+}
+
+/// A Merkle tree root that the collector treats as live.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Root {
+    group: GroupId,
+    hash: DataHash,
+    /// If set, every node reachable from this root must be present in the
+    /// scan, otherwise the collection is aborted.
+    must_be_complete: bool,
+}
+
+/// The parts of a Merkle table row needed for collection.
+pub(super) struct ScannedRow {
+    /// The serialized node from the first cell in the row.
+    pub(super) node: Vec<u8>,
+    /// The column qualifier (instance ID) and timestamp of every cell.
+    pub(super) cells: Vec<(Vec<u8>, i64)>,
+}
+
+/// Cells chosen to be deleted, along with the stats that go into
+/// [`MerkleGcReport`].
+#[derive(Debug, Default, Eq, PartialEq)]
+struct Garbage {
+    cells: Vec<(StoreKey, Vec<u8>)>,
+    nodes_reachable: usize,
+    nodes_unreachable: usize,
+    nodes_too_young: usize,
+    missing_from_older_roots: usize,
+    nodes_corrupt: usize,
+}
+
+impl StoreClient {
+    /// Finds and deletes Merkle nodes that aren't reachable from any recent
+    /// root in the realm. See the [module-level documentation](self) for
+    /// details.
+    #[instrument(level = "trace", skip(self))]
+    pub async fn collect_merkle_garbage(
+        &self,
+        realm: &RealmId,
+        options: &MerkleGcOptions,
+    ) -> Result<MerkleGcReport, MerkleGcError> {
+        let start = Instant::now();
+        let tags = [tag!(?realm), tag!("dry_run": options.dry_run)];
+
+        // The cutoff is calculated before reading the roots. Every node that
+        // a future root might reference is either reachable from a root read
+        // below or was written after the cutoff.
+        let cutoff = to_micros(
+            SystemTime::now()
+                .checked_sub(options.min_age)
+                .unwrap_or(SystemTime::UNIX_EPOCH)
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap(),
+        );
+
+        let groups = self
+            .list_log_groups(realm)
+            .await
+            .map_err(MerkleGcError::ListGroups)?;
+        let mut roots: Vec<Root> = Vec::new();
+        for group in &groups {
+            roots.extend(
+                self.recent_merkle_roots(realm, group, options.roots_per_group)
+                    .await?,
+            );
+        }
+        info!(
+            ?realm,
+            groups = groups.len(),
+            roots = roots.len(),
+            "read recent Merkle roots"
+        );
+
+        let rows = self
+            .scan_merkle_table(realm)
+            .await
+            .map_err(MerkleGcError::Scan)?;
+        let nodes_scanned = rows.len();
+        info!(?realm, nodes = nodes_scanned, "scanned Merkle table");
+
+        let garbage = find_garbage(&rows, &roots, cutoff)?;
+        drop(rows);
+
+        let report = MerkleGcReport {
+            groups: groups.len(),
+            roots: roots.len(),
+            nodes_scanned,
+            nodes_reachable: garbage.nodes_reachable,
+            nodes_unreachable: garbage.nodes_unreachable,
+            nodes_too_young: garbage.nodes_too_young,
+            cells_deleted: garbage.cells.len(),
+            missing_from_older_roots: garbage.missing_from_older_roots,
+            nodes_corrupt: garbage.nodes_corrupt,
+        };
+
+        if !options.dry_run {
+            self.delete_merkle_cells(realm, garbage.cells).await?;
+        }
+
+        let metrics = &self.0.metrics;
+        metrics.gauge("store_client.merkle_gc.roots", report.roots, &tags);
+        metrics.gauge("store_client.merkle_gc.nodes_scanned", nodes_scanned, &tags);
+        metrics.gauge(
+            "store_client.merkle_gc.nodes_reachable",
+            report.nodes_reachable,
+            &tags,
+        );
+        metrics.gauge(
+            "store_client.merkle_gc.nodes_unreachable",
+            report.nodes_unreachable,
+            &tags,
+        );
+        metrics.gauge(
+            "store_client.merkle_gc.nodes_too_young",
+            report.nodes_too_young,
+            &tags,
+        );
+        metrics.gauge(
+            "store_client.merkle_gc.nodes_corrupt",
+            report.nodes_corrupt,
+            &tags,
+        );
+        metrics.count(
+            "store_client.merkle_gc.cells_deleted",
+            i64::try_from(report.cells_deleted).unwrap(),
+            &tags,
+        );
+        metrics.timing("store_client.merkle_gc.time", start.elapsed(), &tags);
+        info!(
+            ?realm,
+            ?report,
+            dry_run = options.dry_run,
+            "Merkle garbage collection completed"
+        );
+        Ok(report)
+    }
+
+    /// Returns the Merkle roots from the last `count` entries in the group's
+    /// log, plus the root of any partition that the group is transferring
+    /// out. The latest root is listed first.
+    async fn recent_merkle_roots(
+        &self,
+        realm: &RealmId,
+        group: &GroupId,
+        count: u16,
+    ) -> Result<Vec<Root>, MerkleGcError> {
+        let last = match self.read_last_log_entry(realm, group).await {
+            Ok(entry) => entry,
+            Err(RetryError::Fatal {
+                error: ReadLastLogEntryFatal::EmptyLog,
+            }) => return Ok(Vec::new()),
+            Err(error) => {
+                return Err(MerkleGcError::ReadLastEntry {
+                    group: *group,
+                    error,
+                })
+            }
+        };
+
+        let mut roots = Vec::new();
+        add_roots(&mut roots, group, &last, true);
+
+        // Older entries may have been compacted already, in which case the
+        // read is restarted after the compacted entry.
+        let mut next = LogIndex(
+            last.index
+                .0
+                .saturating_sub(u64::from(count))
+                .max(LogIndex::FIRST.0),
+        );
+        'outer: while next < last.index {
+            let mut it = self.read_log_entries_iter(*realm, *group, next, count.max(1));
+            loop {
+                match it.next().await {
+                    Ok(entries) if entries.is_empty() => break 'outer,
+                    Ok(entries) => {
+                        for entry in entries {
+                            if entry.index >= last.index {
+                                break 'outer;
+                            }
+                            add_roots(&mut roots, group, &entry, false);
+                            next = entry.index.next();
+                        }
+                    }
+                    Err(RetryError::Fatal {
+                        error: LogEntriesIterError::Compacted(index),
+                    }) => {
+                        next = index.next();
+                        continue 'outer;
+                    }
+                    Err(error) => {
+                        return Err(MerkleGcError::ReadEntries {
+                            group: *group,
+                            error,
+                        })
+                    }
+                }
+            }
+        }
+
+        let mut seen = HashSet::new();
+        roots.retain(|root| seen.insert((root.hash, root.must_be_complete)));
+        Ok(roots)
+    }
+
+    /// Reads every row of the realm's Merkle table.
+    pub(super) async fn scan_merkle_table(
+        &self,
+        realm: &RealmId,
+    ) -> Result<HashMap<StoreKey, ScannedRow>, RetryError<tonic::Status>> {
+        let mut rows: HashMap<StoreKey, ScannedRow> = HashMap::new();
+        Reader::read_rows_stream(
+            &mut self.0.bigtable.clone(),
+            Retry::new("scanning Merkle table")
+                .with(bigtable_retries)
+                .with_deadline(None)
+                .with_metrics(
+                    &self.0.metrics,
+                    "store_client.scan_merkle_table",
+                    &[tag!(?realm)],
+                ),
+            ReadRowsRequest {
+                table_name: merkle_table(&self.0.instance, realm),
+                app_profile_id: String::new(),
+                rows: None,
+                filter: None,
+                rows_limit: 0,
+                request_stats_view: read_rows_request::RequestStatsView::RequestStatsNone.into(),
+                reversed: false,
+            },
+            |row_key, cells| {
+                let cells: Vec<_> = cells
+                    .into_iter()
+                    .filter(|cell| cell.family == "f")
+                    .collect();
+                let Some(first) = cells.first() else {
+                    return;
+                };
+                rows.insert(
+                    StoreKey::from(row_key.0),
+                    ScannedRow {
+                        node: first.value.clone(),
+                        cells: cells
+                            .into_iter()
+                            .map(|cell| (cell.qualifier, cell.timestamp))
+                            .collect(),
+                    },
+                );
+            },
+        )
+        .await?;
+        Ok(rows)
+    }
+
+    /// Deletes the given cells from the Merkle table. Deletes are by instance
+    /// ID, so a node that has been rewritten since the scan is left alone.
+    async fn delete_merkle_cells(
+        &self,
+        realm: &RealmId,
+        cells: Vec<(StoreKey, Vec<u8>)>,
+    ) -> Result<(), MerkleGcError> {
+        let tags = [tag!(?realm)];
+        for batch in cells.chunks(DELETE_BATCH_SIZE) {
+            let run = |_| async {
+                mutate_rows(
+                    &mut self.0.bigtable.clone(),
+                    MutateRowsRequest {
+                        table_name: merkle_table(&self.0.instance, realm),
+                        app_profile_id: String::new(),
+                        entries: batch
+                            .iter()
+                            .map(|(key, qualifier)| mutate_rows_request::Entry {
+                                row_key: key.as_slice().to_vec(),
+                                mutations: vec![Mutation {
+                                    mutation: Some(mutation::Mutation::DeleteFromColumn(
+                                        mutation::DeleteFromColumn {
+                                            family_name: String::from("f"),
+                                            column_qualifier: qualifier.clone(),
+                                            time_range: None,
+                                        },
+                                    )),
+                                }],
+                            })
+                            .collect(),
+                    },
+                )
+                .await?;
+                Ok(())
+            };
+            Retry::new("deleting orphaned Merkle nodes")
+                .with(bigtable_retries)
+                .with_metrics(&self.0.metrics, "store_client.merkle_gc.delete", &tags)
+                .retry(run, retry_logging!())
+                .await
+                .map_err(MerkleGcError::Delete)?;
+        }
+        Ok(())
+    }
+}
+
+/// Adds the Merkle roots referenced by a log entry.
+fn add_roots(roots: &mut Vec<Root>, group: &GroupId, entry: &LogEntry, latest: bool) {
+    if let Some(partition) = &entry.partition {
+        roots.push(Root {
+            group: *group,
+            hash: partition.root_hash,
+            must_be_complete: latest,
+        });
+    }
+    if let Some(Transferring::Out(out)) = &entry.transferring {
+        // The source group may have already deleted some of these nodes if
+        // the transfer is far enough along, so they're not required.
+        roots.push(Root {
+            group: *group,
+            hash: out.partition.root_hash,
+            must_be_complete: false,
+        });
+    }
+}
+
+/// Walks the trees from `roots` over the scanned `rows`, and returns the cells
+/// of unreachable nodes that were written before `cutoff` (in microseconds
+/// since the Unix epoch).
+///
+/// A reachable row that can't be deserialized is logged and skipped. Since
+/// the nodes below it can't be marked as live, no cells are returned if any
+/// are found.
+fn find_garbage(
+    rows: &HashMap<StoreKey, ScannedRow>,
+    roots: &[Root],
+    cutoff: i64,
+) -> Result<Garbage, MerkleGcError> {
+    let mut reachable: HashSet<StoreKey> = HashSet::new();
+    let mut missing_from_older_roots = 0;
+    let mut nodes_corrupt = 0;
+
+    // Walk the roots that must be complete first, so that an incomplete one
+    // is always reported, even if an older root shares the missing node.
+    let mut ordered: Vec<&Root> = roots.iter().collect();
+    ordered.sort_by_key(|root| !root.must_be_complete);
+
+    let mut stack: Vec<NodeKey<DataHash>> = Vec::new();
+    for root in ordered {
+        stack.push(NodeKey::new(KeyVec::new(), root.hash));
+        while let Some(key) = stack.pop() {
+            let store_key = StoreKey::from(&key);
+            if reachable.contains(&store_key) {
+                continue;
+            }
+            let Some(row) = rows.get(&store_key) else {
+                if root.must_be_complete {
+                    return Err(MerkleGcError::IncompleteTree {
+                        group: root.group,
+                        key: store_key,
+                    });
+                }
+                missing_from_older_roots += 1;
+                continue;
+            };
+            match marshalling::from_slice::<Node<DataHash>>(&row.node) {
+                Ok(Node::Interior(interior)) => {
+                    for branch in [interior.left, interior.right].into_iter().flatten() {
+                        stack.push(NodeKey::new(key.prefix.concat(&branch.prefix), branch.hash));
+                    }
+                }
+                Ok(Node::Leaf(_)) => {}
+                Err(error) => {
+                    warn!(key = ?store_key, ?error, "skipping corrupt Merkle node");
+                    nodes_corrupt += 1;
+                }
+            }
+            reachable.insert(store_key);
+        }
+    }
+
+    let mut garbage = Garbage {
+        nodes_reachable: reachable.len(),
+        missing_from_older_roots,
+        nodes_corrupt,
+        ..Garbage::default()
+    };
+    for (key, row) in rows {
+        if reachable.contains(key) {
+            continue;
+        }
+        garbage.nodes_unreachable += 1;
+        let mut too_young = false;
+        for (qualifier, timestamp) in &row.cells {
+            if *timestamp < cutoff {
+                garbage.cells.push((key.clone(), qualifier.clone()));
+            } else {
+                too_young = true;
+            }
+        }
+        if too_young {
+            garbage.nodes_too_young += 1;
+        }
+    }
+    if garbage.nodes_corrupt > 0 {
+        warn!(
+            corrupt = garbage.nodes_corrupt,
+            unreachable = garbage.nodes_unreachable,
+            "found corrupt Merkle nodes, not deleting anything"
+        );
+        garbage.cells.clear();
+    }
+    if garbage.missing_from_older_roots > 0 {
+        warn!(
+            missing = garbage.missing_from_older_roots,
+            "some nodes reachable from older roots were already deleted"
+        );
+    }
+    Ok(garbage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitvec::bitvec;
+    use hsm_api::merkle::{Branch, InteriorNode, LeafNode};
+
+    const GROUP: GroupId = GroupId([7; 16]);
+
+    fn row(node: &Node<DataHash>, cells: &[(u8, i64)]) -> ScannedRow {
+        ScannedRow {
+            node: marshalling::to_vec(node).unwrap(),
+            cells: cells.iter().map(|(q, ts)| (vec![*q], *ts)).collect(),
+        }
+    }
+
+    fn leaf() -> Node<DataHash> {
+        Node::Leaf(LeafNode { value: vec![1, 2] })
+    }
+
+    // root -> (left leaf, right leaf), plus an orphaned leaf.
+    fn tree() -> (HashMap<StoreKey, ScannedRow>, DataHash) {
+        let root_hash = DataHash([1; 32]);
+        let left = NodeKey::new(bitvec![0, 1], DataHash([2; 32]));
+        let right = NodeKey::new(bitvec![1], DataHash([3; 32]));
+        let orphan = NodeKey::new(bitvec![0, 0], DataHash([4; 32]));
+        let root = Node::Interior(InteriorNode::new(
+            Some(Branch::new(left.prefix.clone(), left.hash)),
+            Some(Branch::new(right.prefix.clone(), right.hash)),
+        ));
+        let rows = HashMap::from([
+            (
+                StoreKey::from(NodeKey::new(KeyVec::new(), root_hash)),
+                row(&root, &[(1, 10)]),
+            ),
+            (StoreKey::from(&left), row(&leaf(), &[(2, 10)])),
+            (StoreKey::from(&right), row(&leaf(), &[(3, 10)])),
+            (StoreKey::from(&orphan), row(&leaf(), &[(4, 10), (5, 50)])),
+        ]);
+        (rows, root_hash)
+    }
+
+    #[test]
+    fn find_garbage_deletes_old_unreachable_cells() {
+        let (rows, root_hash) = tree();
+        let roots = [Root {
+            group: GROUP,
+            hash: root_hash,
+            must_be_complete: true,
+        }];
+        let garbage = find_garbage(&rows, &roots, 20).unwrap();
+        assert_eq!(3, garbage.nodes_reachable);
+        assert_eq!(1, garbage.nodes_unreachable);
+        assert_eq!(1, garbage.nodes_too_young);
+        assert_eq!(
+            vec![(
+                StoreKey::from(NodeKey::new(bitvec![0, 0], DataHash([4; 32]))),
+                vec![4]
+            )],
+            garbage.cells
+        );
+
+        let garbage = find_garbage(&rows, &roots, 100).unwrap();
+        assert_eq!(0, garbage.nodes_too_young);
+        assert_eq!(2, garbage.cells.len());
+    }
+
+    #[test]
+    fn find_garbage_incomplete_tree() {
+        let (mut rows, root_hash) = tree();
+        let right = StoreKey::from(NodeKey::new(bitvec![1], DataHash([3; 32])));
+        rows.remove(&right);
+
+        let older = Root {
+            group: GROUP,
+            hash: root_hash,
+            must_be_complete: false,
+        };
+        let garbage = find_garbage(&rows, &[older.clone()], 100).unwrap();
+        assert_eq!(1, garbage.missing_from_older_roots);
+        assert_eq!(2, garbage.nodes_reachable);
+
+        let latest = Root {
+            must_be_complete: true,
+            ..older.clone()
+        };
+        match find_garbage(&rows, &[older, latest], 100) {
+            Err(MerkleGcError::IncompleteTree { group, key }) => {
+                assert_eq!(GROUP, group);
+                assert_eq!(right, key);
+            }
+            other => panic!("unexpected result {other:?}"),
+        }
+    }
+
+    #[test]
+    fn find_garbage_corrupt_node() {
+        let (mut rows, root_hash) = tree();
+        let right = StoreKey::from(NodeKey::new(bitvec![1], DataHash([3; 32])));
+        rows.get_mut(&right).unwrap().node = vec![0xff, 0x00];
+        let roots = [Root {
+            group: GROUP,
+            hash: root_hash,
+            must_be_complete: true,
+        }];
+        let garbage = find_garbage(&rows, &roots, 100).unwrap();
+        assert_eq!(1, garbage.nodes_corrupt);
+        assert_eq!(3, garbage.nodes_reachable);
+        assert_eq!(1, garbage.nodes_unreachable);
+        assert!(garbage.cells.is_empty());
+    }
+
+    #[test]
+    fn find_garbage_no_roots() {
+        let (rows, _) = tree();
+        let garbage = find_garbage(&rows, &[], 100).unwrap();
+        assert_eq!(0, garbage.nodes_reachable);
+        assert_eq!(4, garbage.nodes_unreachable);
+        assert_eq!(5, garbage.cells.len());
+    }
+}
@@ -0,0 +1,220 @@
+use bigtable::read::Reader;
+use bigtable::{bigtable_retries, inspect_grpc_error, Instance};
+use google::bigtable::admin::v2::table::TimestampGranularity;
+use google::bigtable::admin::v2::{ColumnFamily, CreateTableRequest, GcRule, Table};
+use google::bigtable::v2::{
+    mutation, read_rows_request, MutateRowRequest, Mutation, ReadRowsRequest, RowSet,
+};
+use retry_loop::{retry_logging, Retry, RetryError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::SystemTime;
+use tracing::warn;
+
+use super::{BigtableTableAdminClient, StoreClient};
+use hsm_api::RecordId;
+use juicebox_realm_api::types::RealmId;
+
+const FAMILY: &str = "f";
+const COLUMN_NAME: &[u8] = b"p";
+const TABLE_NAME: &str = "offboarding";
+
+/// The progress of deleting all of a tenant's records from a realm.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TenantOffboarding {
+    pub realm: RealmId,
+    pub tenant: String,
+    /// Who authorized the offboarding on behalf of the tenant.
+    pub requested_by: String,
+    pub started: SystemTime,
+    /// Set once every record has been processed.
+    pub finished: Option<SystemTime>,
+    /// Every record up to and including this one has been processed.
+    pub checkpoint: Option<RecordId>,
+    /// The number of records that had a secret that was deleted.
+    pub deleted: u64,
+    /// The number of records that had no secret to delete.
+    pub not_registered: u64,
+    /// The most recent error that stopped the offboarding, if it's not
+    /// finished. The offboarding resumes from the checkpoint after an error.
+    pub last_error: Option<String>,
+}
+
+impl TenantOffboarding {
+    pub fn new(realm: RealmId, tenant: String, requested_by: String) -> Self {
+        Self {
+            realm,
+            tenant,
+            requested_by,
+            started: SystemTime::now(),
+            finished: None,
+            checkpoint: None,
+            deleted: 0,
+            not_registered: 0,
+            last_error: None,
+        }
+    }
+}
+
+pub fn offboarding_table(instance: &Instance) -> String {
+    format!("{path}/tables/{TABLE_NAME}", path = instance.path())
+}
+
+fn row_key(realm: &RealmId, tenant: &str) -> Vec<u8> {
+    format!("{realm:?}:{tenant}").into_bytes()
+}
+
+pub(crate) async fn initialize(
+    bigtable: &mut BigtableTableAdminClient,
+    instance: &Instance,
+) -> Result<(), tonic::Status> {
+    // This is not realm-specific, so it might already exist.
+    if let Err(err) = bigtable
+        .create_table(CreateTableRequest {
+            parent: instance.path(),
+            table_id: String::from(TABLE_NAME),
+            table: Some(Table {
+                name: String::from(""),
+                cluster_states: HashMap::new(),
+                column_families: HashMap::from([(
+                    FAMILY.to_string(),
+                    ColumnFamily {
+                        gc_rule: Some(GcRule { rule: None }),
+                    },
+                )]),
+                granularity: TimestampGranularity::Unspecified.into(),
+                restore_info: None,
+                change_stream_config: None,
+                deletion_protection: false,
+            }),
+            initial_splits: Vec::new(),
+        })
+        .await
+    {
+        if err.code() != tonic::Code::AlreadyExists {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+impl StoreClient {
+    /// Returns the progress of offboarding the tenant from the realm, or
+    /// `None` if it was never started.
+    pub async fn get_tenant_offboarding(
+        &self,
+        realm: &RealmId,
+        tenant: &str,
+    ) -> Result<Option<TenantOffboarding>, RetryError<tonic::Status>> {
+        Ok(self
+            .read_offboardings(Some(RowSet {
+                row_keys: vec![row_key(realm, tenant)],
+                row_ranges: Vec::new(),
+            }))
+            .await?
+            .pop())
+    }
+
+    /// Returns the progress of every tenant offboarding that was started,
+    /// including finished ones.
+    pub async fn list_tenant_offboardings(
+        &self,
+    ) -> Result<Vec<TenantOffboarding>, RetryError<tonic::Status>> {
+        self.read_offboardings(None).await
+    }
+
+    async fn read_offboardings(
+        &self,
+        rows: Option<RowSet>,
+    ) -> Result<Vec<TenantOffboarding>, RetryError<tonic::Status>> {
+        let rows = match Reader::read_rows(
+            &mut self.0.bigtable.clone(),
+            Retry::new("read Bigtable offboarding table")
+                .with(bigtable_retries)
+                .with_metrics(&self.0.metrics, "store_client.offboarding.read", &[]),
+            ReadRowsRequest {
+                table_name: offboarding_table(&self.0.instance),
+                app_profile_id: String::new(),
+                rows,
+                filter: None,
+                rows_limit: 0,
+                request_stats_view: read_rows_request::RequestStatsView::RequestStatsNone.into(),
+                reversed: false,
+            },
+        )
+        .await
+        {
+            Ok(rows) => rows,
+            Err(RetryError::Fatal { error }) if error.code() == tonic::Code::NotFound => {
+                warn!(
+                    error = error.message(),
+                    "couldn't read from Bigtable offboarding table \
+                (the cluster manager should create it)"
+                );
+                return Ok(Vec::new());
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|(key, cells)| {
+                let cell = cells
+                    .into_iter()
+                    .find(|c| c.family == FAMILY && c.qualifier == COLUMN_NAME)?;
+                match juicebox_marshalling::from_slice(&cell.value) {
+                    Ok(offboarding) => Some(offboarding),
+                    Err(err) => {
+                        warn!(?key, ?err, "couldn't deserialize tenant offboarding");
+                        None
+                    }
+                }
+            })
+            .collect())
+    }
+
+    /// Records the progress of offboarding a tenant, replacing any previous
+    /// progress.
+    pub async fn put_tenant_offboarding(
+        &self,
+        offboarding: &TenantOffboarding,
+    ) -> Result<(), RetryError<tonic::Status>> {
+        let run = |_| async {
+            let request = MutateRowRequest {
+                table_name: offboarding_table(&self.0.instance),
+                app_profile_id: String::new(),
+                row_key: row_key(&offboarding.realm, &offboarding.tenant),
+                mutations: vec![
+                    Mutation {
+                        mutation: Some(mutation::Mutation::DeleteFromFamily(
+                            mutation::DeleteFromFamily {
+                                family_name: String::from(FAMILY),
+                            },
+                        )),
+                    },
+                    Mutation {
+                        mutation: Some(mutation::Mutation::SetCell(mutation::SetCell {
+                            family_name: String::from(FAMILY),
+                            column_qualifier: COLUMN_NAME.to_vec(),
+                            timestamp_micros: -1,
+                            value: juicebox_marshalling::to_vec(offboarding).expect("TODO"),
+                        })),
+                    },
+                ],
+            };
+            self.0
+                .bigtable
+                .clone()
+                .mutate_row(request)
+                .await
+                .map_err(inspect_grpc_error)?;
+            Ok(())
+        };
+
+        Retry::new("updating tenant offboarding")
+            .with(bigtable_retries)
+            .with_metrics(&self.0.metrics, "store_client.offboarding.write", &[])
+            .retry(run, retry_logging!())
+            .await
+    }
+}
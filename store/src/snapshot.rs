@@ -0,0 +1,572 @@
+//! Export and import of realm snapshots.
+//!
+//! A snapshot captures enough of a realm's state for HSMs that hold the
+//! realm's keys to resume from it in another store instance. For each group,
+//! it contains the last log entry and every Merkle node reachable from that
+//! entry's roots. It also contains the tenant configurations and the realm's
+//! user accounting rows.
+//!
+//! The groups and tenant configurations are consistent as of
+//! [`RealmSnapshot::created`]: the log entries are the last ones when the
+//! export read them, and the configurations are read at that timestamp. The
+//! user accounting rows can't be read that way, since their cells are
+//! timestamped by day and overwritten in place. They're read after the groups,
+//! so they may include events from log entries after the snapshot, and they
+//! miss events that agents hadn't written yet. Agents write these rows
+//! asynchronously after commits anyway, so the counts built from them already
+//! lag behind the log by a similar amount.
+//!
+//! Snapshots are encoded as a small header followed by the marshalled
+//! [`RealmSnapshot`]. The header holds a magic value, a format version, and a
+//! SHA-256 checksum of the body.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::SystemTime;
+use tracing::{info, instrument, warn};
+
+use super::log::{ReadLastLogEntryError, ReadLastLogEntryFatal};
+use super::merkle::StoreKey;
+use super::merkle_gc::ScannedRow;
+use super::tenant_config::TenantConfiguration;
+use super::tenants::UserAccountingRow;
+use super::{AppendError, StoreClient};
+use bigtable::mutate::MutateRowsError;
+use hsm_api::merkle::{KeyVec, Node, NodeKey};
+use hsm_api::{DataHash, GroupId, LogEntry, Transferring};
+use juicebox_marshalling as marshalling;
+use juicebox_realm_api::types::RealmId;
+use retry_loop::RetryError;
+
+/// Identifies a snapshot file.
+const MAGIC: &[u8; 8] = b"jbsnap\0\0";
+
+/// The current snapshot format version. Bump this whenever [`RealmSnapshot`]
+/// changes in an incompatible way.
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+const CHECKSUM_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + 2 + CHECKSUM_LEN;
+
+/// How many times to re-read the realm when a group's tree changes while it's
+/// being exported.
+const EXPORT_ATTEMPTS: usize = 3;
+
+/// A consistent copy of a realm's state. See the [module-level
+/// documentation](self) for details.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RealmSnapshot {
+    pub realm: RealmId,
+    /// When the groups' last log entries were read. The tenant configurations
+    /// are as of this time too.
+    pub created: SystemTime,
+    pub groups: Vec<GroupSnapshot>,
+    pub tenants: Vec<(String, TenantConfiguration)>,
+    /// These are read after `created`, so they're not exactly consistent with
+    /// the groups. See the [module-level documentation](self).
+    pub user_accounting: Vec<UserAccountingRow>,
+}
+
+/// The state of a single group within a [`RealmSnapshot`].
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct GroupSnapshot {
+    pub group: GroupId,
+    /// The last entry in the group's log. The HSMs resume from here.
+    pub last_entry: LogEntry,
+    /// Every Merkle node reachable from the roots in `last_entry`.
+    pub merkle_nodes: Vec<(NodeKey<DataHash>, Node<DataHash>)>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error("failed to list the groups in the log: {0}")]
+    ListGroups(RetryError<tonic::Status>),
+
+    #[error("failed to read the last log entry for group {group:?}: {error}")]
+    ReadLastEntry {
+        group: GroupId,
+        error: ReadLastLogEntryError,
+    },
+
+    #[error("failed to scan the Merkle table: {0}")]
+    ScanMerkle(RetryError<tonic::Status>),
+
+    /// A node reachable from a group's last log entry wasn't found in the
+    /// Merkle table, even after re-reading the realm. This happens if the
+    /// group keeps appending during the export.
+    #[error("Merkle node {key:?} reachable from the last log entry of group {group:?} is missing")]
+    IncompleteTree { group: GroupId, key: StoreKey },
+
+    /// A node reachable from a group's last log entry couldn't be decoded.
+    #[error("Merkle node {key:?} reachable from the last log entry of group {group:?} is corrupt: {error}")]
+    CorruptNode {
+        group: GroupId,
+        key: StoreKey,
+        error: String,
+    },
+
+    #[error("failed to read tenant configurations: {0}")]
+    ReadTenants(RetryError<tonic::Status>),
+
+    #[error("failed to read user accounting rows: {0}")]
+    ReadUserAccounting(RetryError<tonic::Status>),
+
+    #[error("the log for group {0:?} is not empty")]
+    LogNotEmpty(GroupId),
+
+    #[error("failed to write Merkle nodes for group {group:?}: {error}")]
+    WriteMerkle {
+        group: GroupId,
+        error: RetryError<MutateRowsError>,
+    },
+
+    #[error("failed to write the log entry for group {group:?}: {error}")]
+    WriteLog { group: GroupId, error: AppendError },
+
+    #[error("failed to write configuration for tenant {tenant:?}: {error}")]
+    WriteTenant {
+        tenant: String,
+        error: RetryError<tonic::Status>,
+    },
+
+    #[error("failed to write user accounting rows: {0}")]
+    WriteUserAccounting(RetryError<MutateRowsError>),
+}
+
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum SnapshotEncodeError {
+    #[error("failed to encode snapshot: {0}")]
+    Encode(String),
+}
+
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum SnapshotDecodeError {
+    #[error("not a realm snapshot")]
+    BadMagic,
+
+    #[error("unsupported snapshot version {0} (expected {SNAPSHOT_VERSION})")]
+    UnsupportedVersion(u16),
+
+    #[error("snapshot checksum mismatch")]
+    ChecksumMismatch,
+
+    #[error("failed to decode snapshot: {0}")]
+    Decode(String),
+}
+
+impl RealmSnapshot {
+    /// Serializes the snapshot, including its header.
+    pub fn encode(&self) -> Result<Vec<u8>, SnapshotEncodeError> {
+        let body = marshalling::to_vec(self)
+            .map_err(|err| SnapshotEncodeError::Encode(err.to_string()))?;
+        let mut out = Vec::with_capacity(HEADER_LEN + body.len());
+        out.extend(MAGIC);
+        out.extend(SNAPSHOT_VERSION.to_be_bytes());
+        out.extend(Sha256::digest(&body));
+        out.extend(body);
+        Ok(out)
+    }
+
+    /// Parses a snapshot created by [`Self::encode`], verifying its header.
+    pub fn decode(bytes: &[u8]) -> Result<Self, SnapshotDecodeError> {
+        if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(SnapshotDecodeError::BadMagic);
+        }
+        let (version, rest) = bytes[MAGIC.len()..].split_at(2);
+        let version = u16::from_be_bytes(version.try_into().unwrap());
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotDecodeError::UnsupportedVersion(version));
+        }
+        let (checksum, body) = rest.split_at(CHECKSUM_LEN);
+        if Sha256::digest(body).as_slice() != checksum {
+            return Err(SnapshotDecodeError::ChecksumMismatch);
+        }
+        marshalling::from_slice(body).map_err(|err| SnapshotDecodeError::Decode(err.to_string()))
+    }
+}
+
+impl StoreClient {
+    /// Reads a consistent snapshot of the realm. See the [module-level
+    /// documentation](self) for details.
+    ///
+    /// The Merkle table is held in memory while the trees are walked.
+    #[instrument(level = "trace", skip(self))]
+    pub async fn export_snapshot(&self, realm: &RealmId) -> Result<RealmSnapshot, SnapshotError> {
+        let groups = self
+            .list_log_groups(realm)
+            .await
+            .map_err(SnapshotError::ListGroups)?;
+
+        let mut attempt = 1;
+        let (created, group_snapshots) = loop {
+            let created = SystemTime::now();
+            // The last entries are read before the Merkle table, so any node
+            // they reference was written before the scan starts. It may be
+            // deleted if the group appends more entries in the meantime,
+            // which is why this is retried.
+            let mut last_entries = Vec::with_capacity(groups.len());
+            for group in &groups {
+                match self.read_last_log_entry(realm, group).await {
+                    Ok(entry) => last_entries.push((*group, entry)),
+                    Err(RetryError::Fatal {
+                        error: ReadLastLogEntryFatal::EmptyLog,
+                    }) => {}
+                    Err(error) => {
+                        return Err(SnapshotError::ReadLastEntry {
+                            group: *group,
+                            error,
+                        })
+                    }
+                }
+            }
+
+            let rows = self
+                .scan_merkle_table(realm)
+                .await
+                .map_err(SnapshotError::ScanMerkle)?;
+
+            match snapshot_groups(&rows, last_entries) {
+                Ok(snapshots) => break (created, snapshots),
+                Err(err @ SnapshotError::IncompleteTree { .. }) if attempt < EXPORT_ATTEMPTS => {
+                    warn!(
+                        ?realm,
+                        ?err,
+                        attempt,
+                        "realm changed during export, retrying"
+                    );
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        };
+
+        let tenants = self
+            .get_tenants_at(created)
+            .await
+            .map_err(SnapshotError::ReadTenants)?;
+        let user_accounting = self
+            .read_user_accounting_rows(realm)
+            .await
+            .map_err(SnapshotError::ReadUserAccounting)?;
+
+        info!(
+            ?realm,
+            groups = group_snapshots.len(),
+            merkle_nodes = group_snapshots
+                .iter()
+                .map(|g| g.merkle_nodes.len())
+                .sum::<usize>(),
+            tenants = tenants.len(),
+            user_accounting_rows = user_accounting.len(),
+            "exported realm snapshot"
+        );
+        Ok(RealmSnapshot {
+            realm: *realm,
+            created,
+            groups: group_snapshots,
+            tenants,
+            user_accounting,
+        })
+    }
+
+    /// Writes a snapshot into this store. The realm's tables must already
+    /// exist, and the logs of the snapshot's groups must be empty.
+    ///
+    /// Tenant configurations in the snapshot replace any existing ones with
+    /// the same name.
+    #[instrument(level = "trace", skip(self, snapshot), fields(realm = ?snapshot.realm))]
+    pub async fn import_snapshot(&self, snapshot: &RealmSnapshot) -> Result<(), SnapshotError> {
+        let realm = &snapshot.realm;
+        for group in &snapshot.groups {
+            match self.read_last_log_entry(realm, &group.group).await {
+                Err(RetryError::Fatal {
+                    error: ReadLastLogEntryFatal::EmptyLog,
+                }) => {}
+                Ok(_) => return Err(SnapshotError::LogNotEmpty(group.group)),
+                Err(error) => {
+                    return Err(SnapshotError::ReadLastEntry {
+                        group: group.group,
+                        error,
+                    })
+                }
+            }
+        }
+
+        for group in &snapshot.groups {
+            // The nodes are written before the log entry, the same as a
+            // regular append.
+            let nodes: BTreeMap<NodeKey<DataHash>, Node<DataHash>> =
+                group.merkle_nodes.iter().cloned().collect();
+            self.write_merkle_nodes(realm, &group.group, &nodes)
+                .await
+                .map_err(|error| SnapshotError::WriteMerkle {
+                    group: group.group,
+                    error,
+                })?;
+
+            let mut bigtable = self.0.bigtable.clone();
+            self.log_append(
+                &mut bigtable,
+                realm,
+                &group.group,
+                std::slice::from_ref(&group.last_entry),
+            )
+            .await
+            .map_err(|error| SnapshotError::WriteLog {
+                group: group.group,
+                error,
+            })?;
+        }
+
+        for (tenant, config) in &snapshot.tenants {
+            self.update_tenant(tenant, config).await.map_err(|error| {
+                SnapshotError::WriteTenant {
+                    tenant: tenant.clone(),
+                    error,
+                }
+            })?;
+        }
+
+        self.write_user_accounting_rows(realm, &snapshot.user_accounting)
+            .await
+            .map_err(SnapshotError::WriteUserAccounting)?;
+
+        info!(
+            ?realm,
+            groups = snapshot.groups.len(),
+            tenants = snapshot.tenants.len(),
+            user_accounting_rows = snapshot.user_accounting.len(),
+            "imported realm snapshot"
+        );
+        Ok(())
+    }
+}
+
+/// Collects the Merkle nodes reachable from each group's last log entry out
+/// of the scanned `rows`.
+///
+/// Every node under the entry's partition must be present. Nodes under a
+/// partition that's being transferred out are included when present, since
+/// the group may have already deleted some of them.
+fn snapshot_groups(
+    rows: &HashMap<StoreKey, ScannedRow>,
+    last_entries: Vec<(GroupId, LogEntry)>,
+) -> Result<Vec<GroupSnapshot>, SnapshotError> {
+    let mut snapshots = Vec::with_capacity(last_entries.len());
+    for (group, last_entry) in last_entries {
+        let mut roots: Vec<(DataHash, bool)> = Vec::new();
+        if let Some(partition) = &last_entry.partition {
+            roots.push((partition.root_hash, true));
+        }
+        if let Some(Transferring::Out(out)) = &last_entry.transferring {
+            roots.push((out.partition.root_hash, false));
+        }
+
+        let mut visited: HashSet<StoreKey> = HashSet::new();
+        let mut merkle_nodes = Vec::new();
+        let mut stack: Vec<NodeKey<DataHash>> = Vec::new();
+        for (root, required) in roots {
+            stack.push(NodeKey::new(KeyVec::new(), root));
+            while let Some(key) = stack.pop() {
+                let store_key = StoreKey::from(&key);
+                if !visited.insert(store_key.clone()) {
+                    continue;
+                }
+                let Some(row) = rows.get(&store_key) else {
+                    if required {
+                        return Err(SnapshotError::IncompleteTree {
+                            group,
+                            key: store_key,
+                        });
+                    }
+                    continue;
+                };
+                let node: Node<DataHash> = match marshalling::from_slice(&row.node) {
+                    Ok(node) => node,
+                    Err(err) => {
+                        return Err(SnapshotError::CorruptNode {
+                            group,
+                            key: store_key,
+                            error: err.to_string(),
+                        });
+                    }
+                };
+                if let Node::Interior(interior) = &node {
+                    for branch in [&interior.left, &interior.right].into_iter().flatten() {
+                        stack.push(NodeKey::new(key.prefix.concat(&branch.prefix), branch.hash));
+                    }
+                }
+                merkle_nodes.push((key, node));
+            }
+        }
+
+        snapshots.push(GroupSnapshot {
+            group,
+            last_entry,
+            merkle_nodes,
+        });
+    }
+    Ok(snapshots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitvec::bitvec;
+    use hsm_api::merkle::{Branch, InteriorNode, LeafNode};
+    use hsm_api::{EntryMac, HsmId, LogIndex, OwnedRange, Partition};
+
+    const GROUP: GroupId = GroupId([7; 16]);
+
+    fn row(node: &Node<DataHash>) -> ScannedRow {
+        ScannedRow {
+            node: marshalling::to_vec(node).unwrap(),
+            cells: vec![(vec![1], 10)],
+        }
+    }
+
+    fn leaf() -> Node<DataHash> {
+        Node::Leaf(LeafNode { value: vec![1, 2] })
+    }
+
+    // root -> (left leaf, right leaf), plus an orphaned leaf.
+    fn tree() -> (HashMap<StoreKey, ScannedRow>, DataHash) {
+        let root_hash = DataHash([1; 32]);
+        let left = NodeKey::new(bitvec![0, 1], DataHash([2; 32]));
+        let right = NodeKey::new(bitvec![1], DataHash([3; 32]));
+        let orphan = NodeKey::new(bitvec![0, 0], DataHash([4; 32]));
+        let root = Node::Interior(InteriorNode::new(
+            Some(Branch::new(left.prefix.clone(), left.hash)),
+            Some(Branch::new(right.prefix.clone(), right.hash)),
+        ));
+        let rows = HashMap::from([
+            (
+                StoreKey::from(NodeKey::new(KeyVec::new(), root_hash)),
+                row(&root),
+            ),
+            (StoreKey::from(&left), row(&leaf())),
+            (StoreKey::from(&right), row(&leaf())),
+            (StoreKey::from(&orphan), row(&leaf())),
+        ]);
+        (rows, root_hash)
+    }
+
+    fn entry(root_hash: DataHash) -> LogEntry {
+        LogEntry {
+            index: LogIndex(42),
+            partition: Some(Partition {
+                range: OwnedRange::full(),
+                root_hash,
+            }),
+            transferring: None,
+            prev_mac: EntryMac::from([3; 32]),
+            entry_mac: EntryMac::from([4; 32]),
+            hsm: HsmId([5; 16]),
+        }
+    }
+
+    fn snapshot() -> RealmSnapshot {
+        let (rows, root_hash) = tree();
+        RealmSnapshot {
+            realm: RealmId([6; 16]),
+            created: SystemTime::UNIX_EPOCH,
+            groups: snapshot_groups(&rows, vec![(GROUP, entry(root_hash))]).unwrap(),
+            tenants: vec![(String::from("test-acme"), TenantConfiguration::new(10))],
+            user_accounting: vec![UserAccountingRow {
+                row_key: b"test-acme:00".to_vec(),
+                events: vec![(1_000_000, vec![1])],
+                user: Some((1_000_000, b"aa".to_vec())),
+                slot: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn snapshot_groups_collects_reachable_nodes() {
+        let (rows, root_hash) = tree();
+        let groups = snapshot_groups(&rows, vec![(GROUP, entry(root_hash))]).unwrap();
+        assert_eq!(1, groups.len());
+        assert_eq!(GROUP, groups[0].group);
+        assert_eq!(LogIndex(42), groups[0].last_entry.index);
+        let mut keys: Vec<_> = groups[0]
+            .merkle_nodes
+            .iter()
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+        assert_eq!(
+            vec![
+                NodeKey::new(KeyVec::new(), root_hash),
+                NodeKey::new(bitvec![0, 1], DataHash([2; 32])),
+                NodeKey::new(bitvec![1], DataHash([3; 32])),
+            ],
+            keys
+        );
+    }
+
+    #[test]
+    fn snapshot_groups_incomplete_tree() {
+        let (mut rows, root_hash) = tree();
+        let right = StoreKey::from(NodeKey::new(bitvec![1], DataHash([3; 32])));
+        rows.remove(&right);
+
+        match snapshot_groups(&rows, vec![(GROUP, entry(root_hash))]) {
+            Err(SnapshotError::IncompleteTree { group, key }) => {
+                assert_eq!(GROUP, group);
+                assert_eq!(right, key);
+            }
+            other => panic!("unexpected result {other:?}"),
+        }
+    }
+
+    #[test]
+    fn snapshot_groups_corrupt_node() {
+        let (mut rows, root_hash) = tree();
+        let right = StoreKey::from(NodeKey::new(bitvec![1], DataHash([3; 32])));
+        rows.get_mut(&right).unwrap().node = vec![0xff];
+
+        match snapshot_groups(&rows, vec![(GROUP, entry(root_hash))]) {
+            Err(SnapshotError::CorruptNode { group, key, .. }) => {
+                assert_eq!(GROUP, group);
+                assert_eq!(right, key);
+            }
+            other => panic!("unexpected result {other:?}"),
+        }
+    }
+
+    #[test]
+    fn encode_decode() {
+        let snapshot = snapshot();
+        assert_eq!(
+            Ok(snapshot.clone()),
+            RealmSnapshot::decode(&snapshot.encode().unwrap())
+        );
+    }
+
+    #[test]
+    fn decode_errors() {
+        let encoded = snapshot().encode().unwrap();
+
+        assert_eq!(
+            Err(SnapshotDecodeError::BadMagic),
+            RealmSnapshot::decode(&encoded[..4])
+        );
+
+        let mut bad_version = encoded.clone();
+        bad_version[MAGIC.len() + 1] += 1;
+        assert_eq!(
+            Err(SnapshotDecodeError::UnsupportedVersion(
+                SNAPSHOT_VERSION + 1
+            )),
+            RealmSnapshot::decode(&bad_version)
+        );
+
+        let mut corrupt = encoded;
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            Err(SnapshotDecodeError::ChecksumMismatch),
+            RealmSnapshot::decode(&corrupt)
+        );
+    }
+}
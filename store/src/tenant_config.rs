@@ -1,32 +1,129 @@
 use bigtable::read::Reader;
 use bigtable::{bigtable_retries, inspect_grpc_error, Instance};
+use google::bigtable::admin::v2::gc_rule::{self, Rule};
+use google::bigtable::admin::v2::modify_column_families_request::{modification, Modification};
 use google::bigtable::admin::v2::table::TimestampGranularity;
-use google::bigtable::admin::v2::{ColumnFamily, CreateTableRequest, GcRule, Table};
+use google::bigtable::admin::v2::{
+    ColumnFamily, CreateTableRequest, GcRule, ModifyColumnFamiliesRequest, Table,
+};
+use google::bigtable::v2::row_filter::{self, Filter};
 use google::bigtable::v2::{
-    mutation, read_rows_request, MutateRowRequest, Mutation, ReadRowsRequest,
+    mutation, read_rows_request, MutateRowRequest, Mutation, ReadRowsRequest, RowFilter, RowSet,
+    TimestampRange,
 };
+use juicebox_realm_api::types::RealmId;
 use retry_loop::{retry_logging, Retry, RetryError};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
 use tracing::warn;
 
-use super::{BigtableTableAdminClient, StoreClient};
+use super::{to_micros, BigtableTableAdminClient, StoreClient};
 
 const FAMILY: &str = "f";
 const COLUMN_NAME: &[u8] = &[b'c'];
 const TABLE_NAME: &str = "tenants";
 
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+// Old versions of a tenant's configuration are only kept for its history.
+// Bigtable garbage collects versions that are both older than this and not
+// among the latest `MIN_CONFIG_VERSIONS`.
+const MAX_CONFIG_AGE_DAYS: u32 = 400;
+const MAX_CONFIG_AGE_SECONDS: u32 = MAX_CONFIG_AGE_DAYS * 60 * 60 * 24;
+const MIN_CONFIG_VERSIONS: i32 = 20;
+
+// Rate limits are configured in client operations but enforced on requests.
+// An operation takes up to 3 requests.
+const REQS_PER_OP: usize = 3;
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 pub struct TenantConfiguration {
     pub capacity_ops_per_sec: usize,
+    /// The number of operations the tenant can make at once after a quiet
+    /// period. `None` allows one second's worth.
+    #[serde(default)]
+    pub burst_ops: Option<usize>,
+    /// If set, each of the tenant's records may make at most this many
+    /// operations per minute. This keeps a single user from using the
+    /// tenant's whole capacity, and slows down enumeration.
+    #[serde(default)]
+    pub record_ops_per_min: Option<usize>,
+    /// Capacities for particular realms. These replace `capacity_ops_per_sec`
+    /// and `burst_ops` in those realms, and are limited separately.
+    #[serde(default)]
+    pub realm_capacities: Vec<RealmCapacity>,
+    /// The browser origins that may make requests on behalf of this tenant's
+    /// users. `None` allows requests from any origin.
+    #[serde(default)]
+    pub allowed_origins: Option<Vec<String>>,
+    /// The maximum number of secrets this tenant's users may have registered
+    /// in each realm. `None` means there's no limit.
+    #[serde(default)]
+    pub max_registered_secrets: Option<u64>,
+    /// Set while the tenant is suspended, giving the reason. Requests for a
+    /// suspended tenant's users are rejected. `None` means the tenant is
+    /// enabled.
+    #[serde(default)]
+    pub suspended: Option<String>,
 }
 
 impl TenantConfiguration {
+    /// Returns a configuration with the given capacity and everything else
+    /// left at its default: no per-record or per-realm limits, no origin
+    /// allowlist, no quota, and not suspended.
+    pub fn new(capacity_ops_per_sec: usize) -> Self {
+        Self {
+            capacity_ops_per_sec,
+            ..Self::default()
+        }
+    }
+
     pub fn capacity_reqs_per_sec(&self) -> usize {
-        self.capacity_ops_per_sec * 3
+        self.capacity_ops_per_sec * REQS_PER_OP
+    }
+
+    pub fn burst_reqs(&self) -> usize {
+        self.burst_ops.unwrap_or(self.capacity_ops_per_sec) * REQS_PER_OP
+    }
+
+    pub fn record_reqs_per_min(&self) -> Option<usize> {
+        self.record_ops_per_min.map(|ops| ops * REQS_PER_OP)
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.is_some()
+    }
+}
+
+/// A tenant's capacity in a particular realm (see
+/// [`TenantConfiguration::realm_capacities`]).
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RealmCapacity {
+    pub realm: RealmId,
+    pub capacity_ops_per_sec: usize,
+    #[serde(default)]
+    pub burst_ops: Option<usize>,
+}
+
+impl RealmCapacity {
+    pub fn capacity_reqs_per_sec(&self) -> usize {
+        self.capacity_ops_per_sec * REQS_PER_OP
+    }
+
+    pub fn burst_reqs(&self) -> usize {
+        self.burst_ops.unwrap_or(self.capacity_ops_per_sec) * REQS_PER_OP
     }
 }
 
+/// A version of a tenant's configuration, as returned by
+/// [`StoreClient::get_tenant_history`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TenantConfigurationVersion {
+    /// When this version was written.
+    pub written: SystemTime,
+    /// The configuration, or `None` if the configuration was deleted.
+    pub config: Option<TenantConfiguration>,
+}
+
 pub fn tenant_config_table(instance: &Instance) -> String {
     format!("{path}/tables/{TABLE_NAME}", path = instance.path(),)
 }
@@ -35,6 +132,24 @@ pub(crate) async fn initialize(
     bigtable: &mut BigtableTableAdminClient,
     instance: &Instance,
 ) -> Result<(), tonic::Status> {
+    let family = ColumnFamily {
+        gc_rule: Some(GcRule {
+            rule: Some(Rule::Intersection(gc_rule::Intersection {
+                rules: vec![
+                    GcRule {
+                        rule: Some(Rule::MaxNumVersions(MIN_CONFIG_VERSIONS)),
+                    },
+                    GcRule {
+                        rule: Some(Rule::MaxAge(prost_types::Duration {
+                            seconds: MAX_CONFIG_AGE_SECONDS.into(),
+                            nanos: 0,
+                        })),
+                    },
+                ],
+            })),
+        }),
+    };
+
     // This is not realm-specific, so it might already exist.
     if let Err(err) = bigtable
         .create_table(CreateTableRequest {
@@ -43,12 +158,7 @@ pub(crate) async fn initialize(
             table: Some(Table {
                 name: String::from(""),
                 cluster_states: HashMap::new(),
-                column_families: HashMap::from([(
-                    FAMILY.to_string(),
-                    ColumnFamily {
-                        gc_rule: Some(GcRule { rule: None }),
-                    },
-                )]),
+                column_families: HashMap::from([(FAMILY.to_string(), family.clone())]),
                 granularity: TimestampGranularity::Unspecified.into(),
                 restore_info: None,
                 change_stream_config: None,
@@ -58,19 +168,71 @@ pub(crate) async fn initialize(
         })
         .await
     {
-        {
-            if err.code() != tonic::Code::AlreadyExists {
-                return Err(err);
-            }
+        if err.code() != tonic::Code::AlreadyExists {
+            return Err(err);
         }
+        // Tables created before there was a garbage collection policy kept
+        // every version forever.
+        bigtable
+            .modify_column_families(ModifyColumnFamiliesRequest {
+                name: tenant_config_table(instance),
+                modifications: vec![Modification {
+                    id: FAMILY.to_string(),
+                    r#mod: Some(modification::Mod::Update(family)),
+                }],
+                ignore_warnings: false,
+            })
+            .await?;
     }
     Ok(())
 }
 
+// Every version of a tenant's configuration is kept as a separate cell, so that
+// its history can be shown, until it's garbage collected (see
+// `MAX_CONFIG_AGE_DAYS`). Deleting the configuration writes an empty cell.
+
 impl StoreClient {
+    /// Returns the current configuration of every tenant that has one.
     pub async fn get_tenants(
         &self,
     ) -> Result<Vec<(String, TenantConfiguration)>, RetryError<tonic::Status>> {
+        self.read_tenants(None).await
+    }
+
+    /// Returns the configuration that every tenant had at the given time,
+    /// for tenants that had one then.
+    pub(crate) async fn get_tenants_at(
+        &self,
+        at: SystemTime,
+    ) -> Result<Vec<(String, TenantConfiguration)>, RetryError<tonic::Status>> {
+        self.read_tenants(Some(at)).await
+    }
+
+    async fn read_tenants(
+        &self,
+        at: Option<SystemTime>,
+    ) -> Result<Vec<(String, TenantConfiguration)>, RetryError<tonic::Status>> {
+        let latest = RowFilter {
+            filter: Some(Filter::CellsPerColumnLimitFilter(1)),
+        };
+        let filter = match at {
+            None => latest,
+            Some(at) => RowFilter {
+                filter: Some(Filter::Chain(row_filter::Chain {
+                    filters: vec![
+                        RowFilter {
+                            filter: Some(Filter::TimestampRangeFilter(TimestampRange {
+                                start_timestamp_micros: 0,
+                                end_timestamp_micros: to_micros(
+                                    at.duration_since(SystemTime::UNIX_EPOCH).unwrap(),
+                                ),
+                            })),
+                        },
+                        latest,
+                    ],
+                })),
+            },
+        };
         let mut bigtable = self.0.bigtable.clone();
         let rows = match Reader::read_rows(
             &mut bigtable,
@@ -81,7 +243,7 @@ impl StoreClient {
                 table_name: tenant_config_table(&self.0.instance),
                 app_profile_id: String::new(),
                 rows: None, // everything
-                filter: None,
+                filter: Some(filter),
                 rows_limit: 0,
                 request_stats_view: read_rows_request::RequestStatsView::RequestStatsNone.into(),
                 reversed: false,
@@ -103,14 +265,58 @@ impl StoreClient {
 
         Ok(rows
             .into_iter()
-            .map(|(rowkey, cells)| {
+            .filter_map(|(rowkey, cells)| {
                 let cell = cells
                     .into_iter()
                     .find(|c| c.family == FAMILY && c.qualifier == COLUMN_NAME)
                     .unwrap();
+                if cell.value.is_empty() {
+                    // deleted
+                    return None;
+                }
                 let config = juicebox_marshalling::from_slice(&cell.value).expect("TODO");
                 let tenant = String::from_utf8(rowkey.0).unwrap();
-                (tenant, config)
+                Some((tenant, config))
+            })
+            .collect())
+    }
+
+    /// Returns every version of the tenant's configuration that hasn't been
+    /// garbage collected, newest first.
+    pub async fn get_tenant_history(
+        &self,
+        tenant: &str,
+    ) -> Result<Vec<TenantConfigurationVersion>, RetryError<tonic::Status>> {
+        let rows = Reader::read_rows(
+            &mut self.0.bigtable.clone(),
+            Retry::new("read Bigtable tenant configuration history")
+                .with(bigtable_retries)
+                .with_metrics(&self.0.metrics, "store_client.tenants.get_history", &[]),
+            ReadRowsRequest {
+                table_name: tenant_config_table(&self.0.instance),
+                app_profile_id: String::new(),
+                rows: Some(RowSet {
+                    row_keys: vec![tenant.as_bytes().to_vec()],
+                    row_ranges: Vec::new(),
+                }),
+                filter: None,
+                rows_limit: 0,
+                request_stats_view: read_rows_request::RequestStatsView::RequestStatsNone.into(),
+                reversed: false,
+            },
+        )
+        .await?;
+
+        // Bigtable returns a column's cells newest first.
+        Ok(rows
+            .into_iter()
+            .flat_map(|(_, cells)| cells)
+            .filter(|c| c.family == FAMILY && c.qualifier == COLUMN_NAME)
+            .map(|cell| TenantConfigurationVersion {
+                written: SystemTime::UNIX_EPOCH
+                    + Duration::from_micros(u64::try_from(cell.timestamp).unwrap_or(0)),
+                config: (!cell.value.is_empty())
+                    .then(|| juicebox_marshalling::from_slice(&cell.value).expect("TODO")),
             })
             .collect())
     }
@@ -121,28 +327,20 @@ impl StoreClient {
         config: &TenantConfiguration,
     ) -> Result<(), RetryError<tonic::Status>> {
         let run = |_| async {
-            // Row keys are tenant name. There's one cell with the serialized config object
+            // Row keys are tenant name. Each version of the config is a cell
+            // with the serialized config object.
             let request = MutateRowRequest {
                 table_name: tenant_config_table(&self.0.instance),
                 app_profile_id: String::new(),
                 row_key: tenant.as_bytes().to_vec(),
-                mutations: vec![
-                    Mutation {
-                        mutation: Some(mutation::Mutation::DeleteFromFamily(
-                            mutation::DeleteFromFamily {
-                                family_name: String::from(FAMILY),
-                            },
-                        )),
-                    },
-                    Mutation {
-                        mutation: Some(mutation::Mutation::SetCell(mutation::SetCell {
-                            family_name: String::from(FAMILY),
-                            column_qualifier: COLUMN_NAME.to_vec(),
-                            timestamp_micros: -1,
-                            value: juicebox_marshalling::to_vec(config).expect("TODO"),
-                        })),
-                    },
-                ],
+                mutations: vec![Mutation {
+                    mutation: Some(mutation::Mutation::SetCell(mutation::SetCell {
+                        family_name: String::from(FAMILY),
+                        column_qualifier: COLUMN_NAME.to_vec(),
+                        timestamp_micros: -1,
+                        value: juicebox_marshalling::to_vec(config).expect("TODO"),
+                    })),
+                }],
             };
             self.0
                 .bigtable
@@ -159,4 +357,36 @@ impl StoreClient {
             .retry(run, retry_logging!())
             .await
     }
+
+    /// Removes the tenant's configuration. Its history is kept.
+    pub async fn delete_tenant(&self, tenant: &str) -> Result<(), RetryError<tonic::Status>> {
+        let run = |_| async {
+            let request = MutateRowRequest {
+                table_name: tenant_config_table(&self.0.instance),
+                app_profile_id: String::new(),
+                row_key: tenant.as_bytes().to_vec(),
+                mutations: vec![Mutation {
+                    mutation: Some(mutation::Mutation::SetCell(mutation::SetCell {
+                        family_name: String::from(FAMILY),
+                        column_qualifier: COLUMN_NAME.to_vec(),
+                        timestamp_micros: -1,
+                        value: Vec::new(),
+                    })),
+                }],
+            };
+            self.0
+                .bigtable
+                .clone()
+                .mutate_row(request)
+                .await
+                .map_err(inspect_grpc_error)?;
+            Ok(())
+        };
+
+        Retry::new("deleting tenant configuration")
+            .with(bigtable_retries)
+            .with_metrics(&self.0.metrics, "store_client.tenant.delete", &[])
+            .retry(run, retry_logging!())
+            .await
+    }
 }
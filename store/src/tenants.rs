@@ -7,12 +7,14 @@ use google::bigtable::v2::mutate_rows_request::Entry;
 use google::bigtable::v2::mutation::{self, SetCell};
 use google::bigtable::v2::read_rows_request::RequestStatsView::RequestStatsNone;
 use google::bigtable::v2::row_filter::{Chain, Filter, Interleave};
+use google::bigtable::v2::row_range::{EndKey, StartKey};
 use google::bigtable::v2::value_range::{EndValue, StartValue};
 use google::bigtable::v2::{
-    ColumnRange, MutateRowsRequest, Mutation, ReadRowsRequest, RowFilter, TimestampRange,
-    ValueRange,
+    ColumnRange, MutateRowsRequest, Mutation, ReadRowsRequest, RowFilter, RowRange, RowSet,
+    TimestampRange, ValueRange,
 };
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
 use tracing::{debug, warn};
@@ -20,7 +22,7 @@ use tracing::{debug, warn};
 use super::{BigtableTableAdminClient, Instance, StoreClient};
 use bigtable::bigtable_retries;
 use bigtable::mutate::{mutate_rows, MutateRowsError};
-use bigtable::read::{Reader, RowKey};
+use bigtable::read::{Cell, Reader, RowKey};
 use hsm_api::RecordId;
 use juicebox_realm_api::types::RealmId;
 use observability::metrics_tag as tag;
@@ -28,6 +30,11 @@ use retry_loop::{retry_logging, AttemptError, Retry, RetryError};
 
 const FAMILY: &str = "f";
 const EVENT_COL: &[u8] = b"e";
+// The hashed user ID and hashed slot that the record belongs to. These are
+// only written with events that know them, and the cells are never updated
+// otherwise, so only the latest version matters.
+const USER_COL: &[u8] = b"u";
+const SLOT_COL: &[u8] = b"s";
 
 const MAX_ACCOUNTING_EVENT_AGE_DAYS: u32 = 116;
 const MAX_ACCOUNTING_EVENT_AGE_SECONDS: u32 = MAX_ACCOUNTING_EVENT_AGE_DAYS * 60 * 60 * 24;
@@ -95,6 +102,13 @@ pub struct UserAccounting {
     pub id: RecordId,
     pub when: SystemTime,
     pub event: UserAccountingEvent,
+    /// The hashed user ID that owns the record, if known. A user may own
+    /// several records, one per slot. Records without this are counted as
+    /// belonging to distinct users.
+    pub user: Option<String>,
+    /// The hashed slot within the user's records, if known. Records created
+    /// without a slot claim don't have one.
+    pub slot: Option<String>,
 }
 
 impl UserAccounting {
@@ -109,8 +123,17 @@ impl UserAccounting {
             id,
             when: when.into(),
             event,
+            user: None,
+            slot: None,
         }
     }
+
+    /// Records the hashed user ID and hashed slot that the record belongs to.
+    pub fn with_user(mut self, user: impl Into<String>, slot: Option<String>) -> Self {
+        self.user = Some(user.into());
+        self.slot = slot;
+        self
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -156,16 +179,27 @@ impl StoreClient {
                     app_profile_id: String::from(""),
                     entries: records
                         .iter()
-                        .map(|u| Entry {
-                            row_key: make_row_key(&u.tenant, &u.id),
-                            mutations: vec![Mutation {
+                        .map(|u| {
+                            let timestamp_micros = to_day_micros(u.when);
+                            let set_cell = |column: &[u8], value: Vec<u8>| Mutation {
                                 mutation: Some(mutation::Mutation::SetCell(SetCell {
                                     family_name: FAMILY.to_string(),
-                                    column_qualifier: EVENT_COL.to_vec(),
-                                    timestamp_micros: to_day_micros(u.when),
-                                    value: u.event.as_bytes(),
+                                    column_qualifier: column.to_vec(),
+                                    timestamp_micros,
+                                    value,
                                 })),
-                            }],
+                            };
+                            let mut mutations = vec![set_cell(EVENT_COL, u.event.as_bytes())];
+                            if let Some(user) = &u.user {
+                                mutations.push(set_cell(USER_COL, user.as_bytes().to_vec()));
+                            }
+                            if let Some(slot) = &u.slot {
+                                mutations.push(set_cell(SLOT_COL, slot.as_bytes().to_vec()));
+                            }
+                            Entry {
+                                row_key: make_row_key(&u.tenant, &u.id),
+                                mutations,
+                            }
                         })
                         .collect(),
                 },
@@ -184,10 +218,11 @@ impl StoreClient {
             .await
     }
 
-    // Returns a count of active users by tenant for the specified realm and
-    // date range. start can't be more than 100 days in the past, or be in the
-    // future. The finest date granularity is a day. Start and end times will be
-    // rounded as appropriately.
+    // Returns a count of active users and slots by tenant for the specified
+    // realm and date range. Each active record counts as a slot. start can't
+    // be more than 100 days in the past, or be in the future. The finest date
+    // granularity is a day. Start and end times will be rounded as
+    // appropriately.
     pub async fn count_realm_users(
         &self,
         realm: &RealmId,
@@ -210,7 +245,7 @@ impl StoreClient {
             Err(_) => return Err(CountRealmUsersError::StartInFuture),
         }
 
-        let f = Filter::Chain(Chain {
+        let events = Filter::Chain(Chain {
             filters: vec![
                 // Just the EVENT_COL cells.
                 RowFilter {
@@ -269,6 +304,32 @@ impl StoreClient {
                 },
             ],
         });
+        // The latest hashed user ID cell is read for every row, but the row
+        // is only counted if the events filter above also returns a cell.
+        let user = Filter::Chain(Chain {
+            filters: vec![
+                RowFilter {
+                    filter: Some(Filter::ColumnRangeFilter(ColumnRange {
+                        family_name: FAMILY.to_string(),
+                        start_qualifier: Some(StartQualifier::StartQualifierClosed(
+                            USER_COL.to_vec(),
+                        )),
+                        end_qualifier: Some(EndQualifier::EndQualifierClosed(USER_COL.to_vec())),
+                    })),
+                },
+                RowFilter {
+                    filter: Some(Filter::CellsPerColumnLimitFilter(1)),
+                },
+            ],
+        });
+        let f = Filter::Interleave(Interleave {
+            filters: vec![
+                RowFilter {
+                    filter: Some(events),
+                },
+                RowFilter { filter: Some(user) },
+            ],
+        });
         let read_req = ReadRowsRequest {
             table_name: tenant_user_table(&self.0.instance, realm),
             app_profile_id: String::new(),
@@ -280,22 +341,37 @@ impl StoreClient {
         };
         let mut bigtable = self.0.bigtable.clone();
 
-        let mut results = Vec::new();
+        // The rows are sorted by tenant, so only the last tenant's users need
+        // to be tracked.
+        let mut results: Vec<(String, HashSet<Vec<u8>>, usize)> = Vec::new();
         let mut row_count = 0usize;
         let mut last_report = Instant::now();
-        let row_fn = |key: RowKey, _cells| {
+        let row_fn = |key: RowKey, cells: Vec<Cell>| {
             row_count += 1;
             if row_count % 1000 == 0 && last_report.elapsed() > Duration::from_secs(2) {
                 debug!(row_count, ?key, "reading user accounting rows");
                 last_report = Instant::now();
             }
-            if let Some(t) = parse_tenant(&key.0) {
-                match results.last_mut() {
-                    Some((last_tenant, count)) if last_tenant == t => *count += 1,
-                    None | Some(_) => results.push((t.to_string(), 1)),
+            if !cells.iter().any(|cell| cell.qualifier == EVENT_COL) {
+                return;
+            }
+            let Some(t) = parse_tenant(&key.0) else {
+                warn!(key=?key, "invalid row key, expecting tenant:recordId");
+                return;
+            };
+            // Records written before slots existed don't have a user cell.
+            // Those are each owned by a distinct user, so the row key stands
+            // in for the user.
+            let user = match cells.into_iter().find(|cell| cell.qualifier == USER_COL) {
+                Some(cell) => cell.value,
+                None => key.0,
+            };
+            match results.last_mut() {
+                Some((last_tenant, users, slots)) if last_tenant == t => {
+                    users.insert(user);
+                    *slots += 1;
                 }
-            } else {
-                warn!(key=?key, "invalid row key, expecting tenant:recordId")
+                None | Some(_) => results.push((t.to_string(), HashSet::from([user]), 1)),
             }
         };
         let retry = Retry::new("reading users table")
@@ -310,12 +386,297 @@ impl StoreClient {
                 start: SystemTime::UNIX_EPOCH
                     + Duration::from_micros(start_micros.try_into().unwrap()),
                 end: SystemTime::UNIX_EPOCH + Duration::from_micros(end_micros.try_into().unwrap()),
-                tenant_user_counts: results,
+                tenant_user_counts: results
+                    .iter()
+                    .map(|(tenant, users, _)| (tenant.clone(), users.len()))
+                    .collect(),
+                tenant_slot_counts: results
+                    .into_iter()
+                    .map(|(tenant, _, slots)| (tenant, slots))
+                    .collect(),
             }),
         }
     }
 }
 
+impl StoreClient {
+    /// Returns the IDs of records that have user accounting events for the
+    /// tenant, in order. This includes records whose secrets have since been
+    /// deleted.
+    ///
+    /// At most `limit` IDs are returned, starting after `after` if given. Call
+    /// this repeatedly, passing the last ID returned, to page through all of
+    /// them. An empty result means there are no more.
+    pub async fn read_tenant_record_ids(
+        &self,
+        realm: &RealmId,
+        tenant: &str,
+        after: Option<&RecordId>,
+        limit: usize,
+    ) -> Result<Vec<RecordId>, RetryError<tonic::Status>> {
+        // Every row key for the tenant starts with "tenant:", so the range
+        // ends just before "tenant;".
+        let start_key = match after {
+            Some(id) => StartKey::StartKeyOpen(make_row_key(tenant, id)),
+            None => StartKey::StartKeyClosed(format!("{tenant}:").into_bytes()),
+        };
+        let end_key = EndKey::EndKeyOpen(format!("{tenant};").into_bytes());
+
+        let rows = Reader::read_rows(
+            &mut self.0.bigtable.clone(),
+            Retry::new("reading tenant record IDs")
+                .with(bigtable_retries)
+                .with_metrics(
+                    &self.0.metrics,
+                    "store_client.read_tenant_record_ids",
+                    &[tag!(?realm)],
+                ),
+            ReadRowsRequest {
+                table_name: tenant_user_table(&self.0.instance, realm),
+                app_profile_id: String::new(),
+                rows: Some(RowSet {
+                    row_keys: Vec::new(),
+                    row_ranges: vec![RowRange {
+                        start_key: Some(start_key),
+                        end_key: Some(end_key),
+                    }],
+                }),
+                // Only the row keys are needed.
+                filter: Some(RowFilter {
+                    filter: Some(Filter::Chain(Chain {
+                        filters: vec![
+                            RowFilter {
+                                filter: Some(Filter::CellsPerRowLimitFilter(1)),
+                            },
+                            RowFilter {
+                                filter: Some(Filter::StripValueTransformer(true)),
+                            },
+                        ],
+                    })),
+                }),
+                rows_limit: i64::try_from(limit).unwrap(),
+                request_stats_view: RequestStatsNone.into(),
+                reversed: false,
+            },
+        )
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|(key, _)| match parse_row_key(&key.0) {
+                // Tenant names can't contain ':', so this shouldn't find
+                // other tenants' rows, but check anyway.
+                Some((t, id)) if t == tenant => Some(id),
+                Some(_) => None,
+                None => {
+                    warn!(?key, "invalid row key, expecting tenant:recordId");
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Returns the number of the tenant's records whose latest user
+    /// accounting event is [`UserAccountingEvent::SecretRegistered`].
+    ///
+    /// The events are written asynchronously after registrations and
+    /// deletions commit, so this may lag slightly behind.
+    pub async fn count_registered_records(
+        &self,
+        realm: &RealmId,
+        tenant: &str,
+    ) -> Result<usize, RetryError<tonic::Status>> {
+        let registered = UserAccountingEvent::SecretRegistered.as_bytes();
+        let mut count = 0;
+        Reader::read_rows_stream(
+            &mut self.0.bigtable.clone(),
+            Retry::new("counting registered records")
+                .with(bigtable_retries)
+                .with_deadline(None)
+                .with_metrics(
+                    &self.0.metrics,
+                    "store_client.count_registered_records",
+                    &[tag!(?realm)],
+                ),
+            ReadRowsRequest {
+                table_name: tenant_user_table(&self.0.instance, realm),
+                app_profile_id: String::new(),
+                // See `read_tenant_record_ids` for the range.
+                rows: Some(RowSet {
+                    row_keys: Vec::new(),
+                    row_ranges: vec![RowRange {
+                        start_key: Some(StartKey::StartKeyClosed(
+                            format!("{tenant}:").into_bytes(),
+                        )),
+                        end_key: Some(EndKey::EndKeyOpen(format!("{tenant};").into_bytes())),
+                    }],
+                }),
+                filter: Some(RowFilter {
+                    filter: Some(Filter::Chain(Chain {
+                        filters: vec![
+                            // The latest EVENT_COL cell, if it's a
+                            // registration.
+                            RowFilter {
+                                filter: Some(Filter::ColumnRangeFilter(ColumnRange {
+                                    family_name: FAMILY.to_string(),
+                                    start_qualifier: Some(StartQualifier::StartQualifierClosed(
+                                        EVENT_COL.to_vec(),
+                                    )),
+                                    end_qualifier: Some(EndQualifier::EndQualifierClosed(
+                                        EVENT_COL.to_vec(),
+                                    )),
+                                })),
+                            },
+                            RowFilter {
+                                filter: Some(Filter::CellsPerColumnLimitFilter(1)),
+                            },
+                            RowFilter {
+                                filter: Some(Filter::ValueRangeFilter(ValueRange {
+                                    start_value: Some(StartValue::StartValueClosed(
+                                        registered.clone(),
+                                    )),
+                                    end_value: Some(EndValue::EndValueClosed(registered)),
+                                })),
+                            },
+                            RowFilter {
+                                filter: Some(Filter::StripValueTransformer(true)),
+                            },
+                        ],
+                    })),
+                }),
+                rows_limit: 0,
+                request_stats_view: RequestStatsNone.into(),
+                reversed: false,
+            },
+            |_key: RowKey, _cells: Vec<Cell>| count += 1,
+        )
+        .await?;
+        Ok(count)
+    }
+}
+
+impl StoreClient {
+    /// Reads every cell of the realm's user accounting table, for inclusion
+    /// in a snapshot.
+    pub(crate) async fn read_user_accounting_rows(
+        &self,
+        realm: &RealmId,
+    ) -> Result<Vec<UserAccountingRow>, RetryError<tonic::Status>> {
+        let mut rows = Vec::new();
+        Reader::read_rows_stream(
+            &mut self.0.bigtable.clone(),
+            Retry::new("reading users table")
+                .with(bigtable_retries)
+                .with_deadline(None)
+                .with_metrics(
+                    &self.0.metrics,
+                    "store_client.read_user_accounting_rows",
+                    &[tag!(?realm)],
+                ),
+            ReadRowsRequest {
+                table_name: tenant_user_table(&self.0.instance, realm),
+                app_profile_id: String::new(),
+                rows: None,
+                filter: None,
+                rows_limit: 0,
+                request_stats_view: RequestStatsNone.into(),
+                reversed: false,
+            },
+            |key: RowKey, cells: Vec<Cell>| {
+                let latest = |column: &[u8]| {
+                    cells
+                        .iter()
+                        .filter(|cell| cell.family == FAMILY && cell.qualifier == column)
+                        .max_by_key(|cell| cell.timestamp)
+                        .map(|cell| (cell.timestamp, cell.value.clone()))
+                };
+                let user = latest(USER_COL);
+                let slot = latest(SLOT_COL);
+                rows.push(UserAccountingRow {
+                    row_key: key.0,
+                    events: cells
+                        .into_iter()
+                        .filter(|cell| cell.family == FAMILY && cell.qualifier == EVENT_COL)
+                        .map(|cell| (cell.timestamp, cell.value))
+                        .collect(),
+                    user,
+                    slot,
+                })
+            },
+        )
+        .await?;
+        Ok(rows)
+    }
+
+    /// Writes user accounting rows read by
+    /// [`Self::read_user_accounting_rows`], keeping their original
+    /// timestamps.
+    pub(crate) async fn write_user_accounting_rows(
+        &self,
+        realm: &RealmId,
+        rows: &[UserAccountingRow],
+    ) -> Result<(), RetryError<MutateRowsError>> {
+        for batch in rows.chunks(1000) {
+            let run = |_| async {
+                mutate_rows(
+                    &mut self.0.bigtable.clone(),
+                    MutateRowsRequest {
+                        table_name: tenant_user_table(&self.0.instance, realm),
+                        app_profile_id: String::new(),
+                        entries: batch
+                            .iter()
+                            .map(|row| Entry {
+                                row_key: row.row_key.clone(),
+                                mutations: row
+                                    .events
+                                    .iter()
+                                    .map(|cell| (EVENT_COL, cell))
+                                    .chain(row.user.iter().map(|cell| (USER_COL, cell)))
+                                    .chain(row.slot.iter().map(|cell| (SLOT_COL, cell)))
+                                    .map(|(column, (timestamp, value))| Mutation {
+                                        mutation: Some(mutation::Mutation::SetCell(SetCell {
+                                            family_name: FAMILY.to_string(),
+                                            column_qualifier: column.to_vec(),
+                                            timestamp_micros: *timestamp,
+                                            value: value.clone(),
+                                        })),
+                                    })
+                                    .collect(),
+                            })
+                            .collect(),
+                    },
+                )
+                .await
+                .map_err(AttemptError::from)
+            };
+            Retry::new("writing user accounting rows")
+                .with(bigtable_retries)
+                .with_metrics(
+                    &self.0.metrics,
+                    "store_client.write_user_accounting_rows",
+                    &[tag!(?realm)],
+                )
+                .retry(run, retry_logging!())
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// The raw contents of a row in the user accounting table.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct UserAccountingRow {
+    pub row_key: Vec<u8>,
+    /// The timestamp (in microseconds) and value of every event cell.
+    pub events: Vec<(i64, Vec<u8>)>,
+    /// The timestamp and value of the hashed user ID cell, if any.
+    #[serde(default)]
+    pub user: Option<(i64, Vec<u8>)>,
+    /// The timestamp and value of the hashed slot cell, if any.
+    #[serde(default)]
+    pub slot: Option<(i64, Vec<u8>)>,
+}
+
 #[derive(Debug, Error)]
 pub enum CountRealmUsersError {
     #[error("gRPC error from bigtable: {0}")]
@@ -331,7 +692,11 @@ pub enum CountRealmUsersError {
 pub struct RealmUserSummary {
     pub start: SystemTime,
     pub end: SystemTime,
+    /// The number of distinct active users, by tenant.
     pub tenant_user_counts: Vec<(String, usize)>,
+    /// The number of active records, by tenant. Each of a user's slots is a
+    /// separate record.
+    pub tenant_slot_counts: Vec<(String, usize)>,
 }
 
 fn make_row_key(tenant: &str, id: &RecordId) -> Vec<u8> {
@@ -362,6 +727,13 @@ fn parse_tenant(row_key: &[u8]) -> Option<&str> {
     }
 }
 
+fn parse_row_key(row_key: &[u8]) -> Option<(&str, RecordId)> {
+    let tenant = parse_tenant(row_key)?;
+    let mut id = RecordId::min_id();
+    hex::decode_to_slice(&row_key[tenant.len() + 1..], &mut id.0).ok()?;
+    Some((tenant, id))
+}
+
 // rounds the supplied time down to midnight UTC and returns the number of
 // microseconds since the EPOCH for that time.
 fn to_day_micros(t: SystemTime) -> i64 {
@@ -428,5 +800,11 @@ mod tests {
                 .is_none()
         );
         assert!(parse_tenant(b"bob").is_none());
+
+        assert_eq!(Some(("bob", RecordId([15; 32]))), parse_row_key(&k));
+        assert!(parse_row_key(
+            b"bob:0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0fzz"
+        )
+        .is_none());
     }
 }
@@ -3,7 +3,7 @@ use futures::future::join_all;
 use http::Uri;
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::time::Duration;
 use std::{env, fs, iter};
@@ -226,6 +226,7 @@ pub async fn create_cluster(
     let cluster_managers: Vec<Url> = iter::repeat_with(|| {
         start_cluster_manager(
             &args.bigtable,
+            args.secrets_file.as_deref(),
             args.path_to_target.clone(),
             process_group,
             &ports,
@@ -294,7 +295,9 @@ fn create_load_balancers(
                 .arg("--listen")
                 .arg(address.to_string())
                 .arg("--shutdown-timeout") // no point sitting around for graceful shutdowns in tests
-                .arg("10ms");
+                .arg("10ms")
+                .arg("--default-tenant-concurrency") // matches the agents' default rate limit
+                .arg("1000");
             if let Some(secrets_file) = &args.secrets_file {
                 cmd.arg("--secrets-file").arg(secrets_file);
             }
@@ -309,6 +312,7 @@ fn create_load_balancers(
 
 fn start_cluster_manager(
     args: &store::BigtableArgs,
+    secrets_file: Option<&Path>,
     path_to_target: PathBuf,
     process_group: &mut ProcessGroup,
     ports: &PortIssuer,
@@ -326,6 +330,9 @@ fn start_cluster_manager(
             .join("cluster_manager"),
     );
     cmd.arg("--listen").arg(address.to_string());
+    if let Some(secrets_file) = secrets_file {
+        cmd.arg("--secrets-file").arg(secrets_file);
+    }
     args.add_to_cmd(&mut cmd);
     process_group.spawn(&mut cmd);
     Url::parse(&format!("http://localhost:{port}/")).unwrap()
@@ -177,6 +177,7 @@ async fn make_app_request_to_agents(
             encrypted: NoiseRequest::Handshake { handshake: req },
             tenant: "Bob".into(),
             user: HashedUserId::new("Bob", "Eve"),
+            slot: None,
         };
         match rpc::send(agent_client, agent, r).await {
             Ok(AppResponse::Ok(NoiseResponse::Handshake {
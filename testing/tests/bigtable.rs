@@ -5,7 +5,9 @@ use std::time::{Duration, SystemTime};
 use agent_api::merkle::TreeStoreReader;
 use bitvec::BitVec;
 use hsm_api::merkle::{Branch, DeltaBuilder, InteriorNode, Node, NodeKey, StoreDelta};
-use hsm_api::{DataHash, EntryMac, GroupId, HsmId, LogEntry, LogIndex, OwnedRange, RecordId};
+use hsm_api::{
+    DataHash, EntryMac, GroupId, HsmId, LogEntry, LogIndex, OwnedRange, Partition, RecordId,
+};
 use hsm_core::hsm::MerkleHasher;
 use hsm_core::merkle::Tree;
 use jburl::Url;
@@ -13,9 +15,12 @@ use juicebox_process_group::ProcessGroup;
 use juicebox_realm_api::types::RealmId;
 use observability::metrics;
 use retry_loop::RetryError;
+use store::admission::{self, AdmissionUsage};
 use store::log::testing::{new_log_row, read_log_entry, ReadLogEntryError, TOMBSTONE_WINDOW_SIZE};
 use store::log::{LogEntriesIterError, LogRow, ReadLastLogEntryFatal};
-use store::tenant_config::TenantConfiguration;
+use store::offboarding::TenantOffboarding;
+use store::snapshot::RealmSnapshot;
+use store::tenant_config::{RealmCapacity, TenantConfiguration};
 use store::tenants::UserAccounting;
 use store::{
     discovery, tenants, AppendError, ExtendLeaseError, LeaseKey, LeaseType, ServiceKind,
@@ -72,6 +77,8 @@ async fn test_tenant() {
     let alice = RecordId([3; 32]);
     let simon = RecordId([4; 32]);
     let diego = RecordId([5; 32]);
+    let frank_phone = RecordId([6; 32]);
+    let frank_laptop = RecordId([7; 32]);
 
     let events = vec![
         // bob registered 3 months ago and hasn't done anything since
@@ -132,6 +139,11 @@ async fn test_tenant() {
             now.checked_add_days(Days::new(4)).unwrap(),
             SecretDeleted,
         ),
+        // frank registered secrets in two slots this month
+        UserAccounting::new("teylacorp", frank_phone, now, SecretRegistered)
+            .with_user("frank", Some(String::from("phone"))),
+        UserAccounting::new("teylacorp", frank_laptop, now, SecretRegistered)
+            .with_user("frank", Some(String::from("laptop"))),
     ];
     data.write_user_accounting(&REALM, events).await.unwrap();
 
@@ -147,11 +159,15 @@ async fn test_tenant() {
         )
         .await
         .unwrap();
-    // bob, alice, eve, diego
+    // bob, alice, eve, diego, frank
     assert_eq!(
-        vec![(String::from("jb"), 3), (String::from("teylacorp"), 1)],
+        vec![(String::from("jb"), 3), (String::from("teylacorp"), 2)],
         counts.tenant_user_counts
     );
+    assert_eq!(
+        vec![(String::from("jb"), 3), (String::from("teylacorp"), 3)],
+        counts.tenant_slot_counts
+    );
 
     // last month
     let counts = data
@@ -195,11 +211,64 @@ async fn test_tenant() {
         )
         .await
         .unwrap();
-    // bob,alice,eve,simon,diego
+    // bob,alice,eve,simon,diego,frank
     assert_eq!(
-        vec![(String::from("jb"), 4), (String::from("teylacorp"), 1)],
+        vec![(String::from("jb"), 4), (String::from("teylacorp"), 2)],
         counts.tenant_user_counts
     );
+    assert_eq!(
+        vec![(String::from("jb"), 4), (String::from("teylacorp"), 3)],
+        counts.tenant_slot_counts
+    );
+}
+
+#[tokio::test]
+async fn test_count_registered_records() {
+    use tenants::UserAccountingEvent::*;
+
+    let mut pg = ProcessGroup::new();
+    let args = emulator(PORT.next());
+    let (_, data) = init_bt(&mut pg, args).await;
+
+    let now = Utc::now();
+    let earlier = now.checked_sub_days(Days::new(1)).unwrap();
+    let alice = RecordId([1; 32]);
+    let bob = RecordId([2; 32]);
+    let carol = RecordId([3; 32]);
+    let dave = RecordId([4; 32]);
+
+    assert_eq!(
+        data.count_registered_records(&REALM, "jb").await.unwrap(),
+        0
+    );
+
+    data.write_user_accounting(
+        &REALM,
+        vec![
+            // alice is registered.
+            UserAccounting::new("jb", alice, now, SecretRegistered),
+            // bob registered and then deleted his secret.
+            UserAccounting::new("jb", bob.clone(), earlier, SecretRegistered),
+            UserAccounting::new("jb", bob, now, SecretDeleted),
+            // carol deleted her secret and then registered again.
+            UserAccounting::new("jb", carol.clone(), earlier, SecretDeleted),
+            UserAccounting::new("jb", carol, now, SecretRegistered),
+            // dave belongs to a tenant whose name starts with the other's.
+            UserAccounting::new("jbx", dave, now, SecretRegistered),
+        ],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        data.count_registered_records(&REALM, "jb").await.unwrap(),
+        2
+    );
+    assert_eq!(
+        data.count_registered_records(&REALM, "jbx").await.unwrap(),
+        1
+    );
+    assert_eq!(data.count_registered_records(&REALM, "j").await.unwrap(), 0);
 }
 
 #[tokio::test]
@@ -716,6 +785,61 @@ async fn test_list_log_rows_compacted() {
     );
 }
 
+#[tokio::test]
+async fn test_read_log_rows() {
+    let mut pg = ProcessGroup::new();
+    let (_, data) = init_bt(&mut pg, emulator(PORT.next())).await;
+
+    let entries = create_log_batch(LogIndex::FIRST, EntryMac::from([0; 32]), 12);
+    let mut rows = Vec::new();
+    for batch in entries.chunks(4) {
+        rows.push(
+            data.append(&REALM, &GROUP_1, batch, StoreDelta::default())
+                .await
+                .unwrap(),
+        );
+    }
+    assert_eq!(
+        rows,
+        data.list_log_rows(&REALM, &GROUP_1, LogIndex(u64::MAX))
+            .await
+            .unwrap()
+    );
+
+    // Every entry in every row is returned, in log order, regardless of the
+    // order the rows are given in.
+    let mut reversed = rows.clone();
+    reversed.reverse();
+    assert_eq!(
+        entries,
+        data.read_log_rows(&REALM, &GROUP_1, &reversed)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        &entries[4..8],
+        data.read_log_rows(&REALM, &GROUP_1, &rows[1..2])
+            .await
+            .unwrap()
+    );
+
+    // Tombstone rows contribute nothing, and neither do rows that were
+    // replaced with tombstones after they were listed.
+    assert_eq!(
+        Vec::<LogEntry>::new(),
+        data.read_log_rows(&REALM, &GROUP_1, &[new_log_row(rows[0].index, true)])
+            .await
+            .unwrap()
+    );
+    data.replace_oldest_rows_with_tombstones(&REALM, &GROUP_1, &rows[..1])
+        .await
+        .unwrap();
+    assert_eq!(
+        &entries[4..],
+        data.read_log_rows(&REALM, &GROUP_1, &rows).await.unwrap()
+    );
+}
+
 #[tokio::test]
 async fn test_replace_oldest_rows_with_tombstones() {
     let mut pg = ProcessGroup::new();
@@ -971,6 +1095,100 @@ async fn test_append_store_delta() {
     .unwrap();
 }
 
+#[tokio::test]
+async fn test_snapshot_restores_log_head() {
+    let mut pg = ProcessGroup::new();
+    let args = emulator(PORT.next());
+    let (_, source) = init_bt(&mut pg, args.clone()).await;
+    hsm_core::hash::set_global_rng(Box::new(rand_core::OsRng));
+
+    // Build a log whose last entry's tree holds a record.
+    let record = RecordId([1; RecordId::NUM_BYTES]);
+    let (starting_root, delta) = Tree::<MerkleHasher>::new_tree(&OwnedRange::full());
+    let mut entries = create_log_batch(LogIndex::FIRST, EntryMac::from([0; 32]), 1);
+    entries[0].partition = Some(Partition {
+        range: OwnedRange::full(),
+        root_hash: starting_root,
+    });
+    source
+        .append(&REALM, &GROUP_1, &entries, delta)
+        .await
+        .unwrap();
+
+    let rp = agent_core::merkle::read(
+        &REALM,
+        &source,
+        &OwnedRange::full(),
+        &starting_root,
+        &record,
+        &metrics::Client::NONE,
+        &[],
+    )
+    .await
+    .unwrap();
+    let mut tree = Tree::<MerkleHasher>::with_existing_root(starting_root, 15);
+    let vp = tree.latest_proof(rp).unwrap();
+    let (root, delta) = tree.insert(vp, vec![1, 2, 3]).unwrap();
+    let mut head = create_log_batch(LogIndex::FIRST.next(), entries[0].entry_mac.clone(), 1);
+    head[0].partition = Some(Partition {
+        range: OwnedRange::full(),
+        root_hash: root,
+    });
+    source.append(&REALM, &GROUP_1, &head, delta).await.unwrap();
+
+    let snapshot = source.export_snapshot(&REALM).await.unwrap();
+    let snapshot = RealmSnapshot::decode(&snapshot.encode().unwrap()).unwrap();
+    assert_eq!(1, snapshot.groups.len());
+    assert_eq!(head[0], snapshot.groups[0].last_entry);
+
+    // Restore into another instance on the same emulator.
+    let restored_args = store::BigtableArgs {
+        instance: String::from("restored"),
+        ..args
+    };
+    let restored_admin = restored_args
+        .connect_admin(None, metrics::Client::NONE)
+        .await
+        .unwrap();
+    restored_admin.initialize_shared_tables().await.unwrap();
+    restored_admin.initialize_realm(&REALM).await.unwrap();
+    let restored = restored_args
+        .connect_data(None, store::Options::default())
+        .await
+        .unwrap();
+    restored.import_snapshot(&snapshot).await.unwrap();
+
+    // An HSM resumes by becoming leader from the last log entry, then
+    // verifying proofs against that entry's root. Both must work against the
+    // restored store.
+    let last = restored
+        .read_last_log_entry(&REALM, &GROUP_1)
+        .await
+        .unwrap();
+    assert_eq!(head[0], last);
+    let rp = agent_core::merkle::read(
+        &REALM,
+        &restored,
+        &OwnedRange::full(),
+        &last.partition.as_ref().unwrap().root_hash,
+        &record,
+        &metrics::Client::NONE,
+        &[],
+    )
+    .await
+    .unwrap();
+    let mut tree = Tree::<MerkleHasher>::with_existing_root(root, 15);
+    let vp = tree.latest_proof(rp).unwrap();
+    assert_eq!(Some(vec![1, 2, 3]), vp.leaf.map(|leaf| leaf.value));
+
+    // The restored log is appendable from its head.
+    let next = create_log_batch(last.index.next(), last.entry_mac.clone(), 1);
+    restored
+        .append(&REALM, &GROUP_1, &next, StoreDelta::default())
+        .await
+        .unwrap();
+}
+
 #[tokio::test]
 async fn test_storedelta_delete_add() {
     // During transfer or other tree operations where leaves don't change a node
@@ -1170,44 +1388,84 @@ async fn test_service_discovery() {
 }
 
 #[tokio::test]
-async fn test_tenant_config() {
+async fn test_admission_usage() {
     let mut pg = ProcessGroup::new();
     let (admin, data) = init_bt(&mut pg, emulator(PORT.next())).await;
     admin.initialize_shared_tables().await.unwrap();
-    assert_eq!(
-        Vec::<(String, TenantConfiguration)>::new(),
-        data.get_tenants().await.unwrap()
-    );
-    data.update_tenant(
-        "bob",
-        &TenantConfiguration {
-            capacity_ops_per_sec: 10,
-        },
+    assert!(data.get_admission_usage().await.unwrap().is_empty());
+
+    let lb1 = "lb1/0123456789abcdef";
+    let lb2 = "lb2/fedcba9876543210";
+    let usage = |tenants: &[(&str, usize)]| AdmissionUsage {
+        tenants: tenants
+            .iter()
+            .map(|(tenant, count)| (tenant.to_string(), *count))
+            .collect(),
+    };
+
+    data.put_admission_usage(lb1, &usage(&[("acme", 3)]), SystemTime::now())
+        .await
+        .unwrap();
+    data.put_admission_usage(
+        lb2,
+        &usage(&[("acme", 1), ("bigcorp", 5)]),
+        SystemTime::now(),
     )
     .await
     .unwrap();
-    data.update_tenant(
-        "alice",
-        &TenantConfiguration {
-            capacity_ops_per_sec: 20,
-        },
+    assert_eq!(
+        vec![
+            (lb1.to_owned(), usage(&[("acme", 3)])),
+            (lb2.to_owned(), usage(&[("acme", 1), ("bigcorp", 5)])),
+        ],
+        data.get_admission_usage().await.unwrap()
+    );
+
+    // A new report replaces the previous one.
+    data.put_admission_usage(lb1, &usage(&[]), SystemTime::now())
+        .await
+        .unwrap();
+    assert_eq!(
+        vec![
+            (lb1.to_owned(), usage(&[])),
+            (lb2.to_owned(), usage(&[("acme", 1), ("bigcorp", 5)])),
+        ],
+        data.get_admission_usage().await.unwrap()
+    );
+
+    // Old reports are ignored.
+    data.put_admission_usage(
+        lb2,
+        &usage(&[("acme", 1)]),
+        SystemTime::now() - admission::USAGE_EXPIRY_AGE - Duration::from_secs(1),
     )
     .await
     .unwrap();
+    assert_eq!(
+        vec![(lb1.to_owned(), usage(&[]))],
+        data.get_admission_usage().await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_tenant_config() {
+    let mut pg = ProcessGroup::new();
+    let (admin, data) = init_bt(&mut pg, emulator(PORT.next())).await;
+    admin.initialize_shared_tables().await.unwrap();
+    assert_eq!(
+        Vec::<(String, TenantConfiguration)>::new(),
+        data.get_tenants().await.unwrap()
+    );
+    data.update_tenant("bob", &TenantConfiguration::new(10))
+        .await
+        .unwrap();
+    data.update_tenant("alice", &TenantConfiguration::new(20))
+        .await
+        .unwrap();
     assert_eq!(
         vec![
-            (
-                String::from("alice"),
-                TenantConfiguration {
-                    capacity_ops_per_sec: 20
-                }
-            ),
-            (
-                String::from("bob"),
-                TenantConfiguration {
-                    capacity_ops_per_sec: 10
-                }
-            ),
+            (String::from("alice"), TenantConfiguration::new(20)),
+            (String::from("bob"), TenantConfiguration::new(10)),
         ],
         data.get_tenants().await.unwrap()
     );
@@ -1215,41 +1473,84 @@ async fn test_tenant_config() {
         "bob",
         &TenantConfiguration {
             capacity_ops_per_sec: 5,
+            burst_ops: Some(20),
+            record_ops_per_min: Some(10),
+            realm_capacities: vec![RealmCapacity {
+                realm: RealmId([1; 16]),
+                capacity_ops_per_sec: 50,
+                burst_ops: None,
+            }],
+            allowed_origins: Some(vec![String::from("https://bob.example")]),
+            max_registered_secrets: Some(100),
+            suspended: Some(String::from("contract ended")),
         },
     )
     .await
     .unwrap();
-    data.update_tenant(
-        "test-juiceboxmonitor",
-        &TenantConfiguration {
-            capacity_ops_per_sec: 100,
-        },
-    )
-    .await
-    .unwrap();
+    data.update_tenant("test-juiceboxmonitor", &TenantConfiguration::new(100))
+        .await
+        .unwrap();
     assert_eq!(
         vec![
-            (
-                String::from("alice"),
-                TenantConfiguration {
-                    capacity_ops_per_sec: 20
-                }
-            ),
+            (String::from("alice"), TenantConfiguration::new(20)),
             (
                 String::from("bob"),
                 TenantConfiguration {
-                    capacity_ops_per_sec: 5
+                    capacity_ops_per_sec: 5,
+                    burst_ops: Some(20),
+                    record_ops_per_min: Some(10),
+                    realm_capacities: vec![RealmCapacity {
+                        realm: RealmId([1; 16]),
+                        capacity_ops_per_sec: 50,
+                        burst_ops: None,
+                    }],
+                    allowed_origins: Some(vec![String::from("https://bob.example")]),
+                    max_registered_secrets: Some(100),
+                    suspended: Some(String::from("contract ended")),
                 }
             ),
             (
                 String::from("test-juiceboxmonitor"),
-                TenantConfiguration {
-                    capacity_ops_per_sec: 100
-                }
+                TenantConfiguration::new(100)
             ),
         ],
         data.get_tenants().await.unwrap()
     );
+
+    // Cells are timestamped to the millisecond, so a version written in the
+    // same millisecond would replace the previous one.
+    tokio::time::sleep(Duration::from_millis(2)).await;
+    data.delete_tenant("test-juiceboxmonitor").await.unwrap();
+    assert_eq!(
+        vec![String::from("alice"), String::from("bob")],
+        data.get_tenants()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(tenant, _)| tenant)
+            .collect::<Vec<_>>()
+    );
+    let history = data
+        .get_tenant_history("test-juiceboxmonitor")
+        .await
+        .unwrap();
+    assert_eq!(
+        vec![None, Some(TenantConfiguration::new(100))],
+        history
+            .iter()
+            .map(|version| version.config.clone())
+            .collect::<Vec<_>>()
+    );
+    assert!(history[0].written > history[1].written);
+    assert_eq!(
+        Some(String::from("contract ended")),
+        data.get_tenant_history("bob").await.unwrap()[0]
+            .config
+            .as_ref()
+            .unwrap()
+            .suspended
+    );
+    assert!(data.get_tenant_history("carol").await.unwrap().is_empty());
 }
 
 #[tokio::test]
@@ -1328,6 +1629,65 @@ async fn test_lease() {
     ));
 }
 
+#[tokio::test]
+async fn test_tenant_offboarding() {
+    let mut pg = ProcessGroup::new();
+    let (admin, data) = init_bt(&mut pg, emulator(PORT.next())).await;
+    admin.initialize_shared_tables().await.unwrap();
+
+    assert_eq!(
+        data.get_tenant_offboarding(&REALM, "acme").await.unwrap(),
+        None
+    );
+    assert!(data.list_tenant_offboardings().await.unwrap().is_empty());
+
+    let mut acme = TenantOffboarding::new(REALM, String::from("acme"), String::from("ops@acme"));
+    data.put_tenant_offboarding(&acme).await.unwrap();
+    // Same tenant, different realm.
+    let acme2 = TenantOffboarding::new(
+        RealmId([201; 16]),
+        String::from("acme"),
+        String::from("ops@acme"),
+    );
+    data.put_tenant_offboarding(&acme2).await.unwrap();
+    // This tenant's name is a prefix of the other's.
+    let ac = TenantOffboarding::new(REALM, String::from("ac"), String::from("ops@ac"));
+    data.put_tenant_offboarding(&ac).await.unwrap();
+
+    assert_eq!(
+        data.get_tenant_offboarding(&REALM, "acme").await.unwrap(),
+        Some(acme.clone())
+    );
+    assert_eq!(
+        data.get_tenant_offboarding(&REALM, "ac").await.unwrap(),
+        Some(ac.clone())
+    );
+    assert_eq!(
+        data.get_tenant_offboarding(&REALM, "bigcorp")
+            .await
+            .unwrap(),
+        None
+    );
+
+    // Progress replaces the previous state.
+    acme.checkpoint = Some(RecordId([7; 32]));
+    acme.deleted = 10;
+    acme.not_registered = 2;
+    acme.last_error = Some(String::from("agent went away"));
+    data.put_tenant_offboarding(&acme).await.unwrap();
+    acme.last_error = None;
+    acme.finished = Some(SystemTime::now());
+    data.put_tenant_offboarding(&acme).await.unwrap();
+    assert_eq!(
+        data.get_tenant_offboarding(&REALM, "acme").await.unwrap(),
+        Some(acme.clone())
+    );
+
+    let mut all = data.list_tenant_offboardings().await.unwrap();
+    all.sort_by(|a, b| (a.realm.0, &a.tenant).cmp(&(b.realm.0, &b.tenant)));
+    assert_eq!(all, vec![ac, acme, acme2]);
+}
+
 enum LeaseId {
     A,
     B,
@@ -4,24 +4,34 @@ use once_cell::sync::Lazy;
 use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use store::StoreClient;
 
 use ::reqwest::ClientBuilder;
-use agent_api::{ReloadTenantConfigurationRequest, ReloadTenantConfigurationResponse};
+use agent_api::{
+    AppResponse, HashedUserId, ReloadTenantConfigurationRequest, ReloadTenantConfigurationResponse,
+};
 use cluster_core::{JoinRealmError, NewRealmError};
+use hsm_api::RecordId;
 use jburl::Url;
+use juicebox_marshalling as marshalling;
 use juicebox_networking::reqwest::{self, ClientOptions};
 use juicebox_networking::rpc;
+use juicebox_noise::client::Handshake;
 use juicebox_process_group::ProcessGroup;
+use juicebox_realm_api::requests::{ClientRequestKind, NoiseRequest, SecretsRequest};
+use juicebox_realm_api::types::SessionId;
 use juicebox_sdk::{
     AuthToken, Client, Pin, Policy, RealmId, RecoverError, RegisterError, TokioSleeper, UserInfo,
     UserSecret,
 };
+use rand_core::{OsRng, RngCore};
 use store::tenant_config::TenantConfiguration;
 use testing::exec::bigtable::emulator;
-use testing::exec::cluster_gen::{create_cluster, ClusterConfig, RealmConfig};
+use testing::exec::cluster_gen::{create_cluster, ClusterConfig, RealmConfig, RealmResult};
 use testing::exec::hsm_gen::Entrust;
 use testing::exec::PortIssuer;
+use tokio::time::sleep;
 
 // rust runs the tests in parallel, so we need each test to get its own port.
 static PORT: Lazy<PortIssuer> = Lazy::new(|| PortIssuer::new(8666));
@@ -194,17 +204,156 @@ async fn rate_limiting() {
     assert_eq!(0, rate_limit_errors);
 }
 
+#[tokio::test]
+async fn suspension() {
+    let bt_args = emulator(PORT.next());
+    let mut processes = ProcessGroup::new();
+
+    let cluster_args = ClusterConfig {
+        load_balancers: 1,
+        cluster_managers: 1,
+        realms: vec![RealmConfig {
+            hsms: 3,
+            groups: 1,
+            state_dir: None,
+        }],
+        bigtable: bt_args,
+        local_pubsub: true,
+        secrets_file: Some(PathBuf::from("../secrets-demo.json")),
+        entrust: Entrust(false),
+        path_to_target: fs::canonicalize("..").unwrap(),
+    };
+
+    let cluster = create_cluster(cluster_args, &mut processes, PORT.clone())
+        .await
+        .unwrap();
+    let realm = &cluster.realms[0];
+
+    let register = |user: &str| {
+        let client = cluster.client_for_user(user);
+        async move {
+            client
+                .register(
+                    &Pin::from(vec![1, 2, 3, 4]),
+                    &UserSecret::from(vec![42]),
+                    &UserInfo::from(vec![b'c']),
+                    Policy { num_guesses: 2 },
+                )
+                .await
+        }
+    };
+    register("before").await.unwrap();
+
+    // The load balancer picks up the suspension on its next refresh, without
+    // the agents being told about it.
+    let suspended = TenantConfiguration {
+        suspended: Some(String::from("contract expired")),
+        ..TenantConfiguration::new(100)
+    };
+    cluster
+        .store
+        .update_tenant(&cluster.tenant, &suspended)
+        .await
+        .unwrap();
+    let start = Instant::now();
+    loop {
+        match register("during").await {
+            Err(RegisterError::InvalidAuth) => break,
+            Ok(()) => {
+                assert!(
+                    start.elapsed() < Duration::from_secs(30),
+                    "load balancer didn't reject requests for a suspended tenant"
+                );
+                sleep(Duration::from_millis(500)).await;
+            }
+            Err(e) => panic!("Unexpected error during register {e:?}"),
+        }
+    }
+    // The agents haven't reloaded their configuration yet, so it was the load
+    // balancer that rejected the request.
+    for response in agent_app_requests(realm, &cluster.tenant).await {
+        assert!(
+            !matches!(response, AppResponse::TenantSuspended),
+            "{response:?}"
+        );
+    }
+
+    // Once told, the agents reject the tenant's requests themselves.
+    reload_agents(&realm.agents).await;
+    for response in agent_app_requests(realm, &cluster.tenant).await {
+        assert!(
+            matches!(response, AppResponse::TenantSuspended),
+            "{response:?}"
+        );
+    }
+    // Other tenants aren't affected.
+    for response in agent_app_requests(realm, "other").await {
+        assert!(
+            !matches!(response, AppResponse::TenantSuspended),
+            "{response:?}"
+        );
+    }
+
+    update_rate_limit(&cluster.store, &realm.agents, &cluster.tenant, 100).await;
+    let start = Instant::now();
+    loop {
+        match register("after").await {
+            Ok(()) => break,
+            Err(RegisterError::InvalidAuth) => {
+                assert!(
+                    start.elapsed() < Duration::from_secs(30),
+                    "load balancer didn't resume requests for the tenant"
+                );
+                sleep(Duration::from_millis(500)).await;
+            }
+            Err(e) => panic!("Unexpected error during register {e:?}"),
+        }
+    }
+
+    processes.kill();
+}
+
+/// Sends a `Register1` request for the tenant directly to each of the realm's
+/// agents, bypassing the load balancer.
+async fn agent_app_requests(realm: &RealmResult, tenant: &str) -> Vec<AppResponse> {
+    let agent_client = reqwest::Client::new(ClientOptions::default());
+    let group = *realm.groups.last().unwrap();
+
+    let mut pub_key_bytes = [0u8; 32];
+    pub_key_bytes.copy_from_slice(&realm.communication_public_key.0);
+    let pub_key = x25519_dalek::PublicKey::from(pub_key_bytes);
+    let req = marshalling::to_vec(&SecretsRequest::Register1).unwrap();
+
+    join_all(realm.agents.iter().map(|agent| {
+        let (_, handshake) = Handshake::start(&pub_key, &req, &mut OsRng).unwrap();
+        let mut record_id = RecordId::max_id();
+        OsRng.fill_bytes(&mut record_id.0);
+        let request = agent_api::AppRequest {
+            realm: realm.realm,
+            group,
+            record_id,
+            session_id: SessionId(OsRng.next_u32()),
+            kind: ClientRequestKind::SecretsRequest,
+            encrypted: NoiseRequest::Handshake { handshake },
+            tenant: tenant.to_owned(),
+            user: HashedUserId::new(tenant, "mallory"),
+            slot: None,
+        };
+        let agent_client = &agent_client;
+        async move { rpc::send(agent_client, agent, request).await.unwrap() }
+    }))
+    .await
+}
+
 async fn update_rate_limit(store: &StoreClient, agents: &[Url], tenant: &str, ops_per_sec: usize) {
     store
-        .update_tenant(
-            tenant,
-            &TenantConfiguration {
-                capacity_ops_per_sec: ops_per_sec,
-            },
-        )
+        .update_tenant(tenant, &TenantConfiguration::new(ops_per_sec))
         .await
         .unwrap();
+    reload_agents(agents).await;
+}
 
+async fn reload_agents(agents: &[Url]) {
     let http_client = reqwest::Client::default();
     for r in join_all(
         agents
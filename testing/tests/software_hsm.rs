@@ -28,6 +28,7 @@ async fn hsm_reload_state() {
         HsmHttpClient::new(hsm_url),
         "test".to_owned(),
         metrics::Client::NONE,
+        None,
     );
     wait_til_running(&hsm_client).await;
     hsm_client.send(hsm_api::NewRealmRequest {}).await.unwrap();